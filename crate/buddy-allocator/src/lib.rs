@@ -0,0 +1,327 @@
+//! Buddy system memory allocator
+//!
+//! Every block has a size of power of 2, and is aligned to its size.
+//! A free block is split in halves (buddies) on allocation,
+//! and merged with its buddy on deallocation when both are free.
+
+#![cfg_attr(not(test), no_std)]
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::{max, min};
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use spin::Mutex;
+
+/// Number of block size classes. Block size of class `i` is `2^i`.
+const ORDER: usize = 32;
+
+/// An intrusive singly linked list of free blocks.
+///
+/// The next pointer is stored in the first word of each free block.
+#[derive(Copy, Clone)]
+struct FreeList {
+    head: *mut usize,
+}
+
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    const fn new() -> Self {
+        FreeList { head: ptr::null_mut() }
+    }
+    fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+    unsafe fn push(&mut self, item: *mut usize) {
+        *item = self.head as usize;
+        self.head = item;
+    }
+    fn pop(&mut self) -> Option<*mut usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let item = self.head;
+        self.head = unsafe { *item as *mut usize };
+        Some(item)
+    }
+    /// Remove `item` from the list. Return false if not found.
+    fn remove(&mut self, item: *mut usize) -> bool {
+        let mut prev: *mut *mut usize = &mut self.head;
+        unsafe {
+            while !(*prev).is_null() {
+                if *prev == item {
+                    *prev = *item as *mut usize;
+                    return true;
+                }
+                prev = *prev as *mut *mut usize;
+            }
+        }
+        false
+    }
+}
+
+/// A buddy system heap
+pub struct Heap {
+    free_list: [FreeList; ORDER],
+    /// Bytes requested by users
+    user: usize,
+    /// Bytes actually allocated (rounded up to power of 2)
+    allocated: usize,
+    /// Bytes managed by the heap
+    total: usize,
+}
+
+impl Heap {
+    /// Create an empty heap. Call `init` before using it.
+    pub const fn empty() -> Self {
+        Heap {
+            free_list: [FreeList::new(); ORDER],
+            user: 0,
+            allocated: 0,
+            total: 0,
+        }
+    }
+
+    /// Add memory `[start, start + size)` to the heap
+    pub unsafe fn init(&mut self, start: usize, size: usize) {
+        self.add_to_heap(start, start + size);
+    }
+
+    /// Add memory `[start, end)` to the heap.
+    /// It can be called multiple times with disjoint ranges.
+    pub unsafe fn add_to_heap(&mut self, start: usize, end: usize) {
+        for (addr, class) in Blocks::new(start, end) {
+            self.free_list[class].push(addr as *mut usize);
+            self.total += 1 << class;
+        }
+    }
+
+    /// Allocate a block for `layout`
+    pub fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        let size = block_size(&layout);
+        let class = size.trailing_zeros() as usize;
+        let i = (class..ORDER).find(|&i| !self.free_list[i].is_empty()).ok_or(())?;
+        // split the larger block until we get one of the right size
+        for j in (class + 1..=i).rev() {
+            let block = self.free_list[j].pop().unwrap();
+            unsafe {
+                self.free_list[j - 1].push((block as usize + (1 << (j - 1))) as *mut usize);
+                self.free_list[j - 1].push(block);
+            }
+        }
+        let block = self.free_list[class].pop().unwrap();
+        self.user += layout.size();
+        self.allocated += size;
+        Ok(unsafe { NonNull::new_unchecked(block as *mut u8) })
+    }
+
+    /// Deallocate a block previously allocated with the same `layout`
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let size = block_size(&layout);
+        let mut class = size.trailing_zeros() as usize;
+        let mut current = ptr.as_ptr() as usize;
+        // merge with the buddy while it is free
+        while class + 1 < ORDER {
+            let buddy = current ^ (1 << class);
+            if !self.free_list[class].remove(buddy as *mut usize) {
+                break;
+            }
+            current = min(current, buddy);
+            class += 1;
+        }
+        self.free_list[class].push(current as *mut usize);
+        self.user -= layout.size();
+        self.allocated -= size;
+    }
+
+    /// Bytes requested by users
+    pub fn stats_alloc_user(&self) -> usize {
+        self.user
+    }
+
+    /// Bytes actually allocated
+    pub fn stats_alloc_actual(&self) -> usize {
+        self.allocated
+    }
+
+    /// Bytes managed by the heap
+    pub fn stats_total_bytes(&self) -> usize {
+        self.total
+    }
+}
+
+/// A buddy system heap protected by a spin lock, usable as the global allocator
+pub struct LockedHeap(Mutex<Heap>);
+
+impl LockedHeap {
+    pub const fn empty() -> Self {
+        LockedHeap(Mutex::new(Heap::empty()))
+    }
+    pub fn lock(&self) -> spin::MutexGuard<Heap> {
+        self.0.lock()
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().alloc(layout).ok().map_or(ptr::null_mut(), |p| p.as_ptr())
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().dealloc(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+/// Splits a memory range into blocks aligned to their sizes,
+/// yielding `(address, class)` of each block
+struct Blocks {
+    current: usize,
+    end: usize,
+}
+
+impl Blocks {
+    fn new(start: usize, end: usize) -> Self {
+        let unit = size_of::<usize>();
+        let current = match start.checked_add(unit - 1) {
+            // address 0 is null, a block there can't be returned
+            Some(start) => max(start & !(unit - 1), unit),
+            None => end,
+        };
+        Blocks { current, end: end & !(unit - 1) }
+    }
+}
+
+impl Iterator for Blocks {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        if self.current >= self.end {
+            return None;
+        }
+        let lowbit = self.current & (!self.current + 1);
+        // the largest class is the limit, even if a larger block fits
+        let size = min(min(lowbit, prev_power_of_two(self.end - self.current)), 1 << (ORDER - 1));
+        let block = (self.current, size.trailing_zeros() as usize);
+        self.current += size;
+        Some(block)
+    }
+}
+
+/// Size of the block to serve `layout`
+fn block_size(layout: &Layout) -> usize {
+    max(layout.size().next_power_of_two(), max(layout.align(), size_of::<usize>()))
+}
+
+fn prev_power_of_two(num: usize) -> usize {
+    1 << (8 * size_of::<usize>() - num.leading_zeros() as usize - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4KB heap region aligned to its size
+    #[repr(align(4096))]
+    struct Region([u8; 4096]);
+
+    fn new_heap(region: &mut Region) -> Heap {
+        let mut heap = Heap::empty();
+        unsafe { heap.init(region.0.as_ptr() as usize, region.0.len()); }
+        heap
+    }
+
+    #[test]
+    fn empty() {
+        let mut heap = Heap::empty();
+        assert!(heap.alloc(Layout::from_size_align(1, 1).unwrap()).is_err());
+    }
+
+    #[test]
+    fn alloc_dealloc() {
+        let mut region = Region([0; 4096]);
+        let mut heap = new_heap(&mut region);
+        assert_eq!(heap.stats_total_bytes(), 4096);
+
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let p1 = heap.alloc(layout).unwrap();
+        let p2 = heap.alloc(layout).unwrap();
+        assert_ne!(p1, p2);
+        assert_eq!(heap.stats_alloc_user(), 200);
+        assert_eq!(heap.stats_alloc_actual(), 256);
+        unsafe {
+            heap.dealloc(p1, layout);
+            heap.dealloc(p2, layout);
+        }
+        assert_eq!(heap.stats_alloc_actual(), 0);
+        // all blocks are merged back
+        let whole = Layout::from_size_align(4096, 8).unwrap();
+        assert!(heap.alloc(whole).is_ok());
+    }
+
+    #[test]
+    fn align() {
+        let mut region = Region([0; 4096]);
+        let mut heap = new_heap(&mut region);
+        heap.alloc(Layout::from_size_align(8, 8).unwrap()).unwrap();
+        let p = heap.alloc(Layout::from_size_align(16, 1024).unwrap()).unwrap();
+        assert_eq!(p.as_ptr() as usize % 1024, 0);
+    }
+
+    #[test]
+    fn out_of_memory() {
+        let mut region = Region([0; 4096]);
+        let mut heap = new_heap(&mut region);
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        for _ in 0..4 {
+            heap.alloc(layout).unwrap();
+        }
+        assert!(heap.alloc(layout).is_err());
+        assert!(heap.alloc(Layout::from_size_align(8, 8).unwrap()).is_err());
+    }
+
+    #[test]
+    fn region_at_zero() {
+        // the first word is skipped, instead of looping forever on it
+        let blocks: Vec<_> = Blocks::new(0, 64).collect();
+        let unit = size_of::<usize>();
+        assert_eq!(blocks.first().unwrap().0, unit);
+        assert_eq!(blocks.iter().map(|&(_, class)| 1 << class).sum::<usize>(), 64 - unit);
+        assert_eq!(Blocks::new(0, 0).count(), 0);
+    }
+
+    #[test]
+    fn region_at_end_of_address_space() {
+        assert_eq!(Blocks::new(usize::max_value() - 2, usize::max_value()).count(), 0);
+        let start = usize::max_value() - 4095;
+        let size: usize = Blocks::new(start, usize::max_value()).map(|(_, class)| 1 << class).sum();
+        assert_eq!(size, 4096 - size_of::<usize>());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn huge_region() {
+        let end = usize::max_value() & !(size_of::<usize>() - 1);
+        let mut last = 0;
+        for (addr, class) in Blocks::new(0, end) {
+            assert!(class < ORDER);
+            assert_eq!(addr % (1 << class), 0);
+            assert!(addr >= last);
+            last = addr;
+            if addr > (1 << 40) {
+                break;
+            }
+        }
+        assert!(last > 1 << 40);
+    }
+
+    #[test]
+    fn unaligned_region() {
+        let mut region = Region([0; 4096]);
+        let mut heap = Heap::empty();
+        unsafe { heap.init(region.0.as_mut_ptr() as usize + 8, 4000); }
+        assert_eq!(heap.stats_total_bytes(), 4000);
+        let layout = Layout::from_size_align(2048, 8).unwrap();
+        assert!(heap.alloc(layout).is_err());
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        assert!(heap.alloc(layout).is_ok());
+    }
+}
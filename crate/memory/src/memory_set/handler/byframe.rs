@@ -25,6 +25,10 @@ impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
     fn page_fault_handler(&self, _pt: &mut PageTable, _addr: VirtAddr) -> bool {
         false
     }
+
+    fn readonly(&self) -> bool {
+        self.flags.readonly
+    }
 }
 
 impl<T: FrameAllocator> ByFrame<T> {
@@ -36,6 +36,10 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
         self.flags.apply(entry);
         true
     }
+
+    fn readonly(&self) -> bool {
+        self.flags.readonly
+    }
 }
 
 impl<T: FrameAllocator> Delay<T> {
@@ -23,6 +23,10 @@ impl MemoryHandler for Linear {
     fn page_fault_handler(&self, _pt: &mut PageTable, _addr: VirtAddr) -> bool {
         false
     }
+
+    fn readonly(&self) -> bool {
+        self.flags.readonly
+    }
 }
 
 impl Linear {
@@ -6,6 +6,8 @@ pub trait MemoryHandler: Debug + 'static {
     fn map(&self, pt: &mut PageTable, addr: VirtAddr);
     fn unmap(&self, pt: &mut PageTable, addr: VirtAddr);
     fn page_fault_handler(&self, pt: &mut PageTable, addr: VirtAddr) -> bool;
+    /// Whether the memory is mapped read-only
+    fn readonly(&self) -> bool;
 }
 
 impl Clone for Box<MemoryHandler> {
@@ -1,7 +1,7 @@
 //! memory set, area
 //! and the inactive page table
 
-use alloc::{vec::Vec, boxed::Box};
+use alloc::{vec::Vec, boxed::Box, string::String};
 use core::fmt::{Debug, Error, Formatter};
 use super::*;
 use crate::paging::*;
@@ -43,6 +43,37 @@ impl MemoryArea {
         addr >= self.start_addr && addr < self.end_addr
     }
     /*
+    **  @brief  test whether an array is entirely in the memory area
+    **  @param  ptr: *const S        the start address of the array
+    **  @param  count: usize         the number of elements in the array
+    **  @retval bool                 whether the array is in the memory area
+    */
+    pub fn check_array<S>(&self, ptr: *const S, count: usize) -> bool {
+        let begin = ptr as usize;
+        match count.checked_mul(::core::mem::size_of::<S>()).and_then(|size| begin.checked_add(size)) {
+            Some(end) => begin >= self.start_addr && end <= self.end_addr,
+            None => false,
+        }
+    }
+    /*
+    **  @brief  test whether the memory area can be written
+    **  @retval bool                 whether the memory area is not read-only
+    */
+    pub fn is_writable(&self) -> bool {
+        !self.handler.readonly()
+    }
+    /*
+    **  @brief  read a null-terminated UTF-8 string in the memory area
+    **  @param  ptr: *const u8       the start address of the string
+    **  @retval Option<String>       the copied string, if it is terminated in the memory area
+    */
+    pub fn check_and_clone_cstr(&self, ptr: *const u8) -> Option<String> {
+        if !self.contains(ptr as usize) {
+            return None;
+        }
+        unsafe { clone_cstr(ptr, self.end_addr) }
+    }
+    /*
     **  @brief  test whether the memory area is overlap with another memory area
     **  @param  other: &MemoryArea   another memory area to test
     **  @retval bool                 whether the memory area is overlap with another memory area
@@ -76,6 +107,29 @@ impl MemoryArea {
     }
 }
 
+/*
+**  @brief  copy a null-terminated UTF-8 string which must end before `end`
+**          it is scanned a page at a time, so nothing past the terminator's page is touched
+**  @param  ptr: *const u8       the start address of the string
+**  @param  end: VirtAddr        the end address of the readable memory
+**  @retval Option<String>       the copied string, if it is terminated before `end`
+*/
+pub(crate) unsafe fn clone_cstr(ptr: *const u8, end: VirtAddr) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut addr = ptr as usize;
+    while addr < end {
+        let page_end = (Page::of_addr(addr) + 1).start_address().min(end);
+        let page = ::core::slice::from_raw_parts(addr as *const u8, page_end - addr);
+        if let Some(len) = page.iter().position(|&c| c == 0) {
+            bytes.extend_from_slice(&page[..len]);
+            return String::from_utf8(bytes).ok();
+        }
+        bytes.extend_from_slice(page);
+        addr = page_end;
+    }
+    None
+}
+
 /// The attributes of the memory
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
 pub struct MemoryAttr {
@@ -167,6 +221,73 @@ impl<T: InactivePageTable> MemorySet<T> {
         self.areas.iter().find(|area| area.contains(addr))
     }
     /*
+    **  @brief  test whether a pointer points to a valid object in the memory set
+    **  @param  ptr: *const S        the pointer to test
+    **  @retval bool                 whether the object is in a memory area
+    */
+    pub fn check_ptr<S>(&self, ptr: *const S) -> bool {
+        self.check_array(ptr, 1)
+    }
+    /*
+    **  @brief  test whether a pointer points to a valid and writable object in the memory set
+    **  @param  ptr: *mut S          the pointer to test
+    **  @retval bool                 whether the object is in a writable memory area
+    */
+    pub fn check_mut_ptr<S>(&self, ptr: *mut S) -> bool {
+        self.check_mut_array(ptr, 1)
+    }
+    /*
+    **  @brief  test whether an array is entirely in the memory set
+    **          the array may span adjacent memory areas
+    **  @param  ptr: *const S        the start address of the array
+    **  @param  count: usize         the number of elements in the array
+    **  @retval bool                 whether the array is in memory areas
+    */
+    pub fn check_array<S>(&self, ptr: *const S, count: usize) -> bool {
+        self.check_range(ptr as usize, count, ::core::mem::size_of::<S>(), false)
+    }
+    /*
+    **  @brief  test whether an array is entirely in writable memory areas of the memory set
+    **          the array may span adjacent memory areas
+    **  @param  ptr: *mut S          the start address of the array
+    **  @param  count: usize         the number of elements in the array
+    **  @retval bool                 whether the array is in writable memory areas
+    */
+    pub fn check_mut_array<S>(&self, ptr: *mut S, count: usize) -> bool {
+        self.check_range(ptr as usize, count, ::core::mem::size_of::<S>(), true)
+    }
+    /*
+    **  @brief  test whether `count` objects of `size` bytes from `begin` are covered by memory areas
+    **  @param  begin: VirtAddr      the start address of the objects
+    **  @param  count: usize         the number of objects
+    **  @param  size: usize          the size of an object
+    **  @param  writable: bool       whether the memory areas must be writable
+    **  @retval bool                 whether the objects are covered
+    */
+    fn check_range(&self, begin: VirtAddr, count: usize, size: usize, writable: bool) -> bool {
+        let end = match count.checked_mul(size).and_then(|size| begin.checked_add(size)) {
+            Some(end) => end,
+            None => return false,
+        };
+        let mut addr = begin;
+        while addr < end {
+            match self.find_area(addr) {
+                Some(area) if !writable || area.is_writable() => addr = area.end_addr,
+                _ => return false,
+            }
+        }
+        true
+    }
+    /*
+    **  @brief  copy a null-terminated UTF-8 string from the memory set
+    **  @param  ptr: *const u8       the start address of the string
+    **  @retval Option<String>       the copied string, if it is valid and entirely in a memory area
+    */
+    pub fn check_and_clone_cstr(&self, ptr: *const u8) -> Option<String> {
+        self.find_area(ptr as usize)
+            .and_then(|area| area.check_and_clone_cstr(ptr))
+    }
+    /*
     **  @brief  add the memory area to the memory set
     **  @param  area: MemoryArea     the memory area to add
     **  @retval none
@@ -272,3 +393,89 @@ impl<T: InactivePageTable> Debug for MemorySet<T> {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct MockInactivePageTable(MockPageTable);
+
+    impl InactivePageTable for MockInactivePageTable {
+        type Active = MockPageTable;
+
+        fn new_bare() -> Self {
+            MockInactivePageTable(MockPageTable::new())
+        }
+        fn map_kernel(&mut self) {}
+        fn token(&self) -> usize { 0 }
+        unsafe fn set_token(_token: usize) {}
+        fn active_token() -> usize { 0 }
+        fn flush_tlb() {}
+        fn edit<T>(&mut self, f: impl FnOnce(&mut Self::Active) -> T) -> T {
+            f(&mut self.0)
+        }
+    }
+
+    /// A handler which maps nothing, only the attribute is used
+    #[derive(Debug, Clone)]
+    struct MockHandler(MemoryAttr);
+
+    impl MemoryHandler for MockHandler {
+        fn box_clone(&self) -> Box<MemoryHandler> {
+            Box::new(self.clone())
+        }
+        fn map(&self, _pt: &mut PageTable, _addr: VirtAddr) {}
+        fn unmap(&self, _pt: &mut PageTable, _addr: VirtAddr) {}
+        fn page_fault_handler(&self, _pt: &mut PageTable, _addr: VirtAddr) -> bool {
+            false
+        }
+        fn readonly(&self) -> bool {
+            self.0.readonly
+        }
+    }
+
+    #[test]
+    fn check_array() {
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(0x1000, 0x2000, MockHandler(MemoryAttr::default().readonly()), "text");
+        ms.push(0x2000, 0x3000, MockHandler(MemoryAttr::default()), "data");
+        ms.push(0x4000, 0x5000, MockHandler(MemoryAttr::default()), "stack");
+
+        assert!(ms.check_array(0x1800 as *const u8, 0x100));
+        assert!(ms.check_array(0x1800 as *const u8, 0x1800));
+        assert!(!ms.check_array(0x1800 as *const u8, 0x1801));
+        assert!(!ms.check_array(0x3800 as *const u8, 0x1000));
+        assert!(!ms.check_array(0x0 as *const u8, 0x1000));
+        assert!(!ms.check_array(0x4000 as *const u64, usize::max_value()));
+        assert!(ms.check_ptr(0x4ff8 as *const u64));
+        assert!(!ms.check_ptr(0x4ffc as *const u64));
+
+        assert!(!ms.check_mut_array(0x1800 as *mut u8, 0x100));
+        assert!(!ms.check_mut_array(0x1800 as *mut u8, 0x1000));
+        assert!(ms.check_mut_array(0x2000 as *mut u8, 0x1000));
+        assert!(!ms.check_mut_ptr(0x1ffc as *mut u64));
+        assert!(ms.check_mut_ptr(0x4ff8 as *mut u64));
+    }
+
+    #[test]
+    fn check_and_clone_cstr() {
+        let mut buf = vec![b'a'; PAGE_SIZE * 3];
+        let start = (Page::of_addr(buf.as_ptr() as usize) + 1).start_address();
+        let offset = start - buf.as_ptr() as usize;
+        let mut ms = MemorySet::<MockInactivePageTable>::new();
+        ms.push(start, start + PAGE_SIZE * 2, MockHandler(MemoryAttr::default()), "data");
+
+        // no terminator in the area
+        assert_eq!(ms.check_and_clone_cstr((start + 10) as *const u8), None);
+        // crossing a page
+        buf[offset + PAGE_SIZE + 2] = 0;
+        let s = ms.check_and_clone_cstr((start + PAGE_SIZE - 3) as *const u8);
+        assert_eq!(s.as_ref().map(|s| s.as_str()), Some("aaaaa"));
+        assert_eq!(ms.check_and_clone_cstr(start as *const u8).map(|s| s.len()), Some(PAGE_SIZE + 2));
+        // outside the area
+        assert_eq!(ms.check_and_clone_cstr((start - 1) as *const u8), None);
+        buf[offset + 5] = 0xff;
+        buf[offset + 6] = 0;
+        assert_eq!(ms.check_and_clone_cstr(start as *const u8), None);
+    }
+}
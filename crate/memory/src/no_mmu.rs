@@ -1,4 +1,4 @@
-use alloc::vec::Vec;
+use alloc::{vec::Vec, string::String};
 use alloc::alloc::{Layout, GlobalAlloc};
 use core::marker::PhantomData;
 
@@ -27,6 +27,28 @@ impl<S: NoMMUSupport> MemorySet<S> {
         self.areas.push(area);
         slice
     }
+    /// Check the pointer points to a valid object in the memory set
+    pub fn check_ptr<T>(&self, ptr: *const T) -> bool {
+        self.check_array(ptr, 1)
+    }
+    /// Check the array is entirely in one area of the memory set
+    pub fn check_array<T>(&self, ptr: *const T, count: usize) -> bool {
+        self.areas.iter().any(|area| area.check_array(ptr, count))
+    }
+    /// Check the pointer points to a valid and writable object in the memory set.
+    /// All areas are writable without MMU.
+    pub fn check_mut_ptr<T>(&self, ptr: *mut T) -> bool {
+        self.check_ptr(ptr)
+    }
+    /// Check the array is entirely in one writable area of the memory set
+    pub fn check_mut_array<T>(&self, ptr: *mut T, count: usize) -> bool {
+        self.check_array(ptr, count)
+    }
+    /// Copy a null-terminated UTF-8 string in the memory set
+    pub fn check_and_clone_cstr(&self, ptr: *const u8) -> Option<String> {
+        self.areas.iter().find(|area| area.check_array(ptr, 1))
+            .and_then(|area| unsafe { crate::memory_set::clone_cstr(ptr, area.ptr + area.layout.size()) })
+    }
     // empty impls
     pub fn with<T>(&self, f: impl FnOnce() -> T) -> T { f() }
     pub fn token(&self) -> usize { 0 }
@@ -46,6 +68,13 @@ impl<S: NoMMUSupport> MemoryArea<S> {
         let ptr = unsafe { S::allocator().alloc(layout) } as usize;
         MemoryArea { ptr, layout, support: PhantomData }
     }
+    fn check_array<T>(&self, ptr: *const T, count: usize) -> bool {
+        let begin = ptr as usize;
+        match count.checked_mul(core::mem::size_of::<T>()).and_then(|size| begin.checked_add(size)) {
+            Some(end) => begin >= self.ptr && end <= self.ptr + self.layout.size(),
+            None => false,
+        }
+    }
     unsafe fn as_buf(&self) -> &'static mut [u8] {
         core::slice::from_raw_parts_mut(self.ptr as *mut u8, self.layout.size())
     }
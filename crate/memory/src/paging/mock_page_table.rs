@@ -57,8 +57,8 @@ impl Entry for MockEntry {
     fn set_user(&mut self, value: bool) { unimplemented!() }
     fn execute(&self) -> bool { unimplemented!() }
     fn set_execute(&mut self, value: bool) { unimplemented!() }
-    fn mmio(&self) -> bool { unimplemented!() }
-    fn set_mmio(&mut self, value: bool) { unimplemented!() }
+    fn mmio(&self) -> u8 { unimplemented!() }
+    fn set_mmio(&mut self, value: u8) { unimplemented!() }
 }
 
 type PageFaultHandler = Box<FnMut(&mut MockPageTable, VirtAddr)>;
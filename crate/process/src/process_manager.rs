@@ -1,9 +1,10 @@
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 use log::*;
 use crate::scheduler::Scheduler;
 use crate::event_hub::EventHub;
+use crate::interrupt;
 
 struct Process {
     #[allow(dead_code)]
@@ -13,6 +14,12 @@ struct Process {
     context: Option<Box<Context>>,
     parent: Pid,
     children: Vec<Pid>,
+    /// Removed as soon as it exits, no one will wait for it
+    detached: bool,
+    /// Thread local storage slot
+    local: usize,
+    /// A wakeup came while it was running, the next `park` returns at once
+    wakeup_token: bool,
 }
 
 pub type Pid = usize;
@@ -71,6 +78,9 @@ impl ProcessManager {
             context: Some(context),
             parent,
             children: Vec::new(),
+            detached: false,
+            local: 0,
+            wakeup_token: false,
         });
         self.scheduler.lock().insert(pid);
         self.procs[parent].lock().as_mut().expect("invalid parent proc")
@@ -82,16 +92,26 @@ impl ProcessManager {
     /// Return true if time slice == 0.
     /// Called by timer interrupt handler.
     pub fn tick(&self, pid: Pid) -> bool {
-        let mut event_hub = self.event_hub.lock();
-        event_hub.tick();
-        while let Some(event) = event_hub.pop() {
-            match event {
-                Event::Wakeup(pid) => self.set_status(pid, Status::Ready),
+        self.with_event_hub(|event_hub| {
+            event_hub.tick();
+            while let Some(event) = event_hub.pop() {
+                match event {
+                    Event::Wakeup(pid) => self.set_status(pid, Status::Ready),
+                }
             }
-        }
+        });
         self.scheduler.lock().tick(pid)
     }
 
+    /// Lock the event hub with interrupts disabled,
+    /// since wakeups from interrupt handlers lock it too
+    fn with_event_hub<T>(&self, f: impl FnOnce(&mut EventHub<Event>) -> T) -> T {
+        let flags = unsafe { interrupt::disable_and_store() };
+        let ret = f(&mut self.event_hub.lock());
+        unsafe { interrupt::restore(flags); }
+        ret
+    }
+
     /// Set the priority of process `pid`
     pub fn set_priority(&self, pid: Pid, priority: u8) {
         self.scheduler.lock().set_priority(pid, priority);
@@ -121,7 +141,12 @@ impl ProcessManager {
         proc.context = Some(context);
         match proc.status {
             Status::Ready => self.scheduler.lock().insert(pid),
-            Status::Exited(_) => self.exit_handler(pid, proc),
+            Status::Exited(_) => {
+                self.exit_handler(pid, proc);
+                if proc.detached {
+                    self.release(pid, &mut proc_lock);
+                }
+            }
             _ => {}
         }
     }
@@ -130,13 +155,20 @@ impl ProcessManager {
     /// Insert/Remove it to/from scheduler if necessary.
     fn set_status(&self, pid: Pid, status: Status) {
         let mut proc_lock = self.procs[pid].lock();
+        self.set_status_locked(pid, &mut proc_lock, status);
+    }
+
+    /// `set_status` of process `pid`, locked by `proc_lock`
+    fn set_status_locked(&self, pid: Pid, proc_lock: &mut MutexGuard<Option<Process>>, status: Status) {
         let mut proc = proc_lock.as_mut().expect("process not exist");
         trace!("process {} {:?} -> {:?}", pid, proc.status, status);
         match (&proc.status, &status) {
             (Status::Ready, Status::Ready) => return,
             (Status::Ready, _) => self.scheduler.lock().remove(pid),
             (Status::Exited(_), _) => panic!("can not set status for a exited process"),
-            (Status::Sleeping, Status::Exited(_)) => self.event_hub.lock().remove(Event::Wakeup(pid)),
+            (Status::Sleeping, Status::Exited(_)) => self.with_event_hub(|hub| hub.remove(Event::Wakeup(pid))),
+            // `stop` inserts it when it stops running
+            (Status::Running(_), _) => {}
             (_, Status::Ready) => self.scheduler.lock().insert(pid),
             _ => {}
         }
@@ -145,7 +177,12 @@ impl ProcessManager {
             _ => proc.status = status,
         }
         match proc.status {
-            Status::Exited(_) => self.exit_handler(pid, proc),
+            Status::Exited(_) => {
+                self.exit_handler(pid, proc);
+                if proc.detached {
+                    self.release(pid, proc_lock);
+                }
+            }
             _ => {}
         }
     }
@@ -158,11 +195,33 @@ impl ProcessManager {
     /// Its all children will be set parent to 0.
     pub fn remove(&self, pid: Pid) {
         let mut proc_lock = self.procs[pid].lock();
-        let proc = proc_lock.as_ref().expect("process not exist");
-        match proc.status {
+        match proc_lock.as_ref().expect("process not exist").status {
             Status::Exited(_) => {}
             _ => panic!("can not remove non-exited process"),
         }
+        self.release(pid, &mut proc_lock);
+    }
+
+    /// Remove process `pid` after it exits, instead of waiting for it.
+    /// Return true if it has exited already and has been removed now.
+    pub fn detach(&self, pid: Pid) -> bool {
+        let mut proc_lock = self.procs[pid].lock();
+        let proc = proc_lock.as_mut().expect("process not exist");
+        match proc.status {
+            Status::Exited(_) => {
+                self.release(pid, &mut proc_lock);
+                true
+            }
+            _ => {
+                proc.detached = true;
+                false
+            }
+        }
+    }
+
+    /// Release the pid of exited process `pid`, locked by `proc_lock`
+    fn release(&self, pid: Pid, proc_lock: &mut MutexGuard<Option<Process>>) {
+        let proc = proc_lock.as_ref().expect("process not exist");
         // orphan procs
         for child in proc.children.iter() {
             (&self.procs[*child]).lock().as_mut().expect("process not exist").parent = 0;
@@ -171,7 +230,7 @@ impl ProcessManager {
         self.procs[proc.parent].lock().as_mut().expect("process not exist")
             .children.retain(|&i| i != pid);
         // release the pid
-        *proc_lock = None;
+        **proc_lock = None;
     }
 
     /// Sleep `pid` for `time` ticks.
@@ -179,11 +238,52 @@ impl ProcessManager {
     pub fn sleep(&self, pid: Pid, time: usize) {
         self.set_status(pid, Status::Sleeping);
         if time != 0 {
-            self.event_hub.lock().push(time, Event::Wakeup(pid));
+            self.with_event_hub(|hub| hub.push(time, Event::Wakeup(pid)));
+        }
+    }
+
+    /// Sleep `pid` until it is woken up, or for `time` ticks if it is not 0.
+    /// Return false without sleeping if it has been woken up since the last `park`.
+    pub fn park(&self, pid: Pid, time: usize) -> bool {
+        {
+            // checking the token and going to sleep under one lock,
+            // a wakeup either sets the token before or finds it sleeping after
+            let mut proc_lock = self.procs[pid].lock();
+            let proc = proc_lock.as_mut().expect("process not exist");
+            if proc.wakeup_token {
+                proc.wakeup_token = false;
+                return false;
+            }
+            self.set_status_locked(pid, &mut proc_lock, Status::Sleeping);
+        }
+        if time != 0 {
+            self.with_event_hub(|hub| hub.push(time, Event::Wakeup(pid)));
         }
+        true
     }
 
     pub fn wakeup(&self, pid: Pid) {
+        {
+            let mut proc_lock = self.procs[pid].lock();
+            let proc = proc_lock.as_mut().expect("process not exist");
+            // the status it will have once it stops running
+            let status = match proc.status {
+                Status::Running(_) => &proc.status_after_stop,
+                ref status => status,
+            };
+            // unless it is blocked, it may be about to park, e.g. after adding itself
+            // to a wait queue, also if it was preempted before parking.
+            // keep the wakeup so that it is not lost
+            match status {
+                Status::Sleeping | Status::Waiting(_) => {}
+                _ => {
+                    proc.wakeup_token = true;
+                    return;
+                }
+            }
+        }
+        // it won't wake up by timeout anymore
+        self.with_event_hub(|hub| hub.remove(Event::Wakeup(pid)));
         self.set_status(pid, Status::Ready);
     }
 
@@ -194,6 +294,16 @@ impl ProcessManager {
         self.set_status(pid, Status::Waiting(0));
     }
 
+    /// Get the thread local storage slot of `pid`
+    pub fn get_local(&self, pid: Pid) -> usize {
+        self.procs[pid].lock().as_ref().expect("process not exist").local
+    }
+
+    /// Set the thread local storage slot of `pid`
+    pub fn set_local(&self, pid: Pid, value: usize) {
+        self.procs[pid].lock().as_mut().expect("process not exist").local = value;
+    }
+
     pub fn get_children(&self, pid: Pid) -> Vec<Pid> {
         self.procs[pid].lock().as_ref().expect("process not exist").children.clone()
     }
@@ -220,3 +330,126 @@ fn new_vec_default<T: Default>(size: usize) -> Vec<T> {
     vec.resize_default(size);
     vec
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use crate::scheduler::RRScheduler;
+
+    struct MockContext;
+
+    impl Context for MockContext {
+        unsafe fn switch_to(&mut self, _target: &mut Context) {
+            unreachable!()
+        }
+    }
+
+    /// A manager running one process, which is also its own parent
+    fn manager() -> (ProcessManager, Pid) {
+        let manager = ProcessManager::new(Box::new(RRScheduler::new(10)), 4);
+        let pid = manager.add(Box::new(MockContext), 0);
+        (manager, pid)
+    }
+
+    #[test]
+    fn wakeup_sleeping() {
+        let (manager, pid) = manager();
+        let (pid, context) = manager.run(0);
+        assert!(manager.park(pid, 0));
+        manager.stop(pid, context);
+        assert_eq!(manager.get_status(pid), Some(Status::Sleeping));
+        manager.wakeup(pid);
+        assert_eq!(manager.get_status(pid), Some(Status::Ready));
+        // no token is left, the next park sleeps
+        let (pid, _context) = manager.run(0);
+        assert!(manager.park(pid, 0));
+    }
+
+    #[test]
+    fn wakeup_while_parking() {
+        let (manager, pid) = manager();
+        let (pid, context) = manager.run(0);
+        // parked, but not switched out yet
+        assert!(manager.park(pid, 0));
+        manager.wakeup(pid);
+        manager.stop(pid, context);
+        assert_eq!(manager.get_status(pid), Some(Status::Ready));
+        let (next, _context) = manager.run(0);
+        assert_eq!(next, pid);
+    }
+
+    #[test]
+    fn wakeup_before_park() {
+        let (manager, pid) = manager();
+        let (pid, _context) = manager.run(0);
+        manager.wakeup(pid);
+        assert!(!manager.park(pid, 0));
+        // the token is used up
+        assert!(manager.park(pid, 0));
+    }
+
+    #[test]
+    fn wakeup_preempted_before_park() {
+        let (manager, pid) = manager();
+        let (pid, context) = manager.run(0);
+        // it added itself to a wait queue, then the timer preempted it before parking
+        manager.stop(pid, context);
+        assert_eq!(manager.get_status(pid), Some(Status::Ready));
+        manager.wakeup(pid);
+        assert_eq!(manager.get_status(pid), Some(Status::Ready));
+        let (pid, _context) = manager.run(0);
+        assert!(!manager.park(pid, 0));
+    }
+
+    // `Condvar` in the kernel joins its wait queue, unlocks, then parks.
+    // A notify pops a thread from the queue and wakes it up.
+
+    #[test]
+    fn notify_before_park() {
+        let (manager, pid) = manager();
+        let mut queue = VecDeque::new();
+        let (pid, context) = manager.run(0);
+        queue.push_back(pid);
+        // preempted after unlocking, before parking
+        manager.stop(pid, context);
+        manager.wakeup(queue.pop_front().unwrap());
+        let (pid, _context) = manager.run(0);
+        assert!(!manager.park(pid, 0));
+    }
+
+    #[test]
+    fn notify_before_timeout() {
+        let (manager, pid) = manager();
+        let other = manager.add(Box::new(MockContext), 0);
+        let (pid, context) = manager.run(0);
+        assert!(manager.park(pid, 2));
+        manager.stop(pid, context);
+        manager.wakeup(pid);
+        assert_eq!(manager.get_status(pid), Some(Status::Ready));
+        // the timeout passes while the other one runs, it must not be taken as a wakeup
+        let (next, _context) = manager.run(0);
+        assert_eq!(next, other);
+        for _ in 0..3 {
+            manager.tick(other);
+        }
+        let (next, _context) = manager.run(0);
+        assert_eq!(next, pid);
+        assert!(manager.park(pid, 0));
+    }
+
+    #[test]
+    fn timeout() {
+        let (manager, pid) = manager();
+        let other = manager.add(Box::new(MockContext), 0);
+        let (pid, context) = manager.run(0);
+        assert!(manager.park(pid, 2));
+        manager.stop(pid, context);
+        let (next, _context) = manager.run(0);
+        assert_eq!(next, other);
+        manager.tick(other);
+        assert_eq!(manager.get_status(pid), Some(Status::Sleeping));
+        manager.tick(other);
+        assert_eq!(manager.get_status(pid), Some(Status::Ready));
+    }
+}
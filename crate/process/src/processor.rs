@@ -80,6 +80,12 @@ impl Processor {
         self.inner().proc.as_ref().unwrap().0
     }
 
+    /// The pid of the running process, or `None` if no process is running,
+    /// e.g. before the processor is initialized
+    pub fn try_pid(&self) -> Option<Pid> {
+        unsafe { &*self.inner.get() }.as_ref()?.proc.as_ref().map(|proc| proc.0)
+    }
+
     pub fn context(&self) -> &Context {
         &*self.inner().proc.as_ref().unwrap().1
     }
@@ -1,4 +1,4 @@
-use alloc::{collections::BinaryHeap, vec::Vec};
+use alloc::{collections::{BinaryHeap, BTreeSet}, vec::Vec};
 use log::*;
 
 type Pid = usize;
@@ -15,6 +15,7 @@ pub trait Scheduler {
 
 pub use self::rr::RRScheduler;
 pub use self::stride::StrideScheduler;
+pub use self::cfs::CFSScheduler;
 
 mod rr {
     use super::*;
@@ -84,7 +85,10 @@ mod rr {
 
         fn move_to_head(&mut self, pid: usize) {
             let pid = pid + 1;
-            assert!(self.infos[pid].present);
+            // it may be running or sleeping, then there is nothing to move
+            if !is_present(&self.infos, pid, |info| info.present) {
+                return;
+            }
             self._list_remove(pid);
             self._list_add_after(pid, 0);
             trace!("rr move_to_head {}", pid - 1);
@@ -210,11 +214,15 @@ mod stride {
         }
 
         fn set_priority(&mut self, pid: Pid, priority: u8) {
+            expand(&mut self.infos, pid);
             self.infos[pid].priority = priority;
             trace!("stride {} priority = {}", pid, priority);
         }
 
         fn move_to_head(&mut self, pid: Pid) {
+            if !is_present(&self.infos, pid, |info| info.present) {
+                return;
+            }
             if self.queue.peek().is_some() {
                 let stride = -self.queue.peek().unwrap().0;
                 self.remove(pid);
@@ -235,8 +243,149 @@ mod stride {
     }
 }
 
+mod cfs {
+    use super::*;
+
+    /// Completely fair scheduler: run the process which has had the least
+    /// virtual runtime, which grows slower for processes of higher priority
+    pub struct CFSScheduler {
+        max_time_slice: usize,
+        infos: Vec<CFSProcInfo>,
+        queue: BTreeSet<(VRuntime, Pid)>,
+        /// Never decreases, so that a process which slept long does not get
+        /// the CPU for long to catch up
+        min_vruntime: VRuntime,
+    }
+
+    #[derive(Debug, Default, Copy, Clone)]
+    struct CFSProcInfo {
+        present: bool,
+        rest_slice: usize,
+        vruntime: VRuntime,
+        priority: u8,
+    }
+
+    type VRuntime = u64;
+
+    /// Virtual runtime of a tick for priority 1
+    const TICK_VRUNTIME: VRuntime = 1 << 20;
+    /// Initial `min_vruntime`, so that `move_to_head` can always go below the head
+    const BASE_VRUNTIME: VRuntime = 1 << 40;
+
+    impl CFSProcInfo {
+        fn run_tick(&mut self) {
+            self.vruntime += TICK_VRUNTIME / self.priority.max(1) as VRuntime;
+        }
+    }
+
+    impl Scheduler for CFSScheduler {
+        fn insert(&mut self, pid: Pid) {
+            expand(&mut self.infos, pid);
+            let info = &mut self.infos[pid];
+            assert!(!info.present);
+            info.present = true;
+            if info.rest_slice == 0 {
+                info.rest_slice = self.max_time_slice;
+            }
+            info.vruntime = info.vruntime.max(self.min_vruntime);
+            self.queue.insert((info.vruntime, pid));
+            trace!("cfs insert {}", pid);
+        }
+
+        fn remove(&mut self, pid: Pid) {
+            let info = &mut self.infos[pid];
+            assert!(info.present);
+            info.present = false;
+            self.queue.remove(&(info.vruntime, pid));
+            trace!("cfs remove {}", pid);
+        }
+
+        fn select(&mut self) -> Option<Pid> {
+            let ret = self.queue.iter().next().map(|&(_, pid)| pid);
+            if let Some(pid) = ret {
+                self.min_vruntime = self.min_vruntime.max(self.infos[pid].vruntime);
+            }
+            trace!("cfs select {:?}", ret);
+            ret
+        }
+
+        fn tick(&mut self, current: Pid) -> bool {
+            expand(&mut self.infos, current);
+            assert!(!self.infos[current].present);
+
+            let info = &mut self.infos[current];
+            info.run_tick();
+            let rest = &mut info.rest_slice;
+            if *rest > 0 {
+                *rest -= 1;
+            } else {
+                warn!("current process rest_slice = 0, need reschedule")
+            }
+            *rest == 0
+        }
+
+        fn set_priority(&mut self, pid: Pid, priority: u8) {
+            expand(&mut self.infos, pid);
+            self.infos[pid].priority = priority;
+            trace!("cfs {} priority = {}", pid, priority);
+        }
+
+        fn move_to_head(&mut self, pid: Pid) {
+            if !is_present(&self.infos, pid, |info| info.present) {
+                return;
+            }
+            if let Some(&(vruntime, _)) = self.queue.iter().next() {
+                self.remove(pid);
+                self.infos[pid].vruntime = vruntime.saturating_sub(1);
+                self.queue.insert((self.infos[pid].vruntime, pid));
+                self.infos[pid].present = true;
+            }
+        }
+    }
+
+    impl CFSScheduler {
+        pub fn new(max_time_slice: usize) -> Self {
+            CFSScheduler {
+                max_time_slice,
+                infos: Vec::default(),
+                queue: BTreeSet::default(),
+                min_vruntime: BASE_VRUNTIME,
+            }
+        }
+    }
+}
+
 fn expand<T: Default + Clone>(vec: &mut Vec<T>, id: usize) {
     let len = vec.len();
     vec.resize(len.max(id + 1), T::default());
 }
 
+/// Whether `id` is in the queue, ids which were never inserted are not
+fn is_present<T>(vec: &[T], id: usize, present: impl Fn(&T) -> bool) -> bool {
+    vec.get(id).map_or(false, present)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn move_absent(mut scheduler: impl Scheduler) {
+        scheduler.move_to_head(3);
+        scheduler.insert(1);
+        scheduler.insert(2);
+        scheduler.move_to_head(5);
+        scheduler.move_to_head(2);
+        assert_eq!(scheduler.select(), Some(2));
+        scheduler.remove(2);
+        scheduler.move_to_head(2);
+        assert_eq!(scheduler.select(), Some(1));
+    }
+
+    #[test]
+    fn move_to_head_of_absent_process() {
+        move_absent(RRScheduler::new(5));
+        move_absent(StrideScheduler::new(5));
+        move_absent(CFSScheduler::new(5));
+    }
+}
+
@@ -7,9 +7,11 @@
 //! - `new_kernel_context`: Construct a `Context` of the new kernel thread
 
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use core::marker::PhantomData;
 use core::time::Duration;
 use log::*;
+use spin::Mutex;
 use crate::processor::*;
 use crate::process_manager::*;
 
@@ -38,11 +40,11 @@ pub fn sleep(dur: Duration) {
     let time = dur_to_ticks(dur);
     trace!("sleep: {:?} ticks", time);
     processor().manager().sleep(current().id(), time);
-    park();
+    processor().yield_now();
+}
 
-    fn dur_to_ticks(dur: Duration) -> usize {
-        return dur.as_secs() as usize * 100 + dur.subsec_nanos() as usize / 10_000_000;
-    }
+fn dur_to_ticks(dur: Duration) -> usize {
+    return dur.as_secs() as usize * 100 + dur.subsec_nanos() as usize / 10_000_000;
 }
 
 /// Spawns a new thread, returning a JoinHandle for it.
@@ -56,12 +58,16 @@ pub fn spawn<F, T>(f: F) -> JoinHandle<T>
 {
     trace!("spawn:");
 
+    // The return value is put here for `join`. It is freed with the last
+    // reference, so also if the `JoinHandle` is detached.
+    let packet: Arc<Packet<T>> = Arc::new(Mutex::new(None));
+
     // 注意到下面的问题：
     // Processor只能从入口地址entry+参数arg创建新线程
     // 而我们现在需要让它执行一个未知类型的（闭包）函数f
 
     // 首先把函数本体（代码数据）置于堆空间中
-    let f = Box::into_raw(Box::new(f));
+    let f = Box::into_raw(Box::new((f, packet.clone())));
 
     // 定义一个静态函数作为新线程的入口点
     // 其参数是函数f在堆上的指针
@@ -75,16 +81,14 @@ pub fn spawn<F, T>(f: F) -> JoinHandle<T>
             T: Send + 'static,
     {
         // 在静态函数内部：
-        // 根据传进来的指针，恢复f
-        let f = unsafe { Box::from_raw(f as *mut F) };
-        // 调用f，并将其返回值也放在堆上
-        let ret = Box::new(f());
-        // 清理本地线程存储
-        //   unsafe { LocalKey::<usize>::get_map() }.clear();
+        // 根据传进来的指针，恢复f和packet
+        let (f, packet) = unsafe { *Box::from_raw(f as *mut (F, Arc<Packet<T>>)) };
+        // 调用f，并将其返回值放在packet中
+        *packet.lock() = Some(f());
+        // 线程不会返回，所以要手动释放packet
+        drop(packet);
         // 让Processor退出当前线程
-        // 把f返回值在堆上的指针，以线程返回码的形式传递出去
-        let exit_code = Box::into_raw(ret) as usize;
-        processor().manager().exit(current().id(), exit_code);
+        processor().manager().exit(current().id(), 0);
         processor().yield_now();
         // 再也不会被调度回来了
         unreachable!()
@@ -98,6 +102,7 @@ pub fn spawn<F, T>(f: F) -> JoinHandle<T>
     // 了解是如何获取f返回值的
     return JoinHandle {
         thread: Thread { pid },
+        packet: Some(packet),
         mark: PhantomData,
     };
 }
@@ -111,10 +116,35 @@ pub fn yield_now() {
 /// Blocks unless or until the current thread's token is made available.
 pub fn park() {
     trace!("park:");
-    processor().manager().sleep(current().id(), 0);
-    processor().yield_now();
+    if processor().manager().park(current().id(), 0) {
+        processor().yield_now();
+    }
 }
 
+/// Blocks unless or until the current thread's token is made available
+/// or the specified duration has been reached (may wake up spuriously).
+pub fn park_timeout(dur: Duration) {
+    // at least one tick, 0 would mean forever
+    let time = dur_to_ticks(dur).max(1);
+    trace!("park_timeout: {:?} ticks", time);
+    if processor().manager().park(current().id(), time) {
+        processor().yield_now();
+    }
+}
+
+/// Get the local storage slot of the current thread, 0 if it has not been set
+pub fn local() -> usize {
+    processor().manager().get_local(current().id())
+}
+
+/// Set the local storage slot of the current thread
+pub fn set_local(value: usize) {
+    processor().manager().set_local(current().id(), value);
+}
+
+/// Where a thread puts its return value
+type Packet<T> = Mutex<Option<T>>;
+
 /// A handle to a thread.
 pub struct Thread {
     pid: usize,
@@ -134,6 +164,8 @@ impl Thread {
 /// An owned permission to join on a thread (block on its termination).
 pub struct JoinHandle<T> {
     thread: Thread,
+    /// None for a process, whose exit code is returned instead
+    packet: Option<Arc<Packet<T>>>,
     mark: PhantomData<T>,
 }
 
@@ -149,8 +181,11 @@ impl<T> JoinHandle<T> {
             match processor().manager().get_status(self.thread.pid) {
                 Some(Status::Exited(exit_code)) => {
                     processor().manager().remove(self.thread.pid);
-                    // Find return value on the heap from the exit code.
-                    return Ok(unsafe { *Box::from_raw(exit_code as *mut T) });
+                    return match self.packet {
+                        Some(packet) => packet.lock().take().ok_or(()),
+                        // Find return value on the heap from the exit code.
+                        None => Ok(unsafe { *Box::from_raw(exit_code as *mut T) }),
+                    };
                 }
                 None => return Err(()),
                 _ => {}
@@ -159,10 +194,17 @@ impl<T> JoinHandle<T> {
             processor().yield_now();
         }
     }
+    /// Let the thread run on its own, it is removed as soon as it exits.
+    /// Its return value is dropped.
+    pub fn detach(self) {
+        trace!("{} detach", self.thread.pid);
+        processor().manager().detach(self.thread.pid);
+    }
     /// Force construct a JoinHandle struct
     pub unsafe fn _of(pid: Pid) -> JoinHandle<T> {
         JoinHandle {
             thread: Thread { pid },
+            packet: None,
             mark: PhantomData,
         }
     }
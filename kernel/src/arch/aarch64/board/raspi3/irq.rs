@@ -10,10 +10,12 @@ pub fn handle_irq(_tf: &mut TrapFrame) {
     let controller = bcm2837::timer::Timer::new();
     if controller.is_pending() {
         super::timer::set_next();
+        crate::rand::add_interrupt_entropy(!0);
         crate::trap::timer();
     }
 
     for int in Controller::new().pending_interrupts() {
+        crate::rand::add_interrupt_entropy(int);
         if let Some(handler) = IRQ_HANDLERS[int] {
             handler();
         }
@@ -6,3 +6,15 @@ pub fn id() -> usize {
     // TODO: cpu id
     0
 }
+
+/// Read the physical count of the generic timer
+pub fn cycle() -> u64 {
+    let count: u64;
+    unsafe { asm!("mrs $0, cntpct_el0" : "=r"(count) ::: "volatile") }
+    count
+}
+
+/// There is no hardware random number generator
+pub fn random() -> Option<u64> {
+    None
+}
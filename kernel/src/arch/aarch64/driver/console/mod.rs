@@ -2,11 +2,10 @@
 
 mod color;
 mod escape_parser;
-mod fonts;
 
 use self::color::FramebufferColor;
 use self::escape_parser::{CharacterAttribute, EscapeParser};
-use self::fonts::{Font, Font8x16};
+use crate::drivers::gpu::fonts::{Font, Font8x16};
 
 use super::fb::{ColorDepth::*, FramebufferInfo, FRAME_BUFFER};
 use alloc::vec::Vec;
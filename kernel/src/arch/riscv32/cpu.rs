@@ -35,3 +35,13 @@ pub unsafe fn start_others(hart_mask: usize) {
 pub fn halt() {
     unsafe { riscv::asm::wfi() }
 }
+
+/// Read the timer counter
+pub fn cycle() -> u64 {
+    super::timer::get_cycle()
+}
+
+/// There is no hardware random number generator
+pub fn random() -> Option<u64> {
+    None
+}
@@ -12,6 +12,7 @@ use riscv::register::{
 };
 use riscv::register::{mcause, mepc, sie, mie};
 use crate::drivers::DRIVERS;
+use crate::drivers::irq::plic;
 pub use self::context::*;
 use log::*;
 
@@ -118,6 +119,9 @@ fn sbi(tf: &mut TrapFrame) {
 }
 
 fn external() {
+    // interrupts of devices come through the PLIC if there is one
+    let irq = plic::claim();
+    crate::rand::add_interrupt_entropy(irq.unwrap_or(0) as usize);
     // true means handled, false otherwise
     let handlers = [try_process_serial, try_process_drivers];
     for handler in handlers.iter() {
@@ -125,6 +129,9 @@ fn external() {
             break
         }
     }
+    if let Some(irq) = irq {
+        plic::complete(irq);
+    }
 }
 
 fn try_process_serial() -> bool {
@@ -158,6 +165,7 @@ fn ipi() {
 */
 fn timer() {
     super::timer::set_next();
+    crate::rand::add_interrupt_entropy(1);
     crate::trap::timer();
 }
 
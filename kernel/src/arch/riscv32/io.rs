@@ -61,6 +61,7 @@ pub fn getchar_option() -> Option<char> {
 
 pub fn putfmt(fmt: Arguments) {
     SerialPort.write_fmt(fmt).unwrap();
+    crate::drivers::gpu::console::putfmt(fmt);
 }
 
 const TXDATA: *mut u32 = 0x38000000 as *mut u32;
@@ -0,0 +1,280 @@
+//! Hardware discovery from the ACPI tables
+//!
+//! The MADT gives the CPUs and the interrupt controllers, the MCFG gives the ECAM
+//! of PCI, and the FADT with the `\_S5` object in the DSDT gives how to power off.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use core::slice;
+use log::*;
+use rcore_memory::PAGE_SIZE;
+use spin::Once;
+use x86_64::instructions::port::Port;
+use crate::consts::{KERNEL_OFFSET, MAX_CPU_NUM};
+use super::driver::pci::map;
+
+/// Where the root system description pointer may be, it is on a 16-byte boundary
+const BIOS_AREA: (usize, usize) = (0xe0000, 0x100000);
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+/// Size of the header of a description table
+const SDT_HEADER_SIZE: usize = 36;
+
+/// The MADT has the local APIC address and flags before its entries
+const MADT_ENTRIES: usize = SDT_HEADER_SIZE + 8;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IOAPIC: u8 = 1;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+/// The MCFG table has 8 reserved bytes before its entries
+const MCFG_ENTRIES: usize = SDT_HEADER_SIZE + 8;
+const MCFG_ENTRY_SIZE: usize = 16;
+
+// offsets in the FADT
+const FADT_DSDT: usize = 40;
+const FADT_SMI_CMD: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CNT: usize = 64;
+const FADT_PM1B_CNT: usize = 68;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REG: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const FADT_X_DSDT: usize = 140;
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+/// Address space of a generic address
+const GAS_SYSTEM_IO: u8 = 1;
+
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_EN: u16 = 1 << 13;
+
+// AML opcodes
+const AML_NAME: u8 = 0x08;
+const AML_PACKAGE: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_ROOT: u8 = b'\\';
+
+/// What the ACPI tables tell about the machine
+#[derive(Debug, Default)]
+pub struct Acpi {
+    /// Local APIC IDs of the enabled CPUs
+    pub cpus: Vec<u8>,
+    /// Physical address of the local APICs
+    pub local_apic: usize,
+    /// (ID, physical address, first global system interrupt) of each IOAPIC
+    pub ioapics: Vec<(u8, usize, u32)>,
+    /// Physical address of the ECAM of PCI segment 0
+    pub ecam: Option<usize>,
+    pm1_control: (u16, u16),
+    /// SLP_TYPa and SLP_TYPb of the soft off state
+    sleep_types: Option<(u16, u16)>,
+    smi_command: (u16, u8),
+    /// I/O port and value written to it to reset
+    reset: Option<(u16, u8)>,
+}
+
+static ACPI: Once<Acpi> = Once::new();
+
+/// Map `len` bytes from physical address `paddr`
+fn map_range(paddr: usize, len: usize) -> usize {
+    let first = paddr & !(PAGE_SIZE - 1);
+    for page in (first..paddr + len).step_by(PAGE_SIZE) {
+        map(page);
+    }
+    KERNEL_OFFSET + paddr
+}
+
+/// Read a value of type `T` at physical address `paddr`
+fn read<T>(paddr: usize) -> T {
+    unsafe { read_unaligned(map_range(paddr, size_of::<T>()) as *const T) }
+}
+
+fn find_rsdp() -> Option<usize> {
+    (BIOS_AREA.0..BIOS_AREA.1).step_by(16).find(|&paddr| {
+        &read::<[u8; 8]>(paddr) == RSDP_SIGNATURE
+    })
+}
+
+/// Physical addresses of the tables in the RSDT, or in the XSDT since ACPI 2.0
+fn tables(rsdp: usize) -> Vec<usize> {
+    let revision: u8 = read(rsdp + 15);
+    let (sdt, entry_size) = if revision >= 2 {
+        (read::<u64>(rsdp + 24) as usize, 8)
+    } else {
+        (read::<u32>(rsdp + 16) as usize, 4)
+    };
+    let len = read::<u32>(sdt + 4) as usize;
+    let entries = len.saturating_sub(SDT_HEADER_SIZE) / entry_size;
+    (0..entries).map(|i| {
+        let entry = sdt + SDT_HEADER_SIZE + i * entry_size;
+        if entry_size == 8 {
+            read::<u64>(entry) as usize
+        } else {
+            read::<u32>(entry) as usize
+        }
+    }).collect()
+}
+
+/// The whole table at `paddr`, with its header
+fn table(paddr: usize) -> &'static [u8] {
+    let len = read::<u32>(paddr + 4) as usize;
+    unsafe { slice::from_raw_parts(map_range(paddr, len) as *const u8, len) }
+}
+
+fn get<T>(table: &[u8], offset: usize) -> T {
+    assert!(offset + size_of::<T>() <= table.len());
+    unsafe { read_unaligned(table[offset..].as_ptr() as *const T) }
+}
+
+impl Acpi {
+    fn parse_madt(&mut self, madt: &[u8]) {
+        self.local_apic = get::<u32>(madt, SDT_HEADER_SIZE) as usize;
+        let mut offset = MADT_ENTRIES;
+        while offset + 2 <= madt.len() {
+            let (type_, len) = (madt[offset], madt[offset + 1] as usize);
+            if len < 2 || offset + len > madt.len() {
+                break;
+            }
+            let entry = &madt[offset..offset + len];
+            match type_ {
+                MADT_LOCAL_APIC if len >= 8 => {
+                    if get::<u32>(entry, 4) & LOCAL_APIC_ENABLED != 0 {
+                        self.cpus.push(entry[3]);
+                    }
+                }
+                MADT_IOAPIC if len >= 12 => {
+                    self.ioapics.push((entry[2], get::<u32>(entry, 4) as usize, get::<u32>(entry, 8)));
+                }
+                MADT_LOCAL_APIC_OVERRIDE if len >= 12 => {
+                    self.local_apic = get::<u64>(entry, 4) as usize;
+                }
+                _ => {}
+            }
+            offset += len;
+        }
+    }
+
+    fn parse_mcfg(&mut self, mcfg: &[u8]) {
+        let entries = mcfg.len().saturating_sub(MCFG_ENTRIES) / MCFG_ENTRY_SIZE;
+        self.ecam = (0..entries).map(|i| &mcfg[MCFG_ENTRIES + i * MCFG_ENTRY_SIZE..]).find(|entry| {
+            get::<u16>(entry, 8) == 0 && entry[10] == 0
+        }).map(|entry| get::<u64>(entry, 0) as usize);
+    }
+
+    fn parse_fadt(&mut self, fadt: &[u8]) {
+        self.pm1_control = (get::<u32>(fadt, FADT_PM1A_CNT) as u16, get::<u32>(fadt, FADT_PM1B_CNT) as u16);
+        self.smi_command = (get::<u32>(fadt, FADT_SMI_CMD) as u16, fadt[FADT_ACPI_ENABLE]);
+        if fadt.len() > FADT_RESET_VALUE && get::<u32>(fadt, FADT_FLAGS) & FADT_RESET_REG_SUP != 0
+            && fadt[FADT_RESET_REG] == GAS_SYSTEM_IO {
+            let port = get::<u64>(fadt, FADT_RESET_REG + 4) as u16;
+            self.reset = Some((port, fadt[FADT_RESET_VALUE]));
+        }
+        let dsdt = if fadt.len() >= FADT_X_DSDT + 8 && get::<u64>(fadt, FADT_X_DSDT) != 0 {
+            get::<u64>(fadt, FADT_X_DSDT) as usize
+        } else {
+            get::<u32>(fadt, FADT_DSDT) as usize
+        };
+        if dsdt != 0 {
+            self.sleep_types = find_s5(table(dsdt));
+        }
+    }
+}
+
+/// Find the sleep types of the soft off state, in `Name (\_S5, Package () { a, b, ... })`
+fn find_s5(dsdt: &[u8]) -> Option<(u16, u16)> {
+    let pos = dsdt.windows(4).position(|name| name == b"_S5_")?;
+    let named = pos >= 1 && dsdt[pos - 1] == AML_NAME
+        || pos >= 2 && dsdt[pos - 1] == AML_ROOT && dsdt[pos - 2] == AML_NAME;
+    if !named || dsdt.get(pos + 4) != Some(&AML_PACKAGE) {
+        return None;
+    }
+    // skip the package length, whose top bits tell how many bytes follow,
+    // and the number of elements
+    let mut i = pos + 5;
+    i += (*dsdt.get(i)? >> 6) as usize + 2;
+    let mut value = || {
+        if *dsdt.get(i)? == AML_BYTE_PREFIX {
+            i += 1;
+        }
+        let value = *dsdt.get(i)? as u16;
+        i += 1;
+        Some(value)
+    };
+    let a = value()?;
+    let b = value()?;
+    Some((a, b))
+}
+
+/// Parse the tables, should be called once after memory is set up
+pub fn init() {
+    let acpi = ACPI.call_once(|| {
+        let mut acpi = Acpi::default();
+        let rsdp = match find_rsdp() {
+            Some(rsdp) => rsdp,
+            None => {
+                warn!("acpi: no RSDP found");
+                return acpi;
+            }
+        };
+        for paddr in tables(rsdp) {
+            let signature: [u8; 4] = read(paddr);
+            match &signature {
+                b"APIC" => acpi.parse_madt(table(paddr)),
+                b"MCFG" => acpi.parse_mcfg(table(paddr)),
+                b"FACP" => acpi.parse_fadt(table(paddr)),
+                _ => {}
+            }
+        }
+        acpi
+    });
+    if acpi.local_apic != 0 {
+        map(acpi.local_apic);
+    }
+    for &(_, paddr, _) in acpi.ioapics.iter() {
+        map(paddr);
+    }
+    info!("acpi: {} CPUs {:?}, local APIC at {:#x}, IOAPICs {:x?}",
+          acpi.cpus.len(), acpi.cpus, acpi.local_apic, acpi.ioapics);
+    if acpi.cpus.iter().any(|&id| id as usize >= MAX_CPU_NUM) {
+        warn!("acpi: CPUs with an APIC ID from {} are not used", MAX_CPU_NUM);
+    }
+}
+
+/// What was found in the tables, empty if there are none
+pub fn get_info() -> &'static Acpi {
+    ACPI.call_once(Acpi::default)
+}
+
+/// Enter the soft off state, return if it is not supported
+pub fn poweroff() {
+    let acpi = get_info();
+    let (a, b) = match acpi.sleep_types {
+        Some(types) => types,
+        None => return,
+    };
+    let (pm1a, pm1b) = acpi.pm1_control;
+    if pm1a == 0 {
+        return;
+    }
+    unsafe {
+        // hand the hardware from the firmware to us first
+        let mut control = Port::<u16>::new(pm1a);
+        let (smi_command, enable) = acpi.smi_command;
+        if control.read() & PM1_SCI_EN == 0 && smi_command != 0 {
+            Port::<u8>::new(smi_command).write(enable);
+            while control.read() & PM1_SCI_EN == 0 {}
+        }
+        control.write(a << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+        if pm1b != 0 {
+            Port::<u16>::new(pm1b).write(b << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+        }
+    }
+}
+
+/// Reset the machine by the reset register, return if it is not supported
+pub fn reset() {
+    if let Some((port, value)) = get_info().reset {
+        unsafe { Port::<u8>::new(port).write(value) };
+    }
+}
@@ -1,5 +1,7 @@
 use apic::{LocalApic, XApic};
 use raw_cpuid::CpuId;
+use lazy_static::lazy_static;
+use log::*;
 
 /// Exit qemu
 /// See: https://wiki.osdev.org/Shutdown
@@ -17,16 +19,76 @@ pub fn id() -> usize {
 }
 
 pub fn send_ipi(cpu_id: usize) {
-    let mut lapic = unsafe { XApic::new(0xffffff00_fee00000) };
+    let mut lapic = unsafe { XApic::new(super::interrupt::local_apic()) };
     lapic.send_ipi(cpu_id as u8, 0x30); // TODO: Find a IPI trap num
 }
 
 pub fn init() {
-    let mut lapic = unsafe { XApic::new(0xffffff00_fee00000) };
+    let mut lapic = unsafe { XApic::new(super::interrupt::local_apic()) };
     lapic.cpu_init();
 }
 
+/// Power off the machine by ACPI
+pub fn poweroff() -> ! {
+    super::acpi::poweroff();
+    error!("failed to power off");
+    loop { halt() }
+}
+
+/// Reset the machine by ACPI, or by the keyboard controller
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+    super::acpi::reset();
+    unsafe { Port::<u8>::new(0x64).write(0xfe) };
+    loop { halt() }
+}
+
 pub fn halt() {
     use x86_64::instructions::hlt;
     hlt();
 }
+
+/// Read the time stamp counter
+pub fn cycle() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// A number from the hardware random number generator, by RDSEED or RDRAND
+pub fn random() -> Option<u64> {
+    #[target_feature(enable = "rdseed")]
+    unsafe fn rdseed() -> Option<u64> {
+        let mut value = 0;
+        // it may fail if the entropy is used up, try a few times
+        for _ in 0..10 {
+            if core::arch::x86_64::_rdseed64_step(&mut value) == 1 {
+                return Some(value);
+            }
+        }
+        None
+    }
+    #[target_feature(enable = "rdrand")]
+    unsafe fn rdrand() -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..10 {
+            if core::arch::x86_64::_rdrand64_step(&mut value) == 1 {
+                return Some(value);
+            }
+        }
+        None
+    }
+    lazy_static! {
+        /// Whether RDSEED and RDRAND are supported, CPUID is slow in a VM
+        static ref FEATURES: (bool, bool) = {
+            let cpuid = CpuId::new();
+            (cpuid.get_extended_feature_info().map_or(false, |info| info.has_rdseed()),
+             cpuid.get_feature_info().map_or(false, |info| info.has_rdrand()))
+        };
+    }
+    let (has_rdseed, has_rdrand) = *FEATURES;
+    let seed = if has_rdseed { unsafe { rdseed() } } else { None };
+    match seed {
+        Some(value) => Some(value),
+        None if has_rdrand => unsafe { rdrand() },
+        None => None,
+    }
+}
@@ -0,0 +1,327 @@
+//! AHCI driver of SATA disks
+//!
+//! Each disk uses one command slot, polling for completion,
+//! and a page of bounce buffer for DMA. NCQ is not used.
+
+use alloc::{boxed::Box, sync::Arc};
+use core::ptr::write_bytes;
+use core::slice;
+use core::sync::atomic::{fence, Ordering};
+use log::*;
+use rcore_memory::PAGE_SIZE;
+use simple_filesystem::{BlockedDevice, Device};
+use volatile::Volatile;
+use crate::consts::KERNEL_OFFSET;
+use crate::drivers::{BlockDriver, DeviceType, Driver, BLK_DRIVERS, DRIVERS};
+use crate::drivers::bus::pci::{PciDevice, PciDriver};
+use crate::memory::alloc_frame;
+use crate::sync::SpinNoIrqLock as Mutex;
+use super::pci::map;
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// Size of HBA registers with all 32 ports
+const HBA_SIZE: usize = 0x1100;
+const HBA_GHC: usize = 0x04;
+const HBA_PI: usize = 0x0c;
+const GHC_AE: u32 = 1 << 31;
+
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_BSY: u32 = 0x80;
+const IS_TFES: u32 = 1 << 30;
+/// Device detected and communication established
+const SSTS_DET_PRESENT: u32 = 3;
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_FLUSH_EXT: u8 = 0xea;
+const ATA_CMD_IDENTIFY: u8 = 0xec;
+
+/// Layout of the page of a port: command list, received FIS and command table
+const CMD_LIST: usize = 0;
+const RECEIVED_FIS: usize = 0x400;
+const CMD_TABLE: usize = 0x500;
+/// Offset of the PRDT in the command table
+const PRDT: usize = 0x80;
+
+/// Words of IDENTIFY data
+const IDENT_MAX_LBA: usize = 60;
+const IDENT_MAX_LBA_EXT: usize = 100;
+
+/// Times to poll a register before giving up
+const TIMEOUT: usize = 10_000_000;
+
+fn reg(addr: usize) -> &'static mut Volatile<u32> {
+    unsafe { &mut *(addr as *mut Volatile<u32>) }
+}
+
+struct AHCIPort {
+    num: usize,
+    /// Address of the port registers
+    regs: usize,
+    /// The page of command list, received FIS and command table
+    page: usize,
+    page_phys: usize,
+    /// Bounce buffer for DMA
+    buf: usize,
+    buf_phys: usize,
+    /// Number of sectors
+    sectors: u64,
+}
+
+#[derive(Clone)]
+pub struct AHCIDriver(Arc<Mutex<AHCIPort>>);
+
+impl AHCIPort {
+    fn reg(&self, offset: usize) -> &'static mut Volatile<u32> {
+        reg(self.regs + offset)
+    }
+
+    /// Wait until bits `mask` of register `offset` are all clear
+    fn wait_clear(&self, offset: usize, mask: u32) -> Result<(), ()> {
+        for _ in 0..TIMEOUT {
+            if self.reg(offset).read() & mask == 0 {
+                return Ok(());
+            }
+        }
+        warn!("ahci: port {}: timeout, tfd {:#x}", self.num, self.reg(PX_TFD).read());
+        Err(())
+    }
+
+    /// Stop the port, set up its memory and start it again
+    fn init(&mut self) -> Result<(), ()> {
+        let cmd = self.reg(PX_CMD);
+        cmd.write(cmd.read() & !CMD_ST);
+        self.wait_clear(PX_CMD, CMD_CR)?;
+        cmd.write(cmd.read() & !CMD_FRE);
+        self.wait_clear(PX_CMD, CMD_FR)?;
+
+        unsafe { write_bytes(self.page as *mut u8, 0, PAGE_SIZE) };
+        let clb = self.page_phys + CMD_LIST;
+        let fb = self.page_phys + RECEIVED_FIS;
+        self.reg(PX_CLB).write(clb as u32);
+        self.reg(PX_CLBU).write((clb as u64 >> 32) as u32);
+        self.reg(PX_FB).write(fb as u32);
+        self.reg(PX_FBU).write((fb as u64 >> 32) as u32);
+        // clear errors and interrupts, we use polling
+        self.reg(PX_SERR).write(!0);
+        self.reg(PX_IS).write(!0);
+        self.reg(PX_IE).write(0);
+
+        cmd.write(cmd.read() | CMD_FRE);
+        self.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ)?;
+        cmd.write(cmd.read() | CMD_ST);
+        Ok(())
+    }
+
+    /// Run an ATA command in slot 0, moving `count` sectors between the disk and the bounce buffer
+    fn command(&mut self, command: u8, sector: u64, count: usize, write: bool) -> Result<(), ()> {
+        assert!(count * BLOCK_SIZE <= PAGE_SIZE);
+        self.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ)?;
+
+        let table = self.page + CMD_TABLE;
+        unsafe { write_bytes(table as *mut u8, 0, PRDT + 16) };
+        let fis = unsafe { slice::from_raw_parts_mut(table as *mut u8, 20) };
+        fis[0] = FIS_TYPE_REG_H2D;
+        fis[1] = 0x80; // a command
+        fis[2] = command;
+        // LBA mode, except for IDENTIFY
+        fis[7] = if command == ATA_CMD_IDENTIFY { 0 } else { 0x40 };
+        for i in 0..3 {
+            fis[4 + i] = (sector >> (i * 8)) as u8;
+            fis[8 + i] = (sector >> (24 + i * 8)) as u8;
+        }
+        fis[12] = count as u8;
+        fis[13] = (count >> 8) as u8;
+
+        let prdt = unsafe { slice::from_raw_parts_mut((table + PRDT) as *mut u32, 4) };
+        prdt[0] = self.buf_phys as u32;
+        prdt[1] = (self.buf_phys as u64 >> 32) as u32;
+        prdt[3] = (count * BLOCK_SIZE).saturating_sub(1) as u32;
+
+        let table_phys = self.page_phys + CMD_TABLE;
+        let header = unsafe { slice::from_raw_parts_mut((self.page + CMD_LIST) as *mut u32, 8) };
+        // FIS length in dwords, direction and PRDT length
+        header[0] = 5 | if write { 1 << 6 } else { 0 } | if count > 0 { 1 << 16 } else { 0 };
+        header[1] = 0;
+        header[2] = table_phys as u32;
+        header[3] = (table_phys as u64 >> 32) as u32;
+        // the command must be in memory before it's issued
+        fence(Ordering::SeqCst);
+
+        self.reg(PX_IS).write(!0);
+        self.reg(PX_CI).write(1);
+        for _ in 0..TIMEOUT {
+            if self.reg(PX_IS).read() & IS_TFES != 0 {
+                break;
+            }
+            if self.reg(PX_CI).read() & 1 == 0 {
+                fence(Ordering::SeqCst);
+                let tfd = self.reg(PX_TFD).read();
+                if tfd & TFD_ERR != 0 {
+                    break;
+                }
+                return Ok(());
+            }
+        }
+        warn!("ahci: port {}: command {:#x} at {:#x} failed, tfd {:#x}",
+              self.num, command, sector, self.reg(PX_TFD).read());
+        // restart the port to recover
+        self.init().ok();
+        Err(())
+    }
+
+    /// Send IDENTIFY and get the size of the disk
+    fn identify(&mut self) -> Result<(), ()> {
+        self.command(ATA_CMD_IDENTIFY, 0, 1, false)?;
+        let data = unsafe { slice::from_raw_parts(self.buf as *const u16, 256) };
+        let word = |i: usize| data[i] as u64;
+        self.sectors = word(IDENT_MAX_LBA_EXT) | word(IDENT_MAX_LBA_EXT + 1) << 16 | word(IDENT_MAX_LBA_EXT + 2) << 32;
+        if self.sectors == 0 {
+            self.sectors = word(IDENT_MAX_LBA) | word(IDENT_MAX_LBA + 1) << 16;
+        }
+        Ok(())
+    }
+
+    fn bounce_buffer(&self) -> &'static mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.buf as *mut u8, BLOCK_SIZE) }
+    }
+}
+
+impl Driver for AHCIDriver {
+    fn try_handle_interrupt(&mut self) -> bool {
+        // we use polling
+        false
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriver for AHCIDriver {
+    fn get_device(&self) -> Box<Device> {
+        Box::new(self.clone())
+    }
+}
+
+impl BlockedDevice for AHCIDriver {
+    const BLOCK_SIZE_LOG2: u8 = 9; // 512
+    fn read_at(&mut self, block_id: usize, buf: &mut [u8]) -> bool {
+        let mut port = self.0.lock();
+        if block_id as u64 >= port.sectors || port.command(ATA_CMD_READ_DMA_EXT, block_id as u64, 1, false).is_err() {
+            return false;
+        }
+        let len = buf.len().min(BLOCK_SIZE);
+        buf[..len].copy_from_slice(&port.bounce_buffer()[..len]);
+        true
+    }
+
+    fn write_at(&mut self, block_id: usize, buf: &[u8]) -> bool {
+        let mut port = self.0.lock();
+        if block_id as u64 >= port.sectors {
+            return false;
+        }
+        if buf.len() < BLOCK_SIZE {
+            // a partial block: read, modify and write the whole block
+            if port.command(ATA_CMD_READ_DMA_EXT, block_id as u64, 1, false).is_err() {
+                return false;
+            }
+        }
+        let len = buf.len().min(BLOCK_SIZE);
+        port.bounce_buffer()[..len].copy_from_slice(&buf[..len]);
+        // data is in the disk, not in its cache, when it returns
+        port.command(ATA_CMD_WRITE_DMA_EXT, block_id as u64, 1, true).is_ok()
+            && port.command(ATA_CMD_FLUSH_EXT, 0, 0, false).is_ok()
+    }
+}
+
+/// Init the AHCI controller with registers at physical address `abar`,
+/// and register its disks
+fn init(abar: usize) {
+    for offset in (0..HBA_SIZE).step_by(PAGE_SIZE) {
+        map(abar + offset);
+    }
+    let hba = KERNEL_OFFSET + abar;
+    let ghc = reg(hba + HBA_GHC);
+    ghc.write(ghc.read() | GHC_AE);
+    let implemented = reg(hba + HBA_PI).read();
+    for num in 0..32 {
+        if implemented & (1 << num) == 0 {
+            continue;
+        }
+        let regs = hba + PORT_BASE + num * PORT_SIZE;
+        if reg(regs + PX_SSTS).read() & 0xf != SSTS_DET_PRESENT {
+            continue;
+        }
+        if reg(regs + PX_SIG).read() != SIG_ATA {
+            info!("ahci: port {} is not a disk", num);
+            continue;
+        }
+        let page_phys = alloc_frame().expect("failed to alloc frame");
+        let buf_phys = alloc_frame().expect("failed to alloc frame");
+        let mut port = AHCIPort {
+            num,
+            regs,
+            page: map(page_phys),
+            page_phys,
+            buf: map(buf_phys),
+            buf_phys,
+            sectors: 0,
+        };
+        if port.init().is_err() || port.identify().is_err() {
+            warn!("ahci: failed to init port {}", num);
+            continue;
+        }
+        info!("ahci: port {}: {} sectors", num, port.sectors);
+        let driver = AHCIDriver(Arc::new(Mutex::new(port)));
+        DRIVERS.lock().push(Box::new(driver.clone()));
+        BLK_DRIVERS.lock().push(Box::new(driver));
+    }
+}
+
+/// Class, subclass and programming interface of AHCI controllers
+const CLASS_AHCI: u32 = 0x01_06_01;
+
+fn matches(dev: &PciDevice) -> bool {
+    dev.class == CLASS_AHCI
+}
+
+/// Registers are in BAR5
+fn probe(dev: &PciDevice) {
+    match dev.memory_bar(5) {
+        Some(abar) => {
+            dev.enable();
+            init(abar);
+        }
+        None => warn!("ahci: no registers in BAR5"),
+    }
+}
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches,
+    probe,
+};
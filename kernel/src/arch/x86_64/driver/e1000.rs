@@ -0,0 +1,410 @@
+//! Driver of Intel e1000 and e1000e network cards
+//!
+//! One receive ring and one transmit ring, with a buffer of 2 KiB for each descriptor.
+//! Interrupts are acknowledged, frames are taken by polling `receive`.
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use core::ptr::{read_volatile, write_bytes, write_volatile};
+use core::slice;
+use core::sync::atomic::{fence, Ordering};
+use log::*;
+use rcore_memory::PAGE_SIZE;
+use smoltcp::wire::EthernetAddress;
+use volatile::Volatile;
+use crate::drivers::{DeviceType, Driver, NetDriver, DRIVERS, NET_DRIVERS};
+use crate::drivers::bus::pci::{PciDevice, PciDriver};
+use crate::memory::alloc_frame;
+use crate::sync::SpinNoIrqLock as Mutex;
+use super::pci::map;
+
+/// Size of registers
+const REGS_SIZE: usize = 0x20000;
+const REG_CTRL: usize = 0x0000;
+const REG_EERD: usize = 0x0014;
+const REG_ICR: usize = 0x00c0;
+const REG_IMS: usize = 0x00d0;
+const REG_IMC: usize = 0x00d8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+/// Multicast table array of 128 entries
+const REG_MTA: usize = 0x5200;
+const REG_RAL: usize = 0x5400;
+const REG_RAH: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+/// Receive buffers of 2048 bytes, broadcast accepted and CRC stripped
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+/// Pad short packets, with the recommended collision threshold and distance
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// Recommended inter packet gap
+const TIPG: u32 = 10 | 8 << 10 | 6 << 20;
+const RAH_AV: u32 = 1 << 31;
+
+/// Interrupts of transmit done, link change, receive threshold, overrun and timer
+const IMS_ENABLED: u32 = 1 << 0 | 1 << 2 | 1 << 4 | 1 << 6 | 1 << 7;
+
+/// EEPROM read register of e1000 and e1000e
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+const EERD_ADDR_SHIFT: u32 = 8;
+const EERD_DONE_E: u32 = 1 << 1;
+const EERD_ADDR_SHIFT_E: u32 = 2;
+
+const DESC_DD: u8 = 1 << 0;
+const DESC_EOP: u8 = 1 << 1;
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+
+/// Descriptors in each ring, a multiple of 8
+const RING_SIZE: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+/// Max frame without CRC
+const MAX_FRAME: usize = 1514;
+
+/// Times to poll before giving up
+const TIMEOUT: usize = 1_000_000;
+
+const VENDOR_INTEL: u16 = 0x8086;
+/// (device ID, is e1000e) of supported cards
+const DEVICES: [(u16, bool); 4] = [
+    (0x100e, false), // 82540EM, in QEMU
+    (0x100f, false), // 82545EM
+    (0x10d3, true), // 82574L, e1000e in QEMU
+    (0x1503, true), // 82579V
+];
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RxDesc {
+    addr: u64,
+    len: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct TxDesc {
+    addr: u64,
+    len: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+pub struct E1000 {
+    /// Address of registers
+    regs: usize,
+    irq: u8,
+    mac: EthernetAddress,
+    name: String,
+    rx_ring: *mut RxDesc,
+    tx_ring: *mut TxDesc,
+    /// Addresses of buffers of descriptors
+    rx_buffers: Vec<usize>,
+    tx_buffers: Vec<usize>,
+    /// Last receive descriptor given to the card
+    rx_tail: usize,
+    /// Next transmit descriptor to use
+    tx_tail: usize,
+}
+
+// the rings are only accessed with the lock held
+unsafe impl Send for E1000 {}
+
+#[derive(Clone)]
+pub struct E1000Driver(Arc<Mutex<E1000>>);
+
+fn reg(addr: usize) -> &'static mut Volatile<u32> {
+    unsafe { &mut *(addr as *mut Volatile<u32>) }
+}
+
+/// Allocate a zeroed page for DMA, return its address and physical address
+fn alloc_page() -> (usize, usize) {
+    let phys = alloc_frame().expect("failed to alloc frame");
+    let virt = map(phys);
+    unsafe { write_bytes(virt as *mut u8, 0, PAGE_SIZE) };
+    (virt, phys)
+}
+
+/// Allocate `count` buffers in pages, calling `set_addr` with the index and physical address of each
+fn alloc_buffers(count: usize, mut set_addr: impl FnMut(usize, u64)) -> Vec<usize> {
+    let per_page = PAGE_SIZE / BUFFER_SIZE;
+    let mut buffers = Vec::new();
+    for i in 0..count / per_page {
+        let (virt, phys) = alloc_page();
+        for j in 0..per_page {
+            set_addr(i * per_page + j, (phys + j * BUFFER_SIZE) as u64);
+            buffers.push(virt + j * BUFFER_SIZE);
+        }
+    }
+    buffers
+}
+
+impl E1000 {
+    fn reg(&self, offset: usize) -> &'static mut Volatile<u32> {
+        reg(self.regs + offset)
+    }
+
+    /// Read a word of the EEPROM
+    fn read_eeprom(&self, addr: u32, e1000e: bool) -> Option<u16> {
+        let (shift, done) = if e1000e {
+            (EERD_ADDR_SHIFT_E, EERD_DONE_E)
+        } else {
+            (EERD_ADDR_SHIFT, EERD_DONE)
+        };
+        self.reg(REG_EERD).write(addr << shift | EERD_START);
+        for _ in 0..TIMEOUT {
+            let value = self.reg(REG_EERD).read();
+            if value & done != 0 {
+                return Some((value >> 16) as u16);
+            }
+        }
+        None
+    }
+
+    /// Read the MAC address from the EEPROM, or from the receive address register
+    fn read_mac(&self, e1000e: bool) -> EthernetAddress {
+        let mut mac = [0u8; 6];
+        let words = [self.read_eeprom(0, e1000e), self.read_eeprom(1, e1000e), self.read_eeprom(2, e1000e)];
+        if words.iter().all(|w| w.is_some()) {
+            for (i, word) in words.iter().enumerate() {
+                let word = word.unwrap();
+                mac[i * 2] = word as u8;
+                mac[i * 2 + 1] = (word >> 8) as u8;
+            }
+        } else {
+            warn!("e1000: failed to read EEPROM, use the receive address");
+            let low = self.reg(REG_RAL).read();
+            let high = self.reg(REG_RAH).read();
+            mac[..4].copy_from_slice(&low.to_le_bytes());
+            mac[4..].copy_from_slice(&(high as u16).to_le_bytes());
+        }
+        EthernetAddress(mac)
+    }
+
+    fn reset(&self) -> Result<(), ()> {
+        self.reg(REG_IMC).write(!0);
+        let ctrl = self.reg(REG_CTRL);
+        ctrl.write(ctrl.read() | CTRL_RST);
+        for _ in 0..TIMEOUT {
+            if ctrl.read() & CTRL_RST == 0 {
+                self.reg(REG_IMC).write(!0);
+                self.reg(REG_ICR).read();
+                ctrl.write(ctrl.read() | CTRL_SLU | CTRL_ASDE);
+                return Ok(());
+            }
+        }
+        Err(())
+    }
+
+    fn init_rings(&mut self) {
+        let (rx_virt, rx_phys) = alloc_page();
+        let (tx_virt, tx_phys) = alloc_page();
+        self.rx_ring = rx_virt as *mut RxDesc;
+        self.tx_ring = tx_virt as *mut TxDesc;
+        let rx_ring = unsafe { slice::from_raw_parts_mut(self.rx_ring, RING_SIZE) };
+        let tx_ring = unsafe { slice::from_raw_parts_mut(self.tx_ring, RING_SIZE) };
+        self.rx_buffers = alloc_buffers(RING_SIZE, |i, addr| rx_ring[i].addr = addr);
+        self.tx_buffers = alloc_buffers(RING_SIZE, |i, addr| tx_ring[i].addr = addr);
+        // all transmit descriptors are free
+        for desc in tx_ring.iter_mut() {
+            desc.status = DESC_DD;
+        }
+        fence(Ordering::SeqCst);
+
+        // receive address and no multicast
+        let mac = self.mac.0;
+        self.reg(REG_RAL).write(u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]));
+        self.reg(REG_RAH).write(u16::from_le_bytes([mac[4], mac[5]]) as u32 | RAH_AV);
+        for i in 0..128 {
+            self.reg(REG_MTA + i * 4).write(0);
+        }
+
+        let ring_len = (RING_SIZE * 16) as u32;
+        self.reg(REG_RDBAL).write(rx_phys as u32);
+        self.reg(REG_RDBAH).write((rx_phys as u64 >> 32) as u32);
+        self.reg(REG_RDLEN).write(ring_len);
+        self.reg(REG_RDH).write(0);
+        // all but one descriptor are given to the card
+        self.rx_tail = RING_SIZE - 1;
+        self.reg(REG_RDT).write(self.rx_tail as u32);
+        self.reg(REG_RCTL).write(RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        self.reg(REG_TDBAL).write(tx_phys as u32);
+        self.reg(REG_TDBAH).write((tx_phys as u64 >> 32) as u32);
+        self.reg(REG_TDLEN).write(ring_len);
+        self.reg(REG_TDH).write(0);
+        self.reg(REG_TDT).write(0);
+        self.tx_tail = 0;
+        self.reg(REG_TCTL).write(TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        self.reg(REG_TIPG).write(TIPG);
+
+        self.reg(REG_IMS).write(IMS_ENABLED);
+    }
+}
+
+impl Driver for E1000Driver {
+    fn try_handle_interrupt(&mut self) -> bool {
+        let driver = self.0.lock();
+        // reading clears the causes
+        let icr = driver.reg(REG_ICR).read();
+        if icr != 0 {
+            debug!("{}: interrupt {:#x}", driver.name, icr);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Net
+    }
+}
+
+impl NetDriver for E1000Driver {
+    fn get_mac(&self) -> EthernetAddress {
+        self.0.lock().mac
+    }
+
+    fn get_ifname(&self) -> String {
+        self.0.lock().name.clone()
+    }
+
+    fn send(&self, frame: &[u8]) -> bool {
+        if frame.len() > MAX_FRAME {
+            return false;
+        }
+        let mut driver = self.0.lock();
+        let i = driver.tx_tail;
+        let desc = unsafe { driver.tx_ring.add(i) };
+        if unsafe { read_volatile(desc) }.status & DESC_DD == 0 {
+            // the ring is full
+            return false;
+        }
+        let buffer = unsafe { slice::from_raw_parts_mut(driver.tx_buffers[i] as *mut u8, frame.len()) };
+        buffer.copy_from_slice(frame);
+        let mut new = unsafe { read_volatile(desc) };
+        new.len = frame.len() as u16;
+        new.cmd = TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS;
+        new.status = 0;
+        unsafe { write_volatile(desc, new) };
+        fence(Ordering::SeqCst);
+        driver.tx_tail = (i + 1) % RING_SIZE;
+        let tail = driver.tx_tail;
+        driver.reg(REG_TDT).write(tail as u32);
+        true
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut driver = self.0.lock();
+        loop {
+            let i = (driver.rx_tail + 1) % RING_SIZE;
+            let desc = unsafe { driver.rx_ring.add(i) };
+            let mut value = unsafe { read_volatile(desc) };
+            if value.status & DESC_DD == 0 {
+                return None;
+            }
+            fence(Ordering::SeqCst);
+            // frames are smaller than a buffer, so they have one descriptor
+            let ok = value.status & DESC_EOP != 0 && value.errors == 0;
+            let len = (value.len as usize).min(buf.len());
+            if ok {
+                let data = unsafe { slice::from_raw_parts(driver.rx_buffers[i] as *const u8, len) };
+                buf[..len].copy_from_slice(data);
+            }
+            // give the descriptor back to the card
+            value.status = 0;
+            unsafe { write_volatile(desc, value) };
+            driver.rx_tail = i;
+            driver.reg(REG_RDT).write(i as u32);
+            if ok {
+                return Some(len);
+            }
+            warn!("{}: dropped a frame with errors {:#x}", driver.name, value.errors);
+        }
+    }
+}
+
+/// Init the card with registers at physical address `bar` and interrupt `irq`,
+/// and register it
+fn init(bar: usize, irq: u8, e1000e: bool) {
+    for offset in (0..REGS_SIZE).step_by(PAGE_SIZE) {
+        map(bar + offset);
+    }
+    let mut e1000 = E1000 {
+        regs: map(bar),
+        irq,
+        mac: EthernetAddress([0; 6]),
+        name: format!("eth{}", NET_DRIVERS.lock().len()),
+        rx_ring: 0 as *mut RxDesc,
+        tx_ring: 0 as *mut TxDesc,
+        rx_buffers: Vec::new(),
+        tx_buffers: Vec::new(),
+        rx_tail: 0,
+        tx_tail: 0,
+    };
+    if e1000.reset().is_err() {
+        warn!("e1000: reset timeout");
+        return;
+    }
+    e1000.mac = e1000.read_mac(e1000e);
+    e1000.init_rings();
+    info!("{}: MAC {}, irq {}", e1000.name, e1000.mac, e1000.irq);
+
+    let driver = E1000Driver(Arc::new(Mutex::new(e1000)));
+    DRIVERS.lock().push(Box::new(driver.clone()));
+    NET_DRIVERS.lock().push(Box::new(driver));
+    crate::arch::interrupt::enable_irq(irq);
+}
+
+/// Whether the card is an e1000e, if it is supported
+fn variant(dev: &PciDevice) -> Option<bool> {
+    if dev.vendor != VENDOR_INTEL {
+        return None;
+    }
+    DEVICES.iter().find(|&&(id, _)| id == dev.device).map(|&(_, e1000e)| e1000e)
+}
+
+fn matches(dev: &PciDevice) -> bool {
+    variant(dev).is_some()
+}
+
+fn probe(dev: &PciDevice) {
+    match dev.memory_bar(0) {
+        Some(bar) => {
+            dev.enable();
+            init(bar, dev.irq, variant(dev).unwrap());
+        }
+        None => warn!("e1000: no registers in BAR0"),
+    }
+}
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "e1000",
+    matches,
+    probe,
+};
@@ -0,0 +1,124 @@
+//! Driver of the Intel 6300ESB watchdog timer, emulated by QEMU with `-device i6300esb`
+
+use alloc::boxed::Box;
+use core::ptr::write_volatile;
+use log::*;
+use crate::drivers::bus::pci::{PciDevice, PciDriver};
+use crate::watchdog::{register_watchdog, Watchdog};
+use super::pci::map;
+
+const VENDOR_INTEL: u16 = 0x8086;
+const DEVICE_ESB_WDT: u16 = 0x25ab;
+
+/// Registers in the configuration space
+const ESB_CONFIG_REG: u16 = 0x60;
+const ESB_LOCK_REG: u16 = 0x68;
+
+/// Registers in BAR0
+const ESB_TIMER1_REG: usize = 0x00;
+const ESB_TIMER2_REG: usize = 0x04;
+const ESB_RELOAD_REG: usize = 0x0c;
+
+/// Written to the reload register before writing any other register
+const ESB_UNLOCK1: u16 = 0x80;
+const ESB_UNLOCK2: u16 = 0x86;
+
+/// Reset the machine at the end of the second stage, with no interrupt after the first one
+const ESB_WDT_INTTYPE: u32 = 0x03;
+const ESB_WDT_LOCK: u32 = 1 << 0;
+const ESB_WDT_ENABLE: u32 = 1 << 1;
+const ESB_WDT_RELOAD: u16 = 1 << 8;
+const ESB_WDT_TIMEOUT: u16 = 1 << 9;
+
+struct I6300ESB {
+    dev: PciDevice,
+    regs: usize,
+}
+
+impl I6300ESB {
+    fn unlock(&mut self) {
+        self.write16(ESB_RELOAD_REG, ESB_UNLOCK1);
+        self.write16(ESB_RELOAD_REG, ESB_UNLOCK2);
+    }
+
+    fn write16(&mut self, reg: usize, value: u16) {
+        unsafe { write_volatile((self.regs + reg) as *mut u16, value) }
+    }
+
+    fn write32(&mut self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.regs + reg) as *mut u32, value) }
+    }
+
+    /// Set the low byte of the lock register
+    fn set_lock(&mut self, value: u32) {
+        unsafe {
+            let lock = self.dev.read(ESB_LOCK_REG);
+            self.dev.write(ESB_LOCK_REG, lock & !0xff | value);
+        }
+    }
+
+    fn lock(&self) -> u32 {
+        unsafe { self.dev.read(ESB_LOCK_REG) & 0xff }
+    }
+}
+
+impl Watchdog for I6300ESB {
+    fn start(&mut self, timeout: u32) {
+        // each stage counts `timeout` seconds in units of about 1 ms
+        let value = timeout.min(0x7ff) << 9;
+        self.unlock();
+        self.write32(ESB_TIMER1_REG, value);
+        self.unlock();
+        self.write32(ESB_TIMER2_REG, value);
+        self.unlock();
+        self.write16(ESB_RELOAD_REG, ESB_WDT_RELOAD);
+        self.set_lock(ESB_WDT_ENABLE);
+    }
+
+    fn pet(&mut self) {
+        self.unlock();
+        self.write16(ESB_RELOAD_REG, ESB_WDT_RELOAD);
+    }
+
+    fn stop(&mut self) {
+        self.unlock();
+        self.write16(ESB_RELOAD_REG, ESB_WDT_RELOAD);
+        self.set_lock(0);
+        if self.lock() & ESB_WDT_ENABLE != 0 {
+            warn!("i6300esb: failed to stop");
+        }
+    }
+}
+
+fn matches(dev: &PciDevice) -> bool {
+    dev.vendor == VENDOR_INTEL && dev.device == DEVICE_ESB_WDT
+}
+
+fn probe(dev: &PciDevice) {
+    let bar = match dev.memory_bar(0) {
+        Some(bar) => bar,
+        None => {
+            warn!("i6300esb: no registers in BAR0");
+            return;
+        }
+    };
+    dev.enable();
+    let mut esb = I6300ESB { dev: dev.clone(), regs: map(bar) };
+    unsafe {
+        let config = esb.dev.read(ESB_CONFIG_REG);
+        esb.dev.write(ESB_CONFIG_REG, config & !0xffff | ESB_WDT_INTTYPE);
+    }
+    if esb.lock() & ESB_WDT_LOCK != 0 {
+        warn!("i6300esb: locked by the firmware, it can't be stopped");
+    }
+    // clear the flag of a reset by the watchdog
+    esb.unlock();
+    esb.write16(ESB_RELOAD_REG, ESB_WDT_TIMEOUT);
+    register_watchdog(Box::new(esb));
+}
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "i6300esb",
+    matches,
+    probe,
+};
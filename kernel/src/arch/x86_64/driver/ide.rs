@@ -1,120 +1,222 @@
-//! ATA IO code, handling device multiplexing and IO operations
+//! ATA PIO driver of IDE disks, handling device multiplexing and IO operations
 //!
 //! Borrow from Rucore project. Thanks GWord!
 //! Port from ucore C code.
 
+use alloc::boxed::Box;
+use log::*;
+use simple_filesystem::Device;
+use crate::drivers::{BlockDriver, DeviceType, Driver};
+
 pub const BLOCK_SIZE: usize = 512;
 
+#[derive(Clone)]
 pub struct IDE {
     num: u8,
     /// I/O Base
     base: u16,
     /// Control Base
     ctrl: u16,
+    /// Number of sectors
+    sectors: u64,
+    /// Whether 48-bit LBA is supported
+    lba48: bool,
 }
 
 impl IDE {
-    pub fn new(num: u8) -> Self {
-        let ide = match num {
-            0 => IDE { num: 0, base: 0x1f0, ctrl: 0x3f4 },
-            1 => IDE { num: 1, base: 0x1f0, ctrl: 0x3f4 },
-            2 => IDE { num: 2, base: 0x170, ctrl: 0x374 },
-            3 => IDE { num: 3, base: 0x170, ctrl: 0x374 },
+    /// Probe IDE drive `num`, return None if it is not an ATA disk
+    pub fn new(num: u8) -> Option<Self> {
+        let (base, ctrl) = match num {
+            0 | 1 => (0x1f0, 0x3f4),
+            2 | 3 => (0x170, 0x374),
             _ => panic!("ide number should be 0,1,2,3"),
         };
-        ide.init();
-        ide
+        let mut ide = IDE { num, base, ctrl, sectors: 0, lba48: false };
+        if !ide.identify() {
+            return None;
+        }
+        info!("ide {}: {} sectors, {}-bit LBA", num, ide.sectors, if ide.lba48 { 48 } else { 28 });
+        Some(ide)
     }
 
-    /// Read ATA DMA. Block size = 512 bytes.
+    /// Read ATA PIO. Block size = 512 bytes.
     pub fn read(&self, sector: u64, count: usize, data: &mut [u32]) -> Result<(), ()> {
         assert_eq!(data.len(), count * SECTOR_SIZE);
-        self.wait();
-        unsafe {
-            self.select(sector, count as u8);
-            port::outb(self.base + ISA_COMMAND, IDE_CMD_READ);
-            for i in 0..count {
-                let ptr = &data[(i as usize) * SECTOR_SIZE];
-                if self.wait_error() {
-                    return Err(());
-                }
-                asm!("rep insl" :: "{dx}"(self.base), "{rdi}"(ptr), "{cx}"(SECTOR_SIZE) : "rdi" : "volatile");
+        self.command(sector, count, IDE_CMD_READ, IDE_CMD_READ_EXT)?;
+        for i in 0..count {
+            let ptr = &mut data[i * SECTOR_SIZE] as *mut u32;
+            self.wait_data()?;
+            unsafe {
+                asm!("rep insl" :: "{dx}"(self.base + ISA_DATA), "{rdi}"(ptr), "{cx}"(SECTOR_SIZE) : "rdi", "rcx", "memory" : "volatile");
             }
         }
         Ok(())
     }
-    /// Write ATA DMA. Block size = 512 bytes.
+    /// Write ATA PIO. Block size = 512 bytes.
+    /// Data is in the disk, not in its cache, when it returns.
     pub fn write(&self, sector: u64, count: usize, data: &[u32]) -> Result<(), ()> {
         assert_eq!(data.len(), count * SECTOR_SIZE);
-        self.wait();
+        self.command(sector, count, IDE_CMD_WRITE, IDE_CMD_WRITE_EXT)?;
+        for i in 0..count {
+            let ptr = &data[i * SECTOR_SIZE];
+            self.wait_data()?;
+            unsafe {
+                asm!("rep outsl" :: "{dx}"(self.base + ISA_DATA), "{rsi}"(ptr), "{cx}"(SECTOR_SIZE) : "rsi", "rcx", "memory" : "volatile");
+            }
+        }
+        self.wait()?;
+        let flush = if self.lba48 { IDE_CMD_FLUSH_EXT } else { IDE_CMD_FLUSH };
         unsafe {
-            self.select(sector, count as u8);
-            port::outb(self.base + ISA_COMMAND, IDE_CMD_WRITE);
-            for i in 0..count {
-                let ptr = &data[(i as usize) * SECTOR_SIZE];
-                if self.wait_error() {
-                    return Err(());
-                }
-                asm!("rep outsl" :: "{dx}"(self.base), "{rsi}"(ptr), "{cx}"(SECTOR_SIZE) : "rsi" : "volatile");
+            port::outb(self.base + ISA_COMMAND, flush);
+        }
+        self.delay();
+        self.wait()
+    }
+
+    /// Wait 400ns for the status to be valid after selecting a drive or sending a command
+    fn delay(&self) {
+        for _ in 0..4 {
+            unsafe {
+                port::inb(self.ctrl + ISA_CTRL);
             }
         }
-        Ok(())
     }
 
-    fn wait(&self) {
-        while unsafe { port::inb(self.base + ISA_STATUS) } & IDE_BUSY != 0 {}
+    /// Wait until the drive is not busy, and check for errors
+    fn wait(&self) -> Result<(), ()> {
+        for _ in 0..TIMEOUT {
+            let status = unsafe { port::inb(self.base + ISA_STATUS) };
+            if status & IDE_BUSY != 0 {
+                continue;
+            }
+            if status & (IDE_DF | IDE_ERR) != 0 {
+                let error = unsafe { port::inb(self.base + ISA_ERROR) };
+                warn!("ide {}: status {:#x}, error {:#x}", self.num, status, error);
+                return Err(());
+            }
+            return Ok(());
+        }
+        warn!("ide {}: timeout", self.num);
+        Err(())
     }
 
-    fn wait_error(&self) -> bool {
-        self.wait();
-        let status = unsafe { port::inb(self.base + ISA_STATUS) };
-        status & (IDE_DF | IDE_ERR) != 0
+    /// Wait until the drive is ready to transfer a sector
+    fn wait_data(&self) -> Result<(), ()> {
+        self.wait()?;
+        if unsafe { port::inb(self.base + ISA_STATUS) } & IDE_DRQ == 0 {
+            warn!("ide {}: no data requested", self.num);
+            return Err(());
+        }
+        Ok(())
     }
 
-    fn init(&self) {
-        self.wait();
+    /// Send ATA IDENTIFY, and get the size and features of the disk
+    fn identify(&mut self) -> bool {
         unsafe {
+            // a floating bus means no controller
+            if port::inb(self.base + ISA_STATUS) == 0xff {
+                return false;
+            }
             // step1: select drive
-            port::outb(self.base + ISA_SDH, (0xE0 | ((self.num & 1) << 4)) as u8);
-            self.wait();
+            port::outb(self.base + ISA_SDH, 0xA0 | ((self.num & 1) << 4));
+            self.delay();
 
             // step2: send ATA identify command
+            port::outb(self.base + ISA_SECCNT, 0);
+            port::outb(self.base + ISA_SECTOR, 0);
+            port::outb(self.base + ISA_CYL_LO, 0);
+            port::outb(self.base + ISA_CYL_HI, 0);
             port::outb(self.base + ISA_COMMAND, IDE_CMD_IDENTIFY);
-            self.wait();
+            self.delay();
 
             // step3: polling
-            if port::inb(self.base + ISA_STATUS) == 0 || self.wait_error() {
-                return;
+            if port::inb(self.base + ISA_STATUS) == 0 {
+                return false;
             }
-
-            // ???
-            let data = [0; SECTOR_SIZE];
-            asm!("rep insl" :: "{dx}"(self.base + ISA_DATA), "{rdi}"(data.as_ptr()), "{cx}"(SECTOR_SIZE) : "rdi" : "volatile");
+            for _ in 0..TIMEOUT {
+                if port::inb(self.base + ISA_STATUS) & IDE_BUSY == 0 {
+                    break;
+                }
+            }
+            // ATAPI and SATA devices put a signature here
+            if port::inb(self.base + ISA_CYL_LO) != 0 || port::inb(self.base + ISA_CYL_HI) != 0 {
+                return false;
+            }
+        }
+        if self.wait_data().is_err() {
+            return false;
         }
+        let mut data = [0u32; SECTOR_SIZE];
+        unsafe {
+            asm!("rep insl" :: "{dx}"(self.base + ISA_DATA), "{rdi}"(data.as_mut_ptr()), "{cx}"(SECTOR_SIZE) : "rdi", "rcx", "memory" : "volatile");
+        }
+        let word = |i: usize| (data[i / 2] >> (i % 2 * 16)) as u16 as u64;
+        self.lba48 = word(IDENT_COMMAND_SETS + 1) & (1 << 10) != 0;
+        self.sectors = if self.lba48 {
+            word(IDENT_MAX_LBA_EXT) | word(IDENT_MAX_LBA_EXT + 1) << 16 | word(IDENT_MAX_LBA_EXT + 2) << 32
+        } else {
+            word(IDENT_MAX_LBA) | word(IDENT_MAX_LBA + 1) << 16
+        };
+        self.sectors != 0
     }
 
-    fn select(&self, sector: u64, count: u8) {
-        assert_ne!(count, 0);
-        self.wait();
+    /// Select sectors and send a read or write command,
+    /// using 48-bit LBA only when needed
+    fn command(&self, sector: u64, count: usize, cmd: u8, cmd_ext: u8) -> Result<(), ()> {
+        assert!(count > 0 && count <= MAX_NSECS);
+        if sector + count as u64 > self.sectors {
+            warn!("ide {}: sector {:#x} out of range", self.num, sector);
+            return Err(());
+        }
+        self.wait()?;
         unsafe {
-            // generate interrupt
-            port::outb(self.ctrl + ISA_CTRL, 0);
-            port::outb(self.base + ISA_SECCNT, count);
+            // we use polling
+            port::outb(self.ctrl + ISA_CTRL, IDE_CTRL_NIEN);
+            if sector + count as u64 > 1 << 28 {
+                port::outb(self.base + ISA_SDH, 0x40 | ((self.num & 1) << 4));
+                self.delay();
+                // high bytes first
+                port::outb(self.base + ISA_SECCNT, (count >> 8) as u8);
+                port::outb(self.base + ISA_SECTOR, ((sector >> 24) & 0xFF) as u8);
+                port::outb(self.base + ISA_CYL_LO, ((sector >> 32) & 0xFF) as u8);
+                port::outb(self.base + ISA_CYL_HI, ((sector >> 40) & 0xFF) as u8);
+            } else {
+                port::outb(self.base + ISA_SDH, 0xE0 | ((self.num & 1) << 4) | (((sector >> 24) & 0xF) as u8));
+                self.delay();
+            }
+            port::outb(self.base + ISA_SECCNT, count as u8);
             port::outb(self.base + ISA_SECTOR, (sector & 0xFF) as u8);
             port::outb(self.base + ISA_CYL_LO, ((sector >> 8) & 0xFF) as u8);
             port::outb(self.base + ISA_CYL_HI, ((sector >> 16) & 0xFF) as u8);
-            port::outb(self.base + ISA_SDH, 0xE0 | ((self.num & 1) << 4) | (((sector >> 24) & 0xF) as u8));
+            let cmd = if sector + count as u64 > 1 << 28 { cmd_ext } else { cmd };
+            port::outb(self.base + ISA_COMMAND, cmd);
         }
+        self.delay();
+        Ok(())
+    }
+}
+
+impl Driver for IDE {
+    fn try_handle_interrupt(&mut self) -> bool {
+        // we use polling
+        false
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriver for IDE {
+    fn get_device(&self) -> Box<Device> {
+        Box::new(self.clone())
     }
 }
 
 const SECTOR_SIZE: usize = 128;
-const MAX_DMA_SECTORS: usize = 0x1F_F000 / SECTOR_SIZE;    // Limited by sector count (and PRDT entries)
-// 512 PDRT entries, assume maximum fragmentation = 512 * 4K max = 2^21 = 2MB per transfer
 
 const ISA_DATA: u16 = 0x00;
 const ISA_ERROR: u16 = 0x01;
-const ISA_PRECOMP: u16 = 0x01;
 const ISA_CTRL: u16 = 0x02;
 const ISA_SECCNT: u16 = 0x02;
 const ISA_SECTOR: u16 = 0x03;
@@ -125,17 +227,31 @@ const ISA_COMMAND: u16 = 0x07;
 const ISA_STATUS: u16 = 0x07;
 
 const IDE_BUSY: u8 = 0x80;
-const IDE_DRDY: u8 = 0x40;
 const IDE_DF: u8 = 0x20;
 const IDE_DRQ: u8 = 0x08;
 const IDE_ERR: u8 = 0x01;
 
+/// Disable interrupts of the drive
+const IDE_CTRL_NIEN: u8 = 0x02;
+
 const IDE_CMD_READ: u8 = 0x20;
+const IDE_CMD_READ_EXT: u8 = 0x24;
 const IDE_CMD_WRITE: u8 = 0x30;
+const IDE_CMD_WRITE_EXT: u8 = 0x34;
+const IDE_CMD_FLUSH: u8 = 0xE7;
+const IDE_CMD_FLUSH_EXT: u8 = 0xEA;
 const IDE_CMD_IDENTIFY: u8 = 0xEC;
 
+/// Words of IDENTIFY data
+const IDENT_MAX_LBA: usize = 60;
+const IDENT_COMMAND_SETS: usize = 82;
+const IDENT_MAX_LBA_EXT: usize = 100;
+
 const MAX_NSECS: usize = 128;
 
+/// Times to poll the status before giving up
+const TIMEOUT: usize = 10_000_000;
+
 mod port {
     use x86_64::instructions::port::Port;
 
@@ -146,4 +262,4 @@ mod port {
     pub unsafe fn outb(port: u16, value: u8) {
         Port::new(port).write(value)
     }
-}
\ No newline at end of file
+}
@@ -1,12 +1,66 @@
+//! PS/2 keyboard driver
+//!
+//! Typed characters go to stdin. Every key press and release is also queued
+//! as an event, read from `/dev/kbd`.
+
+use alloc::{collections::VecDeque, string::String, sync::Arc};
+use core::any::Any;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
-use pc_keyboard::{Keyboard, ScancodeSet1, DecodedKey, layouts};
+use pc_keyboard::{Keyboard, ScancodeSet1, DecodedKey, KeyState, layouts};
 use lazy_static::lazy_static;
+use simple_filesystem::*;
+use crate::fs::register_device;
+use crate::sync::Condvar;
+use crate::sync::SpinNoIrqLock as NoIrqMutex;
+
+/// Events kept when nobody reads them, older ones are dropped
+const QUEUE_SIZE: usize = 256;
+/// Prefix of scancodes of extended keys
+const SCANCODE_EXTENDED: u8 = 0xe0;
+/// Bit of a scancode set when the key is released
+const SCANCODE_RELEASED: u8 = 0x80;
 
 pub fn init() {
     use crate::arch::interrupt::consts::*;
     use crate::arch::interrupt::enable_irq;
 	enable_irq(IRQ_KBD);
+    register_device("kbd", KBD.clone());
+}
+
+/// A key pressed or released, as read from `/dev/kbd`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KeyEvent {
+    /// Scancode in set 1 without the release bit, 0xe0xx for extended keys
+    pub code: u16,
+    /// 1 for pressed, 0 for released
+    pub pressed: u16,
+    /// The character typed, or 0
+    pub unicode: u32,
+}
+
+/// Queue of key events
+#[derive(Default)]
+pub struct KeyboardINode {
+    events: NoIrqMutex<VecDeque<KeyEvent>>,
+    pushed: Condvar,
+}
+
+lazy_static! {
+    static ref KBD: Arc<KeyboardINode> = Arc::new(KeyboardINode::default());
+}
+
+impl KeyboardINode {
+    fn push(&self, event: KeyEvent) {
+        let mut events = self.events.lock();
+        if events.len() == QUEUE_SIZE {
+            events.pop_front();
+        }
+        events.push_back(event);
+        drop(events);
+        self.pushed.notify_one();
+    }
 }
 
 /// Receive character from keyboard
@@ -16,18 +70,70 @@ pub fn receive() -> Option<char> {
         static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
             Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
     }
+    /// Whether the last byte was the extended prefix
+    static EXTENDED: Mutex<bool> = Mutex::new(false);
 
     let mut keyboard = KEYBOARD.lock();
     let port = Port::<u8>::new(0x60);
 
     let scancode = unsafe { port.read() };
+    let mut extended = EXTENDED.lock();
+    let code = if scancode == SCANCODE_EXTENDED {
+        None
+    } else if *extended {
+        Some(0xe000 | (scancode & !SCANCODE_RELEASED) as u16)
+    } else {
+        Some((scancode & !SCANCODE_RELEASED) as u16)
+    };
+    *extended = scancode == SCANCODE_EXTENDED;
+
+    let mut character = None;
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        let pressed = key_event.state == KeyState::Down;
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
-                DecodedKey::Unicode(character) => return Some(character),
-                DecodedKey::RawKey(_key) => {}, // TODO: handle RawKey from keyboard
+                DecodedKey::Unicode(c) => character = Some(c),
+                DecodedKey::RawKey(_key) => {},
             }
         }
+        if let Some(code) = code {
+            KBD.push(KeyEvent {
+                code,
+                pressed: pressed as u16,
+                unicode: character.map(|c| c as u32).unwrap_or(0),
+            });
+        }
     }
-    None
-}
\ No newline at end of file
+    character
+}
+
+impl INode for KeyboardINode {
+    /// Wait for an event, then read as many whole events as `buf` holds
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        use core::mem::size_of;
+        use core::slice;
+        let size = size_of::<KeyEvent>();
+        if buf.len() < size {
+            return Err(FsError::InvalidParam);
+        }
+        let mut events = self.events.lock();
+        while events.is_empty() {
+            events = self.pushed.wait(events);
+        }
+        let mut len = 0;
+        while len + size <= buf.len() {
+            let event = match events.pop_front() {
+                Some(event) => event,
+                None => break,
+            };
+            let bytes = unsafe { slice::from_raw_parts(&event as *const _ as *const u8, size) };
+            buf[len..len + size].copy_from_slice(bytes);
+            len += size;
+        }
+        Ok(len)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+    impl_inode!();
+}
@@ -1,3 +1,4 @@
+use alloc::boxed::Box;
 use once::*;
 
 pub mod vga;
@@ -6,6 +7,12 @@ pub mod pic;
 pub mod keyboard;
 pub mod pit;
 pub mod ide;
+pub mod pci;
+pub mod ahci;
+pub mod nvme;
+pub mod rtc;
+pub mod e1000;
+pub mod i6300esb;
 
 pub fn init() {
     assert_has_not_been_called!();
@@ -17,5 +24,19 @@ pub fn init() {
     // pit::init();
 
     serial::init();
+    crate::drivers::gpu::console::init_console(Box::new(vga::VgaScreen::new()));
     keyboard::init();
+    rtc::init();
+
+    // The first disk is the boot image, the second one is the SFS image.
+    // Disks on the secondary channel come after it.
+    for num in 1..4 {
+        if let Some(ide) = ide::IDE::new(num) {
+            crate::drivers::BLK_DRIVERS.lock().push(Box::new(ide));
+        }
+    }
+
+    // Disks on AHCI and NVMe controllers come after IDE disks,
+    // network cards are found there too
+    pci::init();
 }
\ No newline at end of file
@@ -0,0 +1,427 @@
+//! NVMe driver, exposing each namespace as a block device
+//!
+//! There is one I/O queue pair shared by all namespaces, polled for completion.
+//! Data moves through a bounce buffer of pages described by a PRP list.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::ptr::{read_volatile, write_bytes, write_volatile};
+use core::slice;
+use core::sync::atomic::{fence, Ordering};
+use log::*;
+use rcore_memory::PAGE_SIZE;
+use simple_filesystem::Device;
+use volatile::Volatile;
+use crate::drivers::{BlockDriver, DeviceType, Driver, BLK_DRIVERS, DRIVERS};
+use crate::drivers::bus::pci::{PciDevice, PciDriver};
+use crate::memory::alloc_frame;
+use crate::sync::SpinNoIrqLock as Mutex;
+use super::pci::map;
+
+const REG_CAP: usize = 0x00;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1c;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELL_BASE: usize = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+/// Submission and completion queue entries of 64 and 16 bytes
+const CC_IO_ENTRY_SIZES: u32 = 6 << 16 | 4 << 20;
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const IDENTIFY_NAMESPACE: u32 = 0;
+const IDENTIFY_CONTROLLER: u32 = 1;
+
+/// Entries of each queue, filling one page of submissions
+const QUEUE_SIZE: usize = PAGE_SIZE / 64;
+const IO_QUEUE_ID: u16 = 1;
+/// Pages of bounce buffer, limiting bytes moved by a command
+const BOUNCE_PAGES: usize = 32;
+
+/// Times to poll before giving up
+const TIMEOUT: usize = 100_000_000;
+
+fn reg32(addr: usize) -> &'static mut Volatile<u32> {
+    unsafe { &mut *(addr as *mut Volatile<u32>) }
+}
+
+fn reg64(addr: usize) -> &'static mut Volatile<u64> {
+    unsafe { &mut *(addr as *mut Volatile<u64>) }
+}
+
+/// A page for DMA
+struct Page {
+    virt: usize,
+    phys: usize,
+}
+
+impl Page {
+    fn new() -> Self {
+        let phys = alloc_frame().expect("failed to alloc frame");
+        let virt = map(phys);
+        unsafe { write_bytes(virt as *mut u8, 0, PAGE_SIZE) };
+        Page { virt, phys }
+    }
+}
+
+/// A submission queue and its completion queue
+struct QueuePair {
+    id: u16,
+    sq: Page,
+    cq: Page,
+    tail: usize,
+    head: usize,
+    /// Phase tag of new completions, flipped at each wrap
+    phase: bool,
+}
+
+impl QueuePair {
+    fn new(id: u16) -> Self {
+        QueuePair { id, sq: Page::new(), cq: Page::new(), tail: 0, head: 0, phase: true }
+    }
+}
+
+struct NVMe {
+    /// Address of registers
+    regs: usize,
+    /// Stride of doorbell registers
+    doorbell_stride: usize,
+    admin: QueuePair,
+    io: QueuePair,
+    bounce: Vec<Page>,
+    prp_list: Page,
+    /// Max bytes moved by a command
+    max_transfer: usize,
+}
+
+/// A namespace of an NVMe controller
+#[derive(Clone)]
+pub struct NVMeDriver {
+    nvme: Arc<Mutex<NVMe>>,
+    nsid: u32,
+    /// Log2 of block size
+    block_shift: usize,
+    blocks: u64,
+}
+
+impl NVMe {
+    fn doorbell(&self, index: usize) -> &'static mut Volatile<u32> {
+        reg32(self.regs + DOORBELL_BASE + index * self.doorbell_stride)
+    }
+
+    fn wait_ready(&self, ready: bool) -> Result<(), ()> {
+        for _ in 0..TIMEOUT {
+            let csts = reg32(self.regs + REG_CSTS).read();
+            if csts & CSTS_CFS != 0 {
+                warn!("nvme: controller fatal status");
+                return Err(());
+            }
+            if (csts & CSTS_RDY != 0) == ready {
+                return Ok(());
+            }
+        }
+        warn!("nvme: timeout waiting for ready = {}", ready);
+        Err(())
+    }
+
+    /// Submit a command and wait for its completion.
+    /// `cmd` is the 16 dwords of the entry, the command id is filled in.
+    fn submit(&mut self, admin: bool, mut cmd: [u32; 16]) -> Result<u32, ()> {
+        let queue = if admin { &mut self.admin } else { &mut self.io };
+        let cid = queue.tail as u32;
+        cmd[0] |= cid << 16;
+        let entry = (queue.sq.virt + queue.tail * 64) as *mut [u32; 16];
+        unsafe { write_volatile(entry, cmd) };
+        queue.tail = (queue.tail + 1) % QUEUE_SIZE;
+        let (id, tail) = (queue.id as usize, queue.tail);
+        fence(Ordering::SeqCst);
+        self.doorbell(2 * id).write(tail as u32);
+
+        let queue = if admin { &mut self.admin } else { &mut self.io };
+        let completion = (queue.cq.virt + queue.head * 16) as *const [u32; 4];
+        let mut done = None;
+        for _ in 0..TIMEOUT {
+            let entry = unsafe { read_volatile(completion) };
+            if (entry[3] >> 16 & 1 != 0) == queue.phase {
+                done = Some(entry);
+                break;
+            }
+        }
+        let entry = match done {
+            Some(entry) => entry,
+            None => {
+                warn!("nvme: command {:#x} timeout", cmd[0] & 0xff);
+                return Err(());
+            }
+        };
+        queue.head += 1;
+        if queue.head == QUEUE_SIZE {
+            queue.head = 0;
+            queue.phase = !queue.phase;
+        }
+        let head = queue.head;
+        self.doorbell(2 * id + 1).write(head as u32);
+
+        let status = entry[3] >> 17;
+        if status != 0 {
+            warn!("nvme: command {:#x} failed with status {:#x}", cmd[0] & 0xff, status);
+            return Err(());
+        }
+        Ok(entry[0])
+    }
+
+    /// Fill the data pointers of `cmd` for `len` bytes in the bounce buffer
+    fn set_prp(&self, cmd: &mut [u32; 16], len: usize) {
+        let prp1 = self.bounce[0].phys as u64;
+        let prp2 = if len <= PAGE_SIZE {
+            0
+        } else if len <= 2 * PAGE_SIZE {
+            self.bounce[1].phys as u64
+        } else {
+            let list = unsafe { slice::from_raw_parts_mut(self.prp_list.virt as *mut u64, BOUNCE_PAGES) };
+            for (entry, page) in list.iter_mut().zip(self.bounce[1..].iter()) {
+                *entry = page.phys as u64;
+            }
+            self.prp_list.phys as u64
+        };
+        cmd[6] = prp1 as u32;
+        cmd[7] = (prp1 >> 32) as u32;
+        cmd[8] = prp2 as u32;
+        cmd[9] = (prp2 >> 32) as u32;
+    }
+
+    /// Identify into the first page of the bounce buffer
+    fn identify(&mut self, cns: u32, nsid: u32) -> Result<&'static [u8], ()> {
+        let mut cmd = [0u32; 16];
+        cmd[0] = ADMIN_IDENTIFY as u32;
+        cmd[1] = nsid;
+        cmd[10] = cns;
+        self.set_prp(&mut cmd, PAGE_SIZE);
+        self.submit(true, cmd)?;
+        Ok(unsafe { slice::from_raw_parts(self.bounce[0].virt as *const u8, PAGE_SIZE) })
+    }
+
+    /// Read or write `count` blocks of namespace `nsid` with the bounce buffer
+    fn io(&mut self, opcode: u8, nsid: u32, lba: u64, count: usize, len: usize) -> Result<(), ()> {
+        let mut cmd = [0u32; 16];
+        cmd[0] = opcode as u32;
+        cmd[1] = nsid;
+        self.set_prp(&mut cmd, len);
+        cmd[10] = lba as u32;
+        cmd[11] = (lba >> 32) as u32;
+        cmd[12] = (count - 1) as u32;
+        self.submit(false, cmd).map(|_| ())
+    }
+
+    /// Copy between the bounce buffer at `offset` and `buf`
+    fn copy_bounce(&self, offset: usize, len: usize, mut f: impl FnMut(&mut [u8], usize)) {
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let page = &self.bounce[pos / PAGE_SIZE];
+            let n = (PAGE_SIZE - pos % PAGE_SIZE).min(len - done);
+            let data = unsafe { slice::from_raw_parts_mut((page.virt + pos % PAGE_SIZE) as *mut u8, n) };
+            f(data, done);
+            done += n;
+        }
+    }
+}
+
+impl Driver for NVMeDriver {
+    fn try_handle_interrupt(&mut self) -> bool {
+        // we use polling
+        false
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriver for NVMeDriver {
+    fn get_device(&self) -> Box<Device> {
+        Box::new(self.clone())
+    }
+}
+
+impl NVMeDriver {
+    /// Move `len` bytes at `offset` of the namespace, in pieces of at most `max_transfer`
+    fn transfer(&self, offset: usize, len: usize, write: bool, mut f: impl FnMut(&mut [u8], usize)) -> Option<usize> {
+        let size = (self.blocks << self.block_shift) as usize;
+        if offset >= size {
+            return Some(0);
+        }
+        let len = len.min(size - offset);
+        let block_size = 1 << self.block_shift;
+        let mut nvme = self.nvme.lock();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let lba = (pos >> self.block_shift) as u64;
+            let start = pos & (block_size - 1);
+            let n = (nvme.max_transfer - start).min(len - done);
+            let count = (start + n + block_size - 1) >> self.block_shift;
+            let bytes = count << self.block_shift;
+            let partial = start != 0 || n != bytes;
+            if !write || partial {
+                nvme.io(IO_READ, self.nsid, lba, count, bytes).ok()?;
+            }
+            nvme.copy_bounce(start, n, |data, i| f(data, done + i));
+            if write {
+                nvme.io(IO_WRITE, self.nsid, lba, count, bytes).ok()?;
+            }
+            done += n;
+        }
+        if write {
+            let mut cmd = [0u32; 16];
+            cmd[0] = IO_FLUSH as u32;
+            cmd[1] = self.nsid;
+            nvme.submit(false, cmd).ok()?;
+        }
+        Some(len)
+    }
+}
+
+impl Device for NVMeDriver {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        self.transfer(offset, buf.len(), false, |data, i| {
+            let len = data.len();
+            buf[i..i + len].copy_from_slice(data);
+        })
+    }
+
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        self.transfer(offset, buf.len(), true, |data, i| {
+            let len = data.len();
+            data.copy_from_slice(&buf[i..i + len]);
+        })
+    }
+}
+
+/// Init the NVMe controller with registers at physical address `bar`,
+/// and register its namespaces
+fn init(bar: usize) {
+    let regs = map(bar);
+    let cap = reg64(regs + REG_CAP).read();
+    let doorbell_stride = 4 << ((cap >> 32) & 0xf) as usize;
+    // doorbells of the admin and I/O queues
+    let regs_size = DOORBELL_BASE + 4 * doorbell_stride;
+    for offset in (0..regs_size).step_by(PAGE_SIZE) {
+        map(bar + offset);
+    }
+    let max_entries = (cap & 0xffff) as usize + 1;
+    if max_entries < QUEUE_SIZE {
+        warn!("nvme: queues of {} entries are too small", max_entries);
+        return;
+    }
+    let mut nvme = NVMe {
+        regs,
+        doorbell_stride,
+        admin: QueuePair::new(0),
+        io: QueuePair::new(IO_QUEUE_ID),
+        bounce: (0..BOUNCE_PAGES).map(|_| Page::new()).collect(),
+        prp_list: Page::new(),
+        max_transfer: BOUNCE_PAGES * PAGE_SIZE,
+    };
+
+    // reset, then set up the admin queues
+    let cc = reg32(regs + REG_CC);
+    cc.write(cc.read() & !CC_EN);
+    if nvme.wait_ready(false).is_err() {
+        return;
+    }
+    let size = (QUEUE_SIZE - 1) as u32;
+    reg32(regs + REG_AQA).write(size << 16 | size);
+    reg64(regs + REG_ASQ).write(nvme.admin.sq.phys as u64);
+    reg64(regs + REG_ACQ).write(nvme.admin.cq.phys as u64);
+    // 4K pages and the NVM command set
+    cc.write(CC_IO_ENTRY_SIZES | CC_EN);
+    if nvme.wait_ready(true).is_err() {
+        return;
+    }
+
+    let (namespaces, mdts) = match nvme.identify(IDENTIFY_CONTROLLER, 0) {
+        Ok(data) => (u32::from_le_bytes([data[516], data[517], data[518], data[519]]), data[77]),
+        Err(()) => return,
+    };
+    if mdts != 0 {
+        nvme.max_transfer = nvme.max_transfer.min(PAGE_SIZE << mdts);
+    }
+
+    // completion queue first, with interrupts disabled
+    let mut cmd = [0u32; 16];
+    cmd[0] = ADMIN_CREATE_CQ as u32;
+    cmd[6] = nvme.io.cq.phys as u32;
+    cmd[7] = (nvme.io.cq.phys as u64 >> 32) as u32;
+    cmd[10] = size << 16 | IO_QUEUE_ID as u32;
+    cmd[11] = 1; // physically contiguous
+    if nvme.submit(true, cmd).is_err() {
+        return;
+    }
+    let mut cmd = [0u32; 16];
+    cmd[0] = ADMIN_CREATE_SQ as u32;
+    cmd[6] = nvme.io.sq.phys as u32;
+    cmd[7] = (nvme.io.sq.phys as u64 >> 32) as u32;
+    cmd[10] = size << 16 | IO_QUEUE_ID as u32;
+    cmd[11] = (IO_QUEUE_ID as u32) << 16 | 1;
+    if nvme.submit(true, cmd).is_err() {
+        return;
+    }
+
+    let mut found = Vec::new();
+    for nsid in 1..=namespaces {
+        let data = match nvme.identify(IDENTIFY_NAMESPACE, nsid) {
+            Ok(data) => data,
+            Err(()) => continue,
+        };
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&data[0..8]);
+        let blocks = u64::from_le_bytes(size);
+        // the LBA format in use
+        let format = (data[26] & 0xf) as usize;
+        let block_shift = data[128 + format * 4 + 2] as usize;
+        if blocks == 0 || block_shift < 9 || block_shift > 12 {
+            continue;
+        }
+        info!("nvme: namespace {}: {} blocks of {} bytes", nsid, blocks, 1 << block_shift);
+        found.push((nsid, block_shift, blocks));
+    }
+    let nvme = Arc::new(Mutex::new(nvme));
+    for (nsid, block_shift, blocks) in found {
+        let driver = NVMeDriver { nvme: nvme.clone(), nsid, block_shift, blocks };
+        DRIVERS.lock().push(Box::new(driver.clone()));
+        BLK_DRIVERS.lock().push(Box::new(driver));
+    }
+}
+
+/// Class, subclass and programming interface of NVMe controllers
+const CLASS_NVME: u32 = 0x01_08_02;
+
+fn matches(dev: &PciDevice) -> bool {
+    dev.class == CLASS_NVME
+}
+
+fn probe(dev: &PciDevice) {
+    match dev.memory_bar(0) {
+        Some(bar) => {
+            dev.enable();
+            init(bar);
+        }
+        None => warn!("nvme: no registers in BAR0"),
+    }
+}
+
+pub static PCI_DRIVER: PciDriver = PciDriver {
+    name: "nvme",
+    matches,
+    probe,
+};
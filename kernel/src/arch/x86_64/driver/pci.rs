@@ -0,0 +1,57 @@
+//! PCI configuration space access, by ECAM if ACPI describes it or by I/O ports
+
+use alloc::sync::Arc;
+use log::*;
+use rcore_memory::paging::PageTable;
+use x86_64::instructions::port::Port;
+use crate::consts::KERNEL_OFFSET;
+use crate::drivers::bus::pci::{self, ConfigAccess, Ecam, Location};
+use crate::memory::active_table;
+use super::{ahci, e1000, i6300esb, nvme};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Configuration space by I/O ports, only the first 256 bytes of each function
+struct PortIo;
+
+impl PortIo {
+    fn address(loc: Location, offset: u16) -> u32 {
+        1 << 31 | (loc.bus as u32) << 16 | (loc.device as u32) << 11
+            | (loc.function as u32) << 8 | (offset & 0xfc) as u32
+    }
+}
+
+impl ConfigAccess for PortIo {
+    unsafe fn read(&self, loc: Location, offset: u16) -> u32 {
+        Port::new(CONFIG_ADDRESS).write(Self::address(loc, offset));
+        Port::new(CONFIG_DATA).read()
+    }
+
+    unsafe fn write(&self, loc: Location, offset: u16, value: u32) {
+        Port::new(CONFIG_ADDRESS).write(Self::address(loc, offset));
+        Port::new(CONFIG_DATA).write(value);
+    }
+}
+
+/// Map physical address `paddr`, in a BAR or a DMA page, into kernel space
+pub fn map(paddr: usize) -> usize {
+    let vaddr = KERNEL_OFFSET + paddr;
+    active_table().map_if_not_exists(vaddr, paddr);
+    vaddr
+}
+
+/// Register the drivers of PCI devices, and scan all buses
+pub fn init() {
+    pci::register_driver(&ahci::PCI_DRIVER);
+    pci::register_driver(&nvme::PCI_DRIVER);
+    pci::register_driver(&e1000::PCI_DRIVER);
+    pci::register_driver(&i6300esb::PCI_DRIVER);
+    match super::super::acpi::get_info().ecam {
+        Some(base) => {
+            info!("pci: ECAM at {:#x}", base);
+            pci::init(Arc::new(Ecam::new(base, map)));
+        }
+        None => pci::init(Arc::new(PortIo)),
+    }
+}
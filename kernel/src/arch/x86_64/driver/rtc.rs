@@ -0,0 +1,91 @@
+//! Driver of the CMOS real-time clock
+
+use x86_64::instructions::port::Port;
+use spin::Mutex;
+use once::*;
+use log::*;
+use crate::time::{self, DateTime};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATING: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+/// Set in the hour in 12-hour mode
+const HOUR_PM: u8 = 0x80;
+
+/// Serialize accesses to the CMOS index register
+static CMOS: Mutex<()> = Mutex::new(());
+
+fn read_cmos(reg: u8) -> u8 {
+    unsafe {
+        // keep NMI enabled
+        Port::new(CMOS_ADDRESS).write(reg & 0x7f);
+        Port::new(CMOS_DATA).read()
+    }
+}
+
+fn read_registers() -> [u8; 6] {
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATING != 0 {}
+    let mut regs = [0u8; 6];
+    for (value, &reg) in regs.iter_mut().zip([REG_SECOND, REG_MINUTE, REG_HOUR, REG_DAY, REG_MONTH, REG_YEAR].iter()) {
+        *value = read_cmos(reg);
+    }
+    regs
+}
+
+fn from_bcd(value: u8) -> u32 {
+    (value >> 4) as u32 * 10 + (value & 0xf) as u32
+}
+
+/// Read the date and time, as seconds since the Unix epoch
+pub fn read() -> u64 {
+    let _lock = CMOS.lock();
+    // read until two reads agree, so that an update in between is not seen
+    let mut regs = read_registers();
+    loop {
+        let again = read_registers();
+        if again == regs {
+            break;
+        }
+        regs = again;
+    }
+    let status = read_cmos(REG_STATUS_B);
+    let pm = regs[2] & HOUR_PM != 0;
+    regs[2] &= !HOUR_PM;
+    let value = |x: u8| if status & STATUS_B_BINARY != 0 { x as u32 } else { from_bcd(x) };
+    let mut hour = value(regs[2]);
+    if status & STATUS_B_24_HOUR == 0 {
+        // 12 am is 0, 12 pm is 12
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    // the century register is not reliable, assume 20xx
+    let date = DateTime {
+        year: 2000 + value(regs[5]),
+        month: value(regs[4]),
+        day: value(regs[3]),
+        hour,
+        minute: value(regs[1]),
+        second: value(regs[0]),
+    };
+    date.to_unix()
+}
+
+pub fn init() {
+    assert_has_not_been_called!("rtc::init must be called only once");
+    time::register_rtc(read);
+    info!("rtc: {:?}", time::now());
+}
@@ -1,12 +1,26 @@
-// Copy from Redox
+//! Driver of 16550 UARTs
+//!
+//! COM1 is the console: kernel logs are written to it, and its input goes to stdin.
+//! Both ports are also character devices `/dev/ttyS0` and `/dev/ttyS1`.
 
+use alloc::{string::String, sync::Arc};
+use core::any::Any;
+use core::fmt;
 use x86_64::instructions::port::Port;
-use spin::Mutex;
-use uart_16550::SerialPort;
 use once::*;
+use lazy_static::lazy_static;
+use simple_filesystem::*;
+use crate::fs::{register_device, Stdin, STDIN};
+use crate::sync::SpinNoIrqLock as Mutex;
 
-pub static COM1: Mutex<SerialPort> = Mutex::new(SerialPort::new(0x3F8));
-pub static COM2: Mutex<SerialPort> = Mutex::new(SerialPort::new(0x2F8));
+lazy_static! {
+    /// The ports are also locked by their interrupt handlers,
+    /// so interrupts are disabled while they are locked.
+    pub static ref COM1: Mutex<SerialPort> = Mutex::new(SerialPort::new(0x3F8));
+    pub static ref COM2: Mutex<SerialPort> = Mutex::new(SerialPort::new(0x2F8));
+    /// Buffer of input from COM2, COM1 shares the one of stdin
+    static ref COM2_INPUT: Arc<Stdin> = Arc::new(Stdin::default());
+}
 
 pub fn init() {
     assert_has_not_been_called!("serial::init must be called only once");
@@ -16,19 +30,121 @@ pub fn init() {
     use crate::arch::interrupt::{enable_irq, consts::{IRQ_COM1, IRQ_COM2}};
     enable_irq(IRQ_COM1);
     enable_irq(IRQ_COM2);
+
+    register_device("ttyS0", Arc::new(SerialINode { port: &*COM1, input: STDIN.clone() }));
+    register_device("ttyS1", Arc::new(SerialINode { port: &*COM2, input: COM2_INPUT.clone() }));
 }
 
-pub trait SerialRead {
-    fn receive(&mut self) -> u8;
+/// Buffer all received bytes of COM1, called on its interrupt
+pub fn com1_interrupt() {
+    while let Some(c) = COM1.lock().try_receive() {
+        crate::trap::serial(c as char);
+    }
 }
 
-impl SerialRead for SerialPort {
-    fn receive(&mut self) -> u8 {
-        unsafe {
-            let ports = self as *mut _ as *mut [Port<u8>; 6];
-            let _line_sts = &(*ports)[5];
-            let data = &(*ports)[0];
-            data.read()
+/// Buffer all received bytes of COM2, called on its interrupt
+pub fn com2_interrupt() {
+    while let Some(c) = COM2.lock().try_receive() {
+        COM2_INPUT.push(c as char);
+    }
+}
+
+const UART_DATA: u16 = 0;
+/// Divisor latch, when DLAB is set
+const UART_DLL: u16 = 0;
+const UART_DLM: u16 = 1;
+const UART_IER: u16 = 1;
+const UART_FCR: u16 = 2;
+const UART_LCR: u16 = 3;
+const UART_MCR: u16 = 4;
+const UART_LSR: u16 = 5;
+
+const IER_RX_AVAILABLE: u8 = 0x01;
+/// Enable and clear FIFOs, interrupt at 14 bytes
+const FCR_ENABLE: u8 = 0xc7;
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+/// DTR, RTS and OUT2, which gates the interrupt line
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+
+/// 115200 baud
+const DIVISOR: u16 = 1;
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        SerialPort { base }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        unsafe { Port::new(self.base + reg).read() }
+    }
+
+    fn write(&self, reg: u16, value: u8) {
+        unsafe { Port::new(self.base + reg).write(value) }
+    }
+
+    /// Set 8N1 at 115200 baud, and interrupt on received data
+    pub fn init(&mut self) {
+        self.write(UART_IER, 0);
+        self.write(UART_LCR, LCR_DLAB);
+        self.write(UART_DLL, DIVISOR as u8);
+        self.write(UART_DLM, (DIVISOR >> 8) as u8);
+        self.write(UART_LCR, LCR_8N1);
+        self.write(UART_FCR, FCR_ENABLE);
+        self.write(UART_MCR, MCR_DTR_RTS_OUT2);
+        self.write(UART_IER, IER_RX_AVAILABLE);
+    }
+
+    pub fn putchar(&mut self, c: u8) {
+        while self.read(UART_LSR) & LSR_THR_EMPTY == 0 {}
+        self.write(UART_DATA, c);
+    }
+
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.read(UART_LSR) & LSR_DATA_READY != 0 {
+            Some(self.read(UART_DATA))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            self.putchar(c);
+        }
+        Ok(())
+    }
+}
+
+/// A serial port as a character device
+struct SerialINode {
+    port: &'static Mutex<SerialPort>,
+    /// Bytes received by the interrupt handler
+    input: Arc<Stdin>,
+}
+
+impl INode for SerialINode {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = self.input.pop() as u8;
+        Ok(1)
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut port = self.port.lock();
+        for &c in buf {
+            port.putchar(c);
         }
+        Ok(buf.len())
     }
+    impl_inode!();
 }
@@ -1,10 +1,8 @@
-use core::fmt;
-use spin::Mutex;
 use volatile::Volatile;
-use lazy_static::lazy_static;
 use x86_64::instructions::port::Port;
 use crate::logging::Color;
 use crate::consts::KERNEL_OFFSET;
+use crate::drivers::gpu::console::TextScreen;
 
 #[derive(Debug, Clone, Copy)]
 struct ColorCode(u8);
@@ -66,71 +64,40 @@ impl VgaBuffer {
     }
 }
 
-lazy_static! {
-	pub static ref VGA_WRITER: Mutex<VgaWriter> = Mutex::new(
-		VgaWriter::new(unsafe{ &mut *((KERNEL_OFFSET + 0xb8000) as *mut VgaBuffer) })
-	);
-}
-
-pub struct VgaWriter {
-    column_position: usize,
-    color: Color,
+/// The VGA text mode screen, as a console screen
+pub struct VgaScreen {
     buffer: &'static mut VgaBuffer,
 }
 
-impl VgaWriter {
-    fn new(buffer: &'static mut VgaBuffer) -> Self {
-        buffer.clear();
-        VgaWriter {
-            column_position: 0,
-            color: Color::LightGray,
-            buffer,
-        }
+impl VgaScreen {
+    pub fn new() -> Self {
+        VgaScreen { buffer: unsafe { &mut *((KERNEL_OFFSET + 0xb8000) as *mut VgaBuffer) } }
     }
+}
 
-    pub fn set_color(&mut self, color: Color) {
-        self.color = color;
+impl TextScreen for VgaScreen {
+    fn size(&self) -> (usize, usize) {
+        (BUFFER_HEIGHT, BUFFER_WIDTH)
     }
 
-    pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
-
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
-
-                self.buffer.write(row, col, ScreenChar::new(byte, self.color, Color::Black));
-                self.column_position += 1;
-                self.buffer.set_cursor_at(row, col);
-            }
-        }
+    fn put(&mut self, row: usize, col: usize, c: u8) {
+        self.buffer.write(row, col, ScreenChar::new(c, Color::LightGray, Color::Black));
     }
 
-    fn new_line(&mut self) {
+    fn scroll_up(&mut self) {
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let screen_char = self.buffer.read(row, col);
                 self.buffer.write(row - 1, col, screen_char);
             }
         }
-        let blank = ScreenChar::new(b' ', self.color, Color::Black);
+        let blank = ScreenChar::new(b' ', Color::LightGray, Color::Black);
         for col in 0..BUFFER_WIDTH {
             self.buffer.write(BUFFER_HEIGHT - 1, col, blank);
         }
-        self.column_position = 0;
-        self.buffer.set_cursor_at(BUFFER_HEIGHT - 1, 0);
     }
-}
 
-impl fmt::Write for VgaWriter {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for byte in s.bytes() {
-            self.write_byte(byte)
-        }
-        Ok(())
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        self.buffer.set_cursor_at(row, col);
     }
-}
\ No newline at end of file
+}
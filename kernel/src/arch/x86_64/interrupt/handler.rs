@@ -82,13 +82,14 @@ pub extern fn rust_trap(tf: &mut TrapFrame) {
         T_IRQ0...63 => {
             let irq = tf.trap_num as u8 - T_IRQ0;
             super::ack(irq); // must ack before switching
+            crate::rand::add_interrupt_entropy(irq as usize);
             match irq {
                 IRQ_TIMER => crate::trap::timer(),
                 IRQ_KBD => keyboard(),
                 IRQ_COM1 => com1(),
                 IRQ_COM2 => com2(),
                 IRQ_IDE => ide(),
-                _ => panic!("Invalid IRQ number: {}", irq),
+                _ => pci(irq),
             }
         }
         T_SWITCH_TOK => to_kernel(tf),
@@ -129,21 +130,31 @@ fn keyboard() {
 }
 
 fn com1() {
-    use crate::arch::driver::serial::*;
+    use crate::arch::driver::serial;
     trace!("\nInterupt: COM1");
-    crate::trap::serial(COM1.lock().receive() as char);
+    serial::com1_interrupt();
 }
 
 fn com2() {
-    use crate::arch::driver::serial::*;
+    use crate::arch::driver::serial;
     trace!("\nInterupt: COM2");
-    COM2.lock().receive();
+    serial::com2_interrupt();
 }
 
 fn ide() {
     trace!("\nInterupt: IDE");
 }
 
+/// Interrupts of PCI devices are handled by their drivers
+fn pci(irq: u8) {
+    trace!("\nInterupt: IRQ {}", irq);
+    let mut drivers = crate::drivers::DRIVERS.lock();
+    if !drivers.iter_mut().any(|driver| driver.try_handle_interrupt()) {
+        // shared or spurious interrupts may be raised by no driver
+        warn!("unhandled IRQ {}", irq);
+    }
+}
+
 fn to_user(tf: &mut TrapFrame) {
     use crate::arch::gdt;
     info!("\nInterupt: To User");
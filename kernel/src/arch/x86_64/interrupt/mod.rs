@@ -36,14 +36,31 @@ pub fn no_interrupt(f: impl FnOnce()) {
     unsafe { restore(flags) };
 }
 
+/// Virtual address of the local APIC, where ACPI tells or the default
+pub fn local_apic() -> usize {
+    match super::acpi::get_info().local_apic {
+        0 => KERNEL_OFFSET + LAPIC_ADDR,
+        paddr => KERNEL_OFFSET + paddr,
+    }
+}
+
+/// Virtual address of the IOAPIC of the first interrupts, where ACPI tells or the default
+fn ioapic() -> usize {
+    let ioapics = &super::acpi::get_info().ioapics;
+    match ioapics.iter().find(|&&(_, _, gsi_base)| gsi_base == 0) {
+        Some(&(_, paddr, _)) => KERNEL_OFFSET + paddr,
+        None => KERNEL_OFFSET + IOAPIC_ADDR as usize,
+    }
+}
+
 #[inline(always)]
 pub fn enable_irq(irq: u8) {
-    let mut ioapic = unsafe { IoApic::new(KERNEL_OFFSET + IOAPIC_ADDR as usize) };
+    let mut ioapic = unsafe { IoApic::new(ioapic()) };
     ioapic.enable(irq, 0);
 }
 
 #[inline(always)]
 pub fn ack(_irq: u8) {
-    let mut lapic = unsafe { XApic::new(KERNEL_OFFSET + LAPIC_ADDR) };
+    let mut lapic = unsafe { XApic::new(local_apic()) };
     lapic.eoi();
 }
\ No newline at end of file
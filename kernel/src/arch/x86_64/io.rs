@@ -2,11 +2,15 @@ use super::driver::serial::*;
 use core::fmt::{Arguments, Write};
 
 pub fn getchar() -> char {
-    unsafe { COM1.force_unlock(); }
-    COM1.lock().receive() as char
+    // unlock between polls, so that interrupts are not disabled while waiting
+    loop {
+        if let Some(c) = COM1.lock().try_receive() {
+            return c as char;
+        }
+    }
 }
 
 pub fn putfmt(fmt: Arguments) {
-    unsafe { COM1.force_unlock(); }
-    COM1.lock().write_fmt(fmt).unwrap()
+    COM1.lock().write_fmt(fmt).unwrap();
+    crate::drivers::gpu::console::putfmt(fmt);
 }
\ No newline at end of file
@@ -11,6 +11,7 @@ pub mod idt;
 pub mod memory;
 pub mod io;
 pub mod consts;
+pub mod acpi;
 
 static AP_CAN_INIT: AtomicBool = ATOMIC_BOOL_INIT;
 
@@ -36,6 +37,9 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     // Init physical memory management and heap.
     memory::init(boot_info);
 
+    // Find the CPUs and devices
+    acpi::init();
+
     // Now heap is available
     gdt::init();
 
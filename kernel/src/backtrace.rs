@@ -7,9 +7,12 @@ extern "C" {
 
 /// Returns the current frame pointer.
 #[inline(always)]
-#[cfg(any(target_arch = "aarch64", target_arch = "riscv32", target_arch = "riscv64"))]
 pub fn fp() -> usize {
     let ptr: usize;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        asm!("mov %rbp, $0" : "=r"(ptr));
+    }
     #[cfg(target_arch = "aarch64")]
     unsafe {
         asm!("mov $0, x29" : "=r"(ptr));
@@ -23,10 +26,15 @@ pub fn fp() -> usize {
 }
 
 /// Returns the current link register.
+///
+/// On x86_64 there is no link register, return the return address of the current frame instead.
 #[inline(always)]
-#[cfg(any(target_arch = "aarch64", target_arch = "riscv32", target_arch = "riscv64"))]
 pub fn lr() -> usize {
     let ptr: usize;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        ptr = *(fp() as *const usize).offset(1);
+    }
     #[cfg(target_arch = "aarch64")]
     unsafe {
         asm!("mov $0, x30" : "=r"(ptr));
@@ -41,7 +49,6 @@ pub fn lr() -> usize {
 
 // Print the backtrace starting from the caller
 pub fn backtrace() {
-    #[cfg(any(target_arch = "aarch64", target_arch = "riscv32", target_arch = "riscv64"))]
     unsafe {
         let mut current_pc = lr();
         let mut current_fp = fp();
@@ -54,7 +61,7 @@ pub fn backtrace() {
                 current_fp = *(current_fp as *const usize).offset(-2);
                 current_pc = *(current_fp as *const usize).offset(-1);
             }
-            #[cfg(target_arch = "aarch64")]
+            #[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
             {
                 current_fp = *(current_fp as *const usize);
                 if current_fp != 0 {
@@ -0,0 +1,55 @@
+//! Kernel command line, e.g. `sched=cfs root=raid1:blk0,blk1`
+//!
+//! It is given by the boot loader where the architecture has a way to pass it
+//! (`bootargs` of the device tree), otherwise it is environment variable `CMDLINE`
+//! at compile time, set by `make cmdline=...`.
+
+use alloc::string::String;
+use spin::Once;
+
+static CMDLINE: Once<String> = Once::new();
+
+/// Use the command line from the boot loader, must be called before the first `get`
+pub fn init(cmdline: &str) {
+    CMDLINE.call_once(|| String::from(cmdline));
+}
+
+fn cmdline() -> &'static str {
+    CMDLINE.call_once(|| String::from(option_env!("CMDLINE").unwrap_or(""))).as_str()
+}
+
+/// Value of option `key`, "" if it is given without a value
+pub fn get(key: &str) -> Option<&'static str> {
+    find(cmdline(), key)
+}
+
+/// The last `key` or `key=value` in `cmdline` wins
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline.split_whitespace()
+        .filter_map(|option| {
+            let mut iter = option.splitn(2, '=');
+            match iter.next() {
+                Some(k) if k == key => Some(iter.next().unwrap_or("")),
+                _ => None,
+            }
+        })
+        .last()
+}
+
+pub mod test {
+    use super::*;
+
+    fn values() {
+        let cmdline = "sched=cfs  root=raid1:blk0,blk1 quiet sched=stride";
+        assert_eq!(find(cmdline, "sched"), Some("stride"));
+        assert_eq!(find(cmdline, "root"), Some("raid1:blk0,blk1"));
+        assert_eq!(find(cmdline, "quiet"), Some(""));
+        assert_eq!(find(cmdline, "roo"), None);
+        assert_eq!(find("", "sched"), None);
+        assert_eq!(find("root=PARTUUID=0f1e", "root"), Some("PARTUUID=0f1e"));
+    }
+
+    pub fn test_all() {
+        values();
+    }
+}
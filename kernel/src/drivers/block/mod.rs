@@ -1 +1,2 @@
-pub mod virtio_blk;
\ No newline at end of file
+pub mod virtio_blk;
+pub mod sdcard;
\ No newline at end of file
@@ -0,0 +1,335 @@
+//! SD card driver in SPI mode, moving one block per command
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use log::*;
+use simple_filesystem::{BlockedDevice, Device};
+
+use crate::sync::SpinNoIrqLock as Mutex;
+
+use super::super::{BlockDriver, DeviceType, Driver, BLK_DRIVERS, DRIVERS};
+use super::super::bus::spi::SpiBus;
+
+pub const BLOCK_SIZE: usize = 512;
+
+const INIT_CLOCK: u32 = 400_000;
+const CLOCK: u32 = 20_000_000;
+
+const CMD_GO_IDLE_STATE: u8 = 0;
+const CMD_SEND_IF_COND: u8 = 8;
+const CMD_SEND_CSD: u8 = 9;
+const CMD_SET_BLOCKLEN: u8 = 16;
+const CMD_READ_SINGLE_BLOCK: u8 = 17;
+const CMD_WRITE_BLOCK: u8 = 24;
+const CMD_APP_CMD: u8 = 55;
+const CMD_READ_OCR: u8 = 58;
+const CMD_CRC_ON_OFF: u8 = 59;
+const ACMD_SD_SEND_OP_COND: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const DATA_TOKEN: u8 = 0xfe;
+const DATA_ACCEPTED: u8 = 0x05;
+/// Host supports high capacity cards, in ACMD41
+const HCS: u32 = 1 << 30;
+/// Card capacity status in OCR, set for block addressed cards
+const OCR_CCS: u32 = 1 << 30;
+
+/// Bytes to poll for a response or a data token before giving up
+const TIMEOUT: usize = 100_000;
+/// Times to retry a block transfer with a CRC error
+const RETRIES: usize = 3;
+
+/// CRC7 of commands
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1 ^ (crc >> 6) & 1;
+            crc = (crc << 1) & 0x7f;
+            if bit != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// CRC16-CCITT of data blocks
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// An SD card on a SPI bus
+pub struct SDCard {
+    bus: Box<SpiBus>,
+    /// Addressed by block instead of byte
+    block_addressed: bool,
+    /// Number of blocks
+    blocks: usize,
+}
+
+impl SDCard {
+    /// Init the card on `bus`, None if there is no usable card
+    pub fn new(bus: Box<SpiBus>) -> Option<Self> {
+        let mut card = SDCard { bus, block_addressed: false, blocks: 0 };
+        card.init()?;
+        Some(card)
+    }
+
+    fn init(&mut self) -> Option<()> {
+        self.bus.set_clock(INIT_CLOCK);
+        // at least 74 clocks with the card deselected to enter native mode
+        self.bus.select(false);
+        for _ in 0..10 {
+            self.bus.transfer(0xff);
+        }
+
+        // enter SPI mode
+        if (0..10).all(|_| self.command(CMD_GO_IDLE_STATE, 0) != R1_IDLE) {
+            warn!("sdcard: no card");
+            return None;
+        }
+        let mut r7 = [0u8; 4];
+        let r1 = self.command_r(CMD_SEND_IF_COND, 0x1aa, &mut r7);
+        // version 1 cards don't know CMD8
+        let v2 = r1 & R1_ILLEGAL_COMMAND == 0;
+        if v2 && r7[3] != 0xaa {
+            warn!("sdcard: bad CMD8 response {:x?}", r7);
+            return None;
+        }
+        self.command(CMD_CRC_ON_OFF, 1);
+
+        let arg = if v2 { HCS } else { 0 };
+        let mut ready = false;
+        for _ in 0..TIMEOUT {
+            self.command(CMD_APP_CMD, 0);
+            if self.command(ACMD_SD_SEND_OP_COND, arg) == 0 {
+                ready = true;
+                break;
+            }
+        }
+        if !ready {
+            warn!("sdcard: card does not leave idle state");
+            return None;
+        }
+
+        if v2 {
+            let mut ocr = [0u8; 4];
+            if self.command_r(CMD_READ_OCR, 0, &mut ocr) != 0 {
+                return None;
+            }
+            self.block_addressed = u32::from_be_bytes(ocr) & OCR_CCS != 0;
+        }
+        if !self.block_addressed && self.command(CMD_SET_BLOCKLEN, BLOCK_SIZE as u32) != 0 {
+            return None;
+        }
+
+        let mut csd = [0u8; 16];
+        if self.command(CMD_SEND_CSD, 0) != 0 || !self.read_data(&mut csd) {
+            warn!("sdcard: failed to read CSD");
+            return None;
+        }
+        self.blocks = match csd[0] >> 6 {
+            // CSD version 1: (C_SIZE + 1) << (C_SIZE_MULT + 2) blocks of 1 << READ_BL_LEN bytes
+            0 => {
+                let read_bl_len = (csd[5] & 0xf) as usize;
+                let c_size = ((csd[6] & 0x3) as usize) << 10 | (csd[7] as usize) << 2 | (csd[8] >> 6) as usize;
+                let c_size_mult = ((csd[9] & 0x3) << 1 | csd[10] >> 7) as usize;
+                ((c_size + 1) << (c_size_mult + 2 + read_bl_len)) / BLOCK_SIZE
+            }
+            // CSD version 2: (C_SIZE + 1) * 512 KiB
+            _ => {
+                let c_size = ((csd[7] & 0x3f) as usize) << 16 | (csd[8] as usize) << 8 | csd[9] as usize;
+                (c_size + 1) * 1024
+            }
+        };
+        self.bus.set_clock(CLOCK);
+        Some(())
+    }
+
+    /// Send a command, return R1
+    fn command(&mut self, cmd: u8, arg: u32) -> u8 {
+        self.command_r(cmd, arg, &mut [])
+    }
+
+    /// Send a command, return R1 and put the rest of the response in `extra`.
+    /// The card is still selected after commands followed by data, i.e. when R1 is 0.
+    fn command_r(&mut self, cmd: u8, arg: u32, extra: &mut [u8]) -> u8 {
+        self.bus.select(true);
+        self.bus.transfer(0xff);
+        let mut frame = [0x40 | cmd, 0, 0, 0, 0, 0];
+        frame[1..5].copy_from_slice(&arg.to_be_bytes());
+        frame[5] = crc7(&frame[..5]) << 1 | 1;
+        for &byte in frame.iter() {
+            self.bus.transfer(byte);
+        }
+        let mut r1 = 0xff;
+        for _ in 0..10 {
+            r1 = self.bus.transfer(0xff);
+            if r1 & 0x80 == 0 {
+                break;
+            }
+        }
+        for byte in extra.iter_mut() {
+            *byte = self.bus.transfer(0xff);
+        }
+        if r1 != 0 || (cmd != CMD_READ_SINGLE_BLOCK && cmd != CMD_WRITE_BLOCK && cmd != CMD_SEND_CSD) {
+            self.deselect();
+        }
+        r1
+    }
+
+    fn deselect(&mut self) {
+        self.bus.select(false);
+        self.bus.transfer(0xff);
+    }
+
+    /// Receive a data block after a command, and check its CRC
+    fn read_data(&mut self, buf: &mut [u8]) -> bool {
+        let mut token = 0xff;
+        for _ in 0..TIMEOUT {
+            token = self.bus.transfer(0xff);
+            if token != 0xff {
+                break;
+            }
+        }
+        if token != DATA_TOKEN {
+            warn!("sdcard: read error token {:#x}", token);
+            self.deselect();
+            return false;
+        }
+        for byte in buf.iter_mut() {
+            *byte = self.bus.transfer(0xff);
+        }
+        let crc = (self.bus.transfer(0xff) as u16) << 8 | self.bus.transfer(0xff) as u16;
+        self.deselect();
+        if crc != crc16(buf) {
+            warn!("sdcard: data CRC error");
+            return false;
+        }
+        true
+    }
+
+    /// Send a data block after a command, and wait for it to be written
+    fn write_data(&mut self, buf: &[u8]) -> bool {
+        self.bus.transfer(DATA_TOKEN);
+        for &byte in buf {
+            self.bus.transfer(byte);
+        }
+        let crc = crc16(buf);
+        self.bus.transfer((crc >> 8) as u8);
+        self.bus.transfer(crc as u8);
+        let response = self.bus.transfer(0xff) & 0x1f;
+        if response != DATA_ACCEPTED {
+            warn!("sdcard: write rejected with {:#x}", response);
+            self.deselect();
+            return false;
+        }
+        // busy while it reads 0
+        let mut done = false;
+        for _ in 0..TIMEOUT * 10 {
+            if self.bus.transfer(0xff) != 0 {
+                done = true;
+                break;
+            }
+        }
+        self.deselect();
+        done
+    }
+
+    fn address(&self, block_id: usize) -> u32 {
+        if self.block_addressed { block_id as u32 } else { (block_id * BLOCK_SIZE) as u32 }
+    }
+
+    pub fn read_block(&mut self, block_id: usize, buf: &mut [u8]) -> bool {
+        if buf.len() != BLOCK_SIZE || block_id >= self.blocks {
+            return false;
+        }
+        let addr = self.address(block_id);
+        (0..RETRIES).any(|_| self.command(CMD_READ_SINGLE_BLOCK, addr) == 0 && self.read_data(buf))
+    }
+
+    pub fn write_block(&mut self, block_id: usize, buf: &[u8]) -> bool {
+        if buf.len() != BLOCK_SIZE || block_id >= self.blocks {
+            return false;
+        }
+        let addr = self.address(block_id);
+        (0..RETRIES).any(|_| self.command(CMD_WRITE_BLOCK, addr) == 0 && self.write_data(buf))
+    }
+
+    /// Number of blocks
+    pub fn blocks(&self) -> usize {
+        self.blocks
+    }
+}
+
+#[derive(Clone)]
+pub struct SDCardDriver(Arc<Mutex<SDCard>>);
+
+impl Driver for SDCardDriver {
+    fn try_handle_interrupt(&mut self) -> bool {
+        // we use polling
+        false
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+}
+
+impl BlockDriver for SDCardDriver {
+    fn get_device(&self) -> Box<Device> {
+        Box::new(self.clone())
+    }
+}
+
+impl BlockedDevice for SDCardDriver {
+    const BLOCK_SIZE_LOG2: u8 = 9; // 512
+    fn read_at(&mut self, block_id: usize, buf: &mut [u8]) -> bool {
+        let mut card = self.0.lock();
+        if buf.len() >= BLOCK_SIZE {
+            return card.read_block(block_id, &mut buf[..BLOCK_SIZE]);
+        }
+        let mut block = [0u8; BLOCK_SIZE];
+        if !card.read_block(block_id, &mut block) {
+            return false;
+        }
+        let len = buf.len();
+        buf.copy_from_slice(&block[..len]);
+        true
+    }
+
+    fn write_at(&mut self, block_id: usize, buf: &[u8]) -> bool {
+        let mut card = self.0.lock();
+        if buf.len() >= BLOCK_SIZE {
+            return card.write_block(block_id, &buf[..BLOCK_SIZE]);
+        }
+        // a partial block: read, modify and write the whole block
+        let mut block = [0u8; BLOCK_SIZE];
+        if !card.read_block(block_id, &mut block) {
+            return false;
+        }
+        block[..buf.len()].copy_from_slice(buf);
+        card.write_block(block_id, &block)
+    }
+}
+
+/// Init the SD card on `bus` and register it
+pub fn sdcard_init(bus: Box<SpiBus>) {
+    let card = match SDCard::new(bus) {
+        Some(card) => card,
+        None => return,
+    };
+    info!("Found an SD card of size {}KB", card.blocks() / 2);
+    let driver = SDCardDriver(Arc::new(Mutex::new(card)));
+    DRIVERS.lock().push(Box::new(driver.clone()));
+    BLK_DRIVERS.lock().push(Box::new(driver));
+}
@@ -1,9 +1,11 @@
 use alloc::prelude::*;
 use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
 use alloc::vec;
 use core::cmp::min;
 use core::fmt;
 use core::mem::{size_of, zeroed};
+use core::ptr::read_volatile;
 use core::slice;
 
 use bitflags::*;
@@ -14,13 +16,13 @@ use rcore_memory::PAGE_SIZE;
 use rcore_memory::paging::PageTable;
 use volatile::Volatile;
 
-use simple_filesystem::BlockedDevice;
+use simple_filesystem::{BlockedDevice, Device};
 
 use crate::arch::cpu;
 use crate::memory::active_table;
 use crate::sync::SpinNoIrqLock as Mutex;
 
-use super::super::{DeviceType, Driver, DRIVERS};
+use super::super::{AsyncBlockDriver, BlockCompletion, BlockDriver, DeviceType, Driver, BLK_DRIVERS, DRIVERS};
 use super::super::bus::virtio_mmio::*;
 
 pub struct VirtIOBlk {
@@ -28,9 +30,26 @@ pub struct VirtIOBlk {
     interrupt: u32,
     header: usize,
     queue: VirtIOVirtqueue,
-    capacity: usize
+    capacity: usize,
+    /// The device rejects writes
+    read_only: bool,
+    /// Asynchronous requests in flight, indexed by token
+    pending: BTreeMap<usize, PendingRequest>,
+    next_token: usize,
 }
 
+/// An asynchronous request submitted to the device.
+/// The header and response must live until the device finishes.
+struct PendingRequest {
+    _req: Box<VirtIOBlkReq>,
+    resp: Box<VirtIOBlkResp>,
+    buf: &'static mut [u8],
+    done: Box<BlockCompletion>,
+}
+
+/// Token of synchronous requests
+const SYNC_TOKEN: usize = 0;
+
 #[derive(Clone)]
 pub struct VirtIOBlkDriver(Arc<Mutex<VirtIOBlk>>);
 
@@ -105,6 +124,9 @@ impl Driver for VirtIOBlkDriver {
         if interrupt != 0 {
             header.interrupt_ack.write(interrupt);
             debug!("Got interrupt {:?}", interrupt);
+            while let Some((_, _, _, token)) = driver.queue.get() {
+                driver.complete(token);
+            }
             return true;
         }
         return false;
@@ -115,33 +137,170 @@ impl Driver for VirtIOBlkDriver {
     }
 }
 
-impl BlockedDevice for VirtIOBlkDriver {
-    const BLOCK_SIZE_LOG2: u8 = 9; // 512
-    fn read_at(&mut self, block_id: usize, buf: &mut [u8]) -> bool {
+impl VirtIOBlk {
+    /// Finish the asynchronous request `token`
+    fn complete(&mut self, token: usize) {
+        if let Some(req) = self.pending.remove(&token) {
+            // the device writes the response behind the compiler's back
+            let status = unsafe { read_volatile(&req.resp.status) };
+            if status == VIRTIO_BLK_S_OK {
+                req.buf[..VIRTIO_BLK_BLK_SIZE].copy_from_slice(&req.resp.data);
+            }
+            req.done.complete(status == VIRTIO_BLK_S_OK);
+        }
+    }
+
+    /// Finish the asynchronous requests completed by the device
+    fn poll(&mut self) {
+        while let Some((_, _, _, token)) = self.queue.get() {
+            self.complete(token);
+        }
+    }
+
+    /// Add a synchronous request, waiting for room in the queue if it is full
+    fn add_sync(&mut self, input: &[(usize, usize)], output: &[(usize, usize)]) {
+        while !self.queue.add_segments(input, output, SYNC_TOKEN) {
+            self.poll();
+        }
+        self.queue.notify();
+    }
+
+    /// Wait for the synchronous request,
+    /// finishing asynchronous ones completed in the meantime
+    fn wait_sync(&mut self) {
+        loop {
+            let (_, _, _, token) = self.queue.get_block();
+            if token == SYNC_TOKEN {
+                return;
+            }
+            self.complete(token);
+        }
+    }
+}
+
+// Completions are normally found by the interrupt handler, which needs an
+// interrupt controller routing the interrupts of the device, e.g. the PLIC.
+// Without one, they are found by `poll` or when the next request is submitted.
+impl AsyncBlockDriver for VirtIOBlkDriver {
+    fn read_block_async(&self, block_id: usize, buf: &'static mut [u8], done: Box<BlockCompletion>) -> bool {
+        if buf.len() < VIRTIO_BLK_BLK_SIZE {
+            return false;
+        }
+        let mut driver = self.0.lock();
+        // ensure header page is mapped
+        active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
+
+        let req = Box::new(VirtIOBlkReq {
+            req_type: VIRTIO_BLK_T_IN,
+            reserved: 0,
+            sector: block_id as u64,
+        });
+        // `buf` may be user memory, which the device can't address,
+        // so the data goes through a bounce buffer
+        let mut resp: Box<VirtIOBlkResp> = Box::new(unsafe { zeroed() });
+        resp.status = VIRTIO_BLK_S_IOERR;
+        let output = unsafe { slice::from_raw_parts(&*req as *const VirtIOBlkReq as *const u8, size_of::<VirtIOBlkReq>()) };
+        let input = unsafe { slice::from_raw_parts(&*resp as *const VirtIOBlkResp as *const u8, size_of::<VirtIOBlkResp>()) };
+        driver.poll();
+        let token = driver.next_token;
+        if !driver.queue.add_and_notify(&[input], &[output], token) {
+            return false;
+        }
+        driver.next_token = if token == usize::max_value() { SYNC_TOKEN + 1 } else { token + 1 };
+        driver.pending.insert(token, PendingRequest { _req: req, resp, buf, done });
+        true
+    }
+
+    fn poll(&self) {
         let mut driver = self.0.lock();
         // ensure header page is mapped
         active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
+        driver.poll();
+    }
+}
 
-        let mut req = VirtIOBlkReq::default();
-        req.req_type = VIRTIO_BLK_T_IN;
-        req.reserved = 0;
-        req.sector = block_id as u64;
-        let input = [0; size_of::<VirtIOBlkResp>()];
-        let output = unsafe { slice::from_raw_parts(&req as *const VirtIOBlkReq as *const u8, size_of::<VirtIOBlkReq>()) };
-        driver.queue.add_and_notify(&[&input], &[output], 0);
-        driver.queue.get_block();
-        let resp = unsafe { &*(&input as *const u8 as *const VirtIOBlkResp) };
-        if resp.status == VIRTIO_BLK_S_OK {
-            let len = min(buf.len(), VIRTIO_BLK_BLK_SIZE);
-            buf[..len].clone_from_slice(&resp.data[..len]);
-            true
+impl BlockDriver for VirtIOBlkDriver {
+    fn get_device(&self) -> Box<Device> {
+        Box::new(self.clone())
+    }
+
+    fn get_async(&self) -> Option<Box<AsyncBlockDriver>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+impl VirtIOBlkDriver {
+    /// Read or write block `block_id` synchronously, from or to the block at `data`,
+    /// which the device accesses directly, wherever it is mapped.
+    /// None if the device can't access a page of `data`, Some(false) if the request fails.
+    fn request(&self, req_type: u32, block_id: usize, data: usize) -> Option<bool> {
+        let data = phys_segments(data, VIRTIO_BLK_BLK_SIZE, req_type == VIRTIO_BLK_T_IN)?;
+        let req = VirtIOBlkReq {
+            req_type,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let mut status = VIRTIO_BLK_S_IOERR;
+        // on the kernel stack, which is always mapped
+        let header = (virt_to_phys(&req as *const VirtIOBlkReq as usize, false)?, size_of::<VirtIOBlkReq>());
+        let status_segment = (virt_to_phys(&mut status as *mut u8 as usize, true)?, 1);
+
+        let mut driver = self.0.lock();
+        if block_id >= driver.capacity || (req_type == VIRTIO_BLK_T_OUT && driver.read_only) {
+            return Some(false);
+        }
+        // ensure header page is mapped
+        active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
+
+        if req_type == VIRTIO_BLK_T_IN {
+            let mut input = data;
+            input.push(status_segment);
+            driver.add_sync(&input, &[header]);
         } else {
-            false
+            let mut output = vec![header];
+            output.extend(data);
+            driver.add_sync(&[status_segment], &output);
+        }
+        driver.wait_sync();
+        // the device writes `status` behind the compiler's back
+        Some(unsafe { read_volatile(&status) } == VIRTIO_BLK_S_OK)
+    }
+}
+
+impl BlockedDevice for VirtIOBlkDriver {
+    const BLOCK_SIZE_LOG2: u8 = 9; // 512
+    fn read_at(&mut self, block_id: usize, buf: &mut [u8]) -> bool {
+        if buf.len() >= VIRTIO_BLK_BLK_SIZE {
+            if let Some(ok) = self.request(VIRTIO_BLK_T_IN, block_id, buf.as_mut_ptr() as usize) {
+                return ok;
+            }
         }
+        // a partial block, or pages the device can't write, e.g. not mapped yet or
+        // shared by copy on write, go through a bounce buffer
+        let mut block = [0u8; VIRTIO_BLK_BLK_SIZE];
+        if self.request(VIRTIO_BLK_T_IN, block_id, block.as_mut_ptr() as usize) != Some(true) {
+            return false;
+        }
+        let len = min(buf.len(), VIRTIO_BLK_BLK_SIZE);
+        buf[..len].copy_from_slice(&block[..len]);
+        true
     }
 
     fn write_at(&mut self, block_id: usize, buf: &[u8]) -> bool {
-        unimplemented!()
+        if buf.len() >= VIRTIO_BLK_BLK_SIZE {
+            if let Some(ok) = self.request(VIRTIO_BLK_T_OUT, block_id, buf.as_ptr() as usize) {
+                return ok;
+            }
+        }
+        // a partial block, which is read, modified and written as a whole,
+        // or pages which are not mapped yet, go through a bounce buffer
+        let mut block = [0u8; VIRTIO_BLK_BLK_SIZE];
+        if buf.len() < VIRTIO_BLK_BLK_SIZE && !BlockedDevice::read_at(self, block_id, &mut block) {
+            return false;
+        }
+        let len = min(buf.len(), VIRTIO_BLK_BLK_SIZE);
+        block[..len].copy_from_slice(&buf[..len]);
+        self.request(VIRTIO_BLK_T_OUT, block_id, block.as_ptr() as usize) == Some(true)
     }
 }
 
@@ -157,7 +316,7 @@ pub fn virtio_blk_init(node: &Node) {
     info!("Device features {:?}", device_features);
 
     // negotiate these flags only
-    let supported_features = VirtIOBlkFeature::empty();
+    let supported_features = VirtIOBlkFeature::RO;
     let driver_features = (device_features & supported_features).bits();
     header.write_driver_features(driver_features);
 
@@ -176,9 +335,13 @@ pub fn virtio_blk_init(node: &Node) {
         header: from as usize,
         queue: VirtIOVirtqueue::new(header, 0, 16),
         capacity: config.capacity.read() as usize,
+        read_only: device_features.contains(VirtIOBlkFeature::RO),
+        pending: BTreeMap::new(),
+        next_token: SYNC_TOKEN + 1,
     })));
 
     header.status.write(VirtIODeviceStatus::DRIVER_OK.bits());
 
-    DRIVERS.lock().push(Box::new(driver));
+    DRIVERS.lock().push(Box::new(driver.clone()));
+    BLK_DRIVERS.lock().push(Box::new(driver));
 }
\ No newline at end of file
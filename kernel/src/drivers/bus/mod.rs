@@ -1 +1,3 @@
-pub mod virtio_mmio;
\ No newline at end of file
+pub mod virtio_mmio;
+pub mod spi;
+pub mod pci;
\ No newline at end of file
@@ -0,0 +1,499 @@
+//! PCI bus enumeration, and binding of drivers to the devices found
+//!
+//! The configuration space is reached by I/O ports on x86, or by memory (ECAM).
+//! Drivers are registered with `register_driver`, each device found is
+//! probed by the first driver which matches it.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+use core::ptr::{read_volatile, write_volatile};
+use device_tree::util::SliceRead;
+use device_tree::Node;
+use lazy_static::lazy_static;
+use log::*;
+use rcore_memory::paging::PageTable;
+use crate::memory::active_table;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+pub const PCI_VENDOR: u16 = 0x00;
+pub const PCI_COMMAND: u16 = 0x04;
+pub const PCI_CLASS: u16 = 0x08;
+pub const PCI_HEADER_TYPE: u16 = 0x0c;
+pub const PCI_BAR0: u16 = 0x10;
+/// Secondary bus number of a bridge
+pub const PCI_BUS_NUMBERS: u16 = 0x18;
+/// Subsystem vendor and ID
+pub const PCI_SUBSYSTEM: u16 = 0x2c;
+pub const PCI_CAPABILITIES: u16 = 0x34;
+pub const PCI_INTERRUPT_LINE: u16 = 0x3c;
+
+pub const PCI_COMMAND_IO: u32 = 1 << 0;
+pub const PCI_COMMAND_MEMORY: u32 = 1 << 1;
+pub const PCI_COMMAND_MASTER: u32 = 1 << 2;
+pub const PCI_COMMAND_INTX_DISABLE: u32 = 1 << 10;
+/// Set in the status register if there is a list of capabilities
+const PCI_STATUS_CAPABILITIES: u32 = 1 << (16 + 4);
+const PCI_MULTIFUNCTION: u32 = 0x80;
+const PCI_HEADER_BRIDGE: u32 = 0x01;
+
+const BAR_IO: u32 = 0x1;
+const BAR_64BIT: u32 = 0x4;
+const BAR_PREFETCHABLE: u32 = 0x8;
+
+const CAP_MSI: u8 = 0x05;
+const CAP_MSIX: u8 = 0x11;
+const MSI_ENABLE: u32 = 1 << 16;
+const MSI_64BIT: u32 = 1 << 23;
+const MSI_PER_VECTOR_MASK: u32 = 1 << 24;
+const MSIX_ENABLE: u32 = 1 << 31;
+
+/// Class and subclass of PCI-to-PCI bridges
+const CLASS_BRIDGE_PCI: u32 = 0x06_04;
+
+const VENDOR_VIRTIO: u16 = 0x1af4;
+
+/// Location of a function on the PCI bus
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Debug for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A way to reach the configuration space
+pub trait ConfigAccess: Send + Sync {
+    /// Read the 32-bit register at `offset`, which is aligned
+    unsafe fn read(&self, loc: Location, offset: u16) -> u32;
+    /// Write the 32-bit register at `offset`, which is aligned
+    unsafe fn write(&self, loc: Location, offset: u16, value: u32);
+}
+
+/// Memory mapped configuration space, 4 KiB for each function
+pub struct Ecam {
+    base: usize,
+    /// Map a physical page of the configuration space, and return its virtual address
+    map: fn(usize) -> usize,
+}
+
+impl Ecam {
+    pub fn new(base: usize, map: fn(usize) -> usize) -> Self {
+        Ecam { base, map }
+    }
+
+    fn address(&self, loc: Location, offset: u16) -> usize {
+        let paddr = self.base + ((loc.bus as usize) << 20 | (loc.device as usize) << 15
+            | (loc.function as usize) << 12);
+        (self.map)(paddr) + (offset & 0xffc) as usize
+    }
+}
+
+impl ConfigAccess for Ecam {
+    unsafe fn read(&self, loc: Location, offset: u16) -> u32 {
+        read_volatile(self.address(loc, offset) as *const u32)
+    }
+
+    unsafe fn write(&self, loc: Location, offset: u16, value: u32) {
+        write_volatile(self.address(loc, offset) as *mut u32, value)
+    }
+}
+
+/// A base address register
+#[derive(Debug, Clone, Copy)]
+pub enum Bar {
+    Memory { address: u64, size: u64, prefetchable: bool },
+    Io { port: u32, size: u32 },
+}
+
+/// A capability the driver may use
+#[derive(Debug, Clone, Copy)]
+pub enum Capability {
+    Msi {
+        offset: u16,
+        is_64bit: bool,
+        per_vector_mask: bool,
+        /// Number of vectors the device asks for
+        vectors: u8,
+    },
+    MsiX {
+        offset: u16,
+        table_size: u16,
+        /// BAR and offset in it of the table
+        table: (u8, u32),
+        /// BAR and offset in it of the pending bit array
+        pba: (u8, u32),
+    },
+    /// Other capabilities, with their ID and offset
+    Other { id: u8, offset: u16 },
+}
+
+/// A function found on the bus
+#[derive(Clone)]
+pub struct PciDevice {
+    pub loc: Location,
+    pub vendor: u16,
+    pub device: u16,
+    /// Class, subclass and programming interface
+    pub class: u32,
+    pub revision: u8,
+    /// Legacy interrupt line, set by the firmware
+    pub irq: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    access: Arc<ConfigAccess>,
+}
+
+impl fmt::Debug for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {:04x}:{:04x} class {:06x}", self.loc, self.vendor, self.device, self.class)
+    }
+}
+
+impl PciDevice {
+    fn new(access: Arc<ConfigAccess>, loc: Location) -> Option<Self> {
+        let id = unsafe { access.read(loc, PCI_VENDOR) };
+        if id & 0xffff == 0xffff {
+            return None;
+        }
+        let class = unsafe { access.read(loc, PCI_CLASS) };
+        let mut dev = PciDevice {
+            loc,
+            vendor: id as u16,
+            device: (id >> 16) as u16,
+            class: class >> 8,
+            revision: class as u8,
+            irq: unsafe { access.read(loc, PCI_INTERRUPT_LINE) } as u8,
+            bars: [None; 6],
+            capabilities: Vec::new(),
+            access,
+        };
+        // bridges have only two BARs
+        if dev.header_type() & 0x7f != PCI_HEADER_BRIDGE {
+            dev.read_bars();
+        }
+        dev.read_capabilities();
+        Some(dev)
+    }
+
+    /// Read the 32-bit register at `offset` of the configuration space
+    pub unsafe fn read(&self, offset: u16) -> u32 {
+        self.access.read(self.loc, offset)
+    }
+
+    /// Write the 32-bit register at `offset` of the configuration space
+    pub unsafe fn write(&self, offset: u16, value: u32) {
+        self.access.write(self.loc, offset, value)
+    }
+
+    fn header_type(&self) -> u32 {
+        unsafe { self.read(PCI_HEADER_TYPE) >> 16 & 0xff }
+    }
+
+    /// Size BARs by writing all ones, with decoding off in the meantime
+    fn read_bars(&mut self) {
+        unsafe {
+            let command = self.read(PCI_COMMAND);
+            self.write(PCI_COMMAND, command & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY));
+            let mut i = 0;
+            while i < 6 {
+                let offset = PCI_BAR0 + i as u16 * 4;
+                let low = self.read(offset);
+                self.write(offset, !0);
+                let low_mask = self.read(offset);
+                self.write(offset, low);
+                if low & BAR_IO != 0 {
+                    let mask = low_mask & !0x3;
+                    if mask != 0 {
+                        let size = (!(mask | 0xffff_0000)).wrapping_add(1);
+                        self.bars[i] = Some(Bar::Io { port: low & !0x3, size });
+                    }
+                    i += 1;
+                } else if low & BAR_64BIT != 0 && i < 5 {
+                    let high = self.read(offset + 4);
+                    self.write(offset + 4, !0);
+                    let high_mask = self.read(offset + 4);
+                    self.write(offset + 4, high);
+                    let mask = (high_mask as u64) << 32 | (low_mask & !0xf) as u64;
+                    if mask != 0 {
+                        self.bars[i] = Some(Bar::Memory {
+                            address: (high as u64) << 32 | (low & !0xf) as u64,
+                            size: (!mask).wrapping_add(1),
+                            prefetchable: low & BAR_PREFETCHABLE != 0,
+                        });
+                    }
+                    i += 2;
+                } else {
+                    let mask = low_mask & !0xf;
+                    if mask != 0 {
+                        self.bars[i] = Some(Bar::Memory {
+                            address: (low & !0xf) as u64,
+                            size: (!mask).wrapping_add(1) as u64,
+                            prefetchable: low & BAR_PREFETCHABLE != 0,
+                        });
+                    }
+                    i += 1;
+                }
+            }
+            self.write(PCI_COMMAND, command);
+        }
+    }
+
+    fn read_capabilities(&mut self) {
+        unsafe {
+            if self.read(PCI_COMMAND) & PCI_STATUS_CAPABILITIES == 0 {
+                return;
+            }
+            let mut offset = (self.read(PCI_CAPABILITIES) & 0xfc) as u16;
+            // a broken list may loop, there is room for at most 48 capabilities
+            for _ in 0..48 {
+                if offset == 0 {
+                    break;
+                }
+                let header = self.read(offset);
+                let id = header as u8;
+                self.capabilities.push(match id {
+                    CAP_MSI => Capability::Msi {
+                        offset,
+                        is_64bit: header & MSI_64BIT != 0,
+                        per_vector_mask: header & MSI_PER_VECTOR_MASK != 0,
+                        vectors: 1 << (header >> 17 & 0x7),
+                    },
+                    CAP_MSIX => {
+                        let table = self.read(offset + 4);
+                        let pba = self.read(offset + 8);
+                        Capability::MsiX {
+                            offset,
+                            table_size: (header >> 16 & 0x7ff) as u16 + 1,
+                            table: (table as u8 & 0x7, table & !0x7),
+                            pba: (pba as u8 & 0x7, pba & !0x7),
+                        }
+                    }
+                    id => Capability::Other { id, offset },
+                });
+                offset = (header >> 8 & 0xfc) as u16;
+            }
+        }
+    }
+
+    /// Physical address of memory BAR `i`
+    pub fn memory_bar(&self, i: usize) -> Option<usize> {
+        match self.bars[i] {
+            Some(Bar::Memory { address, .. }) => Some(address as usize),
+            _ => None,
+        }
+    }
+
+    /// Enable memory space, I/O space and bus mastering
+    pub fn enable(&self) {
+        unsafe {
+            let command = self.read(PCI_COMMAND);
+            self.write(PCI_COMMAND, command | PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER);
+        }
+    }
+
+    /// Send interrupts as message `data` written to `address`, instead of the legacy line.
+    /// Return false if the device does not support MSI.
+    pub fn enable_msi(&self, address: u64, data: u16) -> bool {
+        let (offset, is_64bit) = match self.capabilities.iter().filter_map(|cap| match *cap {
+            Capability::Msi { offset, is_64bit, .. } => Some((offset, is_64bit)),
+            _ => None,
+        }).next() {
+            Some(msi) => msi,
+            None => return false,
+        };
+        unsafe {
+            self.write(offset + 4, address as u32);
+            if is_64bit {
+                self.write(offset + 8, (address >> 32) as u32);
+                self.write(offset + 12, data as u32);
+            } else {
+                self.write(offset + 8, data as u32);
+            }
+            // one vector only
+            let control = self.read(offset) & !(0x7 << 20);
+            self.write(offset, control | MSI_ENABLE);
+            let command = self.read(PCI_COMMAND);
+            self.write(PCI_COMMAND, command | PCI_COMMAND_INTX_DISABLE);
+        }
+        true
+    }
+
+    /// Turn on MSI-X, the entries of the table are set up by the driver.
+    /// Return false if the device does not support MSI-X.
+    pub fn enable_msix(&self) -> bool {
+        let offset = match self.capabilities.iter().filter_map(|cap| match *cap {
+            Capability::MsiX { offset, .. } => Some(offset),
+            _ => None,
+        }).next() {
+            Some(offset) => offset,
+            None => return false,
+        };
+        unsafe {
+            let control = self.read(offset);
+            self.write(offset, control | MSIX_ENABLE);
+            let command = self.read(PCI_COMMAND);
+            self.write(PCI_COMMAND, command | PCI_COMMAND_INTX_DISABLE);
+        }
+        true
+    }
+}
+
+/// A driver of PCI devices
+pub struct PciDriver {
+    pub name: &'static str,
+    /// Whether the driver handles the device
+    pub matches: fn(&PciDevice) -> bool,
+    /// Init the device and register it
+    pub probe: fn(&PciDevice),
+}
+
+struct Bus {
+    /// Devices found, with the name of the driver bound to them
+    devices: Vec<(PciDevice, Option<&'static str>)>,
+    drivers: Vec<&'static PciDriver>,
+}
+
+lazy_static! {
+    static ref PCI: Mutex<Bus> = Mutex::new(Bus { devices: Vec::new(), drivers: Vec::new() });
+}
+
+/// Bind `driver` to the device if it has no driver and `driver` handles it
+fn bind(dev: &PciDevice, bound: &mut Option<&'static str>, driver: &'static PciDriver) -> bool {
+    if bound.is_none() && (driver.matches)(dev) {
+        info!("pci: {:?} bound to {}", dev, driver.name);
+        *bound = Some(driver.name);
+        true
+    } else {
+        false
+    }
+}
+
+/// Add a driver, and bind it to the devices it handles found so far.
+/// Devices found later are bound when scanned.
+pub fn register_driver(driver: &'static PciDriver) {
+    let mut pci = PCI.lock();
+    pci.drivers.push(driver);
+    let mut bound_devices = Vec::new();
+    for (dev, bound) in pci.devices.iter_mut() {
+        if bind(dev, bound, driver) {
+            bound_devices.push(dev.clone());
+        }
+    }
+    // probe without the lock, drivers may wait for their devices
+    drop(pci);
+    for dev in bound_devices.iter() {
+        (driver.probe)(dev);
+    }
+}
+
+fn add_device(dev: PciDevice) {
+    let mut pci = PCI.lock();
+    let mut bound = None;
+    let driver = pci.drivers.iter().cloned().find(|&driver| bind(&dev, &mut bound, driver));
+    if driver.is_none() {
+        debug!("pci: {:?}", dev);
+    }
+    pci.devices.push((dev.clone(), bound));
+    drop(pci);
+    if let Some(driver) = driver {
+        (driver.probe)(&dev);
+    }
+}
+
+fn scan_bus(access: &Arc<ConfigAccess>, bus: u8) {
+    for device in 0..32u8 {
+        let dev = match PciDevice::new(access.clone(), Location { bus, device, function: 0 }) {
+            Some(dev) => dev,
+            None => continue,
+        };
+        let functions = if dev.header_type() & PCI_MULTIFUNCTION != 0 { 8 } else { 1 };
+        scan_function(access, dev);
+        for function in 1..functions {
+            if let Some(dev) = PciDevice::new(access.clone(), Location { bus, device, function }) {
+                scan_function(access, dev);
+            }
+        }
+    }
+}
+
+fn scan_function(access: &Arc<ConfigAccess>, dev: PciDevice) {
+    let bridge = if dev.class >> 8 == CLASS_BRIDGE_PCI {
+        let secondary = unsafe { dev.read(PCI_BUS_NUMBERS) } >> 8 & 0xff;
+        Some(secondary as u8)
+    } else {
+        None
+    };
+    add_device(dev);
+    if let Some(secondary) = bridge {
+        if secondary != 0 {
+            scan_bus(access, secondary);
+        }
+    }
+}
+
+/// Find the devices behind the host bridges reached by `access`
+pub fn scan(access: Arc<ConfigAccess>) {
+    let host = match PciDevice::new(access.clone(), Location { bus: 0, device: 0, function: 0 }) {
+        Some(host) => host,
+        None => return,
+    };
+    if host.header_type() & PCI_MULTIFUNCTION == 0 {
+        scan_bus(&access, 0);
+    } else {
+        // one host bridge for each function, each with its own bus
+        for function in 0..8u8 {
+            let loc = Location { bus: 0, device: 0, function };
+            if PciDevice::new(access.clone(), loc).is_some() {
+                scan_bus(&access, function);
+            }
+        }
+    }
+}
+
+fn virtio_matches(dev: &PciDevice) -> bool {
+    dev.vendor == VENDOR_VIRTIO && dev.device >= 0x1000 && dev.device <= 0x107f
+}
+
+/// Virtio drivers only speak the MMIO transport, so devices on PCI are just claimed
+fn virtio_probe(dev: &PciDevice) {
+    // transitional devices have IDs from 0x1000, modern ones from 0x1040
+    let device_id = match dev.device {
+        0x1000..=0x103f => unsafe { dev.read(PCI_SUBSYSTEM) >> 16 },
+        id => (id - 0x1040) as u32,
+    };
+    warn!("pci: virtio device {} at {:?} is not supported on PCI", device_id, dev.loc);
+}
+
+static VIRTIO_DRIVER: PciDriver = PciDriver {
+    name: "virtio",
+    matches: virtio_matches,
+    probe: virtio_probe,
+};
+
+/// Register the drivers common to all architectures, then scan the buses
+pub fn init(access: Arc<ConfigAccess>) {
+    register_driver(&VIRTIO_DRIVER);
+    scan(access);
+}
+
+/// Map a page of the configuration space at the same address
+fn map_identity(paddr: usize) -> usize {
+    active_table().map_if_not_exists(paddr, paddr);
+    paddr
+}
+
+/// Scan the buses of a generic ECAM host bridge in the device tree
+pub fn pci_host_probe(node: &Node) {
+    let reg = match node.prop_raw("reg") {
+        Some(reg) => reg,
+        None => return,
+    };
+    let base = reg.as_slice().read_be_u64(0).unwrap() as usize;
+    info!("Detected PCI host bridge with ECAM at {:#x}", base);
+    init(Arc::new(Ecam::new(base, map_identity)));
+}
@@ -0,0 +1,105 @@
+//! SPI buses, and the SPI controller of SiFive SoCs
+
+use alloc::boxed::Box;
+use device_tree::Node;
+use device_tree::util::SliceRead;
+use log::*;
+use rcore_memory::paging::PageTable;
+use volatile::Volatile;
+
+use crate::memory::active_table;
+
+use super::super::block::sdcard;
+
+/// A SPI master with one slave, in mode 0 and MSB first
+pub trait SpiBus: Send {
+    /// Set the clock to at most `hz`
+    fn set_clock(&mut self, hz: u32);
+    /// Assert or deassert the chip select
+    fn select(&mut self, selected: bool);
+    /// Send a byte and receive a byte at the same time
+    fn transfer(&mut self, byte: u8) -> u8;
+}
+
+/// Input clock of the SiFive SPI controller, the bus clock of FU540
+const SIFIVE_SPI_INPUT_CLOCK: u32 = 500_000_000;
+
+const SIFIVE_SPI_CSMODE_HOLD: u32 = 2;
+const SIFIVE_SPI_CSMODE_OFF: u32 = 3;
+/// Single data line, MSB first, 8 bits per frame
+const SIFIVE_SPI_FMT_8BIT: u32 = 8 << 16;
+/// Set in txdata when the FIFO is full, and in rxdata when it is empty
+const SIFIVE_SPI_FIFO_FLAG: u32 = 1 << 31;
+
+#[repr(C)]
+struct SifiveSpiRegs {
+    sckdiv: Volatile<u32>, // 0x00
+    sckmode: Volatile<u32>, // 0x04
+    __r1: [u32; 2],
+    csid: Volatile<u32>, // 0x10
+    csdef: Volatile<u32>, // 0x14
+    csmode: Volatile<u32>, // 0x18
+    __r2: [u32; 9],
+    fmt: Volatile<u32>, // 0x40
+    __r3: u32,
+    txdata: Volatile<u32>, // 0x48
+    rxdata: Volatile<u32>, // 0x4c
+}
+
+/// The SPI controller of SiFive SoCs, e.g. connected to the SD card slot of HiFive Unleashed
+pub struct SifiveSpi {
+    regs: &'static mut SifiveSpiRegs,
+}
+
+impl SifiveSpi {
+    /// Init the controller with registers at `base`, which must be mapped
+    pub unsafe fn new(base: usize) -> Self {
+        let regs = &mut *(base as *mut SifiveSpiRegs);
+        regs.sckmode.write(0);
+        regs.csid.write(0);
+        regs.csmode.write(SIFIVE_SPI_CSMODE_OFF);
+        regs.fmt.write(SIFIVE_SPI_FMT_8BIT);
+        SifiveSpi { regs }
+    }
+}
+
+impl SpiBus for SifiveSpi {
+    fn set_clock(&mut self, hz: u32) {
+        // f_sck = f_in / (2 * (div + 1))
+        let div = (SIFIVE_SPI_INPUT_CLOCK + 2 * hz - 1) / (2 * hz);
+        self.regs.sckdiv.write(div.saturating_sub(1) & 0xfff);
+    }
+
+    fn select(&mut self, selected: bool) {
+        self.regs.csmode.write(if selected { SIFIVE_SPI_CSMODE_HOLD } else { SIFIVE_SPI_CSMODE_OFF });
+    }
+
+    fn transfer(&mut self, byte: u8) -> u8 {
+        while self.regs.txdata.read() & SIFIVE_SPI_FIFO_FLAG != 0 {}
+        self.regs.txdata.write(byte as u32);
+        loop {
+            let data = self.regs.rxdata.read();
+            if data & SIFIVE_SPI_FIFO_FLAG == 0 {
+                return data as u8;
+            }
+        }
+    }
+}
+
+/// Probe a SiFive SPI controller, and the SD card slot on it
+pub fn sifive_spi_probe(node: &Node) {
+    let reg = match node.prop_raw("reg") {
+        Some(reg) => reg,
+        None => return,
+    };
+    let from = reg.as_slice().read_be_u64(0).unwrap() as usize;
+    let has_card = node.children.iter()
+        .any(|child| child.prop_str("compatible").map(|c| c == "mmc-spi-slot").unwrap_or(false));
+    if !has_card {
+        return;
+    }
+    info!("Detected SD card slot on SPI controller at {:#x}", from);
+    active_table().map(from, from);
+    let bus = unsafe { SifiveSpi::new(from) };
+    sdcard::sdcard_init(Box::new(bus));
+}
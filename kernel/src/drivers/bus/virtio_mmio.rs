@@ -6,7 +6,6 @@ use core::sync::atomic::{fence, Ordering};
 
 use bitflags::*;
 use device_tree::Node;
-use device_tree::util::SliceRead;
 use log::*;
 use rcore_memory::PAGE_SIZE;
 use rcore_memory::paging::PageTable;
@@ -17,10 +16,14 @@ use crate::HEAP_ALLOCATOR;
 use crate::memory::active_table;
 use crate::arch::consts::{KERNEL_OFFSET, MEMORY_OFFSET};
 
+use super::super::device_tree::get_info;
 use super::super::block::virtio_blk;
 use super::super::gpu::virtio_gpu;
 use super::super::input::virtio_input;
 use super::super::net::virtio_net;
+use super::super::p9::virtio_9p;
+use super::super::serial::virtio_console;
+use super::super::irq::plic;
 
 // virtio 4.2.4 Legacy interface
 #[repr(C)]
@@ -129,6 +132,15 @@ impl VirtIOVirtqueue {
     // Return true on success, false otherwise
     // ref. linux virtio_ring.c virtqueue_add
     pub fn add(&mut self, input: &[&[u8]], output: &[&[u8]], user_data: usize) -> bool {
+        let segment = |buf: &&[u8]| (buf.as_ptr() as usize - KERNEL_OFFSET + MEMORY_OFFSET, buf.len());
+        let input: Vec<_> = input.iter().map(segment).collect();
+        let output: Vec<_> = output.iter().map(segment).collect();
+        self.add_segments(&input, &output, user_data)
+    }
+
+    // Add buffers given as (physical address, length) to the virtqueue
+    // Return true on success, false otherwise
+    pub fn add_segments(&mut self, input: &[(usize, usize)], output: &[(usize, usize)], user_data: usize) -> bool {
         assert!(input.len() + output.len() > 0);
         if !self.can_add(input.len(), output.len()) {
             return false;
@@ -138,17 +150,17 @@ impl VirtIOVirtqueue {
         let head = self.free_head;
         let mut prev = 0;
         let mut cur = self.free_head;
-        for i in 0..output.len() {
+        for &(addr, len) in output {
             desc[cur].flags.write(VirtIOVirtqueueFlag::NEXT.bits());
-            desc[cur].addr.write(output[i].as_ptr() as u64 - KERNEL_OFFSET as u64 + MEMORY_OFFSET as u64);
-            desc[cur].len.write(output[i].len() as u32);
+            desc[cur].addr.write(addr as u64);
+            desc[cur].len.write(len as u32);
             prev = cur;
             cur = desc[cur].next.read() as usize;
         }
-        for i in 0..input.len() {
+        for &(addr, len) in input {
             desc[cur].flags.write((VirtIOVirtqueueFlag::NEXT | VirtIOVirtqueueFlag::WRITE).bits());
-            desc[cur].addr.write(input[i].as_ptr() as u64 - KERNEL_OFFSET as u64 + MEMORY_OFFSET as u64);
-            desc[cur].len.write(input[i].len() as u32);
+            desc[cur].addr.write(addr as u64);
+            desc[cur].len.write(len as u32);
             prev = cur;
             cur = desc[cur].next.read() as usize;
         }
@@ -199,8 +211,8 @@ impl VirtIOVirtqueue {
         let index = used.ring[last_used_slot].id.read() as usize;
         let len = used.ring[last_used_slot].len.read();
 
-        let user_data = self.desc_state[last_used_slot];
-        self.desc_state[last_used_slot] = 0;
+        let user_data = self.desc_state[index];
+        self.desc_state[index] = 0;
 
         let mut cur = index;
         let desc = unsafe { slice::from_raw_parts_mut(self.desc as *mut VirtIOVirtqueueDesc, self.queue_num) };
@@ -249,6 +261,39 @@ impl VirtIOVirtqueue {
     }
 }
 
+/// Physical address of `vaddr`, which is in the linear mapping of the kernel,
+/// or in a page mapped by the current page table, e.g. of a user process.
+/// None if its page is not mapped, or is read only and the device is to write it,
+/// e.g. a page shared by copy on write.
+pub fn virt_to_phys(vaddr: usize, device_writes: bool) -> Option<usize> {
+    if vaddr >= KERNEL_OFFSET {
+        return Some(vaddr - KERNEL_OFFSET + MEMORY_OFFSET);
+    }
+    let mut table = active_table();
+    let entry = table.get_entry(vaddr)?;
+    if !entry.present() || (device_writes && !entry.writable()) {
+        return None;
+    }
+    Some(entry.target() + vaddr % PAGE_SIZE)
+}
+
+/// Physical segments of `len` bytes at `vaddr` for `add_segments()`,
+/// split where its pages are not contiguous. None if a page can't be used, see `virt_to_phys()`.
+pub fn phys_segments(mut vaddr: usize, len: usize, device_writes: bool) -> Option<Vec<(usize, usize)>> {
+    let mut segments: Vec<(usize, usize)> = Vec::new();
+    let end = vaddr + len;
+    while vaddr < end {
+        let len = (PAGE_SIZE - vaddr % PAGE_SIZE).min(end - vaddr);
+        let paddr = virt_to_phys(vaddr, device_writes)?;
+        match segments.last_mut() {
+            Some(last) if last.0 + last.1 == paddr => last.1 += len,
+            _ => segments.push((paddr, len)),
+        }
+        vaddr += len;
+    }
+    Some(segments)
+}
+
 pub const VIRTIO_CONFIG_SPACE_OFFSET: u64 = 0x100;
 
 impl VirtIOHeader {
@@ -333,12 +378,11 @@ pub fn virtqueue_used_elem_offset(num: usize, align: usize) -> usize {
 }
 
 pub fn virtio_probe(node: &Node) {
-    if let Some(reg) = node.prop_raw("reg") {
-        let from = reg.as_slice().read_be_u64(0).unwrap();
-        let size = reg.as_slice().read_be_u64(8).unwrap();
+    let reg = get_info().device(&node.name).and_then(|dev| dev.regs.first().cloned());
+    if let Some((from, size)) = reg {
         // assuming one page
-        assert_eq!(size as usize, PAGE_SIZE);
-        active_table().map(from as usize, from as usize);
+        assert_eq!(size, PAGE_SIZE);
+        active_table().map(from, from);
         let header = unsafe { &mut *(from as *mut VirtIOHeader) };
         let magic = header.magic.read();
         let version = header.version.read();
@@ -354,12 +398,23 @@ pub fn virtio_probe(node: &Node) {
                 virtio_net::virtio_net_init(node);
             } else if device_id == 2 { // blk device
                 virtio_blk::virtio_blk_init(node);
+            } else if device_id == 3 { // console device
+                virtio_console::virtio_console_init(node);
+            } else if device_id == 9 { // 9p transport
+                virtio_9p::virtio_9p_init(node);
             } else if device_id == 16 { // gpu device
                 virtio_gpu::virtio_gpu_init(node);
             } else if device_id == 18 { // input device
                 virtio_input::virtio_input_init(node);
             } else {
                 println!("Unrecognized virtio device {}", device_id);
+                return;
+            }
+            // without an interrupt controller the drivers are only polled
+            if let Ok(irq) = node.prop_u32("interrupts") {
+                if !plic::enable(irq) {
+                    warn!("virtio: interrupt {} can't be enabled", irq);
+                }
             }
         } else {
             active_table().unmap(from as usize);
@@ -1,16 +1,173 @@
+//! Hardware discovery from the flattened device tree passed by the bootloader
+//!
+//! The tree is walked once: memory ranges, MMIO devices and interrupt controllers
+//! are recorded for later queries, and drivers are probed by `compatible`.
+
+use alloc::prelude::*;
 use core::slice;
 
 use device_tree::{DeviceTree, Node};
+use log::*;
+use spin::Once;
 
 use super::bus::virtio_mmio::virtio_probe;
+use super::bus::spi::sifive_spi_probe;
+use super::bus::pci::pci_host_probe;
+use super::rtc::goldfish::goldfish_rtc_probe;
+use super::irq::plic::plic_probe;
 
 const DEVICE_TREE_MAGIC: u32 = 0xd00dfeed;
 
+/// Cells of `reg` when the parent does not tell, devicetree spec 2.3.5
+const DEFAULT_ADDRESS_CELLS: usize = 2;
+const DEFAULT_SIZE_CELLS: usize = 1;
+
+/// A node with registers or interrupts
+#[derive(Debug)]
+pub struct Device {
+    pub name: String,
+    pub compatible: Option<String>,
+    /// (physical address, size) of each register range
+    pub regs: Vec<(usize, usize)>,
+    /// Phandle of the interrupt controller, maybe inherited from an ancestor
+    pub interrupt_parent: Option<u32>,
+    /// Cells of the `interrupts` property, their meaning depends on the controller
+    pub interrupts: Vec<u32>,
+}
+
+/// What the device tree tells about the machine
+#[derive(Debug, Default)]
+pub struct DeviceTreeInfo {
+    /// (physical address, size) of RAM, parts of it may be in `reserved`
+    pub memory: Vec<(usize, usize)>,
+    /// (physical address, size) of memory which must not be used
+    pub reserved: Vec<(usize, usize)>,
+    pub devices: Vec<Device>,
+    /// (phandle, compatible) of each interrupt controller
+    pub interrupt_controllers: Vec<(u32, String)>,
+    /// Kernel command line, `bootargs` of `/chosen`
+    pub bootargs: Option<String>,
+}
+
+impl DeviceTreeInfo {
+    /// The device named `name`, e.g. "virtio_mmio@10001000"
+    pub fn device(&self, name: &str) -> Option<&Device> {
+        self.devices.iter().find(|dev| dev.name == name)
+    }
+
+    /// The compatible string of the interrupt controller of `dev`
+    pub fn interrupt_controller(&self, dev: &Device) -> Option<&str> {
+        let phandle = dev.interrupt_parent?;
+        self.interrupt_controllers.iter()
+            .find(|&&(handle, _)| handle == phandle)
+            .map(|(_, compatible)| compatible.as_str())
+    }
+}
+
+static INFO: Once<DeviceTreeInfo> = Once::new();
+
+/// `#address-cells` and `#size-cells` which a node gives to its children
+#[derive(Debug, Clone, Copy)]
+struct Cells {
+    address: usize,
+    size: usize,
+}
+
+impl Cells {
+    fn of(node: &Node, parent: Cells) -> Cells {
+        Cells {
+            address: node.prop_u32("#address-cells").map(|n| n as usize).unwrap_or(parent.address),
+            size: node.prop_u32("#size-cells").map(|n| n as usize).unwrap_or(parent.size),
+        }
+    }
+}
+
+/// Read a number of `cells` big-endian 32-bit cells from the start of `data`
+fn read_cells(data: &[u8], cells: usize) -> Option<usize> {
+    if cells > 2 || data.len() < cells * 4 {
+        return None;
+    }
+    Some(data[..cells * 4].chunks(4).fold(0u64, |value, cell| {
+        value << 32 | u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]) as u64
+    }) as usize)
+}
+
+/// (address, size) pairs of property `name`, e.g. `reg`
+fn read_ranges(node: &Node, name: &str, cells: Cells) -> Vec<(usize, usize)> {
+    let data = match node.prop_raw(name) {
+        Some(data) => data.as_slice(),
+        None => return Vec::new(),
+    };
+    let entry = (cells.address + cells.size) * 4;
+    if entry == 0 {
+        return Vec::new();
+    }
+    data.chunks(entry).filter_map(|entry| {
+        let address = read_cells(entry, cells.address)?;
+        let size = read_cells(&entry[cells.address * 4..], cells.size)?;
+        Some((address, size))
+    }).collect()
+}
+
+fn read_u32s(node: &Node, name: &str) -> Vec<u32> {
+    match node.prop_raw(name) {
+        Some(data) => data.chunks(4).filter(|cell| cell.len() == 4)
+            .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Record `node` and its children, whose `reg` are in `cells` of the parent
+fn scan(info: &mut DeviceTreeInfo, node: &Node, cells: Cells, interrupt_parent: Option<u32>) {
+    let compatible = node.prop_str("compatible").ok().map(String::from);
+    let interrupt_parent = node.prop_u32("interrupt-parent").ok().or(interrupt_parent);
+    let regs = read_ranges(node, "reg", cells);
+    if node.prop_str("device_type").ok() == Some("memory") {
+        info.memory.extend(regs);
+    } else if node.name == "chosen" {
+        info.bootargs = node.prop_str("bootargs").ok().map(String::from);
+        return;
+    } else if node.name == "reserved-memory" {
+        let cells = Cells::of(node, cells);
+        for child in node.children.iter() {
+            info.reserved.extend(read_ranges(child, "reg", cells));
+        }
+        return;
+    } else {
+        if node.prop_raw("interrupt-controller").is_some() {
+            if let Ok(phandle) = node.prop_u32("phandle") {
+                info.interrupt_controllers.push((phandle, compatible.clone().unwrap_or_default()));
+            }
+        }
+        let interrupts = read_u32s(node, "interrupts");
+        if !regs.is_empty() || !interrupts.is_empty() {
+            info.devices.push(Device {
+                name: node.name.clone(),
+                compatible,
+                regs,
+                interrupt_parent,
+                interrupts,
+            });
+        }
+    }
+    let cells = Cells::of(node, cells);
+    for child in node.children.iter() {
+        scan(info, child, cells, interrupt_parent);
+    }
+}
+
 fn walk_dt_node(dt: &Node) {
     if let Ok(compatible) = dt.prop_str("compatible") {
         // TODO: query this from table
         if compatible == "virtio,mmio" {
             virtio_probe(dt);
+        } else if compatible == "sifive,spi0" || compatible == "sifive,fu540-c000-spi" {
+            sifive_spi_probe(dt);
+        } else if compatible == "google,goldfish-rtc" {
+            goldfish_rtc_probe(dt);
+        } else if compatible == "pci-host-ecam-generic" {
+            pci_host_probe(dt);
         }
     }
     for child in dt.children.iter() {
@@ -18,6 +175,18 @@ fn walk_dt_node(dt: &Node) {
     }
 }
 
+// interrupt controllers are probed first, so that devices can enable their interrupts
+fn walk_irq_controllers(dt: &Node) {
+    if let Ok(compatible) = dt.prop_str("compatible") {
+        if compatible == "riscv,plic0" || compatible == "sifive,plic-1.0.0" {
+            plic_probe(dt);
+        }
+    }
+    for child in dt.children.iter() {
+        walk_irq_controllers(child);
+    }
+}
+
 struct DtbHeader {
     magic: u32,
     size: u32,
@@ -30,7 +199,29 @@ pub fn init(dtb: usize) {
         let size = u32::from_be(header.size);
         let dtb_data = unsafe { slice::from_raw_parts(dtb as *const u8, size as usize) };
         if let Ok(dt) = DeviceTree::load(dtb_data) {
+            let info = INFO.call_once(|| {
+                let mut info = DeviceTreeInfo::default();
+                let cells = Cells::of(&dt.root, Cells { address: DEFAULT_ADDRESS_CELLS, size: DEFAULT_SIZE_CELLS });
+                for child in dt.root.children.iter() {
+                    scan(&mut info, child, cells, None);
+                }
+                info
+            });
+            info!("device tree: memory {:x?}, reserved {:x?}, {} devices",
+                  info.memory, info.reserved, info.devices.len());
+            match info.bootargs {
+                Some(ref bootargs) if !bootargs.is_empty() => crate::cmdline::init(bootargs),
+                _ => {}
+            }
+            walk_irq_controllers(&dt.root);
             walk_dt_node(&dt.root);
+        } else {
+            warn!("device tree: failed to parse the DTB at {:#x}", dtb);
         }
     }
 }
+
+/// What was found in the device tree, empty if there is none
+pub fn get_info() -> &'static DeviceTreeInfo {
+    INFO.call_once(DeviceTreeInfo::default)
+}
@@ -0,0 +1,242 @@
+//! Text console on a screen, and the `/dev/console` device
+//!
+//! The screen is either a text mode display like VGA,
+//! or a framebuffer with characters drawn by a font.
+
+use alloc::{boxed::Box, string::String, sync::Arc};
+use core::any::Any;
+use core::fmt::{self, Arguments, Write};
+use core::marker::PhantomData;
+use core::ptr;
+use lazy_static::lazy_static;
+use simple_filesystem::*;
+use crate::fs::{register_device, STDIN};
+use crate::sync::SpinNoIrqLock as Mutex;
+use super::fb::FRAMEBUFFER;
+use super::fonts::Font;
+
+/// A screen showing rows of characters
+pub trait TextScreen: Send {
+    /// Number of rows and columns
+    fn size(&self) -> (usize, usize);
+    /// Show `c` at `(row, col)`
+    fn put(&mut self, row: usize, col: usize, c: u8);
+    /// Move all rows up by one, leaving the last row blank
+    fn scroll_up(&mut self);
+    /// Move the cursor to `(row, col)`
+    fn set_cursor(&mut self, _row: usize, _col: usize) {}
+    /// Show changes made since the last flush
+    fn flush(&mut self) {}
+}
+
+const FOREGROUND: u32 = 0x00aa_aaaa;
+const BACKGROUND: u32 = 0x0000_0000;
+
+/// A text screen drawn on `FRAMEBUFFER` with font `F`
+pub struct FramebufferScreen<F: Font> {
+    rows: usize,
+    cols: usize,
+    /// Rows of pixels changed since the last flush
+    dirty: Option<(usize, usize)>,
+    font: PhantomData<F>,
+}
+
+impl<F: Font> FramebufferScreen<F> {
+    /// Create a screen on `FRAMEBUFFER`, which must have been registered
+    pub fn new() -> Self {
+        let mut fb = FRAMEBUFFER.lock();
+        let fb = fb.as_mut().expect("no framebuffer");
+        let (width, height) = (fb.width(), fb.height());
+        for pixel in fb.pixels().iter_mut() {
+            *pixel = BACKGROUND;
+        }
+        fb.flush(0, height);
+        FramebufferScreen {
+            rows: height / F::HEIGHT,
+            cols: width / F::WIDTH,
+            dirty: None,
+            font: PhantomData,
+        }
+    }
+
+    fn mark_dirty(&mut self, top: usize, bottom: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((t, b)) => (t.min(top), b.max(bottom)),
+            None => (top, bottom),
+        });
+    }
+}
+
+impl<F: Font + Send> TextScreen for FramebufferScreen<F> {
+    fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    fn put(&mut self, row: usize, col: usize, c: u8) {
+        let mut fb = FRAMEBUFFER.lock();
+        let fb = match fb.as_mut() {
+            Some(fb) => fb,
+            None => return,
+        };
+        let width = fb.width();
+        let pixels = fb.pixels();
+        for y in 0..F::HEIGHT {
+            let line = (row * F::HEIGHT + y) * width + col * F::WIDTH;
+            for x in 0..F::WIDTH {
+                pixels[line + x] = if F::get(c, x, y) { FOREGROUND } else { BACKGROUND };
+            }
+        }
+        self.mark_dirty(row * F::HEIGHT, (row + 1) * F::HEIGHT);
+    }
+
+    fn scroll_up(&mut self) {
+        let mut fb = FRAMEBUFFER.lock();
+        let fb = match fb.as_mut() {
+            Some(fb) => fb,
+            None => return,
+        };
+        let width = fb.width();
+        let pixels = fb.pixels();
+        let line = F::HEIGHT * width;
+        let text = self.rows * line;
+        // the pixels are in memory, so moving them is fast
+        unsafe { ptr::copy(pixels[line..].as_ptr(), pixels.as_mut_ptr(), text - line) };
+        for pixel in pixels[text - line..text].iter_mut() {
+            *pixel = BACKGROUND;
+        }
+        self.mark_dirty(0, self.rows * F::HEIGHT);
+    }
+
+    fn flush(&mut self) {
+        if let Some((top, bottom)) = self.dirty.take() {
+            if let Some(fb) = FRAMEBUFFER.lock().as_mut() {
+                fb.flush(top, bottom);
+            }
+        }
+    }
+}
+
+/// A terminal writing to a text screen
+pub struct Console {
+    screen: Box<TextScreen>,
+    row: usize,
+    col: usize,
+    /// In an escape sequence, which is skipped
+    escape: bool,
+}
+
+impl Console {
+    pub fn new(mut screen: Box<TextScreen>) -> Self {
+        let (rows, cols) = screen.size();
+        for row in 0..rows {
+            for col in 0..cols {
+                screen.put(row, col, b' ');
+            }
+        }
+        screen.set_cursor(0, 0);
+        screen.flush();
+        Console { screen, row: 0, col: 0, escape: false }
+    }
+
+    fn new_line(&mut self) {
+        let (rows, _) = self.screen.size();
+        self.col = 0;
+        if self.row + 1 < rows {
+            self.row += 1;
+        } else {
+            self.screen.scroll_up();
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if self.escape {
+            // CSI sequences end with a letter
+            if byte != b'[' && byte >= 0x40 && byte <= 0x7e {
+                self.escape = false;
+            }
+            return;
+        }
+        let (_, cols) = self.screen.size();
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.col = 0,
+            b'\t' => {
+                for _ in 0..(8 - self.col % 8) {
+                    self.write_byte(b' ');
+                }
+            }
+            b'\x08' | b'\x7f' => {
+                if self.col > 0 {
+                    self.col -= 1;
+                    self.screen.put(self.row, self.col, b' ');
+                }
+            }
+            b'\x1b' => self.escape = true,
+            byte => {
+                if self.col >= cols {
+                    self.new_line();
+                }
+                self.screen.put(self.row, self.col, byte);
+                self.col += 1;
+            }
+        }
+    }
+
+    /// Move the cursor and show the changes
+    fn flush(&mut self) {
+        let (_, cols) = self.screen.size();
+        self.screen.set_cursor(self.row, self.col.min(cols - 1));
+        self.screen.flush();
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        self.flush();
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+}
+
+/// Show the console on `screen`, replacing the previous one
+pub fn init_console(screen: Box<TextScreen>) {
+    *CONSOLE.lock() = Some(Console::new(screen));
+    register_device("console", Arc::new(ConsoleINode));
+}
+
+/// Write to the console, if there is one.
+///
+/// The message is dropped if the console is busy, e.g. a panic while drawing,
+/// it is also written to the serial port.
+pub fn putfmt(fmt: Arguments) {
+    if let Some(mut console) = CONSOLE.try_lock() {
+        if let Some(console) = console.as_mut() {
+            console.write_fmt(fmt).unwrap();
+        }
+    }
+}
+
+/// Writes to the console and reads from stdin
+struct ConsoleINode;
+
+impl INode for ConsoleINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        STDIN.read_at(offset, buf)
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            for &byte in buf {
+                console.write_byte(byte);
+            }
+            console.flush();
+        }
+        Ok(buf.len())
+    }
+    impl_inode!();
+}
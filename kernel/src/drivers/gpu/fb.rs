@@ -0,0 +1,77 @@
+//! Framebuffer abstraction, and the `/dev/fb0` device
+
+use alloc::{boxed::Box, string::String, sync::Arc};
+use core::any::Any;
+use core::mem::size_of;
+use core::slice;
+use lazy_static::lazy_static;
+use simple_filesystem::*;
+use crate::fs::{register_device, device_info, MODE_CHAR};
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// A screen of 32-bit pixels in memory
+pub trait Framebuffer: Send {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    /// Pixels as 0x00RRGGBB, row by row
+    fn pixels(&mut self) -> &mut [u32];
+    /// Show the changed pixels of rows from `top` to `bottom` (exclusive) on the screen
+    fn flush(&mut self, top: usize, bottom: usize);
+}
+
+lazy_static! {
+    pub static ref FRAMEBUFFER: Mutex<Option<Box<Framebuffer>>> = Mutex::new(None);
+}
+
+/// Use `fb` as the framebuffer, and make it available as `/dev/fb0`
+pub fn register_framebuffer(fb: Box<Framebuffer>) {
+    *FRAMEBUFFER.lock() = Some(fb);
+    register_device("fb0", Arc::new(FramebufferINode));
+}
+
+/// The pixels of the framebuffer as a file
+struct FramebufferINode;
+
+impl FramebufferINode {
+    /// Call `f` with the bytes of pixels from `offset`, then flush the rows touched if `write`
+    fn access(&self, offset: usize, len: usize, write: bool, f: impl FnOnce(&mut [u8])) -> Result<usize> {
+        let mut fb = FRAMEBUFFER.lock();
+        let fb = fb.as_mut().ok_or(FsError::NotSupported)?;
+        let row_size = fb.width() * size_of::<u32>();
+        let pixels = fb.pixels();
+        let bytes = unsafe { slice::from_raw_parts_mut(pixels.as_mut_ptr() as *mut u8, pixels.len() * size_of::<u32>()) };
+        if offset >= bytes.len() {
+            return Ok(0);
+        }
+        let len = len.min(bytes.len() - offset);
+        f(&mut bytes[offset..offset + len]);
+        if write && len > 0 {
+            fb.flush(offset / row_size, (offset + len - 1) / row_size + 1);
+        }
+        Ok(len)
+    }
+}
+
+impl INode for FramebufferINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.access(offset, buf.len(), false, |data| {
+            let len = data.len();
+            buf[..len].copy_from_slice(data);
+        })
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.access(offset, buf.len(), true, |data| {
+            let len = data.len();
+            data.copy_from_slice(&buf[..len]);
+        })
+    }
+    fn info(&self) -> Result<FileInfo> {
+        let fb = FRAMEBUFFER.lock();
+        let fb = fb.as_ref().ok_or(FsError::NotSupported)?;
+        Ok(FileInfo {
+            size: fb.width() * fb.height() * size_of::<u32>(),
+            ..device_info(MODE_CHAR)
+        })
+    }
+    impl_inode!(@without_info);
+}
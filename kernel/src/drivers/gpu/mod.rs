@@ -1,2 +1,4 @@
 pub mod virtio_gpu;
-mod test;
\ No newline at end of file
+pub mod fb;
+pub mod console;
+pub mod fonts;
@@ -1,5 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use alloc::prelude::*;
+use alloc::sync::Arc;
 use core::mem::size_of;
 use core::slice;
 
@@ -15,10 +16,13 @@ use crate::arch::cpu;
 use crate::HEAP_ALLOCATOR;
 use crate::memory::active_table;
 use crate::arch::consts::{KERNEL_OFFSET, MEMORY_OFFSET};
+use crate::sync::SpinNoIrqLock as Mutex;
 
 use super::super::{DeviceType, Driver, DRIVERS};
 use super::super::bus::virtio_mmio::*;
-use super::test::mandelbrot;
+use super::console::{init_console, FramebufferScreen};
+use super::fb::{register_framebuffer, Framebuffer};
+use super::fonts::Font8x16;
 
 const VIRTIO_GPU_EVENT_DISPLAY : u32 = 1 << 0;
 
@@ -32,6 +36,10 @@ struct VirtIOGpu {
     queues: [VirtIOVirtqueue; 2]
 }
 
+/// The driver and the framebuffer share the device
+#[derive(Clone)]
+struct VirtIOGpuDriver(Arc<Mutex<VirtIOGpu>>);
+
 #[repr(C)]
 #[derive(Debug)]
 struct VirtIOGpuConfig {
@@ -128,6 +136,8 @@ struct VirtIOGpuRespDisplayInfo {
 }
 
 const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+/// Pixels of 0x00RRGGBB in little endian
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -186,17 +196,19 @@ const VIRTIO_BUFFER_RECEIVE: usize = 1;
 
 const VIRTIO_GPU_RESOURCE_ID: u32 = 0xbabe;
 
-impl Driver for VirtIOGpu {
+impl Driver for VirtIOGpuDriver {
     fn try_handle_interrupt(&mut self) -> bool {
         // for simplicity
         if cpu::id() > 0 {
             return false
         }
 
+        let driver = self.0.lock();
+
         // ensure header page is mapped
-        active_table().map_if_not_exists(self.header as usize, self.header as usize);
+        active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
 
-        let header = unsafe { &mut *(self.header as *mut VirtIOHeader) };
+        let header = unsafe { &mut *(driver.header as *mut VirtIOHeader) };
         let interrupt = header.interrupt_status.read();
         if interrupt != 0 {
             header.interrupt_ack.write(interrupt);
@@ -232,7 +244,7 @@ fn setup_framebuffer(driver: &mut VirtIOGpu) {
     *request_resource_create_2d = VirtIOGpuResourceCreate2D {
         header: VirtIOGpuCtrlHdr::with_type(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
         resource_id: VIRTIO_GPU_RESOURCE_ID,
-        format: VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM,
+        format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
         width: response_get_display_info.rect.width,
         height: response_get_display_info.rect.height
     };
@@ -246,7 +258,6 @@ fn setup_framebuffer(driver: &mut VirtIOGpu) {
     let frame_buffer = unsafe {
         HEAP_ALLOCATOR.alloc_zeroed(Layout::from_size_align(size as usize, PAGE_SIZE).unwrap())
     } as usize;
-    mandelbrot(driver.rect.width, driver.rect.height, frame_buffer as *mut u32);
     driver.frame_buffer = frame_buffer;
     let request_resource_attach_backing = unsafe { &mut *(driver.queue_buffer[VIRTIO_BUFFER_TRANSMIT] as *mut VirtIOGpuResourceAttachBacking) };
     *request_resource_attach_backing = VirtIOGpuResourceAttachBacking {
@@ -275,36 +286,35 @@ fn setup_framebuffer(driver: &mut VirtIOGpu) {
     let response_set_scanout = unsafe { &mut *(driver.queue_buffer[VIRTIO_BUFFER_RECEIVE] as *mut VirtIOGpuCtrlHdr) };
     info!("response: {:?}", response_set_scanout);
 
-    flush_frame_buffer_to_screen(driver);
+    let rect = driver.rect;
+    flush_frame_buffer_to_screen(driver, rect);
 }
 
-fn flush_frame_buffer_to_screen(driver: &mut VirtIOGpu) {
+/// Copy `rect` of the frame buffer to the host and show it.
+/// It doesn't log, since the console may be on this screen.
+fn flush_frame_buffer_to_screen(driver: &mut VirtIOGpu, rect: VirtIOGpuRect) {
     // copy data from guest to host
     let request_transfer_to_host_2d = unsafe { &mut *(driver.queue_buffer[VIRTIO_BUFFER_TRANSMIT] as *mut VirtIOGpuTransferToHost2D) };
     *request_transfer_to_host_2d = VirtIOGpuTransferToHost2D {
         header: VirtIOGpuCtrlHdr::with_type(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
-        rect: driver.rect,
-        offset: 0,
+        rect,
+        offset: ((rect.y * driver.rect.width + rect.x) * 4) as u64,
         resource_id: VIRTIO_GPU_RESOURCE_ID,
         padding: 0
     };
     request(driver);
     driver.queues[VIRTIO_QUEUE_TRANSMIT].get_block();
-    let response_transfer_to_host_2d = unsafe { &mut *(driver.queue_buffer[VIRTIO_BUFFER_RECEIVE] as *mut VirtIOGpuCtrlHdr) };
-    info!("response: {:?}", response_transfer_to_host_2d);
 
     // flush data to screen
     let request_resource_flush = unsafe { &mut *(driver.queue_buffer[VIRTIO_BUFFER_TRANSMIT] as *mut VirtIOGpuResourceFlush) };
     *request_resource_flush = VirtIOGpuResourceFlush {
         header: VirtIOGpuCtrlHdr::with_type(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
-        rect: driver.rect,
+        rect,
         resource_id: VIRTIO_GPU_RESOURCE_ID,
         padding: 0
     };
     request(driver);
     driver.queues[VIRTIO_QUEUE_TRANSMIT].get_block();
-    let response_resource_flush = unsafe { &mut *(driver.queue_buffer[VIRTIO_BUFFER_RECEIVE] as *mut VirtIOGpuCtrlHdr) };
-    info!("response: {:?}", response_resource_flush);
 }
 
 pub fn virtio_gpu_init(node: &Node) {
@@ -356,5 +366,42 @@ pub fn virtio_gpu_init(node: &Node) {
 
     setup_framebuffer(&mut driver);
 
-    DRIVERS.lock().push(Box::new(driver));
+    let (width, height) = (driver.rect.width as usize, driver.rect.height as usize);
+    let buffer = driver.frame_buffer;
+    let driver = VirtIOGpuDriver(Arc::new(Mutex::new(driver)));
+    DRIVERS.lock().push(Box::new(driver.clone()));
+    register_framebuffer(Box::new(VirtIOGpuFramebuffer { driver, buffer, width, height }));
+    init_console(Box::new(FramebufferScreen::<Font8x16>::new()));
+}
+
+/// The frame buffer of a virtio-gpu device
+struct VirtIOGpuFramebuffer {
+    driver: VirtIOGpuDriver,
+    buffer: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Framebuffer for VirtIOGpuFramebuffer {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn pixels(&mut self) -> &mut [u32] {
+        unsafe { slice::from_raw_parts_mut(self.buffer as *mut u32, self.width * self.height) }
+    }
+
+    fn flush(&mut self, top: usize, bottom: usize) {
+        let rect = VirtIOGpuRect {
+            x: 0,
+            y: top as u32,
+            width: self.width as u32,
+            height: (bottom.min(self.height) - top) as u32,
+        };
+        flush_frame_buffer_to_screen(&mut self.driver.0.lock(), rect);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,107 @@
+//! Driver of the RISC-V platform-level interrupt controller, found on QEMU virt machines
+//!
+//! Interrupts of devices are routed to the hart which probed the PLIC, the others
+//! never claim any.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use device_tree::Node;
+use log::*;
+use rcore_memory::paging::PageTable;
+use rcore_memory::PAGE_SIZE;
+use crate::arch::cpu;
+use crate::memory::active_table;
+use super::super::device_tree::get_info;
+
+/// Priority of each source, as 32-bit registers from source 0
+const PRIORITY: usize = 0x0;
+/// Enable bits of each context, one bit per source
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// Threshold and claim/complete registers of each context
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+/// Sources the priority registers of one page can cover
+const MAX_SOURCES: u32 = (PAGE_SIZE / 4) as u32;
+
+/// Address of registers, 0 if there is no PLIC
+static BASE: AtomicUsize = AtomicUsize::new(0);
+/// Hart which takes the interrupts
+static HART: AtomicUsize = AtomicUsize::new(0);
+
+/// The context of supervisor mode of `hart`, or machine mode if the kernel runs in it
+fn context(hart: usize) -> usize {
+    if cfg!(feature = "m_mode") {
+        hart * 2
+    } else {
+        hart * 2 + 1
+    }
+}
+
+fn context_regs(base: usize, hart: usize) -> usize {
+    base + CONTEXT + context(hart) * CONTEXT_STRIDE
+}
+
+fn enable_regs(base: usize, hart: usize) -> usize {
+    base + ENABLE + context(hart) * ENABLE_STRIDE
+}
+
+pub fn plic_probe(node: &Node) {
+    let base = match get_info().device(&node.name).and_then(|dev| dev.regs.first()) {
+        Some(&(base, _)) => base,
+        None => return,
+    };
+    let hart = cpu::id();
+    info!("Detected PLIC at {:#x}, interrupts go to hart {}", base, hart);
+    // the registers span megabytes, only map the pages used
+    for &page in [base + PRIORITY, enable_regs(base, hart), context_regs(base, hart)].iter() {
+        let page = page & !(PAGE_SIZE - 1);
+        active_table().map(page, page);
+    }
+    unsafe {
+        // take any interrupt with a priority above 0
+        write_volatile((context_regs(base, hart) + THRESHOLD) as *mut u32, 0);
+    }
+    HART.store(hart, Ordering::Relaxed);
+    BASE.store(base, Ordering::Relaxed);
+}
+
+/// Enable interrupt source `irq`, return false if there is no PLIC to route it
+pub fn enable(irq: u32) -> bool {
+    let base = BASE.load(Ordering::Relaxed);
+    if base == 0 || irq == 0 || irq >= MAX_SOURCES {
+        return false;
+    }
+    let enable = enable_regs(base, HART.load(Ordering::Relaxed)) + (irq / 32) as usize * 4;
+    unsafe {
+        write_volatile((base + PRIORITY + irq as usize * 4) as *mut u32, 1);
+        let bits = read_volatile(enable as *const u32);
+        write_volatile(enable as *mut u32, bits | 1 << (irq % 32));
+    }
+    true
+}
+
+/// Take the pending interrupt with the highest priority, if any on this hart
+pub fn claim() -> Option<u32> {
+    let base = BASE.load(Ordering::Relaxed);
+    let hart = HART.load(Ordering::Relaxed);
+    if base == 0 || cpu::id() != hart {
+        return None;
+    }
+    match unsafe { read_volatile((context_regs(base, hart) + CLAIM) as *const u32) } {
+        0 => None,
+        irq => Some(irq),
+    }
+}
+
+/// Tell the PLIC that interrupt `irq` from `claim` has been handled
+pub fn complete(irq: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    let hart = HART.load(Ordering::Relaxed);
+    unsafe {
+        write_volatile((context_regs(base, hart) + CLAIM) as *mut u32, irq);
+    }
+}
@@ -2,22 +2,30 @@ use alloc::prelude::*;
 use core::any::Any;
 
 use lazy_static::lazy_static;
+use simple_filesystem::Device;
 use smoltcp::wire::EthernetAddress;
 
+use crate::fs::P9Transport;
 use crate::sync::SpinNoIrqLock;
 
-mod device_tree;
+pub mod device_tree;
 pub mod bus;
 pub mod net;
 pub mod block;
-mod gpu;
+pub mod gpu;
+pub mod p9;
 mod input;
+mod serial;
+mod rtc;
+pub mod irq;
 
 pub enum DeviceType {
     Net,
     Gpu,
     Input,
-    Block
+    Block,
+    Serial,
+    Fs
 }
 
 pub trait Driver : Send + AsAny {
@@ -35,6 +43,50 @@ pub trait NetDriver: Driver {
 
     // get interface name for this device
     fn get_ifname(&self) -> String;
+
+    // send an Ethernet frame
+    // return false if it can't be sent now
+    fn send(&self, frame: &[u8]) -> bool;
+
+    // receive an Ethernet frame into `buf` and return its length, truncated to `buf`
+    // return None if no frame has arrived
+    fn receive(&self, buf: &mut [u8]) -> Option<usize>;
+}
+
+pub trait BlockDriver: Driver {
+    // get a new handle to this device, used to mount file systems on it
+    fn get_device(&self) -> Box<Device>;
+
+    // get a new handle to the asynchronous interface of this device, if it has one
+    fn get_async(&self) -> Option<Box<AsyncBlockDriver>> {
+        None
+    }
+}
+
+// called when an asynchronous block request finishes
+pub trait BlockCompletion: Send {
+    // `ok` is false if the request failed
+    // it may be called in interrupt context, so it must not block
+    fn complete(self: Box<Self>, ok: bool);
+}
+
+pub trait AsyncBlockDriver: BlockDriver {
+    // submit a read of block `block_id`, a sector of 512 bytes, into `buf` and return immediately
+    // `done` is called when the read finishes, `buf` must stay valid until then
+    // return false if the request can't be submitted now, or `buf` is shorter than a block
+    fn read_block_async(&self, block_id: usize, buf: &'static mut [u8], done: Box<BlockCompletion>) -> bool;
+
+    // finish the requests which the device has completed
+    // needed if the interrupts of the device are not routed to the driver
+    fn poll(&self);
+}
+
+pub trait P9Driver: Driver {
+    // the tag which the host gave to the exported directory
+    fn mount_tag(&self) -> String;
+
+    // get a new handle to this device, used to mount a 9P file system on it
+    fn get_transport(&self) -> Box<P9Transport>;
 }
 
 // little hack, see https://users.rust-lang.org/t/how-to-downcast-from-a-trait-any-to-a-struct/11219/3
@@ -54,6 +106,14 @@ lazy_static! {
     pub static ref NET_DRIVERS: SpinNoIrqLock<Vec<Box<NetDriver>>> = SpinNoIrqLock::new(Vec::new());
 }
 
+lazy_static! {
+    pub static ref BLK_DRIVERS: SpinNoIrqLock<Vec<Box<BlockDriver>>> = SpinNoIrqLock::new(Vec::new());
+}
+
+lazy_static! {
+    pub static ref P9_DRIVERS: SpinNoIrqLock<Vec<Box<P9Driver>>> = SpinNoIrqLock::new(Vec::new());
+}
+
 pub fn init(dtb: usize) {
     device_tree::init(dtb);
 }
\ No newline at end of file
@@ -73,13 +73,32 @@ impl Driver for VirtIONetDriver {
 
 impl VirtIONet {
     fn transmit_available(&self) -> bool {
-        self.queues[VIRTIO_QUEUE_TRANSMIT].can_add(1, 0)
+        // a finished buffer can be reused
+        self.queues[VIRTIO_QUEUE_TRANSMIT].can_add(1, 0) || self.queues[VIRTIO_QUEUE_TRANSMIT].can_get()
     }
 
-
     fn receive_available(&self) -> bool {
         self.queues[VIRTIO_QUEUE_RECEIVE].can_get()
     }
+
+    /// Get a page to put a frame to transmit in,
+    /// reusing one the device has finished with if any
+    fn transmit_buffer(&mut self) -> &'static mut [u8] {
+        let page = if let Some((_, output, _, _)) = self.queues[VIRTIO_QUEUE_TRANSMIT].get() {
+            output[0].as_ptr() as usize
+        } else {
+            unsafe {
+                HEAP_ALLOCATOR.alloc_zeroed(Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap())
+            } as usize
+        };
+        unsafe { slice::from_raw_parts_mut(page as *mut u8, PAGE_SIZE) }
+    }
+
+    /// Transmit the first `len` bytes of frame in `buffer`, got by `transmit_buffer`
+    fn transmit(&mut self, buffer: &'static [u8], len: usize) {
+        let output = &buffer[..size_of::<VirtIONetHeader>() + len];
+        assert!(self.queues[VIRTIO_QUEUE_TRANSMIT].add_and_notify(&[], &[output], 0));
+    }
 }
 
 impl NetDriver for VirtIONetDriver {
@@ -91,6 +110,37 @@ impl NetDriver for VirtIONetDriver {
         format!("virtio{}", self.0.lock().interrupt)
     }
 
+    fn send(&self, frame: &[u8]) -> bool {
+        if size_of::<VirtIONetHeader>() + frame.len() > PAGE_SIZE {
+            return false;
+        }
+        let mut driver = self.0.lock();
+
+        // ensure header page is mapped
+        active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
+
+        if !driver.transmit_available() {
+            return false;
+        }
+        let buffer = driver.transmit_buffer();
+        buffer[size_of::<VirtIONetHeader>()..size_of::<VirtIONetHeader>() + frame.len()].copy_from_slice(frame);
+        driver.transmit(buffer, frame.len());
+        true
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut driver = self.0.lock();
+
+        // ensure header page is mapped
+        active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
+
+        let (input, output, len, user_data) = driver.queues[VIRTIO_QUEUE_RECEIVE].get()?;
+        let frame = &input[0][size_of::<VirtIONetHeader>()..len];
+        let copied = frame.len().min(buf.len());
+        buf[..copied].copy_from_slice(&frame[..copied]);
+        driver.queues[VIRTIO_QUEUE_RECEIVE].add_and_notify(&input, &output, user_data);
+        Some(copied)
+    }
 }
 
 pub struct VirtIONetRxToken(VirtIONetDriver);
@@ -128,11 +178,11 @@ impl<'a> phy::Device<'a> for VirtIONetDriver {
     }
 }
 
-impl phy::RxToken for VirtIONetRxToken { 
+impl phy::RxToken for VirtIONetRxToken {
     fn consume<R, F>(self, _timestamp: Instant, f: F) -> Result<R>
         where F: FnOnce(&[u8]) -> Result<R>
     {
-        let (input, output, _, user_data) = {
+        let (input, output, len, user_data) = {
             let mut driver = (self.0).0.lock();
 
             // ensure header page is mapped
@@ -140,7 +190,8 @@ impl phy::RxToken for VirtIONetRxToken {
 
             driver.queues[VIRTIO_QUEUE_RECEIVE].get().unwrap()
         };
-        let result = f(&input[0][size_of::<VirtIONetHeader>()..]);
+        // the lock is not held, as `f` may transmit a response
+        let result = f(&input[0][size_of::<VirtIONetHeader>()..len]);
 
         let mut driver = (self.0).0.lock();
         driver.queues[VIRTIO_QUEUE_RECEIVE].add_and_notify(&input, &output, user_data);
@@ -152,28 +203,14 @@ impl phy::TxToken for VirtIONetTxToken {
     fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
         where F: FnOnce(&mut [u8]) -> Result<R>,
     {
-        let output = {
-            let mut driver = (self.0).0.lock();
-
-            // ensure header page is mapped
-            active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
+        let mut driver = (self.0).0.lock();
 
-            if let Some((_, output, _, _)) = driver.queues[VIRTIO_QUEUE_TRANSMIT].get() {
-                unsafe { slice::from_raw_parts_mut(output[0].as_ptr() as *mut u8, output[0].len())}
-            } else {
-                // allocate a page for buffer
-                let page = unsafe {
-                    HEAP_ALLOCATOR.alloc_zeroed(Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap())
-                } as usize;
-                unsafe { slice::from_raw_parts_mut(page as *mut u8, PAGE_SIZE) }
-            }
-        };
-        let output_buffer = &mut output[size_of::<VirtIONetHeader>()..(size_of::<VirtIONetHeader>() +len)];
-        let result = f(output_buffer);
-        println!("output {:?}", output_buffer);
+        // ensure header page is mapped
+        active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
 
-        let mut driver = (self.0).0.lock();
-        assert!(driver.queues[VIRTIO_QUEUE_TRANSMIT].add_and_notify(&[], &[output], 0));
+        let buffer = driver.transmit_buffer();
+        let result = f(&mut buffer[size_of::<VirtIONetHeader>()..size_of::<VirtIONetHeader>() + len]);
+        driver.transmit(buffer, len);
         result
     }
 }
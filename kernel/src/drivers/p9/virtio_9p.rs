@@ -0,0 +1,143 @@
+//! Driver of virtio 9P transports, each exporting a directory of the host
+//!
+//! A device is known by its mount tag, e.g. `-virtfs local,path=dir,mount_tag=host0,...`
+//! of QEMU, and is mounted by option `root=9p:host0` of the command line.
+
+use alloc::prelude::*;
+use alloc::sync::Arc;
+use core::ptr::read_volatile;
+
+use bitflags::*;
+use device_tree::Node;
+use device_tree::util::SliceRead;
+use log::*;
+use rcore_memory::PAGE_SIZE;
+use rcore_memory::paging::PageTable;
+
+use crate::fs::{P9Transport, P9_MSIZE};
+use crate::memory::active_table;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+use super::super::{DeviceType, Driver, P9Driver, DRIVERS, P9_DRIVERS};
+use super::super::bus::virtio_mmio::*;
+
+pub struct VirtIO9p {
+    header: usize,
+    queue: VirtIOVirtqueue,
+    tag: String,
+}
+
+#[derive(Clone)]
+pub struct VirtIO9pDriver(Arc<Mutex<VirtIO9p>>);
+
+bitflags! {
+    struct VirtIO9pFeature : u64 {
+        const MOUNT_TAG = 1 << 0;
+        // device independent
+        const NOTIFY_ON_EMPTY = 1 << 24; // legacy
+        const ANY_LAYOUT = 1 << 27; // legacy
+        const RING_INDIRECT_DESC = 1 << 28;
+        const RING_EVENT_IDX = 1 << 29;
+        const UNUSED = 1 << 30; // legacy
+        const VERSION_1 = 1 << 32; // detect legacy
+    }
+}
+
+impl Driver for VirtIO9pDriver {
+    fn try_handle_interrupt(&mut self) -> bool {
+        let driver = self.0.lock();
+
+        // ensure header page is mapped
+        active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
+        let header = unsafe { &mut *(driver.header as *mut VirtIOHeader) };
+        let interrupt = header.interrupt_status.read();
+        if interrupt != 0 {
+            // requests are waited for by polling, see `request`
+            header.interrupt_ack.write(interrupt);
+            return true;
+        }
+        return false;
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Fs
+    }
+}
+
+impl P9Driver for VirtIO9pDriver {
+    fn mount_tag(&self) -> String {
+        self.0.lock().tag.clone()
+    }
+
+    fn get_transport(&self) -> Box<P9Transport> {
+        Box::new(self.clone())
+    }
+}
+
+impl P9Transport for VirtIO9pDriver {
+    fn request(&mut self, msg: &[u8]) -> Option<Vec<u8>> {
+        let mut driver = self.0.lock();
+        // ensure header page is mapped
+        active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
+
+        // both buffers are on the kernel heap, which the device can address
+        let reply = vec![0u8; P9_MSIZE as usize];
+        if !driver.queue.add_and_notify(&[&reply], &[msg], 0) {
+            return None;
+        }
+        let (_, _, len, _) = driver.queue.get_block();
+        // the device writes the reply behind the compiler's back
+        let len = len.min(reply.len());
+        let mut reply: Vec<u8> = reply[..len].iter().map(|b| unsafe { read_volatile(b) }).collect();
+        if len < 4 {
+            warn!("virtio 9p: short reply of {} bytes", len);
+            return None;
+        }
+        let size = u32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]) as usize;
+        reply.truncate(size);
+        Some(reply)
+    }
+}
+
+pub fn virtio_9p_init(node: &Node) {
+    let reg = node.prop_raw("reg").unwrap();
+    let from = reg.as_slice().read_be_u64(0).unwrap();
+    let header = unsafe { &mut *(from as *mut VirtIOHeader) };
+
+    header.status.write(VirtIODeviceStatus::DRIVER.bits());
+
+    let device_features_bits = header.read_device_features();
+    let device_features = VirtIO9pFeature::from_bits_truncate(device_features_bits);
+    info!("Device features {:?}", device_features);
+
+    // negotiate these flags only
+    let supported_features = VirtIO9pFeature::MOUNT_TAG;
+    let driver_features = (device_features & supported_features).bits();
+    header.write_driver_features(driver_features);
+
+    // read configuration space: tag_len: u16, then the tag, not NUL terminated
+    let config = (from + VIRTIO_CONFIG_SPACE_OFFSET) as *const u8;
+    let tag = if device_features.contains(VirtIO9pFeature::MOUNT_TAG) {
+        let len = unsafe { read_volatile(config as *const u16) } as usize;
+        let bytes: Vec<u8> = (0..len).map(|i| unsafe { read_volatile(config.add(2 + i)) }).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    } else {
+        String::new()
+    };
+    info!("Found a 9p transport with mount tag {:?}", tag);
+
+    // virtio 4.2.4 Legacy interface
+    header.guest_page_size.write(PAGE_SIZE as u32); // one page
+
+    let driver = VirtIO9pDriver(Arc::new(Mutex::new(VirtIO9p {
+        header: from as usize,
+        // one request at a time, of two buffers
+        queue: VirtIOVirtqueue::new(header, 0, 2),
+        tag,
+    })));
+
+    header.status.write(VirtIODeviceStatus::DRIVER_OK.bits());
+
+    DRIVERS.lock().push(Box::new(driver.clone()));
+    P9_DRIVERS.lock().push(Box::new(driver));
+}
@@ -0,0 +1,40 @@
+//! Driver of the Goldfish real-time clock, found on QEMU virt machines
+
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use device_tree::Node;
+use log::*;
+use rcore_memory::paging::PageTable;
+use crate::memory::active_table;
+use crate::time;
+use super::super::device_tree::get_info;
+
+/// Nanoseconds since the Unix epoch, reading the low half latches the high half
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+/// Address of registers
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Read the date and time, as seconds since the Unix epoch
+fn read() -> u64 {
+    let base = BASE.load(Ordering::Relaxed);
+    let ns = unsafe {
+        let low = read_volatile((base + TIME_LOW) as *const u32);
+        let high = read_volatile((base + TIME_HIGH) as *const u32);
+        (high as u64) << 32 | low as u64
+    };
+    ns / 1_000_000_000
+}
+
+pub fn goldfish_rtc_probe(node: &Node) {
+    let from = match get_info().device(&node.name).and_then(|dev| dev.regs.first()) {
+        Some(&(from, _)) => from,
+        None => return,
+    };
+    info!("Detected Goldfish RTC at {:#x}", from);
+    active_table().map(from, from);
+    BASE.store(from, Ordering::Relaxed);
+    time::register_rtc(read);
+    info!("rtc: {:?}", time::now());
+}
@@ -0,0 +1 @@
+pub mod virtio_console;
\ No newline at end of file
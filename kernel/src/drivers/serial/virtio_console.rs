@@ -0,0 +1,378 @@
+//! Driver of virtio console devices
+//!
+//! Each port is a character device `vport{n}p{id}`, also registered by its name
+//! if the host gives one. A device which is not multiport has port 0 only.
+
+use alloc::collections::VecDeque;
+use alloc::prelude::*;
+use alloc::sync::Arc;
+use core::any::Any;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use bitflags::*;
+use device_tree::Node;
+use device_tree::util::SliceRead;
+use log::*;
+use rcore_memory::PAGE_SIZE;
+use rcore_memory::paging::PageTable;
+use simple_filesystem::*;
+use volatile::{ReadOnly, WriteOnly};
+
+use crate::arch::cpu;
+use crate::fs::register_device;
+use crate::memory::active_table;
+use crate::sync::Condvar;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+use super::super::{DeviceType, Driver, DRIVERS};
+use super::super::bus::virtio_mmio::*;
+
+/// Ports set up at most, the device may offer more
+const MAX_PORTS: usize = 4;
+const QUEUE_SIZE: usize = 16;
+/// Size of each receive buffer
+const BUFFER_SIZE: usize = 512;
+
+/// Receive and transmit queues of port 0
+const VIRTIO_QUEUE_PORT0_RECEIVE: usize = 0;
+/// Queues of control messages, then the queues of ports from 1
+const VIRTIO_QUEUE_CONTROL_RECEIVE: usize = 2;
+const VIRTIO_QUEUE_CONTROL_TRANSMIT: usize = 3;
+
+// control events
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Number of virtio consoles found, to name the ports
+static CONSOLES: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C)]
+#[derive(Debug)]
+struct VirtIOConsoleConfig {
+    cols: ReadOnly<u16>,
+    rows: ReadOnly<u16>,
+    max_nr_ports: ReadOnly<u32>,
+    emerg_wr: WriteOnly<u32>,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct VirtIOConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+bitflags! {
+    struct VirtIOConsoleFeature : u64 {
+        const SIZE = 1 << 0;
+        const MULTIPORT = 1 << 1;
+        const EMERG_WRITE = 1 << 2;
+        // device independent
+        const NOTIFY_ON_EMPTY = 1 << 24; // legacy
+        const ANY_LAYOUT = 1 << 27; // legacy
+        const RING_INDIRECT_DESC = 1 << 28;
+        const RING_EVENT_IDX = 1 << 29;
+        const UNUSED = 1 << 30; // legacy
+        const VERSION_1 = 1 << 32; // detect legacy
+    }
+}
+
+/// Data received on a port, waiting to be read
+#[derive(Default)]
+struct PortInput {
+    data: Mutex<VecDeque<u8>>,
+    pushed: Condvar,
+}
+
+struct VirtIOConsole {
+    interrupt_parent: u32,
+    interrupt: u32,
+    header: usize,
+    /// Index of the device, used in names of ports
+    num: usize,
+    multiport: bool,
+    /// Receive and transmit queue of each port, then the control queues
+    queues: Vec<VirtIOVirtqueue>,
+    /// Receive buffers of each receive queue, the control receive queue is the last
+    buffers: Vec<Vec<Box<[u8; BUFFER_SIZE]>>>,
+    /// Buffer of data sent on a port, one transfer at a time
+    transmit: Box<[u8; PAGE_SIZE]>,
+    /// Buffers of control messages sent, and those free
+    control: Box<[VirtIOConsoleControl; QUEUE_SIZE]>,
+    control_free: Vec<usize>,
+    ports: Vec<Arc<PortInput>>,
+    /// Ports added by the device, with the names to register them as
+    added: Vec<(usize, String)>,
+}
+
+/// The driver and the ports share the device
+#[derive(Clone)]
+struct VirtIOConsoleDriver(Arc<Mutex<VirtIOConsole>>);
+
+/// Queue numbers of receive and transmit queues of port `id`
+fn port_queues(id: usize) -> (usize, usize) {
+    match id {
+        0 => (VIRTIO_QUEUE_PORT0_RECEIVE, VIRTIO_QUEUE_PORT0_RECEIVE + 1),
+        id => (2 * id + 2, 2 * id + 3),
+    }
+}
+
+impl VirtIOConsole {
+    /// Index in `queues` of queue number `queue`, if it is set up
+    fn queue_index(&self, queue: usize) -> usize {
+        match queue {
+            VIRTIO_QUEUE_CONTROL_RECEIVE => self.ports.len() * 2,
+            VIRTIO_QUEUE_CONTROL_TRANSMIT => self.ports.len() * 2 + 1,
+            queue if queue < 2 => queue,
+            queue => queue - 2,
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16) {
+        let index = self.queue_index(VIRTIO_QUEUE_CONTROL_TRANSMIT);
+        // take back the buffers of messages already sent
+        while let Some((_, _, _, slot)) = self.queues[index].get() {
+            self.control_free.push(slot);
+        }
+        let slot = match self.control_free.pop() {
+            Some(slot) => slot,
+            None => {
+                let (_, _, _, slot) = self.queues[index].get_block();
+                slot
+            }
+        };
+        self.control[slot] = VirtIOConsoleControl { id, event, value };
+        let message = unsafe {
+            slice::from_raw_parts(&self.control[slot] as *const _ as *const u8, size_of::<VirtIOConsoleControl>())
+        };
+        self.queues[index].add_and_notify(&[], &[message], slot);
+    }
+
+    /// Handle a control message from the device, `extra` follows it
+    fn handle_control(&mut self, message: VirtIOConsoleControl, extra: &[u8]) {
+        let id = message.id as usize;
+        match message.event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                if id >= self.ports.len() {
+                    warn!("virtio-console: port {} is over the limit of {}", id, self.ports.len());
+                    self.send_control(message.id, VIRTIO_CONSOLE_PORT_READY, 0);
+                    return;
+                }
+                self.send_control(message.id, VIRTIO_CONSOLE_PORT_READY, 1);
+                // the port is always open on our side
+                self.send_control(message.id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+                let name = format!("vport{}p{}", self.num, id);
+                self.added.push((id, name));
+            }
+            VIRTIO_CONSOLE_DEVICE_REMOVE => info!("virtio-console: port {} removed", id),
+            VIRTIO_CONSOLE_CONSOLE_PORT if id < self.ports.len() => {
+                self.send_control(message.id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            }
+            VIRTIO_CONSOLE_PORT_NAME if id < self.ports.len() => {
+                let name = str::from_utf8(extra).unwrap_or("").trim_end_matches('\0');
+                info!("virtio-console: port {} is {}", id, name);
+                if !name.is_empty() && !name.contains('/') {
+                    self.added.push((id, String::from(name)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Move received data to the ports, and handle control messages
+    fn receive(&mut self) {
+        let receive_queues = (0..self.ports.len()).map(|id| port_queues(id).0)
+            .chain(if self.multiport { Some(VIRTIO_QUEUE_CONTROL_RECEIVE) } else { None });
+        let receive_queues: Vec<usize> = receive_queues.collect();
+        for (i, &queue) in receive_queues.iter().enumerate() {
+            let index = self.queue_index(queue);
+            while let Some((_, _, len, slot)) = self.queues[index].get() {
+                let data = self.buffers[i][slot][..len.min(BUFFER_SIZE)].to_vec();
+                if queue == VIRTIO_QUEUE_CONTROL_RECEIVE {
+                    if data.len() >= size_of::<VirtIOConsoleControl>() {
+                        let message = unsafe { ptr::read_unaligned(data.as_ptr() as *const VirtIOConsoleControl) };
+                        self.handle_control(message, &data[size_of::<VirtIOConsoleControl>()..]);
+                    }
+                } else {
+                    let port = &self.ports[i];
+                    port.data.lock().extend(data.iter());
+                    port.pushed.notify_all();
+                }
+                let buffer = &self.buffers[i][slot][..];
+                self.queues[index].add_and_notify(&[buffer], &[], slot);
+            }
+        }
+    }
+
+    /// Send `data` on port `id`, and wait until the device takes it
+    fn send(&mut self, id: usize, data: &[u8]) {
+        let index = self.queue_index(port_queues(id).1);
+        for chunk in data.chunks(PAGE_SIZE) {
+            self.transmit[..chunk.len()].copy_from_slice(chunk);
+            let buffer = &self.transmit[..chunk.len()];
+            self.queues[index].add_and_notify(&[], &[buffer], 0);
+            self.queues[index].get_block();
+        }
+    }
+}
+
+impl Driver for VirtIOConsoleDriver {
+    fn try_handle_interrupt(&mut self) -> bool {
+        // for simplicity
+        if cpu::id() > 0 {
+            return false
+        }
+
+        let mut driver = self.0.lock();
+
+        // ensure header page is mapped
+        active_table().map_if_not_exists(driver.header as usize, driver.header as usize);
+
+        let header = unsafe { &mut *(driver.header as *mut VirtIOHeader) };
+        let interrupt = header.interrupt_status.read();
+        if interrupt != 0 {
+            header.interrupt_ack.write(interrupt);
+            debug!("Got interrupt {:?}", interrupt);
+            driver.receive();
+            let added: Vec<(usize, String)> = driver.added.drain(..).collect();
+            let inputs: Vec<Arc<PortInput>> = added.iter().map(|&(id, _)| driver.ports[id].clone()).collect();
+            drop(driver);
+            for ((id, name), input) in added.into_iter().zip(inputs) {
+                let inode = PortINode { console: self.clone(), id, input };
+                register_device(&name, Arc::new(inode));
+            }
+            return true;
+        }
+        return false;
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Serial
+    }
+}
+
+/// A port of a virtio console as a file
+struct PortINode {
+    console: VirtIOConsoleDriver,
+    id: usize,
+    input: Arc<PortInput>,
+}
+
+impl INode for PortINode {
+    /// Wait for data, then read what has arrived
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut data = self.input.data.lock();
+        while data.is_empty() {
+            data = self.input.pushed.wait(data);
+        }
+        let len = buf.len().min(data.len());
+        for (b, d) in buf.iter_mut().zip(data.drain(..len)) {
+            *b = d;
+        }
+        Ok(len)
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        self.console.0.lock().send(self.id, buf);
+        Ok(buf.len())
+    }
+    impl_inode!();
+}
+
+pub fn virtio_console_init(node: &Node) {
+    let reg = node.prop_raw("reg").unwrap();
+    let from = reg.as_slice().read_be_u64(0).unwrap();
+    let header = unsafe { &mut *(from as *mut VirtIOHeader) };
+
+    header.status.write(VirtIODeviceStatus::DRIVER.bits());
+
+    let device_features_bits = header.read_device_features();
+    let device_features = VirtIOConsoleFeature::from_bits_truncate(device_features_bits);
+    info!("Device features {:?}", device_features);
+
+    // negotiate these flags only
+    let supported_features = VirtIOConsoleFeature::MULTIPORT;
+    let driver_features = (device_features & supported_features).bits();
+    header.write_driver_features(driver_features);
+
+    // read configuration space
+    let config = unsafe { &mut *((from + VIRTIO_CONFIG_SPACE_OFFSET) as *mut VirtIOConsoleConfig) };
+    info!("Config: {:?}", config);
+    let multiport = device_features.contains(VirtIOConsoleFeature::MULTIPORT);
+    let num_ports = if multiport {
+        (config.max_nr_ports.read() as usize).min(MAX_PORTS).max(1)
+    } else {
+        1
+    };
+
+    // virtio 4.2.4 Legacy interface
+    header.guest_page_size.write(PAGE_SIZE as u32); // one page
+
+    let mut queues = Vec::new();
+    for id in 0..num_ports {
+        let (receive, transmit) = port_queues(id);
+        queues.push(VirtIOVirtqueue::new(header, receive, QUEUE_SIZE));
+        queues.push(VirtIOVirtqueue::new(header, transmit, QUEUE_SIZE));
+    }
+    let mut receive_queues = num_ports;
+    if multiport {
+        queues.push(VirtIOVirtqueue::new(header, VIRTIO_QUEUE_CONTROL_RECEIVE, QUEUE_SIZE));
+        queues.push(VirtIOVirtqueue::new(header, VIRTIO_QUEUE_CONTROL_TRANSMIT, QUEUE_SIZE));
+        receive_queues += 1;
+    }
+
+    let mut driver = VirtIOConsole {
+        interrupt: node.prop_u32("interrupts").unwrap(),
+        interrupt_parent: node.prop_u32("interrupt-parent").unwrap(),
+        header: from as usize,
+        num: CONSOLES.fetch_add(1, Ordering::Relaxed),
+        multiport,
+        queues,
+        buffers: (0..receive_queues).map(|_| {
+            (0..QUEUE_SIZE).map(|_| Box::new([0u8; BUFFER_SIZE])).collect()
+        }).collect(),
+        transmit: Box::new([0u8; PAGE_SIZE]),
+        control: Box::new([VirtIOConsoleControl::default(); QUEUE_SIZE]),
+        control_free: (0..QUEUE_SIZE).collect(),
+        ports: (0..num_ports).map(|_| Arc::new(PortInput::default())).collect(),
+        added: Vec::new(),
+    };
+
+    // fill the receive queues
+    for i in 0..receive_queues {
+        let queue = if i < num_ports { port_queues(i).0 } else { VIRTIO_QUEUE_CONTROL_RECEIVE };
+        let index = driver.queue_index(queue);
+        for slot in 0..QUEUE_SIZE {
+            let buffer = &driver.buffers[i][slot][..];
+            driver.queues[index].add(&[buffer], &[], slot);
+        }
+        driver.queues[index].notify();
+    }
+
+    header.status.write(VirtIODeviceStatus::DRIVER_OK.bits());
+
+    // a multiport device tells which ports are there, port 0 is always there otherwise
+    let port0 = if multiport {
+        driver.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+        None
+    } else {
+        Some(driver.ports[0].clone())
+    };
+
+    let num = driver.num;
+    let driver = VirtIOConsoleDriver(Arc::new(Mutex::new(driver)));
+    if let Some(input) = port0 {
+        let inode = PortINode { console: driver.clone(), id: 0, input };
+        register_device(&format!("vport{}p0", num), Arc::new(inode));
+    }
+    DRIVERS.lock().push(Box::new(driver));
+}
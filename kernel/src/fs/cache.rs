@@ -0,0 +1,372 @@
+//! LRU block cache between file system and device
+
+use simple_filesystem::Device;
+use alloc::{boxed::Box, sync::{Arc, Weak}, collections::BTreeMap, vec::Vec};
+use lazy_static::lazy_static;
+use crate::sync::{SpinNoIrqLock, ThreadLock as Mutex};
+
+/// Size of a cached block, same as the block size of SFS
+pub const BLOCK_SIZE: usize = 4096;
+
+/// A write-back block cache on top of a device, which is also a device itself.
+///
+/// Recently used blocks are kept in memory, the least recently used one
+/// is evicted when the cache is full. Pinned blocks are never evicted.
+///
+/// Writes only modify the cached blocks and mark them dirty. Dirty blocks are
+/// written to the device when evicted, when more than half of the cache is dirty,
+/// or when `flush()` is called. They are always written in ascending block order,
+/// and adjacent ones are merged into one request.
+///
+/// When misses are sequential, following blocks are read ahead in one device read.
+/// A block fully overwritten is not read.
+///
+/// A short read of the device, e.g. at its end, is cached as a short block,
+/// and reads and writes of the cache are short there too.
+///
+/// The handle can be cloned, so the cache is still accessible
+/// after the device is given to a file system.
+///
+/// Device I/O may take long, so the cache is protected by a `ThreadLock`,
+/// others accessing it sleep instead of spinning with interrupts disabled.
+/// Do not access it while holding a spin lock.
+#[derive(Clone)]
+pub struct BlockCache(Arc<Mutex<CacheInner>>);
+
+struct CacheInner {
+    device: Box<Device>,
+    capacity: usize,
+    blocks: BTreeMap<usize, CacheBlock>,
+    /// Unpinned blocks by the time they were last used, the first is evicted
+    lru: BTreeMap<usize, usize>,
+    /// Incremented on each access, used as the LRU timestamp
+    tick: usize,
+    /// Number of dirty blocks
+    dirty: usize,
+    /// The last block read from device, to detect sequential reads
+    last_read: Option<usize>,
+    stats: CacheStats,
+}
+
+struct CacheBlock {
+    /// Shorter than `BLOCK_SIZE` if the device ends in the block
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: usize,
+    /// Number of `pin` not yet undone by `unpin`
+    pins: usize,
+}
+
+/// Statistics of a block cache
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub writebacks: usize,
+    /// Number of blocks read ahead
+    pub readaheads: usize,
+}
+
+/// Max number of blocks read from device at once when reading sequentially
+const READAHEAD_BLOCKS: usize = 8;
+/// Max number of adjacent dirty blocks written to device at once
+const MAX_MERGE_BLOCKS: usize = 16;
+
+lazy_static! {
+    /// All block caches, so they can be flushed together
+    static ref CACHES: SpinNoIrqLock<Vec<Weak<Mutex<CacheInner>>>> = SpinNoIrqLock::new(Vec::new());
+}
+
+/// Write dirty blocks of all caches to devices
+pub fn flush_all() -> Option<()> {
+    let caches: Vec<_> = {
+        let mut caches = CACHES.lock();
+        caches.retain(|cache| cache.upgrade().is_some());
+        caches.iter().filter_map(|cache| cache.upgrade()).collect()
+    };
+    for cache in caches.iter() {
+        cache.lock().flush()?;
+    }
+    Some(())
+}
+
+/// Statistics of all block caches
+pub fn all_stats() -> Vec<CacheStats> {
+    let caches: Vec<_> = {
+        let mut caches = CACHES.lock();
+        caches.retain(|cache| cache.upgrade().is_some());
+        caches.iter().filter_map(|cache| cache.upgrade()).collect()
+    };
+    caches.iter().map(|cache| cache.lock().stats).collect()
+}
+
+impl BlockCache {
+    /// Create a cache of `capacity` blocks on `device`
+    pub fn new(device: Box<Device>, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        let cache = BlockCache(Arc::new(Mutex::new(CacheInner {
+            device,
+            capacity,
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            dirty: 0,
+            last_read: None,
+            stats: CacheStats::default(),
+        })));
+        CACHES.lock().push(Arc::downgrade(&cache.0));
+        cache
+    }
+    /// Write all dirty blocks to the device
+    pub fn flush(&self) -> Option<()> {
+        self.0.lock().flush()
+    }
+    pub fn stats(&self) -> CacheStats {
+        self.0.lock().stats
+    }
+    /// Keep block `id` in the cache until `unpin` is called as many times,
+    /// e.g. for metadata which is accessed frequently
+    pub fn pin(&self, id: usize) -> Option<()> {
+        let mut inner = self.0.lock();
+        inner.get(id)?;
+        let block = inner.blocks.get_mut(&id).unwrap();
+        block.pins += 1;
+        let last_used = block.last_used;
+        inner.lru.remove(&last_used);
+        Some(())
+    }
+    /// Undo a `pin` of block `id`
+    pub fn unpin(&self, id: usize) {
+        let mut inner = self.0.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let block = inner.blocks.get_mut(&id).expect("unpin a block not pinned");
+        assert!(block.pins > 0, "unpin a block not pinned");
+        block.pins -= 1;
+        if block.pins == 0 {
+            // as if it was just used, so that it is not evicted at once
+            block.last_used = tick;
+            inner.lru.insert(tick, id);
+        }
+    }
+}
+
+impl CacheInner {
+    /// Get block `id`, read it from device on miss
+    fn get(&mut self, id: usize) -> Option<&mut CacheBlock> {
+        if self.blocks.contains_key(&id) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            self.read_blocks(id)?;
+        }
+        self.touch(id);
+        self.blocks.get_mut(&id)
+    }
+    /// Get block `id` to overwrite it completely, it is not read from device on miss
+    fn get_for_overwrite(&mut self, id: usize) -> Option<&mut CacheBlock> {
+        if !self.blocks.contains_key(&id) {
+            self.make_room(1)?;
+            self.insert(id, vec![0; BLOCK_SIZE]);
+        }
+        self.touch(id);
+        self.blocks.get_mut(&id)
+    }
+    /// Mark block `id` as the most recently used
+    fn touch(&mut self, id: usize) {
+        self.tick += 1;
+        let block = self.blocks.get_mut(&id).unwrap();
+        if block.pins == 0 {
+            self.lru.remove(&block.last_used);
+            self.lru.insert(self.tick, id);
+        }
+        block.last_used = self.tick;
+    }
+    fn insert(&mut self, id: usize, data: Vec<u8>) {
+        self.tick += 1;
+        self.blocks.insert(id, CacheBlock { data, dirty: false, last_used: self.tick, pins: 0 });
+        self.lru.insert(self.tick, id);
+    }
+    /// Evict blocks until `count` more blocks fit
+    fn make_room(&mut self, count: usize) -> Option<()> {
+        while self.blocks.len() + count > self.capacity {
+            self.evict()?;
+        }
+        Some(())
+    }
+    /// Read block `id` from device.
+    /// If it follows the last read block, also read ahead the following blocks.
+    fn read_blocks(&mut self, id: usize) -> Option<()> {
+        let mut count = 1;
+        if self.last_read == Some(id.wrapping_sub(1)) {
+            let max = READAHEAD_BLOCKS.min(self.capacity / 2).max(1);
+            while count < max && !self.blocks.contains_key(&(id + count)) {
+                count += 1;
+            }
+        }
+        // no room to read ahead if most blocks are pinned
+        if self.make_room(count).is_none() {
+            count = 1;
+            self.make_room(count)?;
+        }
+        let mut data = vec![0; count * BLOCK_SIZE];
+        let mut len = match self.device.read_at(id * BLOCK_SIZE, &mut data) {
+            Some(len) => len,
+            // maybe read beyond the end of device, read only the requested one
+            None if count > 1 => {
+                count = 1;
+                data.truncate(BLOCK_SIZE);
+                self.device.read_at(id * BLOCK_SIZE, &mut data)?
+            }
+            None => return None,
+        };
+        // the device ends in the requested block, or the read is broken
+        if len == 0 || len > data.len() {
+            return None;
+        }
+        data.truncate(len);
+        // blocks read ahead but beyond the end are not cached
+        count = (len + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            if !self.blocks.contains_key(&(id + i)) {
+                self.insert(id + i, chunk.to_vec());
+            }
+            len -= chunk.len();
+        }
+        debug_assert_eq!(len, 0);
+        self.stats.readaheads += count - 1;
+        self.last_read = Some(id + count - 1);
+        Some(())
+    }
+    /// Drop the least recently used unpinned block, write it back if dirty.
+    /// Fail if all blocks are pinned.
+    fn evict(&mut self) -> Option<()> {
+        let (&last_used, &id) = self.lru.iter().next()?;
+        self.write_back(id)?;
+        self.lru.remove(&last_used);
+        self.blocks.remove(&id);
+        self.stats.evictions += 1;
+        Some(())
+    }
+    /// Write block `id` to device if it is dirty
+    fn write_back(&mut self, id: usize) -> Option<()> {
+        let block = self.blocks.get_mut(&id).unwrap();
+        if block.dirty {
+            let len = self.device.write_at(id * BLOCK_SIZE, &block.data)?;
+            block.dirty = false;
+            self.dirty -= 1;
+            self.stats.writebacks += 1;
+            if len != block.data.len() {
+                // the device ends in the block, the rest is lost
+                block.data.truncate(len);
+                return None;
+            }
+        }
+        Some(())
+    }
+    /// Write all dirty blocks to device.
+    /// Runs of adjacent dirty blocks are merged into one device write.
+    fn flush(&mut self) -> Option<()> {
+        let dirty_ids: Vec<usize> = self.blocks.iter()
+            .filter(|(_, b)| b.dirty)
+            .map(|(&id, _)| id)
+            .collect();
+        let mut i = 0;
+        while i < dirty_ids.len() {
+            let start = dirty_ids[i];
+            let mut count = 1;
+            while count < MAX_MERGE_BLOCKS && i + count < dirty_ids.len()
+                && dirty_ids[i + count] == start + count {
+                count += 1;
+            }
+            if count == 1 {
+                self.write_back(start)?;
+            } else {
+                let mut data = Vec::with_capacity(count * BLOCK_SIZE);
+                for id in start..start + count {
+                    data.extend_from_slice(&self.blocks[&id].data);
+                }
+                if self.device.write_at(start * BLOCK_SIZE, &data) == Some(data.len()) {
+                    for id in start..start + count {
+                        self.blocks.get_mut(&id).unwrap().dirty = false;
+                    }
+                    self.dirty -= count;
+                    self.stats.writebacks += count;
+                } else {
+                    // write them one by one to find the failing one
+                    for id in start..start + count {
+                        self.write_back(id)?;
+                    }
+                }
+            }
+            i += count;
+        }
+        Some(())
+    }
+}
+
+impl Device for BlockCache {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let mut inner = self.0.lock();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let begin = pos % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - begin).min(buf.len() - done);
+            let block = match inner.get(pos / BLOCK_SIZE) {
+                Some(block) => block,
+                // beyond the end of device
+                None if done > 0 => break,
+                None => return None,
+            };
+            if begin >= block.data.len() {
+                break;
+            }
+            let len = len.min(block.data.len() - begin);
+            buf[done..done + len].copy_from_slice(&block.data[begin..begin + len]);
+            done += len;
+            if block.data.len() < BLOCK_SIZE {
+                break;
+            }
+        }
+        Some(done)
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        let mut inner = self.0.lock();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let begin = pos % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - begin).min(buf.len() - done);
+            let block = if len == BLOCK_SIZE {
+                inner.get_for_overwrite(pos / BLOCK_SIZE)?
+            } else {
+                match inner.get(pos / BLOCK_SIZE) {
+                    Some(block) => block,
+                    None if done > 0 => break,
+                    None => return None,
+                }
+            };
+            if begin >= block.data.len() {
+                break;
+            }
+            let len = len.min(block.data.len() - begin);
+            block.data[begin..begin + len].copy_from_slice(&buf[done..done + len]);
+            let newly_dirty = !block.dirty;
+            block.dirty = true;
+            let short = block.data.len() < BLOCK_SIZE;
+            if newly_dirty {
+                inner.dirty += 1;
+            }
+            done += len;
+            if short {
+                break;
+            }
+        }
+        if inner.dirty > inner.capacity / 2 {
+            inner.flush()?;
+        }
+        Some(done)
+    }
+}
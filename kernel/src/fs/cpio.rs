@@ -0,0 +1,203 @@
+//! Unpack newc format cpio archives, used as initramfs
+
+use simple_filesystem::*;
+use alloc::{collections::BTreeMap, sync::Arc, string::String};
+use core::str;
+use log::*;
+
+const MAGIC: &[u8] = b"070701";
+/// Magic of the format with checksums, which are not checked
+const MAGIC_CRC: &[u8] = b"070702";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+/// Max size of a name, including the terminating NUL
+const PATH_MAX: usize = 4096;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Size of chunks copied from the archive into files
+const COPY_SIZE: usize = 4096;
+
+/// A header of an entry
+struct Header {
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    file_size: usize,
+    dev_major: u32,
+    dev_minor: u32,
+    name_size: usize,
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if &buf[..6] != MAGIC && &buf[..6] != MAGIC_CRC {
+            return Err(FsError::WrongFs);
+        }
+        // 13 fields of 8 hex digits follow the magic
+        let field = |i: usize| -> Result<u32> {
+            let s = str::from_utf8(&buf[6 + i * 8..14 + i * 8]).map_err(|_| FsError::InvalidParam)?;
+            u32::from_str_radix(s, 16).map_err(|_| FsError::InvalidParam)
+        };
+        Ok(Header {
+            ino: field(0)?,
+            mode: field(1)?,
+            nlink: field(4)?,
+            file_size: field(6)? as usize,
+            dev_major: field(7)?,
+            dev_minor: field(8)?,
+            name_size: field(11)? as usize,
+        })
+    }
+}
+
+fn align4(x: usize) -> usize {
+    (x + 3) & !3
+}
+
+fn read_exact(device: &mut Device, offset: usize, buf: &mut [u8]) -> Result<()> {
+    match device.read_at(offset, buf) {
+        Some(len) if len == buf.len() => Ok(()),
+        _ => {
+            warn!("cpio: archive truncated at {:#x}", offset);
+            Err(FsError::InvalidParam)
+        }
+    }
+}
+
+/// Find or create the directory at `path` under `root`
+fn make_dirs(root: &Arc<INode>, path: &str) -> Result<Arc<INode>> {
+    let mut dir = root.clone();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        dir = match dir.find(name) {
+            Ok(inode) => inode,
+            Err(FsError::EntryNotFound) => dir.create(name, FileType::Dir)?,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(dir)
+}
+
+/// Unpack the archive in `device` into the directory `root`,
+/// which is usually the root of a `RamFileSystem`.
+/// Return the number of files and directories created.
+///
+/// Only directories and regular files are unpacked, others like symlinks and
+/// device nodes are skipped. Hard links are kept.
+/// Entries whose name is already used are skipped with a warning.
+pub fn unpack(device: &mut Device, root: &Arc<INode>) -> Result<usize> {
+    // files with more than one link, by (device, inode number)
+    let mut links: BTreeMap<(u32, u32, u32), Arc<INode>> = BTreeMap::new();
+    let mut offset = 0;
+    let mut count = 0;
+    loop {
+        let mut buf = [0u8; HEADER_SIZE];
+        read_exact(device, offset, &mut buf)?;
+        let header = match Header::parse(&buf) {
+            Err(FsError::WrongFs) if offset != 0 => {
+                warn!("cpio: bad magic at {:#x}", offset);
+                return Err(FsError::InvalidParam);
+            }
+            result => result?,
+        };
+        if header.name_size > PATH_MAX {
+            warn!("cpio: name of {} bytes at {:#x} is too long", header.name_size, offset);
+            return Err(FsError::InvalidParam);
+        }
+        let mut name = vec![0u8; header.name_size];
+        read_exact(device, offset + HEADER_SIZE, &mut name)?;
+        // the name is terminated by NUL
+        let name = String::from_utf8_lossy(&name[..header.name_size.saturating_sub(1)]).into_owned();
+        let data_offset = align4(offset + HEADER_SIZE + header.name_size);
+        offset = align4(data_offset + header.file_size);
+        if name == TRAILER {
+            return Ok(count);
+        }
+
+        let path = name.trim_start_matches("./").trim_start_matches('/');
+        if path.is_empty() || path == "." {
+            continue;
+        }
+        let (parent, file_name) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", path),
+        };
+        let dir = make_dirs(root, parent)?;
+        match header.mode & S_IFMT {
+            S_IFDIR => match dir.find(file_name) {
+                Ok(inode) => {
+                    if inode.info()?.type_ != FileType::Dir {
+                        warn!("cpio: skipping directory {}, a file has the name", path);
+                    }
+                }
+                Err(FsError::EntryNotFound) => {
+                    dir.create(file_name, FileType::Dir)?;
+                    count += 1;
+                }
+                Err(e) => return Err(e),
+            },
+            S_IFREG => {
+                match dir.find(file_name) {
+                    Ok(_) => {
+                        warn!("cpio: skipping duplicate {}", path);
+                        continue;
+                    }
+                    Err(FsError::EntryNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+                let key = (header.dev_major, header.dev_minor, header.ino);
+                let inode = match links.get(&key) {
+                    // only the last link of a file has its data
+                    Some(inode) => {
+                        if let Err(e) = dir.link(file_name, inode) {
+                            warn!("cpio: skipping link {}: {:?}", path, e);
+                            continue;
+                        }
+                        inode.clone()
+                    }
+                    None => {
+                        let inode = dir.create(file_name, FileType::File)?;
+                        if header.nlink > 1 {
+                            links.insert(key, inode.clone());
+                        }
+                        count += 1;
+                        inode
+                    }
+                };
+                let mut chunk = vec![0u8; COPY_SIZE];
+                let mut done = 0;
+                while done < header.file_size {
+                    let len = (header.file_size - done).min(COPY_SIZE);
+                    read_exact(device, data_offset + done, &mut chunk[..len])?;
+                    inode.write_at(done, &chunk[..len])?;
+                    done += len;
+                }
+            }
+            _ => warn!("cpio: skipping {} of mode {:#o}", path, header.mode),
+        }
+    }
+}
+
+/// An archive in memory
+struct Slice<'a>(&'a [u8]);
+
+impl<'a> Device for Slice<'a> {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        if offset >= self.0.len() {
+            return Some(0);
+        }
+        let len = buf.len().min(self.0.len() - offset);
+        buf[..len].copy_from_slice(&self.0[offset..offset + len]);
+        Some(len)
+    }
+    fn write_at(&mut self, _offset: usize, _buf: &[u8]) -> Option<usize> {
+        None
+    }
+}
+
+/// Unpack the archive in memory into the directory `root`
+pub fn unpack_slice(data: &[u8], root: &Arc<INode>) -> Result<usize> {
+    unpack(&mut Slice(data), root)
+}
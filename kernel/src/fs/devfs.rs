@@ -0,0 +1,249 @@
+//! Device file system, presenting devices as files
+
+use simple_filesystem::*;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, string::String, vec::Vec};
+use core::any::Any;
+use lazy_static::lazy_static;
+use crate::drivers::BLK_DRIVERS;
+use crate::sync::{SpinNoIrqLock as Mutex, ThreadLock};
+use super::{STDIN, STDOUT, device_error};
+use super::cache::BlockCache;
+
+/// Type bits of a character device in `FileInfo::mode`, as `FileType` has no variant for devices
+pub const MODE_CHAR: u32 = 0o40000;
+/// Type bits of a block device in `FileInfo::mode`
+pub const MODE_BLOCK: u32 = 0o50000;
+
+/// Info of a device file, `type_` is `MODE_CHAR` or `MODE_BLOCK`.
+/// Block devices are only accessible by the owner, since they bypass
+/// the permissions of the files on them.
+pub fn device_info(type_: u32) -> FileInfo {
+    let permission = if type_ == MODE_BLOCK { 0o600 } else { 0o666 };
+    FileInfo {
+        size: 0,
+        mode: type_ | permission,
+        type_: FileType::File,
+        blocks: 0,
+        nlinks: 1,
+    }
+}
+
+/// Character devices, block devices are taken from `BLK_DRIVERS`
+lazy_static! {
+    static ref DEVICES: Mutex<BTreeMap<String, Arc<INode>>> = {
+        let mut devices: BTreeMap<String, Arc<INode>> = BTreeMap::new();
+        devices.insert(String::from("stdin"), STDIN.clone());
+        devices.insert(String::from("stdout"), STDOUT.clone());
+        devices.insert(String::from("null"), Arc::new(Null));
+        devices.insert(String::from("zero"), Arc::new(Zero));
+        devices.insert(String::from("full"), Arc::new(Full));
+        devices.insert(String::from("random"), Arc::new(Random));
+        devices.insert(String::from("urandom"), Arc::new(Random));
+        Mutex::new(devices)
+    };
+}
+
+/// Register a character device as `name`.
+/// Return false if the name has already been used.
+pub fn register_device(name: &str, inode: Arc<INode>) -> bool {
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return false;
+    }
+    devices.insert(String::from(name), inode);
+    true
+}
+
+/// Register a block device as `name`, e.g. a loop device.
+/// Return false if the name has already been used.
+pub fn register_block_device(name: &str, device: Box<Device>) -> bool {
+    register_device(name, Arc::new(BlockINode(ThreadLock::new(device))))
+}
+
+lazy_static! {
+    /// Caches used by file systems on block devices, by the index in `BLK_DRIVERS`
+    static ref BLOCK_CACHES: Mutex<BTreeMap<usize, BlockCache>> = Mutex::new(BTreeMap::new());
+}
+
+/// Access the `i`-th block device through `cache`, which a file system on it uses.
+/// Otherwise writes to the device file are not seen by the file system,
+/// and are overwritten by its dirty blocks.
+pub fn set_block_cache(i: usize, cache: BlockCache) {
+    BLOCK_CACHES.lock().insert(i, cache);
+}
+
+/// Name of the `i`-th block device
+fn block_name(i: usize) -> String {
+    format!("blk{}", i)
+}
+
+/// The device file system. All instances show the same devices.
+pub struct DevFileSystem;
+
+impl DevFileSystem {
+    pub fn new() -> Arc<Self> {
+        Arc::new(DevFileSystem)
+    }
+}
+
+impl FileSystem for DevFileSystem {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        Arc::new(DevRoot)
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: 255 };
+        &INFO
+    }
+}
+
+/// The root directory of devfs
+struct DevRoot;
+
+impl DevRoot {
+    fn names(&self) -> Vec<String> {
+        let mut names = vec![String::from("."), String::from("..")];
+        names.extend(DEVICES.lock().keys().cloned());
+        names.extend((0..BLK_DRIVERS.lock().len()).map(block_name));
+        names
+    }
+}
+
+impl INode for DevRoot {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+    fn info(&self) -> Result<FileInfo> {
+        Ok(FileInfo {
+            size: self.names().len(),
+            mode: 0o755,
+            type_: FileType::Dir,
+            blocks: 0,
+            nlinks: 2,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, _len: usize) -> Result<()> {
+        Err(FsError::IsDir)
+    }
+    fn create(&self, _name: &str, _type_: FileType) -> Result<Arc<INode>> {
+        Err(FsError::NotSupported)
+    }
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn link(&self, _name: &str, _other: &Arc<INode>) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn rename(&self, _old_name: &str, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn move_(&self, _old_name: &str, _target: &Arc<INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        if name == "." || name == ".." {
+            return Ok(Arc::new(DevRoot));
+        }
+        if let Some(inode) = DEVICES.lock().get(name) {
+            return Ok(inode.clone());
+        }
+        let drivers = BLK_DRIVERS.lock();
+        let i = (0..drivers.len()).find(|&i| block_name(i) == name).ok_or(FsError::EntryNotFound)?;
+        let device = match BLOCK_CACHES.lock().get(&i) {
+            Some(cache) => Box::new(cache.clone()) as Box<Device>,
+            None => drivers[i].get_device(),
+        };
+        Ok(Arc::new(BlockINode(ThreadLock::new(device))))
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.names().into_iter().nth(id).ok_or(FsError::EntryNotFound)
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        DevFileSystem::new()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
+
+/// A block device, accessed by byte offset
+struct BlockINode(ThreadLock<Box<Device>>);
+
+impl INode for BlockINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.0.lock().read_at(offset, buf).ok_or_else(device_error)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.0.lock().write_at(offset, buf).ok_or_else(device_error)
+    }
+    impl_inode!(MODE_BLOCK);
+}
+
+/// Discards writes and reads nothing
+struct Null;
+
+impl INode for Null {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+    impl_inode!();
+}
+
+/// Discards writes and reads zeros
+struct Zero;
+
+impl INode for Zero {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        Ok(buf.len())
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+    impl_inode!();
+}
+
+/// Reads zeros and is always full, for testing how programs handle a full disk
+struct Full;
+
+impl INode for Full {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        Ok(buf.len())
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NoDeviceSpace)
+    }
+    impl_inode!();
+}
+
+/// Reads random bytes, writes are mixed into the entropy pool without being credited.
+/// The pool is seeded at boot, so `random` never blocks and is the same as `urandom`.
+struct Random;
+
+impl INode for Random {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        crate::rand::fill(buf);
+        Ok(buf.len())
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        crate::rand::add_data(buf);
+        Ok(buf.len())
+    }
+    impl_inode!();
+}
@@ -0,0 +1,105 @@
+//! Implement Device
+
+use simple_filesystem::*;
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(target_arch = "x86_64")]
+use crate::arch::driver::ide;
+
+#[cfg(not(target_arch = "x86_64"))]
+pub struct MemBuf(&'static [u8]);
+
+#[cfg(not(target_arch = "x86_64"))]
+impl MemBuf {
+    pub unsafe fn new(begin: unsafe extern fn(), end: unsafe extern fn()) -> Self {
+        use core::slice;
+        MemBuf(slice::from_raw_parts(begin as *const u8, end as usize - begin as usize))
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Device for MemBuf {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let slice = self.0;
+        if offset >= slice.len() {
+            return Some(0);
+        }
+        let len = buf.len().min(slice.len() - offset);
+        buf[..len].copy_from_slice(&slice[offset..offset + len]);
+        Some(len)
+    }
+    fn write_at(&mut self, _offset: usize, _buf: &[u8]) -> Option<usize> {
+        None
+    }
+}
+
+/// A RAM disk
+pub struct MemDevice(Vec<u8>);
+
+impl MemDevice {
+    /// Create a zeroed RAM disk of `size` bytes
+    pub fn new(size: usize) -> Self {
+        MemDevice(vec![0; size])
+    }
+    /// Create a RAM disk from existing data, e.g. a file system image
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        MemDevice(data)
+    }
+}
+
+impl Device for MemDevice {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        if offset >= self.0.len() {
+            return Some(0);
+        }
+        let len = buf.len().min(self.0.len() - offset);
+        buf[..len].copy_from_slice(&self.0[offset..offset + len]);
+        Some(len)
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        if offset >= self.0.len() {
+            return Some(0);
+        }
+        let len = buf.len().min(self.0.len() - offset);
+        self.0[offset..offset + len].copy_from_slice(&buf[..len]);
+        Some(len)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl BlockedDevice for ide::IDE {
+    const BLOCK_SIZE_LOG2: u8 = 9;
+    fn read_at(&mut self, block_id: usize, buf: &mut [u8]) -> bool {
+        use core::slice;
+        if buf.len() < ide::BLOCK_SIZE {
+            return false;
+        }
+        let buf = unsafe { slice::from_raw_parts_mut(buf.as_ptr() as *mut u32, ide::BLOCK_SIZE / 4) };
+        self.read(block_id as u64, 1, buf).is_ok()
+    }
+    fn write_at(&mut self, block_id: usize, buf: &[u8]) -> bool {
+        use core::slice;
+        if buf.len() < ide::BLOCK_SIZE {
+            return false;
+        }
+        let buf = unsafe { slice::from_raw_parts(buf.as_ptr() as *mut u32, ide::BLOCK_SIZE / 4) };
+        self.write(block_id as u64, 1, buf).is_ok()
+    }
+}
+
+/// A device backed by a file, so that a file system image stored in a file can be mounted
+pub struct LoopDevice(Arc<INode>);
+
+impl LoopDevice {
+    pub fn new(inode: Arc<INode>) -> Self {
+        LoopDevice(inode)
+    }
+}
+
+impl Device for LoopDevice {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        self.0.read_at(offset, buf).ok()
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        self.0.write_at(offset, buf).ok()
+    }
+}
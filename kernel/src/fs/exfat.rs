@@ -0,0 +1,1010 @@
+//! exFAT file system
+
+use simple_filesystem::*;
+use alloc::{boxed::Box, collections::BTreeMap, sync::{Arc, Weak}, string::String, vec::Vec};
+use core::any::Any;
+use core::mem;
+use crate::sync::ThreadLock as Mutex;
+use super::{device_error, FsUsage};
+use crate::time;
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// Entry types. An entry is in use if its type has the high bit set.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_IN_USE: u8 = 0x80;
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE: u8 = 0x82;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xc0;
+const ENTRY_NAME: u8 = 0xc1;
+
+const ATTR_READ_ONLY: u16 = 0x01;
+const ATTR_DIRECTORY: u16 = 0x10;
+const ATTR_ARCHIVE: u16 = 0x20;
+
+/// Flags of a stream extension entry
+const FLAG_ALLOC_POSSIBLE: u8 = 0x01;
+/// The clusters are contiguous and not recorded in the FAT
+const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+
+/// Number of UTF-16 code units in a file name entry
+const NAME_CHARS: usize = 15;
+const MAX_NAME_LEN: usize = 255;
+const END_OF_CHAIN: u32 = 0xffff_ffff;
+
+/// An exFAT file system
+pub struct ExfatFileSystem {
+    inner: Mutex<ExfatInner>,
+    /// Open files by the first slot of their entry sets, so that every
+    /// handle follows a file which is renamed or moved
+    inodes: Mutex<BTreeMap<usize, Weak<ExfatINode>>>,
+    self_ref: Mutex<Weak<ExfatFileSystem>>,
+}
+
+struct ExfatInner {
+    device: Box<Device>,
+    bytes_per_sector: usize,
+    cluster_size: usize,
+    /// Byte offset of the FAT
+    fat_offset: usize,
+    /// Byte offset of cluster 2
+    heap_offset: usize,
+    /// Number of data clusters
+    clusters: u32,
+    root_cluster: u32,
+    /// Byte offset of the allocation bitmap, which is contiguous
+    bitmap_offset: usize,
+    /// Up-case table, indexed by UTF-16 code unit
+    upcase: Vec<u16>,
+    /// Where to start searching for a free cluster
+    alloc_hint: u32,
+}
+
+/// A directory entry set: a file entry, a stream extension entry and file name entries
+struct Entry {
+    name: String,
+    attr: u16,
+    flags: u8,
+    first_cluster: u32,
+    /// Allocated size
+    size: u64,
+    /// Bytes written, the rest reads as zeros
+    valid_size: u64,
+    /// Byte offsets of all slots of the entry set
+    slots: Vec<usize>,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    read_u16(&b[0..2]) as u32 | (read_u16(&b[2..4]) as u32) << 16
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    read_u32(&b[0..4]) as u64 | (read_u32(&b[4..8]) as u64) << 32
+}
+
+fn write_u16(b: &mut [u8], v: u16) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+}
+
+fn write_u32(b: &mut [u8], v: u32) {
+    write_u16(&mut b[0..2], v as u16);
+    write_u16(&mut b[2..4], (v >> 16) as u16);
+}
+
+fn write_u64(b: &mut [u8], v: u64) {
+    write_u32(&mut b[0..4], v as u32);
+    write_u32(&mut b[4..8], (v >> 32) as u32);
+}
+
+/// Checksum of an entry set, stored in bytes 2..4 of the file entry
+fn set_checksum(raws: &[[u8; DIR_ENTRY_SIZE]]) -> u16 {
+    let mut sum = 0u16;
+    for (i, raw) in raws.iter().enumerate() {
+        for (j, &b) in raw.iter().enumerate() {
+            if i == 0 && (j == 2 || j == 3) {
+                continue;
+            }
+            sum = sum.rotate_right(1).wrapping_add(b as u16);
+        }
+    }
+    sum
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.encode_utf16().count() > MAX_NAME_LEN
+        || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(())
+}
+
+impl ExfatFileSystem {
+    /// Open an exFAT file system on `device`
+    pub fn open(mut device: Box<Device>) -> Result<Arc<Self>> {
+        let mut boot = [0u8; 512];
+        if device.read_at(0, &mut boot) != Some(512) || &boot[3..11] != b"EXFAT   "
+            || boot[510] != 0x55 || boot[511] != 0xaa {
+            return Err(FsError::WrongFs);
+        }
+        // the BIOS parameter block of FAT must be zero
+        if boot[11..64].iter().any(|&b| b != 0) {
+            return Err(FsError::WrongFs);
+        }
+        let bytes_per_sector_shift = boot[108] as usize;
+        let sectors_per_cluster_shift = boot[109] as usize;
+        let num_fats = boot[110];
+        if bytes_per_sector_shift < 9 || bytes_per_sector_shift > 12
+            || bytes_per_sector_shift + sectors_per_cluster_shift > 25 {
+            return Err(FsError::WrongFs);
+        }
+        if num_fats != 1 {
+            // TexFAT is not supported
+            return Err(FsError::NotSupported);
+        }
+        let bytes_per_sector = 1 << bytes_per_sector_shift;
+        let mut inner = ExfatInner {
+            device,
+            bytes_per_sector,
+            cluster_size: bytes_per_sector << sectors_per_cluster_shift,
+            fat_offset: read_u32(&boot[80..84]) as usize * bytes_per_sector,
+            heap_offset: read_u32(&boot[88..92]) as usize * bytes_per_sector,
+            clusters: read_u32(&boot[92..96]),
+            root_cluster: read_u32(&boot[96..100]),
+            bitmap_offset: 0,
+            upcase: Vec::new(),
+            alloc_hint: 2,
+        };
+        if !inner.is_valid_cluster(inner.root_cluster) {
+            return Err(FsError::WrongFs);
+        }
+        inner.load_system_entries()?;
+        let fs = Arc::new(ExfatFileSystem {
+            inner: Mutex::new(inner),
+            inodes: Mutex::new(BTreeMap::new()),
+            self_ref: Mutex::new(Weak::new()),
+        });
+        *fs.self_ref.lock() = Arc::downgrade(&fs);
+        Ok(fs)
+    }
+
+    /// Free clusters are counted in the allocation bitmap on each call
+    pub fn usage(&self) -> Result<FsUsage> {
+        let mut inner = self.inner.lock();
+        let used = inner.used_clusters()?;
+        let blocks = inner.clusters as usize;
+        Ok(FsUsage { block_size: inner.cluster_size, blocks, free_blocks: blocks - used })
+    }
+
+    fn arc(&self) -> Arc<ExfatFileSystem> {
+        self.self_ref.lock().upgrade().unwrap()
+    }
+
+    fn root(&self) -> Arc<ExfatINode> {
+        ExfatINode::new(self.arc(), None, true, None)
+    }
+
+    /// The inode of `entry` in the directory `parent`, shared by all handles of the file
+    fn inode(&self, entry: &Entry, parent: Arc<ExfatINode>) -> Arc<ExfatINode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&entry.slots[0]).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let inode = ExfatINode::new(self.arc(), Some(entry.slots.clone()), entry.is_dir(), Some(parent));
+        inodes.insert(entry.slots[0], Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Point open handles of the entry set at `old` to `new` in the directory `parent`
+    fn relocate(&self, old: &[usize], new: Vec<usize>, parent: Arc<ExfatINode>) {
+        let mut inodes = self.inodes.lock();
+        let inode = match inodes.remove(&old[0]).and_then(|inode| inode.upgrade()) {
+            Some(inode) => inode,
+            None => return,
+        };
+        inodes.insert(new[0], Arc::downgrade(&inode));
+        let mut loc = inode.loc.lock();
+        loc.entry = Some(new);
+        // the old parent may be the last handle of it, whose drop takes `inodes`
+        let old_parent = mem::replace(&mut loc.parent, Some(parent));
+        drop(loc);
+        drop(inodes);
+        drop(old_parent);
+    }
+
+    /// Detach open handles from the removed entry set at `slots`
+    fn forget(&self, slots: &[usize]) {
+        let inode = self.inodes.lock().remove(&slots[0]).and_then(|inode| inode.upgrade());
+        if let Some(inode) = inode {
+            // reading an empty entry set fails, so the handles see the file is gone
+            inode.loc.lock().entry = Some(Vec::new());
+        }
+    }
+}
+
+impl ExfatInner {
+    fn read(&mut self, pos: usize, buf: &mut [u8]) -> Result<()> {
+        match self.device.read_at(pos, buf) {
+            Some(len) if len == buf.len() => Ok(()),
+            _ => Err(device_error()),
+        }
+    }
+    fn write(&mut self, pos: usize, buf: &[u8]) -> Result<()> {
+        match self.device.write_at(pos, buf) {
+            Some(len) if len == buf.len() => Ok(()),
+            _ => Err(device_error()),
+        }
+    }
+
+    fn cluster_pos(&self, cluster: u32) -> Result<usize> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(FsError::InvalidParam);
+        }
+        Ok(self.heap_offset + (cluster as usize - 2) * self.cluster_size)
+    }
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.clusters + 2
+    }
+
+    /// Find the allocation bitmap and up-case table in the root directory
+    fn load_system_entries(&mut self) -> Result<()> {
+        let mut bitmap = None;
+        let mut upcase = None;
+        for pos in self.dir_slots(None)? {
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            self.read(pos, &mut raw)?;
+            match raw[0] {
+                ENTRY_END => break,
+                ENTRY_BITMAP if raw[1] & 1 == 0 => bitmap = Some(read_u32(&raw[20..24])),
+                ENTRY_UPCASE => upcase = Some((read_u32(&raw[20..24]), read_u64(&raw[24..32]) as usize)),
+                _ => {}
+            }
+        }
+        let bitmap = bitmap.ok_or(FsError::WrongFs)?;
+        if !self.is_valid_cluster(bitmap) {
+            return Err(FsError::WrongFs);
+        }
+        self.bitmap_offset = self.cluster_pos(bitmap)?;
+        // the table is compressed: 0xffff followed by n skips n identical code units
+        self.upcase = (0..=0xffff).map(|c| c as u16).collect();
+        match upcase {
+            Some((cluster, len)) if self.is_valid_cluster(cluster) && len <= 0x20000 => {
+                let mut data = vec![0u8; len];
+                let pos = self.cluster_pos(cluster)?;
+                self.read(pos, &mut data)?;
+                let mut c = 0usize;
+                let mut units = data.chunks(2).map(read_u16);
+                while let Some(unit) = units.next() {
+                    if c > 0xffff {
+                        break;
+                    }
+                    if unit == 0xffff {
+                        c += units.next().unwrap_or(0) as usize;
+                    } else {
+                        self.upcase[c] = unit;
+                        c += 1;
+                    }
+                }
+            }
+            _ => {
+                for c in b'a'..=b'z' {
+                    self.upcase[c as usize] = (c - b'a' + b'A') as u16;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn upcase_name(&self, name: &str) -> Vec<u16> {
+        name.encode_utf16().map(|c| self.upcase[c as usize]).collect()
+    }
+    fn name_hash(&self, name: &str) -> u16 {
+        let mut hash = 0u16;
+        for c in self.upcase_name(name) {
+            hash = hash.rotate_right(1).wrapping_add(c & 0xff);
+            hash = hash.rotate_right(1).wrapping_add(c >> 8);
+        }
+        hash
+    }
+
+    fn fat_get(&mut self, cluster: u32) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        let pos = self.fat_offset + cluster as usize * 4;
+        self.read(pos, &mut buf)?;
+        Ok(read_u32(&buf))
+    }
+    fn fat_set(&mut self, cluster: u32, value: u32) -> Result<()> {
+        let mut buf = [0u8; 4];
+        write_u32(&mut buf, value);
+        let pos = self.fat_offset + cluster as usize * 4;
+        self.write(pos, &buf)
+    }
+
+    /// Count the clusters marked used in the allocation bitmap
+    fn used_clusters(&mut self) -> Result<usize> {
+        let clusters = self.clusters as usize;
+        let mut buf = vec![0u8; 4096];
+        let mut used = 0;
+        let mut i = 0;
+        while i < clusters {
+            let len = (buf.len() * 8).min(clusters - i);
+            let pos = self.bitmap_offset + i / 8;
+            self.read(pos, &mut buf[..(len + 7) / 8])?;
+            // bits of the last byte beyond the last cluster are not counted
+            used += (0..len).filter(|&j| buf[j / 8] & (1 << (j % 8)) != 0).count();
+            i += len;
+        }
+        Ok(used)
+    }
+    fn bitmap_get(&mut self, cluster: u32) -> Result<bool> {
+        let i = (cluster - 2) as usize;
+        let mut byte = [0u8; 1];
+        let pos = self.bitmap_offset + i / 8;
+        self.read(pos, &mut byte)?;
+        Ok(byte[0] & (1 << (i % 8)) != 0)
+    }
+    fn bitmap_set(&mut self, cluster: u32, used: bool) -> Result<()> {
+        let i = (cluster - 2) as usize;
+        let mut byte = [0u8; 1];
+        let pos = self.bitmap_offset + i / 8;
+        self.read(pos, &mut byte)?;
+        if used {
+            byte[0] |= 1 << (i % 8);
+        } else {
+            byte[0] &= !(1 << (i % 8));
+        }
+        self.write(pos, &byte)
+    }
+
+    /// Clusters of the chain starting from `first`
+    fn chain(&mut self, first: u32) -> Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cur = first;
+        while self.is_valid_cluster(cur) && clusters.len() <= self.clusters as usize {
+            clusters.push(cur);
+            cur = self.fat_get(cur)?;
+        }
+        Ok(clusters)
+    }
+
+    /// Allocate a zeroed cluster, append it to `prev` if given
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32> {
+        for i in 0..self.clusters {
+            let cluster = (self.alloc_hint - 2 + i) % self.clusters + 2;
+            if !self.bitmap_get(cluster)? {
+                self.bitmap_set(cluster, true)?;
+                self.fat_set(cluster, END_OF_CHAIN)?;
+                if let Some(prev) = prev {
+                    self.fat_set(prev, cluster)?;
+                }
+                let zeros = vec![0u8; self.cluster_size];
+                let pos = self.cluster_pos(cluster)?;
+                self.write(pos, &zeros)?;
+                self.alloc_hint = cluster;
+                return Ok(cluster);
+            }
+        }
+        Err(FsError::NoDeviceSpace)
+    }
+
+    fn free_clusters(&mut self, clusters: &[u32]) -> Result<()> {
+        for &cluster in clusters {
+            self.bitmap_set(cluster, false)?;
+            self.fat_set(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Read the entry set at `slots`
+    fn read_entry(&mut self, slots: &[usize]) -> Result<Entry> {
+        let mut raws = Vec::new();
+        for &pos in slots {
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            self.read(pos, &mut raw)?;
+            raws.push(raw);
+        }
+        // the file may have been removed or renamed through another handle
+        parse_entry(&raws, slots.to_vec()).ok_or(FsError::EntryNotFound)
+    }
+
+    /// The entry of a file, or a pseudo entry for the root directory
+    fn meta(&mut self, entry: Option<&[usize]>) -> Result<Entry> {
+        match entry {
+            Some(slots) => self.read_entry(slots),
+            None => {
+                let root = self.root_cluster;
+                let size = (self.chain(root)?.len() * self.cluster_size) as u64;
+                Ok(Entry {
+                    name: String::new(),
+                    attr: ATTR_DIRECTORY,
+                    flags: FLAG_ALLOC_POSSIBLE,
+                    first_cluster: root,
+                    size,
+                    valid_size: size,
+                    slots: Vec::new(),
+                })
+            }
+        }
+    }
+
+    /// Clusters holding the data of a file
+    fn clusters_of(&mut self, entry: &Entry) -> Result<Vec<u32>> {
+        if entry.first_cluster == 0 {
+            return Ok(Vec::new());
+        }
+        if entry.flags & FLAG_NO_FAT_CHAIN != 0 {
+            let cs = self.cluster_size as u64;
+            let count = (entry.size + cs - 1) / cs;
+            if !self.is_valid_cluster(entry.first_cluster)
+                || entry.first_cluster as u64 + count > self.clusters as u64 + 2 {
+                return Err(FsError::InvalidParam);
+            }
+            return Ok((entry.first_cluster..entry.first_cluster + count as u32).collect());
+        }
+        self.chain(entry.first_cluster)
+    }
+
+    /// Modify the stream extension entry of the set at `slots` and update the checksum
+    fn update_stream(&mut self, slots: &[usize], f: impl FnOnce(&mut [u8; DIR_ENTRY_SIZE])) -> Result<()> {
+        let mut raws = Vec::new();
+        for &pos in slots {
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            self.read(pos, &mut raw)?;
+            raws.push(raw);
+        }
+        f(&mut raws[1]);
+        let checksum = set_checksum(&raws);
+        write_u16(&mut raws[0][2..4], checksum);
+        self.write(slots[0], &raws[0])?;
+        self.write(slots[1], &raws[1])
+    }
+    fn set_size(&mut self, slots: &[usize], size: u64, valid_size: u64) -> Result<()> {
+        self.update_stream(slots, |raw| {
+            write_u64(&mut raw[8..16], valid_size);
+            write_u64(&mut raw[24..32], size);
+        })
+    }
+
+    /// Make the file hold at least `clusters` clusters, return its clusters.
+    /// The size in its entry is not changed.
+    fn grow(&mut self, entry: Option<&[usize]>, clusters: usize) -> Result<Vec<u32>> {
+        let meta = self.meta(entry)?;
+        let mut chain = self.clusters_of(&meta)?;
+        if chain.len() >= clusters {
+            return Ok(chain);
+        }
+        if meta.flags & FLAG_NO_FAT_CHAIN != 0 {
+            // record the contiguous clusters in the FAT, so that clusters can be appended anywhere
+            for i in 1..chain.len() {
+                self.fat_set(chain[i - 1], chain[i])?;
+            }
+            if let Some(&last) = chain.last() {
+                self.fat_set(last, END_OF_CHAIN)?;
+            }
+            self.update_stream(entry.unwrap(), |raw| raw[1] &= !FLAG_NO_FAT_CHAIN)?;
+        }
+        while chain.len() < clusters {
+            let cluster = self.alloc_cluster(chain.last().cloned())?;
+            if chain.is_empty() {
+                self.update_stream(entry.unwrap(), |raw| {
+                    raw[1] |= FLAG_ALLOC_POSSIBLE;
+                    write_u32(&mut raw[20..24], cluster);
+                })?;
+            }
+            chain.push(cluster);
+        }
+        Ok(chain)
+    }
+
+    /// Free clusters of a file after the first `clusters` ones
+    fn shrink(&mut self, slots: &[usize], clusters: usize) -> Result<()> {
+        let meta = self.read_entry(slots)?;
+        let chain = self.clusters_of(&meta)?;
+        if chain.len() <= clusters {
+            return Ok(());
+        }
+        self.free_clusters(&chain[clusters..])?;
+        if clusters == 0 {
+            self.update_stream(slots, |raw| write_u32(&mut raw[20..24], 0))?;
+        } else if meta.flags & FLAG_NO_FAT_CHAIN == 0 {
+            self.fat_set(chain[clusters - 1], END_OF_CHAIN)?;
+        }
+        Ok(())
+    }
+
+    /// Split `len` bytes of file data in `clusters` at `offset` into
+    /// (device offset, buffer offset, length) in each cluster
+    fn data_segments(&self, clusters: &[u32], offset: usize, len: usize) -> Result<Vec<(usize, usize, usize)>> {
+        let cs = self.cluster_size;
+        let mut segments = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let n = (cs - pos % cs).min(len - done);
+            segments.push((self.cluster_pos(clusters[pos / cs])? + pos % cs, done, n));
+            done += n;
+        }
+        Ok(segments)
+    }
+    fn read_data(&mut self, clusters: &[u32], offset: usize, buf: &mut [u8]) -> Result<()> {
+        for (pos, start, len) in self.data_segments(clusters, offset, buf.len())? {
+            self.read(pos, &mut buf[start..start + len])?;
+        }
+        Ok(())
+    }
+    fn write_data(&mut self, clusters: &[u32], offset: usize, buf: &[u8]) -> Result<()> {
+        for (pos, start, len) in self.data_segments(clusters, offset, buf.len())? {
+            self.write(pos, &buf[start..start + len])?;
+        }
+        Ok(())
+    }
+
+    /// Byte offsets of all slots of a directory
+    fn dir_slots(&mut self, entry: Option<&[usize]>) -> Result<Vec<usize>> {
+        let meta = self.meta(entry)?;
+        let mut slots = Vec::new();
+        for cluster in self.clusters_of(&meta)? {
+            let pos = self.cluster_pos(cluster)?;
+            slots.extend((0..self.cluster_size / DIR_ENTRY_SIZE).map(|i| pos + i * DIR_ENTRY_SIZE));
+        }
+        Ok(slots)
+    }
+
+    /// Files and directories in a directory
+    fn dir_entries(&mut self, entry: Option<&[usize]>) -> Result<Vec<Entry>> {
+        let slots = self.dir_slots(entry)?;
+        let mut entries = Vec::new();
+        let mut i = 0;
+        while i < slots.len() {
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            self.read(slots[i], &mut raw)?;
+            if raw[0] == ENTRY_END {
+                break;
+            }
+            if raw[0] != ENTRY_FILE {
+                i += 1;
+                continue;
+            }
+            let count = raw[1] as usize + 1;
+            if i + count > slots.len() {
+                break;
+            }
+            let mut raws = vec![raw];
+            for &pos in slots[i + 1..i + count].iter() {
+                let mut raw = [0u8; DIR_ENTRY_SIZE];
+                self.read(pos, &mut raw)?;
+                raws.push(raw);
+            }
+            match parse_entry(&raws, slots[i..i + count].to_vec()) {
+                Some(entry) => {
+                    entries.push(entry);
+                    i += count;
+                }
+                None => i += 1,
+            }
+        }
+        Ok(entries)
+    }
+
+    fn find_entry(&mut self, dir: Option<&[usize]>, name: &str) -> Result<Option<Entry>> {
+        let name = self.upcase_name(name);
+        let entries = self.dir_entries(dir)?;
+        Ok(entries.into_iter().find(|e| self.upcase_name(&e.name) == name))
+    }
+
+    /// Find `count` consecutive free slots in a directory, extend it if needed
+    fn alloc_slots(&mut self, dir: Option<&[usize]>, count: usize) -> Result<Vec<usize>> {
+        loop {
+            let slots = self.dir_slots(dir)?;
+            let mut run = Vec::new();
+            for &pos in slots.iter() {
+                let mut type_ = [0u8; 1];
+                self.read(pos, &mut type_)?;
+                if type_[0] & ENTRY_IN_USE == 0 {
+                    run.push(pos);
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+            let cs = self.cluster_size;
+            let clusters = slots.len() * DIR_ENTRY_SIZE / cs + 1;
+            self.grow(dir, clusters)?;
+            if let Some(dir) = dir {
+                let size = (clusters * cs) as u64;
+                self.set_size(dir, size, size)?;
+            }
+        }
+    }
+
+    /// Create an entry set named `name` in a directory, return its slots
+    fn add_entry(&mut self, dir: Option<&[usize]>, name: &str, attr: u16, flags: u8,
+                 first_cluster: u32, size: u64, valid_size: u64) -> Result<Vec<usize>> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let name_entries = (units.len() + NAME_CHARS - 1) / NAME_CHARS;
+        let mut raws = vec![[0u8; DIR_ENTRY_SIZE]; 2 + name_entries];
+        raws[0][0] = ENTRY_FILE;
+        raws[0][1] = (1 + name_entries) as u8;
+        write_u16(&mut raws[0][4..6], attr);
+        // created, modified and accessed
+        let timestamp = time::now().to_dos();
+        for &off in [8, 12, 16].iter() {
+            write_u32(&mut raws[0][off..off + 4], timestamp);
+        }
+        raws[1][0] = ENTRY_STREAM;
+        raws[1][1] = flags;
+        raws[1][3] = units.len() as u8;
+        write_u16(&mut raws[1][4..6], self.name_hash(name));
+        write_u64(&mut raws[1][8..16], valid_size);
+        write_u32(&mut raws[1][20..24], first_cluster);
+        write_u64(&mut raws[1][24..32], size);
+        for (raw, chunk) in raws[2..].iter_mut().zip(units.chunks(NAME_CHARS)) {
+            raw[0] = ENTRY_NAME;
+            for (j, &c) in chunk.iter().enumerate() {
+                write_u16(&mut raw[2 + j * 2..4 + j * 2], c);
+            }
+        }
+        let checksum = set_checksum(&raws);
+        write_u16(&mut raws[0][2..4], checksum);
+        let slots = self.alloc_slots(dir, raws.len())?;
+        for (&pos, raw) in slots.iter().zip(raws.iter()) {
+            self.write(pos, raw)?;
+        }
+        Ok(slots)
+    }
+
+    fn remove_entry(&mut self, entry: &Entry) -> Result<()> {
+        for &pos in entry.slots.iter() {
+            let mut type_ = [0u8; 1];
+            self.read(pos, &mut type_)?;
+            type_[0] &= !ENTRY_IN_USE;
+            self.write(pos, &type_)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse an entry set, None if it is malformed
+fn parse_entry(raws: &[[u8; DIR_ENTRY_SIZE]], slots: Vec<usize>) -> Option<Entry> {
+    if raws.len() < 3 || raws[0][0] != ENTRY_FILE || raws[0][1] as usize + 1 != raws.len()
+        || raws[1][0] != ENTRY_STREAM {
+        return None;
+    }
+    let stream = &raws[1];
+    let name_len = stream[3] as usize;
+    let mut units = Vec::new();
+    for raw in raws[2..].iter().take_while(|raw| raw[0] == ENTRY_NAME) {
+        units.extend(raw[2..].chunks(2).map(read_u16));
+    }
+    if units.len() < name_len {
+        return None;
+    }
+    let name = core::char::decode_utf16(units[..name_len].iter().cloned())
+        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+        .collect::<String>();
+    Some(Entry {
+        name,
+        attr: read_u16(&raws[0][4..6]),
+        flags: stream[1],
+        first_cluster: read_u32(&stream[20..24]),
+        size: read_u64(&stream[24..32]),
+        valid_size: read_u64(&stream[8..16]),
+        slots,
+    })
+}
+
+impl FileSystem for ExfatFileSystem {
+    fn sync(&self) -> Result<()> {
+        // all changes are written to device immediately
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        self.root()
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: MAX_NAME_LEN };
+        &INFO
+    }
+}
+
+/// A file or directory in an exFAT file system.
+///
+/// Size and clusters are read from its directory entry on each access,
+/// so different handles to the same file always agree.
+pub struct ExfatINode {
+    pub(super) fs: Arc<ExfatFileSystem>,
+    is_dir: bool,
+    /// Changed by rename, move and unlink, with the file system locked
+    loc: Mutex<Location>,
+}
+
+struct Location {
+    /// Byte offsets of the slots of its entry set, None for the root directory,
+    /// empty once it has been removed
+    entry: Option<Vec<usize>>,
+    /// The directory containing it, None for the root directory.
+    /// exFAT directories have no `..` entries.
+    parent: Option<Arc<ExfatINode>>,
+    this: Weak<ExfatINode>,
+}
+
+impl ExfatINode {
+    fn new(fs: Arc<ExfatFileSystem>, entry: Option<Vec<usize>>, is_dir: bool,
+           parent: Option<Arc<ExfatINode>>) -> Arc<Self> {
+        let inode = Arc::new(ExfatINode {
+            fs,
+            is_dir,
+            loc: Mutex::new(Location { entry, parent, this: Weak::new() }),
+        });
+        inode.loc.lock().this = Arc::downgrade(&inode);
+        inode
+    }
+    /// Slots of its entry set, only valid while the file system is locked
+    fn slots(&self) -> Option<Vec<usize>> {
+        self.loc.lock().entry.clone()
+    }
+    fn this(&self) -> Arc<ExfatINode> {
+        self.loc.lock().this.upgrade().unwrap()
+    }
+    fn parent(&self) -> Option<Arc<ExfatINode>> {
+        self.loc.lock().parent.clone()
+    }
+    fn check_dir(&self) -> Result<()> {
+        if !self.is_dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(())
+    }
+    /// Entry of `name` which can be renamed or deleted
+    fn find_removable(&self, inner: &mut ExfatInner, name: &str) -> Result<Entry> {
+        self.check_dir()?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidParam);
+        }
+        let dir = self.slots();
+        inner.find_entry(dir.as_ref().map(Vec::as_slice), name)?.ok_or(FsError::EntryNotFound)
+    }
+    /// Whether the directory `entry` is this directory or one of its ancestors
+    fn is_descendant_of(&self, inner: &mut ExfatInner, entry: &Entry) -> Result<bool> {
+        let mut node = Some(self.this());
+        while let Some(dir) = node {
+            let slots = dir.slots();
+            if inner.meta(slots.as_ref().map(Vec::as_slice))?.first_cluster == entry.first_cluster {
+                return Ok(true);
+            }
+            node = dir.parent();
+        }
+        Ok(false)
+    }
+}
+
+impl Drop for ExfatINode {
+    fn drop(&mut self) {
+        let slots = self.loc.lock().entry.take();
+        if let Some(&pos) = slots.as_ref().and_then(|slots| slots.first()) {
+            let mut inodes = self.fs.inodes.lock();
+            // another handle may have been opened since this one was released,
+            // it must not be dropped with `inodes` locked
+            let other = inodes.get(&pos).and_then(Weak::upgrade);
+            if other.is_none() {
+                inodes.remove(&pos);
+            }
+            drop(inodes);
+        }
+    }
+}
+
+impl INode for ExfatINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.is_dir {
+            return Err(FsError::IsDir);
+        }
+        let mut inner = self.fs.inner.lock();
+        let slots = self.slots();
+        let meta = inner.meta(slots.as_ref().map(Vec::as_slice))?;
+        let size = meta.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        let valid = (meta.valid_size as usize).min(offset + len).max(offset) - offset;
+        let clusters = inner.clusters_of(&meta)?;
+        if clusters.len() * inner.cluster_size < offset + valid {
+            return Err(FsError::InvalidParam);
+        }
+        inner.read_data(&clusters, offset, &mut buf[..valid])?;
+        for b in buf[valid..len].iter_mut() {
+            *b = 0;
+        }
+        Ok(len)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        if self.is_dir {
+            return Err(FsError::IsDir);
+        }
+        let mut inner = self.fs.inner.lock();
+        let slots = self.slots().unwrap();
+        let meta = inner.meta(Some(&slots[..]))?;
+        let end = offset + buf.len();
+        let cs = inner.cluster_size;
+        let clusters = inner.grow(Some(&slots[..]), (end + cs - 1) / cs)?;
+        let valid = meta.valid_size as usize;
+        if offset > valid {
+            // data between the valid size and the write must read as zeros
+            let zeros = vec![0u8; cs];
+            let mut pos = valid;
+            while pos < offset {
+                let len = (cs - pos % cs).min(offset - pos);
+                inner.write_data(&clusters, pos, &zeros[..len])?;
+                pos += len;
+            }
+        }
+        inner.write_data(&clusters, offset, buf)?;
+        let size = (meta.size as usize).max(end) as u64;
+        let valid_size = valid.max(end) as u64;
+        if size != meta.size || valid_size != meta.valid_size {
+            inner.set_size(&slots, size, valid_size)?;
+        }
+        Ok(buf.len())
+    }
+    fn info(&self) -> Result<FileInfo> {
+        let mut inner = self.fs.inner.lock();
+        let slots = self.slots();
+        let meta = inner.meta(slots.as_ref().map(Vec::as_slice))?;
+        let clusters = inner.clusters_of(&meta)?.len();
+        Ok(FileInfo {
+            size: meta.size as usize,
+            // use default permissions
+            mode: 0,
+            type_: if self.is_dir { FileType::Dir } else { FileType::File },
+            blocks: clusters * inner.cluster_size / inner.bytes_per_sector,
+            nlinks: 1,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, len: usize) -> Result<()> {
+        if self.is_dir {
+            return Err(FsError::IsDir);
+        }
+        let mut inner = self.fs.inner.lock();
+        let slots = self.slots().unwrap();
+        let meta = inner.meta(Some(&slots[..]))?;
+        let cs = inner.cluster_size;
+        let clusters = (len + cs - 1) / cs;
+        inner.grow(Some(&slots[..]), clusters)?;
+        inner.shrink(&slots, clusters)?;
+        inner.set_size(&slots, len as u64, meta.valid_size.min(len as u64))
+    }
+    fn create(&self, name: &str, type_: FileType) -> Result<Arc<INode>> {
+        check_name(name)?;
+        self.check_dir()?;
+        let mut inner = self.fs.inner.lock();
+        let dir = self.slots();
+        let dir = dir.as_ref().map(Vec::as_slice);
+        if inner.find_entry(dir, name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        let slots = match type_ {
+            FileType::File => inner.add_entry(dir, name, ATTR_ARCHIVE, FLAG_ALLOC_POSSIBLE, 0, 0, 0)?,
+            FileType::Dir => {
+                let cluster = inner.alloc_cluster(None)?;
+                let size = inner.cluster_size as u64;
+                match inner.add_entry(dir, name, ATTR_DIRECTORY, FLAG_ALLOC_POSSIBLE, cluster, size, size) {
+                    Ok(slots) => slots,
+                    Err(e) => {
+                        // report the first error, failing to free only leaks the cluster
+                        let _ = inner.free_clusters(&[cluster]);
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        let entry = inner.read_entry(&slots)?;
+        Ok(self.fs.inode(&entry, self.this()))
+    }
+    fn unlink(&self, name: &str) -> Result<()> {
+        let mut inner = self.fs.inner.lock();
+        let entry = self.find_removable(&mut inner, name)?;
+        if entry.attr & ATTR_READ_ONLY != 0 {
+            return Err(FsError::NotSupported);
+        }
+        if entry.is_dir() && !inner.dir_entries(Some(&entry.slots))?.is_empty() {
+            return Err(FsError::DirNotEmpty);
+        }
+        let clusters = inner.clusters_of(&entry)?;
+        inner.remove_entry(&entry)?;
+        self.fs.forget(&entry.slots);
+        inner.free_clusters(&clusters)?;
+        Ok(())
+    }
+    fn link(&self, _name: &str, _other: &Arc<INode>) -> Result<()> {
+        // exFAT has no hard links
+        Err(FsError::NotSupported)
+    }
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        check_name(new_name)?;
+        let mut inner = self.fs.inner.lock();
+        let entry = self.find_removable(&mut inner, old_name)?;
+        let dir = self.slots();
+        let dir = dir.as_ref().map(Vec::as_slice);
+        match inner.find_entry(dir, new_name)? {
+            // allow changing the case of a name
+            Some(ref other) if other.slots != entry.slots => return Err(FsError::EntryExist),
+            _ => {}
+        }
+        // add first, so that the file is not lost if the directory is full
+        let slots = inner.add_entry(dir, new_name, entry.attr, entry.flags, entry.first_cluster,
+                                    entry.size, entry.valid_size)?;
+        self.fs.relocate(&entry.slots, slots, self.this());
+        inner.remove_entry(&entry)
+    }
+    fn move_(&self, old_name: &str, target: &Arc<INode>, new_name: &str) -> Result<()> {
+        check_name(new_name)?;
+        let target = target.as_any_ref().downcast_ref::<ExfatINode>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        target.check_dir()?;
+        let mut inner = self.fs.inner.lock();
+        let entry = self.find_removable(&mut inner, old_name)?;
+        if entry.is_dir() && target.is_descendant_of(&mut inner, &entry)? {
+            // can not move a directory into itself
+            return Err(FsError::InvalidParam);
+        }
+        let dir = target.slots();
+        let dir = dir.as_ref().map(Vec::as_slice);
+        if inner.find_entry(dir, new_name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        let slots = inner.add_entry(dir, new_name, entry.attr, entry.flags, entry.first_cluster,
+                                    entry.size, entry.valid_size)?;
+        self.fs.relocate(&entry.slots, slots, target.this());
+        inner.remove_entry(&entry)
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        self.check_dir()?;
+        match name {
+            "." => return Ok(self.this()),
+            ".." => return Ok(self.parent().unwrap_or_else(|| self.fs.root())),
+            _ => {}
+        }
+        let mut inner = self.fs.inner.lock();
+        let dir = self.slots();
+        let entry = inner.find_entry(dir.as_ref().map(Vec::as_slice), name)?.ok_or(FsError::EntryNotFound)?;
+        Ok(self.fs.inode(&entry, self.this()))
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.check_dir()?;
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            _ => {
+                let mut inner = self.fs.inner.lock();
+                let dir = self.slots();
+                let entries = inner.dir_entries(dir.as_ref().map(Vec::as_slice))?;
+                entries.into_iter().nth(id - 2).map(|e| e.name).ok_or(FsError::EntryNotFound)
+            }
+        }
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
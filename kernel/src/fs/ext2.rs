@@ -0,0 +1,436 @@
+//! ext2 file system, read only
+//!
+//! ext4 file systems are also supported if they use no feature changing the
+//! layout other than extents, 64-bit block numbers and flexible block groups.
+
+use simple_filesystem::*;
+use alloc::{boxed::Box, sync::{Arc, Weak}, string::String, vec::Vec};
+use core::any::Any;
+use log::*;
+use crate::sync::ThreadLock as Mutex;
+use super::{device_error, FsUsage};
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INO: u32 = 2;
+/// Number of direct blocks in an inode
+const DIRECT_BLOCKS: usize = 12;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFLNK: u16 = 0o120000;
+
+/// Inode flag: the block array holds an extent tree
+const EXTENTS_FL: u32 = 0x80000;
+const EXTENT_MAGIC: u16 = 0xf30a;
+/// An extent longer than this is uninitialized, reading as zeros
+const EXTENT_INIT_MAX_LEN: usize = 32768;
+/// Max depth of an extent tree
+const EXTENT_MAX_DEPTH: u16 = 5;
+/// Max size of the group descriptor table read on opening
+const MAX_DESC_TABLE_SIZE: usize = 16 << 20;
+/// Max size of a directory, which is read at once
+const MAX_DIR_SIZE: usize = 16 << 20;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_EXTENTS: u32 = 0x0040;
+const INCOMPAT_64BIT: u32 = 0x0080;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+/// Incompatible features this driver understands.
+/// Checksums are not verified, and hashed directories are read linearly.
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_EXTENTS | INCOMPAT_64BIT
+    | INCOMPAT_FLEX_BG | INCOMPAT_CSUM_SEED | INCOMPAT_LARGEDIR;
+
+/// An ext2 file system. Modifications are not supported.
+pub struct Ext2FileSystem {
+    inner: Mutex<Ext2Inner>,
+    /// Read on opening, so that getting the root can not fail
+    root: RawInode,
+    /// Read on opening, as it never changes
+    usage: FsUsage,
+    self_ref: Mutex<Weak<Ext2FileSystem>>,
+}
+
+struct Ext2Inner {
+    device: Box<Device>,
+    block_size: usize,
+    inodes_per_group: usize,
+    inode_size: usize,
+    /// First block of the inode table of each group
+    inode_tables: Vec<u64>,
+}
+
+/// The fields of an on-disk inode used by this driver
+#[derive(Clone)]
+struct RawInode {
+    mode: u16,
+    size: usize,
+    links: u16,
+    /// Number of 512-byte sectors
+    sectors: u32,
+    flags: u32,
+    /// Block map, extent tree or symlink target
+    block: [u8; 60],
+}
+
+impl RawInode {
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+    /// Symbolic link whose target is stored in the block array
+    fn is_fast_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK && self.flags & EXTENTS_FL == 0 && self.size < 60
+    }
+    /// The `i`-th entry of the block map
+    fn block(&self, i: usize) -> u32 {
+        read_u32(&self.block[i * 4..i * 4 + 4])
+    }
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    read_u16(&b[0..2]) as u32 | (read_u16(&b[2..4]) as u32) << 16
+}
+
+impl Ext2FileSystem {
+    /// Open an ext2 file system on `device`
+    pub fn open(mut device: Box<Device>) -> Result<Arc<Self>> {
+        let mut sb = [0u8; 1024];
+        if device.read_at(SUPERBLOCK_OFFSET, &mut sb) != Some(1024) || read_u16(&sb[56..58]) != EXT2_MAGIC {
+            return Err(FsError::WrongFs);
+        }
+        let mut blocks = read_u32(&sb[4..8]) as u64;
+        let mut free_blocks = read_u32(&sb[12..16]) as u64;
+        let first_data_block = read_u32(&sb[20..24]) as usize;
+        let log_block_size = read_u32(&sb[24..28]);
+        let blocks_per_group = read_u32(&sb[32..36]) as usize;
+        let inodes_per_group = read_u32(&sb[40..44]) as usize;
+        let rev_level = read_u32(&sb[76..80]);
+        let (inode_size, incompat) = match rev_level {
+            0 => (128, 0),
+            _ => (read_u16(&sb[88..90]) as usize, read_u32(&sb[96..100])),
+        };
+        if log_block_size > 6 || blocks_per_group == 0 || inodes_per_group == 0 || inode_size < 128 {
+            return Err(FsError::WrongFs);
+        }
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            // e.g. compression, inline data, meta block groups, journal recovery needed
+            warn!("ext2: unsupported incompatible features {:#x}", incompat & !INCOMPAT_SUPPORTED);
+            return Err(FsError::WrongFs);
+        }
+        let mut desc_size = 32;
+        if incompat & INCOMPAT_64BIT != 0 {
+            blocks |= (read_u32(&sb[0x150..0x154]) as u64) << 32;
+            free_blocks |= (read_u32(&sb[0x158..0x15c]) as u64) << 32;
+            desc_size = read_u16(&sb[0xfe..0x100]) as usize;
+            if desc_size < 64 || desc_size > 1024 || !desc_size.is_power_of_two() {
+                return Err(FsError::WrongFs);
+            }
+        }
+        let block_size = 1024 << log_block_size;
+        if blocks <= first_data_block as u64 {
+            return Err(FsError::WrongFs);
+        }
+        let groups = (blocks - first_data_block as u64 + blocks_per_group as u64 - 1) / blocks_per_group as u64;
+        let desc_table_size = match (groups as usize).checked_mul(desc_size) {
+            Some(size) if size <= MAX_DESC_TABLE_SIZE => size,
+            _ => return Err(FsError::WrongFs),
+        };
+        // group descriptors start at the block after the superblock
+        let mut desc = vec![0u8; desc_table_size];
+        let desc_offset = (first_data_block + 1) * block_size;
+        if device.read_at(desc_offset, &mut desc) != Some(desc.len()) {
+            return Err(FsError::WrongFs);
+        }
+        let inode_tables = desc.chunks(desc_size).map(|d| {
+            let hi = if desc_size >= 64 { read_u32(&d[0x28..0x2c]) } else { 0 };
+            (hi as u64) << 32 | read_u32(&d[8..12]) as u64
+        }).collect();
+        let mut inner = Ext2Inner { device, block_size, inodes_per_group, inode_size, inode_tables };
+        let root = inner.read_inode(ROOT_INO)?;
+        if !root.is_dir() {
+            return Err(FsError::WrongFs);
+        }
+        let usage = FsUsage { block_size, blocks: blocks as usize, free_blocks: free_blocks as usize };
+        let fs = Arc::new(Ext2FileSystem {
+            inner: Mutex::new(inner),
+            root,
+            usage,
+            self_ref: Mutex::new(Weak::new()),
+        });
+        *fs.self_ref.lock() = Arc::downgrade(&fs);
+        Ok(fs)
+    }
+
+    pub fn usage(&self) -> Result<FsUsage> {
+        Ok(self.usage)
+    }
+
+    fn arc(&self) -> Arc<Ext2FileSystem> {
+        self.self_ref.lock().upgrade().unwrap()
+    }
+
+    fn inode(&self, ino: u32) -> Result<Arc<INode>> {
+        let inode = self.inner.lock().read_inode(ino)?;
+        Ok(Arc::new(Ext2INode { fs: self.arc(), inode }))
+    }
+}
+
+impl Ext2Inner {
+    fn read(&mut self, pos: usize, buf: &mut [u8]) -> Result<()> {
+        match self.device.read_at(pos, buf) {
+            Some(len) if len == buf.len() => Ok(()),
+            _ => Err(device_error()),
+        }
+    }
+
+    fn read_inode(&mut self, ino: u32) -> Result<RawInode> {
+        let index = ino as usize - 1;
+        let group = index / self.inodes_per_group;
+        let table = *self.inode_tables.get(group).ok_or(FsError::InvalidParam)? as usize;
+        let pos = table * self.block_size + index % self.inodes_per_group * self.inode_size;
+        let mut raw = [0u8; 128];
+        self.read(pos, &mut raw)?;
+        let mut block = [0u8; 60];
+        block.copy_from_slice(&raw[40..100]);
+        let mode = read_u16(&raw[0..2]);
+        let mut size = read_u32(&raw[4..8]) as usize;
+        if mode & S_IFMT != S_IFDIR {
+            // high 32 bits of size of regular files (i_dir_acl)
+            size |= (read_u32(&raw[108..112]) as u64 as usize) << 16 << 16;
+        }
+        Ok(RawInode {
+            mode,
+            size,
+            links: read_u16(&raw[26..28]),
+            sectors: read_u32(&raw[28..32]),
+            flags: read_u32(&raw[32..36]),
+            block,
+        })
+    }
+
+    /// Read the `index`-th entry of an indirect block
+    fn indirect(&mut self, block: u32, index: usize) -> Result<u32> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut buf = [0u8; 4];
+        let pos = block as usize * self.block_size + index * 4;
+        self.read(pos, &mut buf)?;
+        Ok(read_u32(&buf))
+    }
+
+    /// Map the `n`-th block of a file to a block on disk, 0 for holes
+    fn block_map(&mut self, inode: &RawInode, n: usize) -> Result<u64> {
+        if inode.flags & EXTENTS_FL != 0 {
+            return self.extent_map(inode, n);
+        }
+        let per_block = self.block_size / 4;
+        if n < DIRECT_BLOCKS {
+            return Ok(inode.block(n) as u64);
+        }
+        let n = n - DIRECT_BLOCKS;
+        if n < per_block {
+            return Ok(self.indirect(inode.block(12), n)? as u64);
+        }
+        let n = n - per_block;
+        if n < per_block * per_block {
+            let b = self.indirect(inode.block(13), n / per_block)?;
+            return Ok(self.indirect(b, n % per_block)? as u64);
+        }
+        let n = n - per_block * per_block;
+        let b = self.indirect(inode.block(14), n / per_block / per_block)?;
+        let b = self.indirect(b, n / per_block % per_block)?;
+        Ok(self.indirect(b, n % per_block)? as u64)
+    }
+
+    /// Map the `n`-th block of a file to a block on disk by its extent tree
+    fn extent_map(&mut self, inode: &RawInode, n: usize) -> Result<u64> {
+        let mut node = inode.block.to_vec();
+        let mut expected_depth = None;
+        loop {
+            // a node is a 12-byte header followed by 12-byte entries
+            if read_u16(&node[0..2]) != EXTENT_MAGIC {
+                warn!("ext2: bad extent tree");
+                return Ok(0);
+            }
+            let entries = (read_u16(&node[2..4]) as usize).min(node.len() / 12 - 1);
+            let depth = read_u16(&node[6..8]);
+            // each level is one shallower than its parent, so the walk ends
+            if depth > EXTENT_MAX_DEPTH || expected_depth.map_or(false, |d| d != depth) {
+                warn!("ext2: bad extent tree depth {}", depth);
+                return Err(FsError::WrongFs);
+            }
+            // the last entry starting at or before `n`
+            let entry = match (0..entries).map(|i| &node[12 + i * 12..24 + i * 12])
+                .take_while(|e| read_u32(&e[0..4]) as usize <= n)
+                .last() {
+                Some(entry) => entry,
+                None => return Ok(0),
+            };
+            if depth == 0 {
+                let start = read_u32(&entry[0..4]) as usize;
+                let len = read_u16(&entry[4..6]) as usize;
+                if len > EXTENT_INIT_MAX_LEN || n >= start + len {
+                    return Ok(0);
+                }
+                let block = (read_u16(&entry[6..8]) as u64) << 32 | read_u32(&entry[8..12]) as u64;
+                return Ok(block + (n - start) as u64);
+            }
+            let child = (read_u16(&entry[8..10]) as u64) << 32 | read_u32(&entry[4..8]) as u64;
+            expected_depth = Some(depth - 1);
+            node = vec![0u8; self.block_size];
+            self.read(child as usize * self.block_size, &mut node)?;
+        }
+    }
+
+    fn read_file(&mut self, inode: &RawInode, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buf.len().min(inode.size - offset);
+        let bs = self.block_size;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let n = (bs - pos % bs).min(len - done);
+            match self.block_map(inode, pos / bs)? {
+                0 => {
+                    for b in buf[done..done + n].iter_mut() {
+                        *b = 0;
+                    }
+                }
+                block => self.read(block as usize * bs + pos % bs, &mut buf[done..done + n])?,
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// (name, inode number) of all entries in a directory
+    fn dir_entries(&mut self, inode: &RawInode) -> Result<Vec<(String, u32)>> {
+        if inode.size > MAX_DIR_SIZE {
+            return Err(FsError::WrongFs);
+        }
+        let mut data = vec![0u8; inode.size];
+        self.read_file(inode, 0, &mut data)?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let ino = read_u32(&data[pos..pos + 4]);
+            let rec_len = read_u16(&data[pos + 4..pos + 6]) as usize;
+            let name_len = data[pos + 6] as usize;
+            if rec_len < 8 || pos + 8 + name_len > data.len() {
+                break;
+            }
+            if ino != 0 {
+                let name = String::from_utf8_lossy(&data[pos + 8..pos + 8 + name_len]).into_owned();
+                entries.push((name, ino));
+            }
+            pos += rec_len;
+        }
+        Ok(entries)
+    }
+}
+
+impl FileSystem for Ext2FileSystem {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        Arc::new(Ext2INode { fs: self.arc(), inode: self.root.clone() })
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: 255 };
+        &INFO
+    }
+}
+
+/// An inode in an ext2 file system.
+///
+/// Since the file system is read only, the inode is read only once on opening.
+/// Symbolic links are presented as files containing the target path.
+pub struct Ext2INode {
+    pub(super) fs: Arc<Ext2FileSystem>,
+    inode: RawInode,
+}
+
+impl Ext2INode {
+    fn entries(&self) -> Result<Vec<(String, u32)>> {
+        if !self.inode.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        self.fs.inner.lock().dir_entries(&self.inode)
+    }
+}
+
+impl INode for Ext2INode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.inode.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        if self.inode.is_fast_symlink() {
+            let size = self.inode.size;
+            if offset >= size {
+                return Ok(0);
+            }
+            let len = buf.len().min(size - offset);
+            buf[..len].copy_from_slice(&self.inode.block[offset..offset + len]);
+            return Ok(len);
+        }
+        self.fs.inner.lock().read_file(&self.inode, offset, buf)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+    fn info(&self) -> Result<FileInfo> {
+        Ok(FileInfo {
+            size: self.inode.size,
+            mode: (self.inode.mode & !S_IFMT) as u32,
+            type_: if self.inode.is_dir() { FileType::Dir } else { FileType::File },
+            blocks: self.inode.sectors as usize,
+            nlinks: self.inode.links as usize,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, _len: usize) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn create(&self, _name: &str, _type_: FileType) -> Result<Arc<INode>> {
+        Err(FsError::NotSupported)
+    }
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn link(&self, _name: &str, _other: &Arc<INode>) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn rename(&self, _old_name: &str, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn move_(&self, _old_name: &str, _target: &Arc<INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        let (_, ino) = self.entries()?.into_iter()
+            .find(|(n, _)| n == name)
+            .ok_or(FsError::EntryNotFound)?;
+        self.fs.inode(ino)
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.entries()?.into_iter().nth(id).map(|(name, _)| name).ok_or(FsError::EntryNotFound)
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
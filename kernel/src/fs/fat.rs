@@ -0,0 +1,962 @@
+//! FAT16 / FAT32 file system, with long file names
+
+use simple_filesystem::*;
+use alloc::{boxed::Box, collections::BTreeMap, sync::{Arc, Weak}, string::String, vec::Vec};
+use core::any::Any;
+use crate::sync::ThreadLock as Mutex;
+use super::{device_error, FsUsage};
+use crate::time;
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// First byte of a deleted directory entry
+const ENTRY_DELETED: u8 = 0xe5;
+/// Number of UCS-2 characters in a long name entry
+const LFN_CHARS: usize = 13;
+/// Byte offsets of characters in a long name entry
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// A FAT file system
+pub struct FatFileSystem {
+    inner: Mutex<FatInner>,
+    /// Open inodes by the byte offset of their short entry, so that all handles
+    /// of a file follow it when it is renamed or removed
+    inodes: Mutex<BTreeMap<usize, Weak<FatINode>>>,
+    self_ref: Mutex<Weak<FatFileSystem>>,
+}
+
+struct FatInner {
+    device: Box<Device>,
+    fat_type: FatType,
+    bytes_per_sector: usize,
+    cluster_size: usize,
+    /// Byte offset of the first FAT
+    fat_offset: usize,
+    fat_size: usize,
+    num_fats: usize,
+    /// Byte offset and size of the fixed root directory (FAT16)
+    root_offset: usize,
+    root_size: usize,
+    /// First cluster of the root directory (FAT32)
+    root_cluster: u32,
+    /// Byte offset of cluster 2
+    data_offset: usize,
+    /// Number of data clusters
+    clusters: u32,
+    /// Where to start searching for a free cluster
+    alloc_hint: u32,
+    /// The chain last walked with its first cluster, dropped when the FAT changes
+    chain_cache: Option<(u32, Vec<u32>)>,
+}
+
+/// Where the entries of a directory are stored
+#[derive(Debug, Clone, Copy)]
+enum DirLoc {
+    /// The fixed root directory of FAT16
+    FixedRoot,
+    Chain(u32),
+}
+
+/// A directory entry, with its long name if any
+struct Entry {
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    /// Byte offsets of all slots of the entry, the short entry is the last one
+    slots: Vec<usize>,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+    fn pos(&self) -> usize {
+        *self.slots.last().unwrap()
+    }
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    read_u16(&b[0..2]) as u32 | (read_u16(&b[2..4]) as u32) << 16
+}
+
+fn write_u16(b: &mut [u8], v: u16) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+}
+
+fn write_u32(b: &mut [u8], v: u32) {
+    write_u16(&mut b[0..2], v as u16);
+    write_u16(&mut b[2..4], (v >> 16) as u16);
+}
+
+impl FatFileSystem {
+    /// Open a FAT16 / FAT32 file system on `device`
+    pub fn open(mut device: Box<Device>) -> Result<Arc<Self>> {
+        let mut bpb = [0u8; 512];
+        if device.read_at(0, &mut bpb) != Some(512) || bpb[510] != 0x55 || bpb[511] != 0xaa {
+            return Err(FsError::WrongFs);
+        }
+        let bytes_per_sector = read_u16(&bpb[11..13]) as usize;
+        let sectors_per_cluster = bpb[13] as usize;
+        let reserved_sectors = read_u16(&bpb[14..16]) as usize;
+        let num_fats = bpb[16] as usize;
+        let root_entries = read_u16(&bpb[17..19]) as usize;
+        let total_sectors = match read_u16(&bpb[19..21]) {
+            0 => read_u32(&bpb[32..36]) as usize,
+            n => n as usize,
+        };
+        let fat_sectors = match read_u16(&bpb[22..24]) {
+            0 => read_u32(&bpb[36..40]) as usize,
+            n => n as usize,
+        };
+        if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 512
+            || !sectors_per_cluster.is_power_of_two() || num_fats == 0 || fat_sectors == 0 {
+            return Err(FsError::WrongFs);
+        }
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE + bytes_per_sector - 1) / bytes_per_sector;
+        let data_sector = reserved_sectors + num_fats * fat_sectors + root_sectors;
+        if total_sectors <= data_sector {
+            return Err(FsError::WrongFs);
+        }
+        let clusters = ((total_sectors - data_sector) / sectors_per_cluster) as u32;
+        let fat_type = if clusters < 4085 {
+            // FAT12 is not supported
+            return Err(FsError::WrongFs);
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        let inner = FatInner {
+            device,
+            fat_type,
+            bytes_per_sector,
+            cluster_size: bytes_per_sector * sectors_per_cluster,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            fat_size: fat_sectors * bytes_per_sector,
+            num_fats,
+            root_offset: (reserved_sectors + num_fats * fat_sectors) * bytes_per_sector,
+            root_size: root_sectors * bytes_per_sector,
+            root_cluster: read_u32(&bpb[44..48]),
+            data_offset: data_sector * bytes_per_sector,
+            clusters,
+            alloc_hint: 2,
+            chain_cache: None,
+        };
+        let fs = Arc::new(FatFileSystem {
+            inner: Mutex::new(inner),
+            inodes: Mutex::new(BTreeMap::new()),
+            self_ref: Mutex::new(Weak::new()),
+        });
+        *fs.self_ref.lock() = Arc::downgrade(&fs);
+        Ok(fs)
+    }
+
+    /// Free clusters are counted in the FAT on each call
+    pub fn usage(&self) -> Result<FsUsage> {
+        let mut inner = self.inner.lock();
+        let free = inner.free_clusters()?;
+        Ok(FsUsage { block_size: inner.cluster_size, blocks: inner.clusters as usize, free_blocks: free })
+    }
+
+    fn arc(&self) -> Arc<FatFileSystem> {
+        self.self_ref.lock().upgrade().unwrap()
+    }
+
+    /// The inode of the short entry at `pos`, shared by all handles of the file
+    fn inode(&self, pos: usize, is_dir: bool) -> Arc<FatINode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&pos).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let inode = Arc::new(FatINode { fs: self.arc(), is_dir, loc: Mutex::new(Location::Entry(pos)) });
+        inodes.insert(pos, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Point open handles of the short entry at `old` to `new`
+    fn relocate(&self, old: usize, new: usize) {
+        let mut inodes = self.inodes.lock();
+        let inode = match inodes.remove(&old).and_then(|inode| inode.upgrade()) {
+            Some(inode) => inode,
+            None => return,
+        };
+        inodes.insert(new, Arc::downgrade(&inode));
+        *inode.loc.lock() = Location::Entry(new);
+        // it may be the last handle, whose drop takes `inodes`
+        drop(inodes);
+    }
+
+    /// Detach open handles from the removed `entry`, they keep its clusters until dropped.
+    /// Return None if it is not open.
+    fn orphan(&self, entry: &Entry) -> Option<Arc<FatINode>> {
+        let inode = self.inodes.lock().remove(&entry.pos()).and_then(|inode| inode.upgrade())?;
+        *inode.loc.lock() = Location::Orphan(entry.first_cluster, entry.size);
+        Some(inode)
+    }
+}
+
+impl FatInner {
+    fn read(&mut self, pos: usize, buf: &mut [u8]) -> Result<()> {
+        match self.device.read_at(pos, buf) {
+            Some(len) if len == buf.len() => Ok(()),
+            _ => Err(device_error()),
+        }
+    }
+    fn write(&mut self, pos: usize, buf: &[u8]) -> Result<()> {
+        match self.device.write_at(pos, buf) {
+            Some(len) if len == buf.len() => Ok(()),
+            _ => Err(device_error()),
+        }
+    }
+
+    fn cluster_pos(&self, cluster: u32) -> usize {
+        self.data_offset + (cluster as usize - 2) * self.cluster_size
+    }
+    fn fat_entry_pos(&self, cluster: u32) -> usize {
+        match self.fat_type {
+            FatType::Fat16 => self.fat_offset + cluster as usize * 2,
+            FatType::Fat32 => self.fat_offset + cluster as usize * 4,
+        }
+    }
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.clusters + 2
+    }
+
+    fn fat_get(&mut self, cluster: u32) -> Result<u32> {
+        let pos = self.fat_entry_pos(cluster);
+        let mut buf = [0u8; 4];
+        match self.fat_type {
+            FatType::Fat16 => {
+                self.read(pos, &mut buf[..2])?;
+                Ok(read_u16(&buf) as u32)
+            }
+            FatType::Fat32 => {
+                self.read(pos, &mut buf)?;
+                Ok(read_u32(&buf) & 0x0fff_ffff)
+            }
+        }
+    }
+    /// Count the clusters whose FAT entry is 0
+    fn free_clusters(&mut self) -> Result<usize> {
+        let entry_size = self.fat_entry_pos(1) - self.fat_entry_pos(0);
+        let mut buf = vec![0u8; 4096];
+        let mut free = 0;
+        let mut cluster = 2;
+        let end = self.clusters as usize + 2;
+        let fat_type = self.fat_type;
+        while cluster < end {
+            let count = (buf.len() / entry_size).min(end - cluster);
+            let pos = self.fat_entry_pos(cluster as u32);
+            self.read(pos, &mut buf[..count * entry_size])?;
+            free += buf[..count * entry_size].chunks(entry_size).filter(|entry| match fat_type {
+                FatType::Fat16 => read_u16(entry) == 0,
+                FatType::Fat32 => read_u32(entry) & 0x0fff_ffff == 0,
+            }).count();
+            cluster += count;
+        }
+        Ok(free)
+    }
+    /// Set FAT entry of `cluster` in all FATs
+    fn fat_set(&mut self, cluster: u32, value: u32) -> Result<()> {
+        self.chain_cache = None;
+        let pos = self.fat_entry_pos(cluster);
+        for i in 0..self.num_fats {
+            let pos = pos + i * self.fat_size;
+            let mut buf = [0u8; 4];
+            match self.fat_type {
+                FatType::Fat16 => {
+                    write_u16(&mut buf, value as u16);
+                    self.write(pos, &buf[..2])?;
+                }
+                FatType::Fat32 => {
+                    // keep the reserved high 4 bits
+                    self.read(pos, &mut buf)?;
+                    let value = (read_u32(&buf) & 0xf000_0000) | (value & 0x0fff_ffff);
+                    write_u32(&mut buf, value);
+                    self.write(pos, &buf)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Clusters of the chain starting from `first`
+    fn chain(&mut self, first: u32) -> Result<&[u32]> {
+        let cached = match self.chain_cache {
+            Some((cached, _)) => cached == first,
+            None => false,
+        };
+        if !cached {
+            let mut clusters = Vec::new();
+            let mut cur = first;
+            while self.is_valid_cluster(cur) && clusters.len() <= self.clusters as usize {
+                clusters.push(cur);
+                cur = self.fat_get(cur)?;
+            }
+            self.chain_cache = Some((first, clusters));
+        }
+        Ok(&self.chain_cache.as_ref().unwrap().1)
+    }
+
+    /// Allocate a zeroed cluster, append it to `prev` if given
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32> {
+        // keep the cached chain if the cluster is appended to it
+        let cache = self.chain_cache.take()
+            .filter(|(_, chain)| prev.is_some() && chain.last() == prev.as_ref());
+        for i in 0..self.clusters {
+            let cluster = (self.alloc_hint - 2 + i) % self.clusters + 2;
+            if self.fat_get(cluster)? == 0 {
+                let eoc = self.end_of_chain();
+                self.fat_set(cluster, eoc)?;
+                if let Some(prev) = prev {
+                    self.fat_set(prev, cluster)?;
+                }
+                let zeros = vec![0u8; self.cluster_size];
+                let pos = self.cluster_pos(cluster);
+                self.write(pos, &zeros)?;
+                self.alloc_hint = cluster;
+                if let Some((first, mut chain)) = cache {
+                    chain.push(cluster);
+                    self.chain_cache = Some((first, chain));
+                }
+                return Ok(cluster);
+            }
+        }
+        Err(FsError::NoDeviceSpace)
+    }
+
+    /// Free clusters of the chain starting from `first`
+    fn free_chain(&mut self, first: u32) -> Result<()> {
+        for cluster in self.chain(first)?.to_vec() {
+            self.fat_set(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Byte offsets of all slots of a directory
+    fn dir_slots(&mut self, loc: DirLoc) -> Result<Vec<usize>> {
+        match loc {
+            DirLoc::FixedRoot => Ok((0..self.root_size / DIR_ENTRY_SIZE)
+                .map(|i| self.root_offset + i * DIR_ENTRY_SIZE)
+                .collect()),
+            DirLoc::Chain(first) => {
+                let mut slots = Vec::new();
+                for cluster in self.chain(first)?.to_vec() {
+                    let pos = self.cluster_pos(cluster);
+                    slots.extend((0..self.cluster_size / DIR_ENTRY_SIZE).map(|i| pos + i * DIR_ENTRY_SIZE));
+                }
+                Ok(slots)
+            }
+        }
+    }
+
+    /// Entries of a directory, excluding volume labels
+    fn dir_entries(&mut self, loc: DirLoc) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut lfn: Vec<(u8, [u16; LFN_CHARS])> = Vec::new();
+        let mut lfn_slots = Vec::new();
+        let mut lfn_checksum = 0;
+        for pos in self.dir_slots(loc)? {
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            self.read(pos, &mut raw)?;
+            if raw[0] == 0 {
+                break;
+            }
+            if raw[0] == ENTRY_DELETED {
+                lfn.clear();
+                lfn_slots.clear();
+                continue;
+            }
+            if raw[11] == ATTR_LONG_NAME {
+                if raw[0] & 0x40 != 0 {
+                    lfn.clear();
+                    lfn_slots.clear();
+                    lfn_checksum = raw[13];
+                }
+                let mut chars = [0u16; LFN_CHARS];
+                for (c, &off) in chars.iter_mut().zip(LFN_CHAR_OFFSETS.iter()) {
+                    *c = read_u16(&raw[off..off + 2]);
+                }
+                lfn.push((raw[0] & 0x1f, chars));
+                lfn_slots.push(pos);
+                continue;
+            }
+            let attr = raw[11];
+            if attr & ATTR_VOLUME_ID != 0 {
+                lfn.clear();
+                lfn_slots.clear();
+                continue;
+            }
+            let mut short_name = [0u8; 11];
+            short_name.copy_from_slice(&raw[..11]);
+            let long_name = if !lfn.is_empty() && lfn_checksum == short_name_checksum(&short_name) {
+                lfn.sort_by_key(|&(ord, _)| ord);
+                let units: Vec<u16> = lfn.iter()
+                    .flat_map(|(_, chars)| chars.iter().cloned())
+                    .take_while(|&c| c != 0)
+                    .collect();
+                Some(core::char::decode_utf16(units.iter().cloned())
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                    .collect::<String>())
+            } else {
+                None
+            };
+            let mut slots = if long_name.is_some() { lfn_slots.clone() } else { Vec::new() };
+            slots.push(pos);
+            entries.push(Entry {
+                name: long_name.unwrap_or_else(|| decode_short_name(&short_name)),
+                short_name,
+                attr,
+                first_cluster: (read_u16(&raw[20..22]) as u32) << 16 | read_u16(&raw[26..28]) as u32,
+                size: read_u32(&raw[28..32]),
+                slots,
+            });
+            lfn.clear();
+            lfn_slots.clear();
+        }
+        Ok(entries)
+    }
+
+    fn find_entry(&mut self, loc: DirLoc, name: &str) -> Result<Option<Entry>> {
+        Ok(self.dir_entries(loc)?.into_iter().find(|e| {
+            e.name.eq_ignore_ascii_case(name) || decode_short_name(&e.short_name).eq_ignore_ascii_case(name)
+        }))
+    }
+
+    /// Find `count` consecutive free slots in a directory, extend it if needed
+    fn alloc_slots(&mut self, loc: DirLoc, count: usize) -> Result<Vec<usize>> {
+        loop {
+            let slots = self.dir_slots(loc)?;
+            let mut run = Vec::new();
+            for &pos in slots.iter() {
+                let mut first = [0u8; 1];
+                self.read(pos, &mut first)?;
+                if first[0] == 0 || first[0] == ENTRY_DELETED {
+                    run.push(pos);
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+            match loc {
+                DirLoc::FixedRoot => return Err(FsError::NoDeviceSpace),
+                DirLoc::Chain(first) => {
+                    let last = *self.chain(first)?.last().ok_or(FsError::InvalidParam)?;
+                    self.alloc_cluster(Some(last))?;
+                }
+            }
+        }
+    }
+
+    /// Create entries named `name` in a directory, return the byte offset of the short entry
+    fn add_entry(&mut self, loc: DirLoc, name: &str, attr: u8, first_cluster: u32, size: u32) -> Result<usize> {
+        let existing: Vec<[u8; 11]> = self.dir_entries(loc)?.iter().map(|e| e.short_name).collect();
+        let (short_name, need_lfn) = match encode_short_name(name) {
+            Some(short) if !existing.contains(&short) => (short, false),
+            _ => {
+                let short = (1..1000000)
+                    .map(|n| alias_short_name(name, n))
+                    .find(|s| !existing.contains(s))
+                    .ok_or(FsError::EntryExist)?;
+                (short, true)
+            }
+        };
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let lfn_count = if need_lfn { (units.len() + LFN_CHARS - 1) / LFN_CHARS } else { 0 };
+        let slots = self.alloc_slots(loc, lfn_count + 1)?;
+        let checksum = short_name_checksum(&short_name);
+        for (i, &pos) in slots[..lfn_count].iter().enumerate() {
+            let ord = lfn_count - i;
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            raw[0] = ord as u8 | if i == 0 { 0x40 } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+            for (j, &off) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                let k = (ord - 1) * LFN_CHARS + j;
+                let c = if k < units.len() { units[k] } else if k == units.len() { 0 } else { 0xffff };
+                write_u16(&mut raw[off..off + 2], c);
+            }
+            self.write(pos, &raw)?;
+        }
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[..11].copy_from_slice(&short_name);
+        raw[11] = attr;
+        // created, accessed and modified
+        let timestamp = time::now().to_dos();
+        write_u32(&mut raw[14..18], timestamp);
+        write_u16(&mut raw[18..20], (timestamp >> 16) as u16);
+        write_u32(&mut raw[22..26], timestamp);
+        write_u16(&mut raw[20..22], (first_cluster >> 16) as u16);
+        write_u16(&mut raw[26..28], first_cluster as u16);
+        write_u32(&mut raw[28..32], size);
+        self.write(slots[lfn_count], &raw)?;
+        Ok(slots[lfn_count])
+    }
+
+    fn remove_entry(&mut self, entry: &Entry) -> Result<()> {
+        for &pos in entry.slots.iter() {
+            self.write(pos, &[ENTRY_DELETED])?;
+        }
+        Ok(())
+    }
+
+    fn read_short_entry(&mut self, pos: usize) -> Result<[u8; DIR_ENTRY_SIZE]> {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        self.read(pos, &mut raw)?;
+        Ok(raw)
+    }
+    fn set_first_cluster(&mut self, pos: usize, cluster: u32) -> Result<()> {
+        let mut raw = self.read_short_entry(pos)?;
+        write_u16(&mut raw[20..22], (cluster >> 16) as u16);
+        write_u16(&mut raw[26..28], cluster as u16);
+        self.write(pos, &raw)
+    }
+    fn set_size(&mut self, pos: usize, size: u32) -> Result<()> {
+        let mut buf = [0u8; 4];
+        write_u32(&mut buf, size);
+        self.write(pos + 28, &buf)
+    }
+}
+
+/// Checksum of a short name, stored in its long name entries
+fn short_name_checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+fn decode_short_name(raw: &[u8; 11]) -> String {
+    let mut name: String = raw[..8].iter().map(|&c| c as char).collect::<String>().trim_end().into();
+    if name.starts_with('\u{5}') {
+        // 0x05 stands for 0xe5 in the first byte
+        name.replace_range(..1, "\u{e5}");
+    }
+    let ext: String = raw[8..].iter().map(|&c| c as char).collect::<String>().trim_end().into();
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+/// Encode `name` as a 8.3 short name, None if it needs a long name
+fn encode_short_name(name: &str) -> Option<[u8; 11]> {
+    if name == "." || name == ".." {
+        let mut raw = [b' '; 11];
+        raw[..name.len()].copy_from_slice(name.as_bytes());
+        return Some(raw);
+    }
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3
+        || !base.chars().chain(ext.chars()).all(is_short_name_char) {
+        return None;
+    }
+    let mut raw = [b' '; 11];
+    raw[..base.len()].copy_from_slice(base.as_bytes());
+    raw[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(raw)
+}
+
+/// Generate the `n`-th short alias of a long name, like `LONGNA~1.TXT`
+fn alias_short_name(name: &str, n: usize) -> [u8; 11] {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+    let convert = |s: &str| -> Vec<u8> {
+        s.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| c.to_ascii_uppercase())
+            .map(|c| if is_short_name_char(c) { c as u8 } else { b'_' })
+            .collect()
+    };
+    let tail = format!("~{}", n);
+    let mut base = convert(base);
+    base.truncate(8 - tail.len());
+    base.extend_from_slice(tail.as_bytes());
+    let mut ext = convert(ext);
+    ext.truncate(3);
+    let mut raw = [b' '; 11];
+    raw[..base.len()].copy_from_slice(&base);
+    raw[8..8 + ext.len()].copy_from_slice(&ext);
+    raw
+}
+
+impl FileSystem for FatFileSystem {
+    fn sync(&self) -> Result<()> {
+        // all changes are written to device immediately
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        Arc::new(FatINode { fs: self.arc(), is_dir: true, loc: Mutex::new(Location::Root) })
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: 255 };
+        &INFO
+    }
+}
+
+/// A file or directory in a FAT file system.
+///
+/// Size and first cluster are read from its directory entry on each access,
+/// and there is one inode for each open file, so different handles always agree.
+pub struct FatINode {
+    pub(super) fs: Arc<FatFileSystem>,
+    is_dir: bool,
+    /// Changed by rename and unlink, with the file system locked
+    loc: Mutex<Location>,
+}
+
+/// Where the size and first cluster of an inode are kept
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    /// The root directory, which has no entry
+    Root,
+    /// Byte offset of its short directory entry
+    Entry(usize),
+    /// (first cluster, size) of a file removed while open,
+    /// its clusters are freed when the last handle is dropped
+    Orphan(u32, u32),
+}
+
+impl FatINode {
+    fn location(&self) -> Location {
+        *self.loc.lock()
+    }
+    /// (first cluster, size) of this file
+    fn meta(&self, inner: &mut FatInner) -> Result<(u32, u32)> {
+        match self.location() {
+            Location::Root => Ok((inner.root_cluster, 0)),
+            Location::Entry(pos) => {
+                let raw = inner.read_short_entry(pos)?;
+                Ok(((read_u16(&raw[20..22]) as u32) << 16 | read_u16(&raw[26..28]) as u32, read_u32(&raw[28..32])))
+            }
+            Location::Orphan(first, size) => Ok((first, size)),
+        }
+    }
+    /// Set (first cluster, size) of this file
+    fn set_meta(&self, inner: &mut FatInner, first: u32, size: u32) -> Result<()> {
+        let mut loc = self.loc.lock();
+        match *loc {
+            Location::Root => Err(FsError::IsDir),
+            Location::Entry(pos) => {
+                inner.set_first_cluster(pos, first)?;
+                inner.set_size(pos, size)
+            }
+            Location::Orphan(..) => {
+                *loc = Location::Orphan(first, size);
+                Ok(())
+            }
+        }
+    }
+    /// Make this file hold at least `clusters` clusters, return its first cluster
+    fn grow_chain(&self, inner: &mut FatInner, first: u32, size: u32, clusters: usize) -> Result<u32> {
+        let (mut len, mut last) = {
+            let chain = inner.chain(first)?;
+            (chain.len(), chain.last().cloned())
+        };
+        let mut first = first;
+        while len < clusters {
+            let cluster = inner.alloc_cluster(last)?;
+            if last.is_none() {
+                first = cluster;
+                self.set_meta(inner, first, size)?;
+            }
+            last = Some(cluster);
+            len += 1;
+        }
+        Ok(first)
+    }
+    fn dir_loc(&self, inner: &mut FatInner) -> Result<DirLoc> {
+        if !self.is_dir {
+            return Err(FsError::NotDir);
+        }
+        match self.location() {
+            Location::Root if inner.fat_type == FatType::Fat16 => Ok(DirLoc::FixedRoot),
+            Location::Orphan(..) => Err(FsError::DirRemoved),
+            _ => Ok(DirLoc::Chain(self.meta(inner)?.0)),
+        }
+    }
+    fn child(&self, entry: &Entry) -> Arc<INode> {
+        if entry.is_dir() && entry.first_cluster == 0 {
+            // `..` pointing to the root directory
+            return self.fs.root_inode();
+        }
+        self.fs.inode(entry.pos(), entry.is_dir())
+    }
+    /// Entry of `name` which can be renamed or deleted
+    fn find_removable(&self, inner: &mut FatInner, name: &str) -> Result<(DirLoc, Entry)> {
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidParam);
+        }
+        let loc = self.dir_loc(inner)?;
+        let entry = inner.find_entry(loc, name)?.ok_or(FsError::EntryNotFound)?;
+        Ok((loc, entry))
+    }
+}
+
+impl Drop for FatINode {
+    fn drop(&mut self) {
+        match self.location() {
+            Location::Root => {}
+            Location::Entry(pos) => {
+                let mut inodes = self.fs.inodes.lock();
+                // another handle may have been opened since this one was released,
+                // it must not be dropped with `inodes` locked
+                let other = inodes.get(&pos).and_then(Weak::upgrade);
+                if other.is_none() {
+                    inodes.remove(&pos);
+                }
+                drop(inodes);
+            }
+            Location::Orphan(first, _) => {
+                // nothing to report to, failing to free only leaks the clusters
+                let _ = self.fs.inner.lock().free_chain(first);
+            }
+        }
+    }
+}
+
+impl INode for FatINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.is_dir {
+            return Err(FsError::IsDir);
+        }
+        let mut inner = self.fs.inner.lock();
+        let (first, size) = self.meta(&mut inner)?;
+        let size = size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        let cs = inner.cluster_size;
+        let clusters = inner.chain(first)?
+            .get(offset / cs..(offset + len + cs - 1) / cs)
+            .ok_or(FsError::InvalidParam)?
+            .to_vec();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let cluster = clusters[pos / cs - offset / cs];
+            let n = (cs - pos % cs).min(len - done);
+            let dev_pos = inner.cluster_pos(cluster) + pos % cs;
+            inner.read(dev_pos, &mut buf[done..done + n])?;
+            done += n;
+        }
+        Ok(len)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        if self.is_dir {
+            return Err(FsError::IsDir);
+        }
+        let mut inner = self.fs.inner.lock();
+        let (first, size) = self.meta(&mut inner)?;
+        let end = match offset.checked_add(buf.len()) {
+            Some(end) if end <= u32::max_value() as usize => end,
+            _ => return Err(FsError::InvalidParam),
+        };
+        let cs = inner.cluster_size;
+        let first = self.grow_chain(&mut inner, first, size, (end + cs - 1) / cs)?;
+        let clusters = inner.chain(first)?[offset / cs..(end + cs - 1) / cs].to_vec();
+        let mut done = 0;
+        while done < buf.len() {
+            let p = offset + done;
+            let n = (cs - p % cs).min(buf.len() - done);
+            let dev_pos = inner.cluster_pos(clusters[p / cs - offset / cs]) + p % cs;
+            inner.write(dev_pos, &buf[done..done + n])?;
+            done += n;
+        }
+        if end > size as usize {
+            self.set_meta(&mut inner, first, end as u32)?;
+        }
+        Ok(buf.len())
+    }
+    fn info(&self) -> Result<FileInfo> {
+        let mut inner = self.fs.inner.lock();
+        let (first, size) = self.meta(&mut inner)?;
+        let clusters = match self.dir_loc(&mut inner) {
+            Ok(DirLoc::FixedRoot) => 0,
+            _ => inner.chain(first)?.len(),
+        };
+        let size = if self.is_dir { clusters * inner.cluster_size } else { size as usize };
+        Ok(FileInfo {
+            size,
+            // use default permissions
+            mode: 0,
+            type_: if self.is_dir { FileType::Dir } else { FileType::File },
+            blocks: clusters * inner.cluster_size / inner.bytes_per_sector,
+            nlinks: 1,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, len: usize) -> Result<()> {
+        if self.is_dir {
+            return Err(FsError::IsDir);
+        }
+        if len > u32::max_value() as usize {
+            return Err(FsError::InvalidParam);
+        }
+        let mut inner = self.fs.inner.lock();
+        let (first, size) = self.meta(&mut inner)?;
+        let cs = inner.cluster_size;
+        let clusters = (len + cs - 1) / cs;
+        let mut first = self.grow_chain(&mut inner, first, size, clusters)?;
+        let chain = inner.chain(first)?.to_vec();
+        if clusters == 0 {
+            if !chain.is_empty() {
+                inner.free_chain(chain[0])?;
+                first = 0;
+            }
+        } else if chain.len() > clusters {
+            inner.free_chain(chain[clusters])?;
+            let eoc = inner.end_of_chain();
+            inner.fat_set(chain[clusters - 1], eoc)?;
+        }
+        if len < size as usize && len % cs != 0 {
+            // zero the tail of the last cluster, which is read again if the file grows
+            let zeros = vec![0u8; cs - len % cs];
+            let pos = inner.cluster_pos(chain[clusters - 1]) + len % cs;
+            inner.write(pos, &zeros)?;
+        }
+        self.set_meta(&mut inner, first, len as u32)
+    }
+    fn create(&self, name: &str, type_: FileType) -> Result<Arc<INode>> {
+        if name.is_empty() || name.len() > 255 || name.contains('/') || name == "." || name == ".." {
+            return Err(FsError::InvalidParam);
+        }
+        let mut inner = self.fs.inner.lock();
+        let loc = self.dir_loc(&mut inner)?;
+        if inner.find_entry(loc, name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        let pos = match type_ {
+            FileType::File => inner.add_entry(loc, name, ATTR_ARCHIVE, 0, 0)?,
+            FileType::Dir => {
+                let cluster = inner.alloc_cluster(None)?;
+                let parent = match (self.location(), loc) {
+                    // `..` of entries in the root directory is 0
+                    (Location::Root, _) | (_, DirLoc::FixedRoot) => 0,
+                    (_, DirLoc::Chain(c)) => c,
+                };
+                let this = DirLoc::Chain(cluster);
+                let result = inner.add_entry(this, ".", ATTR_DIRECTORY, cluster, 0)
+                    .and_then(|_| inner.add_entry(this, "..", ATTR_DIRECTORY, parent, 0))
+                    .and_then(|_| inner.add_entry(loc, name, ATTR_DIRECTORY, cluster, 0));
+                match result {
+                    Ok(pos) => pos,
+                    Err(e) => {
+                        // report the first error, failing to free only leaks the cluster
+                        let _ = inner.free_chain(cluster);
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        drop(inner);
+        Ok(self.fs.inode(pos, type_ == FileType::Dir))
+    }
+    fn unlink(&self, name: &str) -> Result<()> {
+        let mut inner = self.fs.inner.lock();
+        let (_, entry) = self.find_removable(&mut inner, name)?;
+        if entry.attr & ATTR_READ_ONLY != 0 {
+            return Err(FsError::NotSupported);
+        }
+        if entry.is_dir() {
+            let children = inner.dir_entries(DirLoc::Chain(entry.first_cluster))?;
+            if children.iter().any(|e| e.name != "." && e.name != "..") {
+                return Err(FsError::DirNotEmpty);
+            }
+        }
+        inner.remove_entry(&entry)?;
+        let open = self.fs.orphan(&entry);
+        if open.is_none() {
+            inner.free_chain(entry.first_cluster)?;
+        }
+        // it may be the last handle, whose drop frees the clusters with the file system locked
+        drop(inner);
+        drop(open);
+        Ok(())
+    }
+    fn link(&self, _name: &str, _other: &Arc<INode>) -> Result<()> {
+        // FAT has no hard links
+        Err(FsError::NotSupported)
+    }
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        if new_name.is_empty() || new_name.len() > 255 || new_name.contains('/') {
+            return Err(FsError::InvalidParam);
+        }
+        let mut inner = self.fs.inner.lock();
+        let (loc, entry) = self.find_removable(&mut inner, old_name)?;
+        if inner.find_entry(loc, new_name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        // remove first, so that the new entry can reuse the slots
+        inner.remove_entry(&entry)?;
+        let pos = inner.add_entry(loc, new_name, entry.attr, entry.first_cluster, entry.size)?;
+        self.fs.relocate(entry.pos(), pos);
+        Ok(())
+    }
+    fn move_(&self, _old_name: &str, _target: &Arc<INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        let mut inner = self.fs.inner.lock();
+        let loc = self.dir_loc(&mut inner)?;
+        if let Location::Root = self.location() {
+            if name == "." || name == ".." {
+                // the root directory has no `.` and `..`
+                drop(inner);
+                return Ok(self.fs.root_inode());
+            }
+        }
+        let entry = inner.find_entry(loc, name)?.ok_or(FsError::EntryNotFound)?;
+        drop(inner);
+        Ok(self.child(&entry))
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        let mut inner = self.fs.inner.lock();
+        let loc = self.dir_loc(&mut inner)?;
+        let entries = inner.dir_entries(loc)?;
+        entries.into_iter().nth(id).map(|e| e.name).ok_or(FsError::EntryNotFound)
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
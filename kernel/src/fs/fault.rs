@@ -0,0 +1,150 @@
+//! Device wrapper injecting faults, for testing error paths and crash consistency
+
+use simple_filesystem::Device;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use crate::arch::cpu;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// What faults to inject. Can be changed at any time through `FaultyDevice::config()`.
+#[derive(Debug, Default, Clone)]
+pub struct FaultConfig {
+    /// Fail the N-th write (counting from 0)
+    pub fail_write: Option<usize>,
+    /// Simulate a power cut after N writes: later writes report success but are dropped
+    pub power_cut_after: Option<usize>,
+    /// Flip the lowest bit of bytes at these device offsets when read
+    pub flip_bits: Vec<usize>,
+    /// Busy wait this many cycles for each request
+    pub latency: u64,
+    /// Number of writes so far
+    pub writes: usize,
+}
+
+impl FaultConfig {
+    /// Parse a comma separated list like "fail_write:3,power_cut:100,flip:4096,latency:1000",
+    /// as given by option `fault=` of the command line. `flip` may be repeated.
+    pub fn parse(s: &str) -> Option<FaultConfig> {
+        let mut config = FaultConfig::default();
+        for item in s.split(',').filter(|item| !item.is_empty()) {
+            let mut iter = item.splitn(2, ':');
+            let key = iter.next()?;
+            let value = iter.next()?.parse().ok()?;
+            match key {
+                "fail_write" => config.fail_write = Some(value),
+                "power_cut" => config.power_cut_after = Some(value),
+                "flip" => config.flip_bits.push(value),
+                "latency" => config.latency = value as u64,
+                _ => return None,
+            }
+        }
+        Some(config)
+    }
+}
+
+/// A device wrapper which fails, drops or corrupts requests as configured
+pub struct FaultyDevice {
+    device: Box<Device>,
+    config: Arc<Mutex<FaultConfig>>,
+}
+
+impl FaultyDevice {
+    pub fn new(device: Box<Device>, config: FaultConfig) -> Self {
+        FaultyDevice { device, config: Arc::new(Mutex::new(config)) }
+    }
+    /// Handle to change the configuration after the device is given away
+    pub fn config(&self) -> Arc<Mutex<FaultConfig>> {
+        self.config.clone()
+    }
+}
+
+fn delay(cycles: u64) {
+    let begin = cpu::cycle();
+    while cpu::cycle().wrapping_sub(begin) < cycles {}
+}
+
+impl Device for FaultyDevice {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let config = self.config.lock().clone();
+        delay(config.latency);
+        let len = self.device.read_at(offset, buf)?;
+        for &pos in config.flip_bits.iter() {
+            if pos >= offset && pos < offset + len {
+                buf[pos - offset] ^= 1;
+            }
+        }
+        Some(len)
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        let (n, config) = {
+            let mut config = self.config.lock();
+            config.writes += 1;
+            (config.writes - 1, config.clone())
+        };
+        delay(config.latency);
+        if config.fail_write == Some(n) {
+            return None;
+        }
+        if config.power_cut_after.map_or(false, |cut| n >= cut) {
+            return Some(buf.len());
+        }
+        self.device.write_at(offset, buf)
+    }
+}
+
+pub mod test {
+    use super::*;
+    use crate::fs::MemDevice;
+
+    fn parse() {
+        let config = FaultConfig::parse("fail_write:3,power_cut:100,flip:10,flip:20,latency:5").unwrap();
+        assert_eq!(config.fail_write, Some(3));
+        assert_eq!(config.power_cut_after, Some(100));
+        assert_eq!(config.flip_bits, vec![10, 20]);
+        assert_eq!(config.latency, 5);
+        assert!(FaultConfig::parse("").is_some());
+        assert!(FaultConfig::parse("fail_write").is_none());
+        assert!(FaultConfig::parse("fail_write:x").is_none());
+        assert!(FaultConfig::parse("fail_read:1").is_none());
+    }
+
+    fn fail_write() {
+        let config = FaultConfig { fail_write: Some(1), ..FaultConfig::default() };
+        let mut device = FaultyDevice::new(Box::new(MemDevice::new(64)), config);
+        assert_eq!(device.write_at(0, &[1; 8]), Some(8));
+        assert_eq!(device.write_at(8, &[2; 8]), None);
+        assert_eq!(device.write_at(16, &[3; 8]), Some(8));
+        let mut buf = [0; 24];
+        assert_eq!(device.read_at(0, &mut buf), Some(24));
+        assert_eq!(buf[..8], [1; 8]);
+        assert_eq!(buf[8..16], [0; 8]);
+        assert_eq!(buf[16..], [3; 8]);
+        assert_eq!(device.config().lock().writes, 3);
+    }
+
+    fn power_cut() {
+        let mut device = FaultyDevice::new(Box::new(MemDevice::new(64)), FaultConfig::default());
+        assert_eq!(device.write_at(0, &[1; 8]), Some(8));
+        device.config().lock().power_cut_after = Some(1);
+        assert_eq!(device.write_at(0, &[2; 8]), Some(8));
+        let mut buf = [0; 8];
+        assert_eq!(device.read_at(0, &mut buf), Some(8));
+        assert_eq!(buf, [1; 8]);
+    }
+
+    fn flip_bits() {
+        let config = FaultConfig { flip_bits: vec![3, 40], ..FaultConfig::default() };
+        let mut device = FaultyDevice::new(Box::new(MemDevice::new(64)), config);
+        let mut buf = [0; 8];
+        assert_eq!(device.read_at(0, &mut buf), Some(8));
+        assert_eq!(buf, [0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(device.read_at(36, &mut buf), Some(8));
+        assert_eq!(buf, [0, 0, 0, 0, 1, 0, 0, 0]);
+    }
+
+    pub fn test_all() {
+        parse();
+        fail_write();
+        power_cut();
+        flip_bits();
+    }
+}
@@ -0,0 +1,249 @@
+//! Decompress zlib and raw DEFLATE streams (RFC 1950, RFC 1951)
+
+use alloc::vec::Vec;
+
+type Result<T> = core::result::Result<T, &'static str>;
+
+/// Base lengths and extra bits of length codes 257..285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distances and extra bits of distance codes 0..29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+/// Order of code length code lengths in a dynamic block header
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+const MAX_BITS: usize = 15;
+
+/// Reads bits from the least significant end of each byte
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        while self.bit_count < n {
+            let byte = *self.data.get(self.pos).ok_or("unexpected end of stream")?;
+            self.bit_buf |= (byte as u32) << self.bit_count;
+            self.pos += 1;
+            self.bit_count += 8;
+        }
+        let v = self.bit_buf & ((1u32 << n) - 1);
+        self.bit_buf >>= n;
+        self.bit_count -= n;
+        Ok(v)
+    }
+    /// Skip to the next byte boundary
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+/// A canonical Huffman code, decoded one bit at a time
+struct Huffman {
+    /// Number of codes of each length
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; offsets[MAX_BITS + 1] as usize];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        // first code of the current length, and index of its symbol
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..=MAX_BITS {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code")
+    }
+}
+
+/// Decompress a raw DEFLATE stream, producing at most `limit` bytes
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let mut reader = BitReader { data, pos: 0, bit_buf: 0, bit_count: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => stored_block(&mut reader, &mut out)?,
+            1 => {
+                let mut lengths = [0u8; 288];
+                for (i, len) in lengths.iter_mut().enumerate() {
+                    *len = match i {
+                        0..=143 => 8,
+                        144..=255 => 9,
+                        256..=279 => 7,
+                        _ => 8,
+                    };
+                }
+                let lit = Huffman::new(&lengths);
+                let dist = Huffman::new(&[5u8; 30]);
+                huffman_block(&mut reader, &mut out, &lit, &dist, limit)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_codes(&mut reader)?;
+                huffman_block(&mut reader, &mut out, &lit, &dist, limit)?;
+            }
+            _ => return Err("invalid block type"),
+        }
+        if out.len() > limit {
+            return Err("output too long");
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Decompress a zlib stream, producing at most `limit` bytes.
+/// The Adler-32 checksum is not verified.
+pub fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    if data.len() < 2 {
+        return Err("unexpected end of stream");
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0f != 8 || ((cmf as u16) << 8 | flg as u16) % 31 != 0 {
+        return Err("invalid zlib header");
+    }
+    if flg & 0x20 != 0 {
+        return Err("preset dictionary is not supported");
+    }
+    inflate(&data[2..], limit)
+}
+
+fn stored_block(reader: &mut BitReader, out: &mut Vec<u8>) -> Result<()> {
+    reader.align();
+    let data = reader.data;
+    let pos = reader.pos;
+    if pos + 4 > data.len() {
+        return Err("unexpected end of stream");
+    }
+    let len = data[pos] as usize | (data[pos + 1] as usize) << 8;
+    let nlen = data[pos + 2] as usize | (data[pos + 3] as usize) << 8;
+    if len != !nlen & 0xffff {
+        return Err("invalid stored block length");
+    }
+    if pos + 4 + len > data.len() {
+        return Err("unexpected end of stream");
+    }
+    out.extend_from_slice(&data[pos + 4..pos + 4 + len]);
+    reader.pos = pos + 4 + len;
+    Ok(())
+}
+
+/// Read the literal/length and distance codes of a dynamic block
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let nlen = reader.bits(5)? as usize + 257;
+    let ndist = reader.bits(5)? as usize + 1;
+    let ncode = reader.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err("too many codes");
+    }
+    let mut code_lengths = [0u8; 19];
+    for &i in CODE_LENGTH_ORDER[..ncode].iter() {
+        code_lengths[i] = reader.bits(3)? as u8;
+    }
+    let code = Huffman::new(&code_lengths);
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let symbol = code.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if i == 0 {
+                    return Err("repeat with no previous length");
+                }
+                (lengths[i - 1], 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if i + repeat > nlen + ndist {
+            return Err("too many code lengths");
+        }
+        for len in lengths[i..i + repeat].iter_mut() {
+            *len = value;
+        }
+        i += repeat;
+    }
+    Ok((Huffman::new(&lengths[..nlen]), Huffman::new(&lengths[nlen..])))
+}
+
+fn huffman_block(reader: &mut BitReader, out: &mut Vec<u8>, lit: &Huffman, dist: &Huffman, limit: usize) -> Result<()> {
+    loop {
+        let symbol = lit.decode(reader)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                if i >= LENGTH_BASE.len() {
+                    return Err("invalid length code");
+                }
+                let len = LENGTH_BASE[i] as usize + reader.bits(LENGTH_EXTRA[i] as u32)? as usize;
+                let j = dist.decode(reader)? as usize;
+                if j >= DIST_BASE.len() {
+                    return Err("invalid distance code");
+                }
+                let distance = DIST_BASE[j] as usize + reader.bits(DIST_EXTRA[j] as u32)? as usize;
+                if distance > out.len() {
+                    return Err("distance too far back");
+                }
+                if out.len() + len > limit {
+                    return Err("output too long");
+                }
+                // the copy may overlap the bytes being written
+                let start = out.len() - distance;
+                for k in 0..len {
+                    let b = out[start + k];
+                    out.push(b);
+                }
+            }
+        }
+    }
+}
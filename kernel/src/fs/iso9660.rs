@@ -0,0 +1,330 @@
+//! ISO 9660 file system (CD images), read only, with Rock Ridge extensions
+
+use simple_filesystem::*;
+use alloc::{boxed::Box, sync::{Arc, Weak}, string::String, vec::Vec};
+use core::any::Any;
+use crate::sync::ThreadLock as Mutex;
+use super::{device_error, FsUsage};
+
+const SECTOR_SIZE: usize = 2048;
+/// Volume descriptors start at sector 16
+const FIRST_DESCRIPTOR: usize = 16;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+/// The record of the root directory in the primary volume descriptor
+const ROOT_RECORD_OFFSET: usize = 156;
+
+/// Flag of the NM entry: the name continues in the next NM entry
+const NM_CONTINUE: u8 = 0x01;
+const NM_CURRENT: u8 = 0x02;
+const NM_PARENT: u8 = 0x04;
+
+/// An ISO 9660 file system
+pub struct IsoFileSystem {
+    device: Mutex<Box<Device>>,
+    root: Record,
+    /// Bytes to skip at the start of each system use area,
+    /// None if there are no Rock Ridge extensions
+    susp_skip: Option<usize>,
+    /// Number of sectors, the volume space size
+    sectors: usize,
+    self_ref: Mutex<Weak<IsoFileSystem>>,
+}
+
+/// A directory record
+#[derive(Clone)]
+struct Record {
+    name: String,
+    extent: usize,
+    size: usize,
+    is_dir: bool,
+    /// Permissions from the Rock Ridge PX entry
+    mode: u32,
+    nlinks: usize,
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+impl IsoFileSystem {
+    /// Open an ISO 9660 file system on `device`
+    pub fn open(mut device: Box<Device>) -> Result<Arc<Self>> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut index = FIRST_DESCRIPTOR;
+        loop {
+            if device.read_at(index * SECTOR_SIZE, &mut sector) != Some(SECTOR_SIZE) || &sector[1..6] != b"CD001" {
+                return Err(FsError::WrongFs);
+            }
+            match sector[0] {
+                DESCRIPTOR_PRIMARY => break,
+                DESCRIPTOR_TERMINATOR => return Err(FsError::WrongFs),
+                _ => index += 1,
+            }
+        }
+        let logical_block_size = sector[128] as usize | (sector[129] as usize) << 8;
+        if logical_block_size != SECTOR_SIZE {
+            return Err(FsError::WrongFs);
+        }
+        let root_raw = sector[ROOT_RECORD_OFFSET..ROOT_RECORD_OFFSET + 34].to_vec();
+        let mut fs = IsoFileSystem {
+            device: Mutex::new(device),
+            root: parse_record(&root_raw).ok_or(FsError::WrongFs)?,
+            susp_skip: None,
+            sectors: read_u32(&sector[80..84]) as usize,
+            self_ref: Mutex::new(Weak::new()),
+        };
+        fs.root.name = String::from(".");
+        // Rock Ridge is present if the `.` entry of the root directory begins with SP
+        let mut first = [0u8; 255];
+        fs.read(fs.root.extent * SECTOR_SIZE, &mut first)?;
+        let system_use = system_use_area(&first[..first[0] as usize]);
+        if system_use.len() >= 7 && &system_use[0..2] == b"SP" && system_use[4..6] == [0xbe, 0xef] {
+            fs.susp_skip = Some(system_use[6] as usize);
+        }
+        let fs = Arc::new(fs);
+        *fs.self_ref.lock() = Arc::downgrade(&fs);
+        Ok(fs)
+    }
+
+    /// There are no free sectors in a read only file system
+    pub fn usage(&self) -> Result<FsUsage> {
+        Ok(FsUsage { block_size: SECTOR_SIZE, blocks: self.sectors, free_blocks: 0 })
+    }
+
+    fn arc(&self) -> Arc<IsoFileSystem> {
+        self.self_ref.lock().upgrade().unwrap()
+    }
+
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<()> {
+        match self.device.lock().read_at(pos, buf) {
+            Some(len) if len == buf.len() => Ok(()),
+            _ => Err(device_error()),
+        }
+    }
+
+    /// All records in the directory, including `.` and `..`
+    fn dir_records(&self, dir: &Record) -> Result<Vec<Record>> {
+        let mut data = vec![0u8; dir.size];
+        self.read(dir.extent * SECTOR_SIZE, &mut data)?;
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = data[pos] as usize;
+            if len == 0 {
+                // records do not cross sectors, the rest of the sector is padding
+                pos = (pos / SECTOR_SIZE + 1) * SECTOR_SIZE;
+                continue;
+            }
+            if pos + len > data.len() {
+                break;
+            }
+            let raw = &data[pos..pos + len];
+            if let Some(mut record) = parse_record(raw) {
+                if let Some(skip) = self.susp_skip {
+                    let system_use = system_use_area(raw);
+                    if system_use.len() >= skip {
+                        self.apply_rock_ridge(&mut record, &system_use[skip..])?;
+                    }
+                }
+                records.push(record);
+            }
+            pos += len;
+        }
+        Ok(records)
+    }
+
+    /// Update `record` by Rock Ridge entries in `area`, following continuation areas
+    fn apply_rock_ridge(&self, record: &mut Record, area: &[u8]) -> Result<()> {
+        let mut name = String::new();
+        let mut area = area.to_vec();
+        // limit the number of continuation areas, in case of loops
+        for _ in 0..16 {
+            let mut next = None;
+            let mut pos = 0;
+            while pos + 4 <= area.len() {
+                let len = area[pos + 2] as usize;
+                if len < 4 || pos + len > area.len() {
+                    break;
+                }
+                let entry = &area[pos..pos + len];
+                match &entry[0..2] {
+                    b"NM" if len >= 5 => {
+                        let flags = entry[4];
+                        if flags & (NM_CURRENT | NM_PARENT) == 0 {
+                            name += &String::from_utf8_lossy(&entry[5..]);
+                            if flags & NM_CONTINUE == 0 {
+                                record.name = core::mem::replace(&mut name, String::new());
+                            }
+                        }
+                    }
+                    b"PX" if len >= 20 => {
+                        record.mode = read_u32(&entry[4..8]) & 0o7777;
+                        record.nlinks = read_u32(&entry[12..16]) as usize;
+                    }
+                    b"CE" if len >= 28 => {
+                        let block = read_u32(&entry[4..8]) as usize;
+                        let offset = read_u32(&entry[12..16]) as usize;
+                        let size = read_u32(&entry[20..24]) as usize;
+                        next = Some((block * SECTOR_SIZE + offset, size.min(SECTOR_SIZE)));
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+                pos += len;
+            }
+            match next {
+                Some((pos, size)) => {
+                    area = vec![0u8; size];
+                    self.read(pos, &mut area)?;
+                }
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a directory record, without Rock Ridge extensions
+fn parse_record(raw: &[u8]) -> Option<Record> {
+    if raw.len() < 34 || raw.len() < 33 + raw[32] as usize {
+        return None;
+    }
+    let name_raw = &raw[33..33 + raw[32] as usize];
+    let is_dir = raw[25] & FLAG_DIRECTORY != 0;
+    let name = match name_raw {
+        [0] => String::from("."),
+        [1] => String::from(".."),
+        _ => {
+            let mut name = String::from_utf8_lossy(name_raw).into_owned();
+            // strip the version number and the trailing dot of files without extension
+            if let Some(i) = name.find(';') {
+                name.truncate(i);
+            }
+            if !is_dir && name.ends_with('.') {
+                name.pop();
+            }
+            name
+        }
+    };
+    Some(Record {
+        name,
+        extent: read_u32(&raw[2..6]) as usize,
+        size: read_u32(&raw[10..14]) as usize,
+        is_dir,
+        mode: if is_dir { 0o555 } else { 0o444 },
+        nlinks: 1,
+    })
+}
+
+/// The system use area of a directory record, after the name and its padding
+fn system_use_area(raw: &[u8]) -> &[u8] {
+    if raw.len() < 34 {
+        return &[];
+    }
+    let name_len = raw[32] as usize;
+    let start = 33 + name_len + (name_len + 1) % 2;
+    if start >= raw.len() {
+        return &[];
+    }
+    &raw[start..]
+}
+
+impl FileSystem for IsoFileSystem {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        Arc::new(IsoINode { fs: self.arc(), record: self.root.clone() })
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: 255 };
+        &INFO
+    }
+}
+
+/// A file or directory in an ISO 9660 file system
+pub struct IsoINode {
+    pub(super) fs: Arc<IsoFileSystem>,
+    record: Record,
+}
+
+impl IsoINode {
+    fn records(&self) -> Result<Vec<Record>> {
+        if !self.record.is_dir {
+            return Err(FsError::NotDir);
+        }
+        self.fs.dir_records(&self.record)
+    }
+}
+
+impl INode for IsoINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.record.is_dir {
+            return Err(FsError::IsDir);
+        }
+        if offset >= self.record.size {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.record.size - offset);
+        self.fs.read(self.record.extent * SECTOR_SIZE + offset, &mut buf[..len])?;
+        Ok(len)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+    fn info(&self) -> Result<FileInfo> {
+        Ok(FileInfo {
+            size: self.record.size,
+            mode: self.record.mode,
+            type_: if self.record.is_dir { FileType::Dir } else { FileType::File },
+            blocks: (self.record.size + 511) / 512,
+            nlinks: self.record.nlinks,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, _len: usize) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn create(&self, _name: &str, _type_: FileType) -> Result<Arc<INode>> {
+        Err(FsError::NotSupported)
+    }
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn link(&self, _name: &str, _other: &Arc<INode>) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn rename(&self, _old_name: &str, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn move_(&self, _old_name: &str, _target: &Arc<INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        let records = self.records()?;
+        // without Rock Ridge, names are upper case
+        let record = records.iter().find(|r| r.name == name)
+            .or_else(|| records.iter().find(|r| self.fs.susp_skip.is_none() && r.name.eq_ignore_ascii_case(name)))
+            .ok_or(FsError::EntryNotFound)?;
+        let mut record = record.clone();
+        if record.extent == self.fs.root.extent {
+            // `..` of the root directory, or `.` of it
+            record = self.fs.root.clone();
+        }
+        Ok(Arc::new(IsoINode { fs: self.fs.clone(), record }))
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.records()?.into_iter().nth(id).map(|r| r.name).ok_or(FsError::EntryNotFound)
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
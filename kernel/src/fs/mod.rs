@@ -0,0 +1,595 @@
+use simple_filesystem::*;
+use alloc::{boxed::Box, collections::BTreeSet, string::String, sync::Arc, vec::Vec};
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use log::*;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::drivers::{self, AsyncBlockDriver, BlockCompletion};
+use crate::thread;
+use crate::process::processor;
+use crate::net::{self, TcpStream};
+
+pub use self::stdio::{Stdin, STDIN, STDOUT};
+pub use self::device::{LoopDevice, MemDevice};
+pub use self::raid::{StripedDevice, MirroredDevice};
+pub use self::nbd::{NbdDevice, Stream as NbdStream};
+pub use self::ramfs::RamFileSystem;
+pub use self::devfs::{DevFileSystem, register_device, register_block_device, device_info, MODE_CHAR, MODE_BLOCK};
+pub use self::procfs::ProcFileSystem;
+pub use self::overlayfs::OverlayFileSystem;
+pub use self::p9::{P9FileSystem, Transport as P9Transport, MSIZE as P9_MSIZE};
+pub use self::nfs::{NfsFileSystem, Transport as NfsTransport};
+#[cfg(not(target_arch = "x86_64"))]
+use self::device::MemBuf;
+use self::cache::BlockCache;
+use self::partition::{Disk, Guid, Partition};
+use self::stats::StatsDevice;
+use self::fault::{FaultConfig, FaultyDevice};
+
+mod device;
+#[macro_use]
+mod stdio;
+mod cache;
+mod partition;
+mod raid;
+mod nbd;
+pub mod stats;
+pub mod fault;
+mod fat;
+mod ext2;
+mod iso9660;
+mod exfat;
+mod inflate;
+mod squashfs;
+mod ramfs;
+mod devfs;
+mod procfs;
+mod overlayfs;
+mod p9;
+mod nfs;
+pub mod cpio;
+
+/// Number of blocks in the cache of the root file system
+const ROOT_CACHE_BLOCKS: usize = 64;
+/// Stripe size of RAID-0 root devices
+const RAID0_STRIPE_SIZE: usize = 64 * 1024;
+/// Number of blocks in the cache of a loop device, the file is cached by its file system too
+const LOOP_CACHE_BLOCKS: usize = 16;
+
+lazy_static! {
+    /// Threads whose last error is from `device_error`
+    static ref DEVICE_ERRORS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+}
+
+/// Error of a failed read or write of a device.
+///
+/// `FsError` has no variant for I/O errors, so it is `NotSupported`,
+/// and the current thread is recorded to tell it from a real `NotSupported`.
+pub fn device_error() -> FsError {
+    if let Some(pid) = processor().try_pid() {
+        DEVICE_ERRORS.lock().insert(pid);
+    }
+    FsError::NotSupported
+}
+
+/// Whether the last error of the current thread is from `device_error`.
+/// The record is cleared, call it once for each error.
+pub fn take_device_error() -> bool {
+    match processor().try_pid() {
+        Some(pid) => DEVICE_ERRORS.lock().remove(&pid),
+        None => false,
+    }
+}
+
+/// A file system type which can be mounted on a device
+#[derive(Clone)]
+pub struct FsType {
+    pub name: &'static str,
+    pub mount: fn(Box<Device>) -> Result<Arc<FileSystem>>,
+}
+
+fn mount_sfs(device: Box<Device>) -> Result<Arc<FileSystem>> {
+    Ok(SimpleFileSystem::open(device)?)
+}
+
+fn mount_fat(device: Box<Device>) -> Result<Arc<FileSystem>> {
+    Ok(fat::FatFileSystem::open(device)?)
+}
+
+fn mount_ext2(device: Box<Device>) -> Result<Arc<FileSystem>> {
+    Ok(ext2::Ext2FileSystem::open(device)?)
+}
+
+fn mount_iso9660(device: Box<Device>) -> Result<Arc<FileSystem>> {
+    Ok(iso9660::IsoFileSystem::open(device)?)
+}
+
+fn mount_exfat(device: Box<Device>) -> Result<Arc<FileSystem>> {
+    Ok(exfat::ExfatFileSystem::open(device)?)
+}
+
+fn mount_squashfs(device: Box<Device>) -> Result<Arc<FileSystem>> {
+    Ok(squashfs::SquashFileSystem::open(device)?)
+}
+
+lazy_static! {
+    /// Registered file system types, see `init()` for the built-in ones
+    static ref FS_TYPES: Mutex<Vec<FsType>> = Mutex::new(Vec::new());
+}
+
+/// Register the built-in file system types.
+/// Should be called before the root file system is mounted.
+pub fn init() {
+    let builtin = vec![
+        FsType { name: "sfs", mount: mount_sfs },
+        FsType { name: "fat", mount: mount_fat },
+        FsType { name: "ext2", mount: mount_ext2 },
+        FsType { name: "iso9660", mount: mount_iso9660 },
+        FsType { name: "exfat", mount: mount_exfat },
+        FsType { name: "squashfs", mount: mount_squashfs },
+    ];
+    for fs_type in builtin {
+        let name = fs_type.name;
+        if !register_fs_type(fs_type) {
+            warn!("file system type {} is already registered", name);
+        }
+    }
+}
+
+/// Register a new file system type.
+/// Return false if the name has already been used.
+pub fn register_fs_type(fs_type: FsType) -> bool {
+    let mut fs_types = FS_TYPES.lock();
+    if fs_types.iter().any(|t| t.name == fs_type.name) {
+        return false;
+    }
+    fs_types.push(fs_type);
+    true
+}
+
+/// Mount the device as file system type `name`
+pub fn mount(name: &str, device: Box<Device>) -> Result<Arc<FileSystem>> {
+    let fs_type = FS_TYPES.lock().iter()
+        .find(|t| t.name == name)
+        .cloned()
+        .ok_or(FsError::NotSupported)?;
+    let fs = (fs_type.mount)(device)?;
+    add_mount(fs_type.name, &fs);
+    Ok(fs)
+}
+
+/// A mounted file system.
+/// The root inode is kept instead of the file system, which may not be `Send`.
+struct MountInfo {
+    fs_type: &'static str,
+    root: Arc<INode>,
+}
+
+lazy_static! {
+    /// Mounted file systems, kept alive since there is no unmount
+    static ref MOUNTS: Mutex<Vec<MountInfo>> = Mutex::new(Vec::new());
+}
+
+fn add_mount(fs_type: &'static str, fs: &Arc<FileSystem>) {
+    MOUNTS.lock().push(MountInfo { fs_type, root: fs.root_inode() });
+}
+
+/// Mounted file systems with their type names
+pub fn mounts() -> Vec<(&'static str, Arc<FileSystem>)> {
+    MOUNTS.lock().iter().map(|m| (m.fs_type, m.root.fs())).collect()
+}
+
+/// Block usage of a file system
+#[derive(Debug, Clone, Copy)]
+pub struct FsUsage {
+    pub block_size: usize,
+    pub blocks: usize,
+    pub free_blocks: usize,
+}
+
+/// Block usage of the file system of `root`.
+/// `None` if its type doesn't report it, e.g. SFS and ramfs.
+fn usage(root: &Arc<INode>) -> Option<Result<FsUsage>> {
+    let root = root.as_any_ref();
+    if let Some(inode) = root.downcast_ref::<fat::FatINode>() {
+        Some(inode.fs.usage())
+    } else if let Some(inode) = root.downcast_ref::<ext2::Ext2INode>() {
+        Some(inode.fs.usage())
+    } else if let Some(inode) = root.downcast_ref::<iso9660::IsoINode>() {
+        Some(inode.fs.usage())
+    } else if let Some(inode) = root.downcast_ref::<exfat::ExfatINode>() {
+        Some(inode.fs.usage())
+    } else if let Some(inode) = root.downcast_ref::<squashfs::SquashINode>() {
+        Some(inode.fs.usage())
+    } else {
+        None
+    }
+}
+
+/// Mounted file systems with their type names and block usages
+pub fn mount_usages() -> Vec<(&'static str, Arc<FileSystem>, Option<Result<FsUsage>>)> {
+    let mounts: Vec<_> = MOUNTS.lock().iter().map(|m| (m.fs_type, m.root.clone())).collect();
+    // not counted with `MOUNTS` locked, as it may read the device
+    mounts.into_iter().map(|(fs_type, root)| (fs_type, root.fs(), usage(&root))).collect()
+}
+
+lazy_static! {
+    pub static ref ROOT_INODE: Arc<INode> = {
+        let root = crate::cmdline::get("root").unwrap_or("");
+        // fall back to an empty ramfs, so the kernel can run without a disk
+        let fs = match mount_root(root) {
+            Ok(fs) => fs,
+            Err(e) => {
+                warn!("failed to mount root file system {:?}: {:?}, using ramfs", root, e);
+                RamFileSystem::new()
+            }
+        };
+        if !mounts().iter().any(|(_, m)| Arc::ptr_eq(m, &fs)) {
+            add_mount("ramfs", &fs);
+        }
+        fs.root_inode()
+    };
+}
+
+/// Find the inode at `path`.
+/// Paths under `/dev` and `/proc` are looked up in devfs and procfs.
+pub fn lookup(path: &str) -> Result<Arc<INode>> {
+    if path.starts_with('/') {
+        let path = path.trim_start_matches('/');
+        let (first, rest) = match path.find('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => (path, ""),
+        };
+        let fs = match first {
+            "dev" => Some(DevFileSystem::new() as Arc<FileSystem>),
+            "proc" => Some(ProcFileSystem::new() as Arc<FileSystem>),
+            _ => None,
+        };
+        if let Some(fs) = fs {
+            return fs.root_inode().lookup(rest.trim_start_matches('/'));
+        }
+    }
+    ROOT_INODE.lookup(path)
+}
+
+/// File system types which can be used as root, in the order of trying
+const ROOT_FS_TYPES: [&str; 4] = ["sfs", "ext2", "squashfs", "iso9660"];
+/// Root file system types which are read only, a ramfs is stacked over them
+const READ_ONLY_FS_TYPES: [&str; 3] = ["ext2", "squashfs", "iso9660"];
+
+/// The cache of the root device, shared by all partitions on it.
+/// Faults are injected into the device if option `fault=` is given, see `FaultConfig::parse()`.
+fn root_cache(device: Box<Device>) -> BlockCache {
+    let device = match crate::cmdline::get("fault").map(FaultConfig::parse) {
+        Some(Some(config)) => {
+            warn!("injecting faults into the root device: {:?}", config);
+            Box::new(FaultyDevice::new(device, config))
+        }
+        Some(None) => {
+            warn!("invalid option fault=, ignored");
+            device
+        }
+        None => device,
+    };
+    BlockCache::new(Box::new(StatsDevice::new("root", device)), ROOT_CACHE_BLOCKS)
+}
+
+/// The `i`-th block device
+fn block_device(i: usize) -> Result<Box<Device>> {
+    let drivers = drivers::BLK_DRIVERS.lock();
+    let driver = drivers.get(i).ok_or(FsError::EntryNotFound)?;
+    Ok(driver.get_device())
+}
+
+/// Index of a block device named like "blk0"
+fn parse_block_name(name: &str) -> Result<usize> {
+    if !name.starts_with("blk") {
+        return Err(FsError::InvalidParam);
+    }
+    name["blk".len()..].parse().map_err(|_| FsError::InvalidParam)
+}
+
+/// Block devices in a comma separated list like "blk0,blk1"
+fn block_devices(names: &str) -> Result<Vec<Box<Device>>> {
+    names.split(',').map(|name| block_device(parse_block_name(name)?)).collect()
+}
+
+/// The root cache over the `i`-th block device, also used by `/dev/blk<i>`,
+/// so that writes to it are seen by the root file system
+fn block_cache(i: usize) -> Result<BlockCache> {
+    let cache = root_cache(block_device(i)?);
+    devfs::set_block_cache(i, cache.clone());
+    Ok(cache)
+}
+
+/// The root device when option `root=` is not given:
+/// the first block device, or the linked user image
+#[cfg(not(feature = "link_user"))]
+fn default_root_cache() -> Result<BlockCache> {
+    block_cache(0)
+}
+
+#[cfg(feature = "link_user")]
+fn default_root_cache() -> Result<BlockCache> {
+    extern {
+        fn _user_img_start();
+        fn _user_img_end();
+    }
+    Ok(root_cache(Box::new(unsafe { MemBuf::new(_user_img_start, _user_img_end) })))
+}
+
+/// Mount the root file system chosen by option `root=` of the command line:
+/// - none: the default device, see `default_root_cache()`
+/// - `blk<N>`: the N-th block device
+/// - `PARTUUID=<guid>`: the GPT partition with this unique GUID on the first block device
+/// - `loop:<path>`: the image at `path` of the file system on the default device
+/// - `raid0:<blk>,<blk>...`, `raid1:<blk>,<blk>...`: block devices striped or mirrored
+/// - `9p:<tag>`: the directory exported by the host through the virtio 9P device with this mount tag
+/// - `mem:<blk>`: a RAM disk `/dev/ram0` loaded from the block device, changes are not written back
+/// - `nfs:<ip>:<path>`: the directory exported by an NFS server, see `crate::net` for the address of the kernel
+/// - `nbd:<ip>:<port>[:<export>]`: `/dev/nbd0` exported by an NBD server, the default export if none is given
+fn mount_root(root: &str) -> Result<Arc<FileSystem>> {
+    if root.starts_with("nfs:") {
+        let fs: Arc<FileSystem> = mount_nfs(&root["nfs:".len()..])?;
+        add_mount("nfs", &fs);
+        info!("root file system: nfs on {}", &root["nfs:".len()..]);
+        return Ok(fs);
+    }
+    if root.starts_with("9p:") {
+        let tag = &root["9p:".len()..];
+        let transport = drivers::P9_DRIVERS.lock().iter()
+            .find(|driver| driver.mount_tag() == tag)
+            .map(|driver| driver.get_transport())
+            .ok_or(FsError::EntryNotFound)?;
+        let fs: Arc<FileSystem> = P9FileSystem::new(transport, "root", "")?;
+        add_mount("9p", &fs);
+        info!("root file system: 9p on {}", tag);
+        return Ok(fs);
+    }
+    if root.starts_with("PARTUUID=") {
+        let guid = Guid::parse(&root["PARTUUID=".len()..]).ok_or(FsError::InvalidParam)?;
+        let cache = block_cache(0)?;
+        let part = Disk::new(Box::new(cache.clone())).partition_by_guid(guid)
+            .ok_or(FsError::EntryNotFound)?;
+        return mount_partition(&cache, &part).ok_or(FsError::WrongFs);
+    }
+    let cache = if root.is_empty() {
+        default_root_cache()?
+    } else if root.starts_with("blk") {
+        block_cache(parse_block_name(root)?)?
+    } else if root.starts_with("raid0:") {
+        let members = block_devices(&root["raid0:".len()..])?;
+        root_cache(Box::new(StripedDevice::new(members, RAID0_STRIPE_SIZE)))
+    } else if root.starts_with("raid1:") {
+        let members = block_devices(&root["raid1:".len()..])?;
+        root_cache(Box::new(MirroredDevice::new(members)))
+    } else if root.starts_with("mem:") {
+        let i = parse_block_name(&root["mem:".len()..])?;
+        let async_driver = drivers::BLK_DRIVERS.lock().get(i).ok_or(FsError::EntryNotFound)?.get_async();
+        let data = match async_driver {
+            Some(driver) => read_all_async(&*driver)?,
+            None => read_all(&mut *block_device(i)?)?,
+        };
+        info!("root device: /dev/ram0 of {} bytes", data.len());
+        let cache = root_cache(Box::new(MemDevice::from_vec(data)));
+        register_block_device("ram0", Box::new(cache.clone()));
+        cache
+    } else if root.starts_with("nbd:") {
+        let device = connect_nbd(&root["nbd:".len()..])?;
+        info!("root device: /dev/nbd0 of {} bytes", device.size());
+        let cache = root_cache(Box::new(device));
+        register_block_device("nbd0", Box::new(cache.clone()));
+        cache
+    } else if root.starts_with("loop:") {
+        let lower = probe_root(default_root_cache()?)?;
+        let file = lower.root_inode().lookup(&root["loop:".len()..])?;
+        let (name, cache) = losetup(file)?;
+        info!("root device: /dev/{}", name);
+        cache
+    } else {
+        return Err(FsError::InvalidParam);
+    };
+    probe_root(cache)
+}
+
+/// Mount `<ip>:<path>` exported by an NFS server,
+/// connecting to the ports of the MOUNT and NFS programs told by its portmapper
+fn mount_nfs(spec: &str) -> Result<Arc<NfsFileSystem>> {
+    let mut iter = spec.splitn(2, ':');
+    let server = iter.next().and_then(net::parse_ipv4).ok_or(FsError::InvalidParam)?;
+    let path = iter.next().ok_or(FsError::InvalidParam)?;
+    // FsError has no I/O error
+    let connect = |port: u16| -> Result<Box<NfsTransport>> {
+        Ok(Box::new(TcpStream::connect(server, port).ok_or(FsError::NotSupported)?))
+    };
+    let (mount_port, nfs_port) = nfs::ports(connect(nfs::PORTMAP_PORT)?)?;
+    NfsFileSystem::new(connect(mount_port)?, connect(nfs_port)?, path)
+}
+
+/// Connect to `<ip>:<port>[:<export>]` of an NBD server
+fn connect_nbd(spec: &str) -> Result<NbdDevice> {
+    let mut iter = spec.splitn(3, ':');
+    let server = iter.next().and_then(net::parse_ipv4).ok_or(FsError::InvalidParam)?;
+    let port = iter.next().and_then(|port| port.parse::<u16>().ok()).ok_or(FsError::InvalidParam)?;
+    let name = iter.next().unwrap_or("");
+    let stream = TcpStream::connect(server, port).ok_or_else(device_error)?;
+    NbdDevice::connect(Box::new(stream), name).ok_or_else(device_error)
+}
+
+/// Read `device` until its end.
+/// Drivers fail reads beyond the end, so the last chunk is read a sector at a time.
+fn read_all(device: &mut Device) -> Result<Vec<u8>> {
+    const CHUNK: usize = 64 * 1024;
+    let mut data = Vec::new();
+    let mut chunk = CHUNK;
+    loop {
+        let len = data.len();
+        data.resize(len + chunk, 0);
+        match device.read_at(len, &mut data[len..]) {
+            Some(read) => {
+                data.truncate(len + read);
+                if read < chunk {
+                    return Ok(data);
+                }
+            }
+            None if chunk == CHUNK => {
+                data.truncate(len);
+                chunk = partition::SECTOR_SIZE;
+            }
+            None if len > 0 => {
+                data.truncate(len);
+                return Ok(data);
+            }
+            None => return Err(device_error()),
+        }
+    }
+}
+
+/// Blocks read at once by `read_all_async`
+const ASYNC_READS: usize = 8;
+
+/// Read `driver` until its end like `read_all`, with `ASYNC_READS` requests in flight
+fn read_all_async(driver: &AsyncBlockDriver) -> Result<Vec<u8>> {
+    const BLOCK_SIZE: usize = partition::SECTOR_SIZE;
+    /// Records the result of request `.1` of a round
+    struct Done(Arc<Mutex<[Option<bool>; ASYNC_READS]>>, usize);
+    impl BlockCompletion for Done {
+        fn complete(self: Box<Self>, ok: bool) {
+            (self.0).lock()[self.1] = Some(ok);
+        }
+    }
+    let mut data = Vec::new();
+    loop {
+        let len = data.len();
+        data.resize(len + ASYNC_READS * BLOCK_SIZE, 0);
+        let results = Arc::new(Mutex::new([None; ASYNC_READS]));
+        for i in 0..ASYNC_READS {
+            let block_id = len / BLOCK_SIZE + i;
+            let ptr = data[len + i * BLOCK_SIZE..].as_mut_ptr();
+            loop {
+                // `data` is left alone until all requests of the round finish
+                let buf = unsafe { slice::from_raw_parts_mut(ptr, BLOCK_SIZE) };
+                if driver.read_block_async(block_id, buf, Box::new(Done(results.clone(), i))) {
+                    break;
+                }
+                // the queue is full
+                driver.poll();
+            }
+        }
+        while results.lock().iter().any(Option::is_none) {
+            driver.poll();
+            thread::yield_now();
+        }
+        let results = *results.lock();
+        // blocks beyond the end fail
+        if let Some(i) = results.iter().position(|&ok| ok != Some(true)) {
+            data.truncate(len + i * BLOCK_SIZE);
+            if data.is_empty() {
+                return Err(device_error());
+            }
+            return Ok(data);
+        }
+    }
+}
+
+/// Number of the next loop device
+static NEXT_LOOP: AtomicUsize = AtomicUsize::new(0);
+
+/// Attach `file` to a new loop device, like `losetup -f`.
+/// Return its name in `/dev`, e.g. "loop0", and the device.
+pub fn losetup(file: Arc<INode>) -> Result<(String, BlockCache)> {
+    if file.info()?.type_ != FileType::File {
+        return Err(FsError::NotFile);
+    }
+    let name = format!("loop{}", NEXT_LOOP.fetch_add(1, Ordering::Relaxed));
+    let cache = BlockCache::new(Box::new(LoopDevice::new(file)), LOOP_CACHE_BLOCKS);
+    if !register_block_device(&name, Box::new(cache.clone())) {
+        return Err(FsError::EntryExist);
+    }
+    Ok((name, cache))
+}
+
+/// Mount the first root file system found in partitions, or on the whole device.
+/// If none is found, unpack the device as a cpio archive into a ramfs.
+fn probe_root(cache: BlockCache) -> Result<Arc<FileSystem>> {
+    let disk = Disk::new(Box::new(cache.clone()));
+    for part in disk.partitions().into_iter().chain(Some(disk.whole())) {
+        if let Some(fs) = mount_partition(&cache, &part) {
+            return Ok(fs);
+        }
+    }
+    let fs = RamFileSystem::new();
+    let count = cpio::unpack(&mut disk.whole(), &fs.root_inode())?;
+    info!("root file system: initramfs with {} files", count);
+    Ok(fs)
+}
+
+/// Mount `part` of the device under `cache` as one of `ROOT_FS_TYPES`
+fn mount_partition(cache: &BlockCache, part: &Partition) -> Option<Arc<FileSystem>> {
+    let info = part.info;
+    for &name in ROOT_FS_TYPES.iter() {
+        if let Ok(fs) = mount(name, Box::new(part.clone())) {
+            info!("root file system: {} on {:?}", name, info);
+            // the superblock, and the root directory of SFS, are used all the time
+            let first_block = info.start_lba * partition::SECTOR_SIZE / cache::BLOCK_SIZE;
+            if cache.pin(first_block).is_none() {
+                warn!("failed to pin the first block of the root file system");
+            }
+            if READ_ONLY_FS_TYPES.contains(&name) {
+                return Some(overlay_ramfs(&fs));
+            }
+            return Some(fs);
+        }
+    }
+    None
+}
+
+/// Stack a ramfs over the read only `lower`, so that it appears writable.
+/// Changes are kept in memory and lost on reboot.
+fn overlay_ramfs(lower: &Arc<FileSystem>) -> Arc<FileSystem> {
+    let upper: Arc<FileSystem> = RamFileSystem::new();
+    add_mount("ramfs", &upper);
+    let fs: Arc<FileSystem> = OverlayFileSystem::new(&upper, lower);
+    add_mount("overlay", &fs);
+    info!("root file system: overlay of ramfs");
+    fs
+}
+
+/// Write all file system metadata and cached blocks to devices
+pub fn sync() -> Result<()> {
+    ROOT_INODE.fs().sync()?;
+    cache::flush_all().ok_or_else(device_error)?;
+    Ok(())
+}
+
+/// Interval between two background syncs
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Start a kernel thread syncing file systems periodically,
+/// so the amount of data lost on crash is bounded without explicit fsync.
+///
+/// Should be called after the root file system is mounted.
+pub fn start_flusher() {
+    thread::spawn(|| loop {
+        thread::sleep(FLUSH_INTERVAL);
+        if let Err(e) = sync() {
+            warn!("background sync failed: {:?}", e);
+        }
+    }).detach();
+}
+
+pub trait INodeExt {
+    fn read_as_vec(&self) -> Result<Vec<u8>>;
+}
+
+impl INodeExt for INode {
+    fn read_as_vec(&self) -> Result<Vec<u8>> {
+        let size = self.info()?.size;
+        let mut buf = Vec::with_capacity(size);
+        unsafe { buf.set_len(size); }
+        self.read_at(0, buf.as_mut_slice())?;
+        Ok(buf)
+    }
+}
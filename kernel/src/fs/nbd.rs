@@ -0,0 +1,178 @@
+//! Network block device client, using a block device exported by an NBD server
+//!
+//! The connection is a `Stream`, usually a TCP socket, e.g. of option `root=nbd:<ip>:<port>`.
+//! On the host: `nbd-server -C /dev/null 10809 disk.img`, or `qemu-nbd disk.img`.
+
+use simple_filesystem::Device;
+use alloc::{boxed::Box, vec::Vec};
+use log::*;
+
+/// A reliable byte stream to a server
+pub trait Stream: Send {
+    /// Send all bytes of `buf`, None if the stream fails
+    fn send(&mut self, buf: &[u8]) -> Option<()>;
+    /// Receive exactly `buf.len()` bytes, None if the stream fails or is closed
+    fn recv(&mut self, buf: &mut [u8]) -> Option<()>;
+}
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const OPT_EXPORT_NAME: u32 = 1;
+
+const FLAG_READ_ONLY: u16 = 1 << 1;
+const FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+
+/// Max bytes read or written by one request
+const MAX_REQUEST: usize = 64 * 1024;
+
+fn read_u16(b: &[u8]) -> u16 {
+    (b[0] as u16) << 8 | b[1] as u16
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    (read_u16(&b[0..2]) as u32) << 16 | read_u16(&b[2..4]) as u32
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    (read_u32(&b[0..4]) as u64) << 32 | read_u32(&b[4..8]) as u64
+}
+
+/// A block device exported by an NBD server
+pub struct NbdDevice {
+    stream: Box<Stream>,
+    size: usize,
+    flags: u16,
+    next_handle: u64,
+}
+
+impl NbdDevice {
+    /// Do the fixed newstyle handshake on `stream` and open the export `name`.
+    /// An empty name is the default export.
+    pub fn connect(mut stream: Box<Stream>, name: &str) -> Option<Self> {
+        let mut hello = [0u8; 18];
+        stream.recv(&mut hello)?;
+        if read_u64(&hello[0..8]) != NBD_MAGIC || read_u64(&hello[8..16]) != IHAVEOPT {
+            warn!("nbd: bad handshake, oldstyle servers are not supported");
+            return None;
+        }
+        let server_flags = read_u16(&hello[16..18]);
+        if server_flags & FLAG_FIXED_NEWSTYLE == 0 {
+            warn!("nbd: server does not support fixed newstyle");
+            return None;
+        }
+        let client_flags = (server_flags & (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)) as u32;
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&client_flags.to_be_bytes());
+        msg.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        msg.extend_from_slice(&OPT_EXPORT_NAME.to_be_bytes());
+        msg.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        msg.extend_from_slice(name.as_bytes());
+        stream.send(&msg)?;
+        // the server closes the connection if there is no such export
+        let mut reply = [0u8; 10 + 124];
+        let len = if server_flags & FLAG_NO_ZEROES != 0 { 10 } else { reply.len() };
+        stream.recv(&mut reply[..len])?;
+        let size = read_u64(&reply[0..8]) as usize;
+        let flags = read_u16(&reply[8..10]);
+        info!("nbd: export {:?} of {} bytes{}", name, size,
+              if flags & FLAG_READ_ONLY != 0 { ", read only" } else { "" });
+        Some(NbdDevice { stream, size, flags, next_handle: 0 })
+    }
+
+    /// Size of the export in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Send a request and check the reply.
+    /// Data of a write is sent after the request, and data of a read is received into `read_buf`.
+    fn request(&mut self, type_: u16, offset: usize, len: usize, write_buf: &[u8], read_buf: &mut [u8]) -> Option<()> {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        let mut msg = Vec::with_capacity(28 + write_buf.len());
+        msg.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes());
+        msg.extend_from_slice(&type_.to_be_bytes());
+        msg.extend_from_slice(&handle.to_be_bytes());
+        msg.extend_from_slice(&(offset as u64).to_be_bytes());
+        msg.extend_from_slice(&(len as u32).to_be_bytes());
+        msg.extend_from_slice(write_buf);
+        self.stream.send(&msg)?;
+        if type_ == CMD_DISC {
+            // no reply
+            return Some(());
+        }
+        let mut reply = [0u8; 16];
+        self.stream.recv(&mut reply)?;
+        if read_u32(&reply[0..4]) != REPLY_MAGIC || read_u64(&reply[8..16]) != handle {
+            warn!("nbd: bad reply");
+            return None;
+        }
+        let error = read_u32(&reply[4..8]);
+        if error != 0 {
+            warn!("nbd: request {} at {:#x} failed: error {}", type_, offset, error);
+            return None;
+        }
+        if type_ == CMD_READ {
+            self.stream.recv(read_buf)?;
+        }
+        Some(())
+    }
+
+    /// Ask the server to write its caches to disk
+    pub fn flush(&mut self) -> Option<()> {
+        if self.flags & FLAG_SEND_FLUSH == 0 {
+            return Some(());
+        }
+        self.request(CMD_FLUSH, 0, 0, &[], &mut [])
+    }
+}
+
+impl Device for NbdDevice {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        if offset >= self.size {
+            return Some(0);
+        }
+        let len = buf.len().min(self.size - offset);
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(MAX_REQUEST);
+            self.request(CMD_READ, offset + done, n, &[], &mut buf[done..done + n])?;
+            done += n;
+        }
+        Some(len)
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        if self.flags & FLAG_READ_ONLY != 0 {
+            return None;
+        }
+        if offset >= self.size {
+            return Some(0);
+        }
+        let len = buf.len().min(self.size - offset);
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(MAX_REQUEST);
+            self.request(CMD_WRITE, offset + done, n, &buf[done..done + n], &mut [])?;
+            done += n;
+        }
+        Some(len)
+    }
+}
+
+impl Drop for NbdDevice {
+    fn drop(&mut self) {
+        self.flush();
+        self.request(CMD_DISC, 0, 0, &[], &mut []);
+    }
+}
@@ -0,0 +1,565 @@
+//! NFSv3 client, presenting a directory exported by a server as a file system
+//!
+//! RPC messages are carried by a `Transport`, e.g. a UDP or TCP socket.
+//! It is mounted as root by option `root=nfs:<ip>:<path>` of the command line, over TCP.
+
+use simple_filesystem::*;
+use alloc::{boxed::Box, sync::{Arc, Weak}, string::String, vec::Vec};
+use core::any::Any;
+use log::*;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// Carries ONC RPC messages to a server program
+pub trait Transport: Send {
+    /// Send a complete call message and return the complete reply message,
+    /// None if the transport fails.
+    /// A TCP transport adds and removes the record marks.
+    fn call(&mut self, msg: &[u8]) -> Option<Vec<u8>>;
+}
+
+const RPC_VERSION: u32 = 2;
+const CALL: u32 = 0;
+const REPLY: u32 = 1;
+const MSG_ACCEPTED: u32 = 0;
+const SUCCESS: u32 = 0;
+const AUTH_NONE: u32 = 0;
+const AUTH_UNIX: u32 = 1;
+
+/// Port of the portmapper, which tells the ports of the other programs
+pub const PORTMAP_PORT: u16 = 111;
+const PMAP_PROGRAM: u32 = 100000;
+const PMAP_VERSION: u32 = 2;
+const PMAPPROC_GETPORT: u32 = 3;
+const IPPROTO_TCP: u32 = 6;
+
+const MOUNT_PROGRAM: u32 = 100005;
+const MOUNT_VERSION: u32 = 3;
+const MOUNTPROC_MNT: u32 = 1;
+const MOUNTPROC_UMNT: u32 = 3;
+
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+const NFSPROC_GETATTR: u32 = 1;
+const NFSPROC_SETATTR: u32 = 2;
+const NFSPROC_LOOKUP: u32 = 3;
+const NFSPROC_READ: u32 = 6;
+const NFSPROC_WRITE: u32 = 7;
+const NFSPROC_CREATE: u32 = 8;
+const NFSPROC_MKDIR: u32 = 9;
+const NFSPROC_REMOVE: u32 = 12;
+const NFSPROC_RMDIR: u32 = 13;
+const NFSPROC_RENAME: u32 = 14;
+const NFSPROC_LINK: u32 = 15;
+const NFSPROC_READDIR: u32 = 16;
+const NFSPROC_FSINFO: u32 = 19;
+
+const NF3DIR: u32 = 2;
+const FILE_SYNC: u32 = 2;
+const GUARDED: u32 = 1;
+/// Size of fattr3
+const FATTR_SIZE: usize = 84;
+/// Size of wcc_attr
+const WCC_ATTR_SIZE: usize = 24;
+/// Max bytes read or written by one call, also limited by the server
+const MAX_IO_SIZE: u32 = 32 * 1024;
+/// Max size of the reply to READDIR
+const READDIR_SIZE: u32 = 4096;
+
+/// An XDR encoded message being built
+struct Msg(Vec<u8>);
+
+impl Msg {
+    fn new() -> Self {
+        Msg(Vec::new())
+    }
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+    fn bool(&mut self, v: bool) -> &mut Self {
+        self.u32(v as u32)
+    }
+    /// Variable length opaque data, padded to 4 bytes
+    fn opaque(&mut self, data: &[u8]) -> &mut Self {
+        self.u32(data.len() as u32);
+        self.0.extend_from_slice(data);
+        while self.0.len() % 4 != 0 {
+            self.0.push(0);
+        }
+        self
+    }
+    fn str(&mut self, s: &str) -> &mut Self {
+        self.opaque(s.as_bytes())
+    }
+    /// diropargs3
+    fn dirop(&mut self, dir: &[u8], name: &str) -> &mut Self {
+        self.opaque(dir).str(name)
+    }
+    /// sattr3 setting only the mode
+    fn sattr_mode(&mut self, mode: u32) -> &mut Self {
+        self.bool(true).u32(mode).bool(false).bool(false).bool(false).u32(0).u32(0)
+    }
+}
+
+/// A received XDR encoded message, read from the start of its results
+struct Reply {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Reply {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.pos + len > self.data.len() {
+            warn!("nfs: truncated reply");
+            return Err(FsError::InvalidParam);
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+    }
+    fn u64(&mut self) -> Result<u64> {
+        let hi = self.u32()? as u64;
+        Ok(hi << 32 | self.u32()? as u64)
+    }
+    fn bool(&mut self) -> Result<bool> {
+        Ok(self.u32()? != 0)
+    }
+    fn opaque(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        let data = self.take(len)?.to_vec();
+        self.take((4 - len % 4) % 4)?;
+        Ok(data)
+    }
+    fn str(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.opaque()?).into_owned())
+    }
+    /// Read nfsstat3, failing if it is not NFS3_OK
+    fn status(&mut self) -> Result<()> {
+        match self.u32()? {
+            0 => Ok(()),
+            status => Err(status_to_error(status)),
+        }
+    }
+    fn fattr(&mut self) -> Result<Attr> {
+        let type_ = self.u32()?;
+        let mode = self.u32()?;
+        let nlink = self.u32()?;
+        let _uid = self.u32()?;
+        let _gid = self.u32()?;
+        let size = self.u64()?;
+        let used = self.u64()?;
+        self.take(FATTR_SIZE - 36)?;
+        Ok(Attr { is_dir: type_ == NF3DIR, mode, nlink, size, used })
+    }
+    fn post_op_attr(&mut self) -> Result<()> {
+        if self.bool()? {
+            self.take(FATTR_SIZE)?;
+        }
+        Ok(())
+    }
+    fn wcc_data(&mut self) -> Result<()> {
+        if self.bool()? {
+            self.take(WCC_ATTR_SIZE)?;
+        }
+        self.post_op_attr()
+    }
+}
+
+/// Convert nfsstat3, whose values are taken from Linux errno
+fn status_to_error(status: u32) -> FsError {
+    match status {
+        2 => FsError::EntryNotFound,
+        17 => FsError::EntryExist,
+        18 => FsError::NotSameFs,
+        20 => FsError::NotDir,
+        21 => FsError::IsDir,
+        22 | 63 => FsError::InvalidParam,
+        28 => FsError::NoDeviceSpace,
+        66 => FsError::DirNotEmpty,
+        _ => FsError::NotSupported,
+    }
+}
+
+/// Attributes of a file in fattr3
+struct Attr {
+    is_dir: bool,
+    mode: u32,
+    nlink: u32,
+    size: u64,
+    used: u64,
+}
+
+/// Calls procedures of a program on the server
+struct RpcClient {
+    transport: Box<Transport>,
+    program: u32,
+    version: u32,
+    xid: u32,
+}
+
+impl RpcClient {
+    fn new(transport: Box<Transport>, program: u32, version: u32) -> Self {
+        RpcClient { transport, program, version, xid: 0 }
+    }
+
+    /// Call `procedure` with encoded `args` and return the reply positioned at the results
+    fn call(&mut self, procedure: u32, args: &Msg) -> Result<Reply> {
+        self.xid = self.xid.wrapping_add(1);
+        let mut msg = Msg::new();
+        msg.u32(self.xid).u32(CALL).u32(RPC_VERSION).u32(self.program).u32(self.version).u32(procedure);
+        // AUTH_UNIX credential of root
+        let mut cred = Msg::new();
+        cred.u32(0).str("rcore").u32(0).u32(0).u32(0);
+        msg.u32(AUTH_UNIX).opaque(&cred.0);
+        msg.u32(AUTH_NONE).u32(0);
+        msg.0.extend_from_slice(&args.0);
+        // FsError has no I/O error
+        let data = self.transport.call(&msg.0).ok_or(FsError::NotSupported)?;
+        let mut reply = Reply { data, pos: 0 };
+        if reply.u32()? != self.xid || reply.u32()? != REPLY {
+            warn!("nfs: unexpected reply to procedure {}", procedure);
+            return Err(FsError::InvalidParam);
+        }
+        if reply.u32()? != MSG_ACCEPTED {
+            warn!("nfs: call to procedure {} denied", procedure);
+            return Err(FsError::NotSupported);
+        }
+        let _verifier_flavor = reply.u32()?;
+        reply.opaque()?;
+        let accept_stat = reply.u32()?;
+        if accept_stat != SUCCESS {
+            warn!("nfs: procedure {} failed: {}", procedure, accept_stat);
+            return Err(FsError::NotSupported);
+        }
+        Ok(reply)
+    }
+}
+
+struct Client {
+    rpc: RpcClient,
+    read_size: u32,
+    write_size: u32,
+}
+
+impl Client {
+    fn getattr(&mut self, fh: &[u8]) -> Result<Attr> {
+        let mut reply = self.rpc.call(NFSPROC_GETATTR, Msg::new().opaque(fh))?;
+        reply.status()?;
+        reply.fattr()
+    }
+
+    /// Look up `name` in the directory `dir`, returning the handle and attributes
+    fn lookup(&mut self, dir: &[u8], name: &str) -> Result<(Vec<u8>, Attr)> {
+        let mut reply = self.rpc.call(NFSPROC_LOOKUP, Msg::new().dirop(dir, name))?;
+        reply.status()?;
+        let fh = reply.opaque()?;
+        if !reply.bool()? {
+            // the server may omit the attributes
+            let attr = self.getattr(&fh)?;
+            return Ok((fh, attr));
+        }
+        let attr = reply.fattr()?;
+        Ok((fh, attr))
+    }
+
+    fn read(&mut self, fh: &[u8], offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(self.read_size as usize);
+            let mut reply = self.rpc.call(NFSPROC_READ, Msg::new().opaque(fh).u64((offset + done) as u64).u32(count as u32))?;
+            reply.status()?;
+            reply.post_op_attr()?;
+            let _count = reply.u32()?;
+            let eof = reply.bool()?;
+            let data = reply.opaque()?;
+            let len = data.len().min(count);
+            buf[done..done + len].copy_from_slice(&data[..len]);
+            done += len;
+            if eof || len == 0 {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write(&mut self, fh: &[u8], offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(self.write_size as usize);
+            let mut args = Msg::new();
+            args.opaque(fh).u64((offset + done) as u64).u32(count as u32).u32(FILE_SYNC)
+                .opaque(&buf[done..done + count]);
+            let mut reply = self.rpc.call(NFSPROC_WRITE, &args)?;
+            reply.status()?;
+            reply.wcc_data()?;
+            let len = (reply.u32()? as usize).min(count);
+            done += len;
+            if len < count {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Names of all entries in the directory `dir`
+    fn readdir(&mut self, dir: &[u8]) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut cookie = 0;
+        let mut cookie_verifier = [0u8; 8];
+        loop {
+            let mut args = Msg::new();
+            args.opaque(dir).u64(cookie);
+            args.0.extend_from_slice(&cookie_verifier);
+            args.u32(READDIR_SIZE);
+            let mut reply = self.rpc.call(NFSPROC_READDIR, &args)?;
+            reply.status()?;
+            reply.post_op_attr()?;
+            cookie_verifier.copy_from_slice(reply.take(8)?);
+            while reply.bool()? {
+                let _fileid = reply.u64()?;
+                names.push(reply.str()?);
+                cookie = reply.u64()?;
+            }
+            if reply.bool()? {
+                return Ok(names);
+            }
+        }
+    }
+
+    /// Call a procedure taking only a diropargs3, like REMOVE and RMDIR
+    fn dirop(&mut self, procedure: u32, dir: &[u8], name: &str) -> Result<()> {
+        self.rpc.call(procedure, Msg::new().dirop(dir, name))?.status()
+    }
+}
+
+/// A directory exported by an NFSv3 server
+/// Ports of the MOUNT and NFS programs over TCP, asked to the portmapper through `portmap`
+pub fn ports(portmap: Box<Transport>) -> Result<(u16, u16)> {
+    let mut portmap = RpcClient::new(portmap, PMAP_PROGRAM, PMAP_VERSION);
+    let mut getport = |program: u32, version: u32| -> Result<u16> {
+        let mut args = Msg::new();
+        args.u32(program).u32(version).u32(IPPROTO_TCP).u32(0);
+        match portmap.call(PMAPPROC_GETPORT, &args)?.u32()? {
+            0 => {
+                warn!("nfs: program {} is not registered", program);
+                Err(FsError::NotSupported)
+            }
+            port => Ok(port as u16),
+        }
+    };
+    Ok((getport(MOUNT_PROGRAM, MOUNT_VERSION)?, getport(NFS_PROGRAM, NFS_VERSION)?))
+}
+
+pub struct NfsFileSystem {
+    client: Mutex<Client>,
+    /// Used to unmount the directory when dropped
+    mount: Mutex<RpcClient>,
+    path: String,
+    root: Vec<u8>,
+    self_ref: Mutex<Weak<NfsFileSystem>>,
+}
+
+impl NfsFileSystem {
+    /// Mount the exported directory `path` using the MOUNT program through `mount`,
+    /// then access it using the NFS program through `nfs`
+    pub fn new(mount: Box<Transport>, nfs: Box<Transport>, path: &str) -> Result<Arc<Self>> {
+        let mut mount = RpcClient::new(mount, MOUNT_PROGRAM, MOUNT_VERSION);
+        let mut reply = mount.call(MOUNTPROC_MNT, Msg::new().str(path))?;
+        let status = reply.u32()?;
+        if status != 0 {
+            warn!("nfs: failed to mount {}: {}", path, status);
+            return Err(status_to_error(status));
+        }
+        let root = reply.opaque()?;
+        let mut client = Client {
+            rpc: RpcClient::new(nfs, NFS_PROGRAM, NFS_VERSION),
+            read_size: MAX_IO_SIZE,
+            write_size: MAX_IO_SIZE,
+        };
+        let mut reply = client.rpc.call(NFSPROC_FSINFO, Msg::new().opaque(&root))?;
+        reply.status()?;
+        reply.post_op_attr()?;
+        let rtmax = reply.u32()?;
+        let _rtpref = reply.u32()?;
+        let _rtmult = reply.u32()?;
+        let wtmax = reply.u32()?;
+        if rtmax != 0 {
+            client.read_size = rtmax.min(MAX_IO_SIZE);
+        }
+        if wtmax != 0 {
+            client.write_size = wtmax.min(MAX_IO_SIZE);
+        }
+        let fs = Arc::new(NfsFileSystem {
+            client: Mutex::new(client),
+            mount: Mutex::new(mount),
+            path: String::from(path),
+            root,
+            self_ref: Mutex::new(Weak::new()),
+        });
+        *fs.self_ref.lock() = Arc::downgrade(&fs);
+        // check the root here instead of in `root_inode`, dropping `fs` unmounts it on failure
+        let attr = fs.client.lock().getattr(&fs.root)?;
+        if !attr.is_dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(fs)
+    }
+
+    fn arc(&self) -> Arc<NfsFileSystem> {
+        self.self_ref.lock().upgrade().unwrap()
+    }
+}
+
+impl Drop for NfsFileSystem {
+    fn drop(&mut self) {
+        if let Err(e) = self.mount.lock().call(MOUNTPROC_UMNT, Msg::new().str(&self.path)) {
+            warn!("nfs: failed to unmount {}: {:?}", self.path, e);
+        }
+    }
+}
+
+impl FileSystem for NfsFileSystem {
+    fn sync(&self) -> Result<()> {
+        // writes are committed to stable storage by the server immediately
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        Arc::new(NfsINode { fs: self.arc(), fh: self.root.clone(), is_dir: true })
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: 255 };
+        &INFO
+    }
+}
+
+/// A file or directory on an NFS server, identified by its file handle
+pub struct NfsINode {
+    fs: Arc<NfsFileSystem>,
+    fh: Vec<u8>,
+    is_dir: bool,
+}
+
+impl NfsINode {
+    fn check_dir(&self) -> Result<()> {
+        if !self.is_dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(())
+    }
+
+    fn check_file(&self) -> Result<()> {
+        if self.is_dir {
+            return Err(FsError::IsDir);
+        }
+        Ok(())
+    }
+
+    fn same_fs<'a>(&self, other: &'a Arc<INode>) -> Result<&'a NfsINode> {
+        let other = other.as_any_ref().downcast_ref::<NfsINode>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &other.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        Ok(other)
+    }
+}
+
+impl INode for NfsINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.check_file()?;
+        self.fs.client.lock().read(&self.fh, offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.check_file()?;
+        self.fs.client.lock().write(&self.fh, offset, buf)
+    }
+    fn info(&self) -> Result<FileInfo> {
+        let attr = self.fs.client.lock().getattr(&self.fh)?;
+        Ok(FileInfo {
+            size: attr.size as usize,
+            mode: attr.mode & 0o7777,
+            type_: if attr.is_dir { FileType::Dir } else { FileType::File },
+            blocks: (attr.used / 512) as usize,
+            nlinks: attr.nlink as usize,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, len: usize) -> Result<()> {
+        self.check_file()?;
+        let mut args = Msg::new();
+        // sattr3 setting only the size, with no guard
+        args.opaque(&self.fh).bool(false).bool(false).bool(false).bool(true).u64(len as u64)
+            .u32(0).u32(0).bool(false);
+        self.fs.client.lock().rpc.call(NFSPROC_SETATTR, &args)?.status()
+    }
+    fn create(&self, name: &str, type_: FileType) -> Result<Arc<INode>> {
+        self.check_dir()?;
+        {
+            let mut client = self.fs.client.lock();
+            let mut reply = match type_ {
+                FileType::File => {
+                    let mut args = Msg::new();
+                    args.dirop(&self.fh, name).u32(GUARDED).sattr_mode(0o644);
+                    client.rpc.call(NFSPROC_CREATE, &args)?
+                }
+                FileType::Dir => {
+                    let mut args = Msg::new();
+                    args.dirop(&self.fh, name).sattr_mode(0o755);
+                    client.rpc.call(NFSPROC_MKDIR, &args)?
+                }
+            };
+            reply.status()?;
+        }
+        // the handle in the reply is optional, so look it up
+        self.find(name)
+    }
+    fn unlink(&self, name: &str) -> Result<()> {
+        let is_dir = self.find(name)?.info()?.type_ == FileType::Dir;
+        let procedure = if is_dir { NFSPROC_RMDIR } else { NFSPROC_REMOVE };
+        self.fs.client.lock().dirop(procedure, &self.fh, name)
+    }
+    fn link(&self, name: &str, other: &Arc<INode>) -> Result<()> {
+        self.check_dir()?;
+        let other = self.same_fs(other)?;
+        let mut args = Msg::new();
+        args.opaque(&other.fh).dirop(&self.fh, name);
+        self.fs.client.lock().rpc.call(NFSPROC_LINK, &args)?.status()
+    }
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        self.check_dir()?;
+        let mut args = Msg::new();
+        args.dirop(&self.fh, old_name).dirop(&self.fh, new_name);
+        self.fs.client.lock().rpc.call(NFSPROC_RENAME, &args)?.status()
+    }
+    fn move_(&self, old_name: &str, target: &Arc<INode>, new_name: &str) -> Result<()> {
+        self.check_dir()?;
+        let target = self.same_fs(target)?;
+        let mut args = Msg::new();
+        args.dirop(&self.fh, old_name).dirop(&target.fh, new_name);
+        self.fs.client.lock().rpc.call(NFSPROC_RENAME, &args)?.status()
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        self.check_dir()?;
+        let (fh, attr) = self.fs.client.lock().lookup(&self.fh, name)?;
+        Ok(Arc::new(NfsINode { fs: self.fs.clone(), fh, is_dir: attr.is_dir }))
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.check_dir()?;
+        let names = self.fs.client.lock().readdir(&self.fh)?;
+        names.into_iter().nth(id).ok_or(FsError::EntryNotFound)
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
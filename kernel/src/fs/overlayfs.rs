@@ -0,0 +1,377 @@
+//! Overlay file system: a writable upper layer over a read only lower layer
+//!
+//! Files are copied to the upper layer when modified. Deleted entries of the lower
+//! layer are hidden by whiteouts, which are empty files named `.wh.<name>` in the
+//! upper layer. A directory containing `.wh..wh..opq` hides the lower directory
+//! of the same path completely.
+
+use simple_filesystem::*;
+use alloc::{sync::{Arc, Weak}, string::String, vec::Vec};
+use core::any::Any;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+/// Size of chunks when copying a file to the upper layer
+const COPY_CHUNK_SIZE: usize = 4096;
+
+pub struct OverlayFileSystem {
+    /// Root directories of the layers.
+    /// The file systems are not kept, since they may not be `Send`.
+    upper: Arc<INode>,
+    lower: Arc<INode>,
+    self_ref: Mutex<Weak<OverlayFileSystem>>,
+}
+
+impl OverlayFileSystem {
+    /// Stack `upper` over `lower`. `lower` is never modified.
+    pub fn new(upper: &Arc<FileSystem>, lower: &Arc<FileSystem>) -> Arc<Self> {
+        let fs = Arc::new(OverlayFileSystem {
+            upper: upper.root_inode(),
+            lower: lower.root_inode(),
+            self_ref: Mutex::new(Weak::new()),
+        });
+        *fs.self_ref.lock() = Arc::downgrade(&fs);
+        fs
+    }
+
+    fn arc(&self) -> Arc<OverlayFileSystem> {
+        self.self_ref.lock().upgrade().unwrap()
+    }
+}
+
+impl FileSystem for OverlayFileSystem {
+    fn sync(&self) -> Result<()> {
+        self.upper.fs().sync()
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        Arc::new(OverlayINode {
+            fs: self.arc(),
+            parent: None,
+            name: String::new(),
+            upper: Mutex::new(Some(self.upper.clone())),
+            lower: Some(self.lower.clone()),
+        })
+    }
+    fn info(&self) -> &'static FsInfo {
+        self.upper.fs().info()
+    }
+}
+
+/// A file or directory in an overlay file system.
+///
+/// It is backed by an inode in the upper layer, the lower layer, or both if it is
+/// a directory existing in both layers, whose entries are merged.
+pub struct OverlayINode {
+    fs: Arc<OverlayFileSystem>,
+    /// None for the root directory
+    parent: Option<Arc<OverlayINode>>,
+    name: String,
+    /// None if it is only in the lower layer and has not been copied up
+    upper: Mutex<Option<Arc<INode>>>,
+    lower: Option<Arc<INode>>,
+}
+
+fn whiteout(name: &str) -> String {
+    format!("{}{}", WHITEOUT_PREFIX, name)
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." || name.starts_with(WHITEOUT_PREFIX) {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(())
+}
+
+/// Names of entries in a directory, excluding `.` and `..`
+fn entry_names(dir: &Arc<INode>) -> Vec<String> {
+    (0..).map(|i| dir.get_entry(i))
+        .take_while(|entry| entry.is_ok())
+        .filter_map(|entry| entry.ok())
+        .filter(|name| name != "." && name != "..")
+        .collect()
+}
+
+impl OverlayINode {
+    /// Another handle to the same inode
+    fn dup(&self) -> OverlayINode {
+        OverlayINode {
+            fs: self.fs.clone(),
+            parent: self.parent.clone(),
+            name: self.name.clone(),
+            upper: Mutex::new(self.upper.lock().clone()),
+            lower: self.lower.clone(),
+        }
+    }
+
+    /// The inode in the upper layer, if any
+    fn upper(&self) -> Option<Arc<INode>> {
+        let mut upper = self.upper.lock();
+        if upper.is_none() {
+            // it may have been copied up through another handle
+            let parent = self.parent.as_ref().unwrap();
+            *upper = parent.upper().and_then(|dir| dir.find(&self.name).ok());
+        }
+        upper.clone()
+    }
+
+    /// The inode to read from: the upper one if it exists
+    fn current(&self) -> Arc<INode> {
+        self.upper().or_else(|| self.lower.clone()).unwrap()
+    }
+
+    fn is_dir(&self) -> Result<bool> {
+        Ok(self.current().info()?.type_ == FileType::Dir)
+    }
+
+    /// Path from the root, used to tell whether two handles are the same inode
+    fn path(&self) -> String {
+        match self.parent {
+            Some(ref parent) => format!("{}/{}", parent.path(), self.name),
+            None => String::new(),
+        }
+    }
+
+    /// Whether the lower directory is hidden
+    fn is_opaque(&self) -> bool {
+        self.upper().map_or(false, |upper| upper.find(OPAQUE_MARKER).is_ok())
+    }
+
+    fn is_whited_out(&self, name: &str) -> bool {
+        self.upper().map_or(false, |upper| upper.find(&whiteout(name)).is_ok())
+    }
+
+    /// Copy the inode to the upper layer, together with its ancestors
+    fn copy_up(&self) -> Result<Arc<INode>> {
+        if let Some(upper) = self.upper() {
+            return Ok(upper);
+        }
+        let parent = self.parent.as_ref().unwrap().copy_up()?;
+        let lower = self.lower.as_ref().unwrap();
+        let type_ = lower.info()?.type_;
+        let upper = match parent.create(&self.name, type_) {
+            Err(FsError::EntryExist) => return parent.find(&self.name),
+            result => result?,
+        };
+        if type_ == FileType::File {
+            let mut buf = [0u8; COPY_CHUNK_SIZE];
+            let mut offset = 0;
+            loop {
+                let len = lower.read_at(offset, &mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                upper.write_at(offset, &buf[..len])?;
+                offset += len;
+            }
+        }
+        *self.upper.lock() = Some(upper.clone());
+        Ok(upper)
+    }
+
+    /// Look up a child in the merged directory
+    fn child(&self, name: &str) -> Result<OverlayINode> {
+        if !self.is_dir()? {
+            return Err(FsError::NotDir);
+        }
+        let upper = if name.starts_with(WHITEOUT_PREFIX) {
+            None
+        } else {
+            self.upper().and_then(|dir| dir.find(name).ok())
+        };
+        let lower = if self.is_opaque() || self.is_whited_out(name) {
+            None
+        } else {
+            self.lower.as_ref().and_then(|dir| dir.find(name).ok())
+        };
+        let lower = match upper {
+            // a lower entry is only visible under an upper one if both are directories
+            Some(ref upper) => match lower {
+                Some(lower) if upper.info()?.type_ == FileType::Dir && lower.info()?.type_ == FileType::Dir => Some(lower),
+                _ => None,
+            },
+            None => lower,
+        };
+        if upper.is_none() && lower.is_none() {
+            return Err(FsError::EntryNotFound);
+        }
+        Ok(OverlayINode {
+            fs: self.fs.clone(),
+            parent: Some(Arc::new(self.dup())),
+            name: String::from(name),
+            upper: Mutex::new(upper),
+            lower,
+        })
+    }
+
+    /// Names in the merged directory, including `.` and `..`
+    fn names(&self) -> Result<Vec<String>> {
+        if !self.is_dir()? {
+            return Err(FsError::NotDir);
+        }
+        let mut names = vec![String::from("."), String::from("..")];
+        if let Some(upper) = self.upper() {
+            names.extend(entry_names(&upper).into_iter().filter(|name| !name.starts_with(WHITEOUT_PREFIX)));
+        }
+        if let Some(ref lower) = self.lower {
+            if !self.is_opaque() {
+                for name in entry_names(lower) {
+                    if !names.contains(&name) && !self.is_whited_out(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Remove the whiteout of `name` in the upper directory `dir`.
+    /// Return whether it existed.
+    fn remove_whiteout(dir: &Arc<INode>, name: &str) -> Result<bool> {
+        let whiteout = whiteout(name);
+        if dir.find(&whiteout).is_err() {
+            return Ok(false);
+        }
+        dir.unlink(&whiteout)?;
+        Ok(true)
+    }
+
+    fn move_to(&self, old_name: &str, target: &OverlayINode, new_name: &str) -> Result<()> {
+        check_name(old_name)?;
+        check_name(new_name)?;
+        let child = self.child(old_name)?;
+        match target.child(new_name) {
+            Ok(_) => return Err(FsError::EntryExist),
+            Err(FsError::EntryNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let is_dir = child.is_dir()?;
+        if is_dir && child.lower.is_some() {
+            // would need to copy up the whole tree
+            return Err(FsError::NotSupported);
+        }
+        child.copy_up()?;
+        let src = self.copy_up()?;
+        let dst = target.copy_up()?;
+        let whited_out = Self::remove_whiteout(&dst, new_name)?;
+        if self.path() == target.path() {
+            src.rename(old_name, new_name)?;
+        } else {
+            src.move_(old_name, &dst, new_name)?;
+        }
+        if is_dir && whited_out {
+            // hide the deleted lower directory at the new place
+            dst.find(new_name)?.create(OPAQUE_MARKER, FileType::File)?;
+        }
+        if child.lower.is_some() {
+            src.create(&whiteout(old_name), FileType::File)?;
+        }
+        Ok(())
+    }
+}
+
+impl INode for OverlayINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.current().read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        if self.is_dir()? {
+            return Err(FsError::IsDir);
+        }
+        self.copy_up()?.write_at(offset, buf)
+    }
+    fn info(&self) -> Result<FileInfo> {
+        self.current().info()
+    }
+    fn sync(&self) -> Result<()> {
+        self.upper().map_or(Ok(()), |upper| upper.sync())
+    }
+    fn resize(&self, len: usize) -> Result<()> {
+        if self.is_dir()? {
+            return Err(FsError::IsDir);
+        }
+        self.copy_up()?.resize(len)
+    }
+    fn create(&self, name: &str, type_: FileType) -> Result<Arc<INode>> {
+        check_name(name)?;
+        match self.child(name) {
+            Ok(_) => return Err(FsError::EntryExist),
+            Err(FsError::EntryNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let dir = self.copy_up()?;
+        let whited_out = Self::remove_whiteout(&dir, name)?;
+        let inode = dir.create(name, type_)?;
+        if whited_out && type_ == FileType::Dir {
+            // hide the deleted lower directory
+            inode.create(OPAQUE_MARKER, FileType::File)?;
+        }
+        Ok(Arc::new(self.child(name)?))
+    }
+    fn unlink(&self, name: &str) -> Result<()> {
+        check_name(name)?;
+        let child = self.child(name)?;
+        if child.is_dir()? && child.names()?.len() > 2 {
+            return Err(FsError::DirNotEmpty);
+        }
+        let dir = self.copy_up()?;
+        if let Some(upper) = child.upper() {
+            if child.is_dir()? {
+                // only whiteouts are left
+                for name in entry_names(&upper) {
+                    upper.unlink(&name)?;
+                }
+            }
+            dir.unlink(name)?;
+        }
+        if child.lower.is_some() {
+            dir.create(&whiteout(name), FileType::File)?;
+        }
+        Ok(())
+    }
+    fn link(&self, name: &str, other: &Arc<INode>) -> Result<()> {
+        check_name(name)?;
+        let other = other.as_any_ref().downcast_ref::<OverlayINode>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &other.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        if other.is_dir()? {
+            return Err(FsError::IsDir);
+        }
+        match self.child(name) {
+            Ok(_) => return Err(FsError::EntryExist),
+            Err(FsError::EntryNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let other = other.copy_up()?;
+        let dir = self.copy_up()?;
+        Self::remove_whiteout(&dir, name)?;
+        dir.link(name, &other)
+    }
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        self.move_to(old_name, self, new_name)
+    }
+    fn move_(&self, old_name: &str, target: &Arc<INode>, new_name: &str) -> Result<()> {
+        let target = target.as_any_ref().downcast_ref::<OverlayINode>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        self.move_to(old_name, target, new_name)
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        match name {
+            "." => Ok(Arc::new(self.dup())),
+            ".." => Ok(Arc::new(self.parent.as_ref().map_or_else(|| self.dup(), |parent| parent.dup()))),
+            _ => Ok(Arc::new(self.child(name)?)),
+        }
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.names()?.into_iter().nth(id).ok_or(FsError::EntryNotFound)
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
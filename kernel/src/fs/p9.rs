@@ -0,0 +1,526 @@
+//! 9P2000.L client, presenting a remote file tree as a file system
+//!
+//! Messages are carried by a `Transport`, e.g. a virtio-9p device or a socket.
+
+use simple_filesystem::*;
+use alloc::{boxed::Box, sync::{Arc, Weak}, string::String, vec::Vec};
+use core::any::Any;
+use log::*;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// Carries 9P messages to a server
+pub trait Transport: Send {
+    /// Send a complete T-message and return the complete R-message,
+    /// None if the transport fails
+    fn request(&mut self, msg: &[u8]) -> Option<Vec<u8>>;
+}
+
+const VERSION: &str = "9P2000.L";
+/// Max message size requested from the server
+pub const MSIZE: u32 = 64 * 1024;
+/// Size of the header of Tread, Twrite, Rread and Rwrite messages before the data
+const IO_HEADER_SIZE: u32 = 4 + 1 + 2 + 4 + 8 + 4;
+const NOFID: u32 = !0;
+const ROOT_FID: u32 = 0;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const O_RDONLY: u32 = 0;
+const O_RDWR: u32 = 2;
+const AT_REMOVEDIR: u32 = 0x200;
+const GETATTR_BASIC: u64 = 0x7ff;
+const SETATTR_SIZE: u32 = 0x8;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const QID_SIZE: usize = 13;
+
+/// A message being built
+struct Msg(Vec<u8>);
+
+impl Msg {
+    fn new(type_: u8) -> Self {
+        // size is filled in when sent, tag is not used since requests are not pipelined
+        let mut msg = Msg(Vec::new());
+        msg.u32(0).u8(type_).u16(0);
+        msg
+    }
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.0.push(v);
+        self
+    }
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn str(&mut self, s: &str) -> &mut Self {
+        self.u16(s.len() as u16);
+        self.0.extend_from_slice(s.as_bytes());
+        self
+    }
+    fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(data);
+        self
+    }
+}
+
+/// A received message, read from the start of its body
+struct Reply {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Reply {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.pos + len > self.data.len() {
+            warn!("9p: truncated reply");
+            return Err(FsError::InvalidParam);
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(b[0] as u16 | (b[1] as u16) << 8)
+    }
+    fn u32(&mut self) -> Result<u32> {
+        let lo = self.u16()? as u32;
+        Ok(lo | (self.u16()? as u32) << 16)
+    }
+    fn u64(&mut self) -> Result<u64> {
+        let lo = self.u32()? as u64;
+        Ok(lo | (self.u32()? as u64) << 32)
+    }
+    fn str(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+/// Convert a Linux errno from Rlerror
+fn errno_to_error(errno: u32) -> FsError {
+    match errno {
+        2 => FsError::EntryNotFound,
+        17 => FsError::EntryExist,
+        18 => FsError::NotSameFs,
+        20 => FsError::NotDir,
+        21 => FsError::IsDir,
+        22 | 36 => FsError::InvalidParam,
+        28 => FsError::NoDeviceSpace,
+        39 => FsError::DirNotEmpty,
+        _ => FsError::NotSupported,
+    }
+}
+
+struct Client {
+    transport: Box<Transport>,
+    msize: u32,
+    next_fid: u32,
+}
+
+impl Client {
+    /// Send `msg` and check the reply type
+    fn rpc(&mut self, msg: &mut Msg) -> Result<Reply> {
+        let size = msg.0.len() as u32;
+        msg.0[0..4].copy_from_slice(&size.to_le_bytes());
+        let type_ = msg.0[4];
+        // FsError has no I/O error
+        let data = self.transport.request(&msg.0).ok_or(FsError::NotSupported)?;
+        let mut reply = Reply { data, pos: 0 };
+        let _size = reply.u32()?;
+        let reply_type = reply.u8()?;
+        let _tag = reply.u16()?;
+        if reply_type == RLERROR {
+            return Err(errno_to_error(reply.u32()?));
+        }
+        if reply_type != type_ + 1 {
+            warn!("9p: unexpected reply type {} to {}", reply_type, type_);
+            return Err(FsError::InvalidParam);
+        }
+        Ok(reply)
+    }
+
+    fn alloc_fid(&mut self) -> u32 {
+        self.next_fid += 1;
+        self.next_fid
+    }
+
+    /// Walk from `fid` along `names` to a new fid
+    fn walk(&mut self, fid: u32, names: &[&str]) -> Result<u32> {
+        let newfid = self.alloc_fid();
+        let mut msg = Msg::new(TWALK);
+        msg.u32(fid).u32(newfid).u16(names.len() as u16);
+        for name in names {
+            msg.str(name);
+        }
+        let mut reply = self.rpc(&mut msg)?;
+        if reply.u16()? as usize != names.len() {
+            // the walk stopped early, newfid is not used
+            return Err(FsError::EntryNotFound);
+        }
+        Ok(newfid)
+    }
+
+    fn clunk(&mut self, fid: u32) {
+        if let Err(e) = self.rpc(Msg::new(TCLUNK).u32(fid)) {
+            warn!("9p: failed to clunk fid {}: {:?}", fid, e);
+        }
+    }
+
+    /// Open a new fid for I/O on the file of `fid`
+    fn open(&mut self, fid: u32, flags: u32) -> Result<u32> {
+        let newfid = self.walk(fid, &[])?;
+        if let Err(e) = self.rpc(Msg::new(TLOPEN).u32(newfid).u32(flags)) {
+            self.clunk(newfid);
+            return Err(e);
+        }
+        Ok(newfid)
+    }
+
+    /// (mode, nlink, size, blocks)
+    fn getattr(&mut self, fid: u32) -> Result<(u32, u64, u64, u64)> {
+        let mut reply = self.rpc(Msg::new(TGETATTR).u32(fid).u64(GETATTR_BASIC))?;
+        let _valid = reply.u64()?;
+        reply.take(QID_SIZE)?;
+        let mode = reply.u32()?;
+        let _uid = reply.u32()?;
+        let _gid = reply.u32()?;
+        let nlink = reply.u64()?;
+        let _rdev = reply.u64()?;
+        let size = reply.u64()?;
+        let _blksize = reply.u64()?;
+        let blocks = reply.u64()?;
+        Ok((mode, nlink, size, blocks))
+    }
+
+    fn read(&mut self, fid: u32, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let max = (self.msize - IO_HEADER_SIZE) as usize;
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(max);
+            let mut reply = self.rpc(Msg::new(TREAD).u32(fid).u64((offset + done) as u64).u32(count as u32))?;
+            let len = (reply.u32()? as usize).min(count);
+            buf[done..done + len].copy_from_slice(reply.take(len)?);
+            done += len;
+            if len < count {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write(&mut self, fid: u32, offset: usize, buf: &[u8]) -> Result<usize> {
+        let max = (self.msize - IO_HEADER_SIZE) as usize;
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(max);
+            let mut msg = Msg::new(TWRITE);
+            msg.u32(fid).u64((offset + done) as u64).u32(count as u32).bytes(&buf[done..done + count]);
+            let len = (self.rpc(&mut msg)?.u32()? as usize).min(count);
+            done += len;
+            if len < count {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Names of all entries in the directory of `fid`
+    fn readdir(&mut self, fid: u32) -> Result<Vec<String>> {
+        let dir = self.open(fid, O_RDONLY)?;
+        let result = self.readdir_opened(dir);
+        self.clunk(dir);
+        result
+    }
+
+    fn readdir_opened(&mut self, dir: u32) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut offset = 0;
+        loop {
+            let count = self.msize - IO_HEADER_SIZE;
+            let mut reply = self.rpc(Msg::new(TREADDIR).u32(dir).u64(offset).u32(count))?;
+            let len = reply.u32()? as usize;
+            if len == 0 {
+                return Ok(names);
+            }
+            let end = reply.pos + len;
+            while reply.pos < end {
+                reply.take(QID_SIZE)?;
+                offset = reply.u64()?;
+                let _type = reply.u8()?;
+                names.push(reply.str()?);
+            }
+        }
+    }
+}
+
+/// A file system on a 9P server
+pub struct P9FileSystem {
+    client: Mutex<Client>,
+    self_ref: Mutex<Weak<P9FileSystem>>,
+}
+
+impl P9FileSystem {
+    /// Negotiate the protocol and attach to the tree `aname` on the server as `uname`
+    pub fn new(transport: Box<Transport>, uname: &str, aname: &str) -> Result<Arc<Self>> {
+        let mut client = Client { transport, msize: MSIZE, next_fid: ROOT_FID };
+        let mut reply = client.rpc(Msg::new(TVERSION).u32(MSIZE).str(VERSION))?;
+        let msize = reply.u32()?;
+        if reply.str()? != VERSION || msize <= IO_HEADER_SIZE {
+            warn!("9p: server does not support {}", VERSION);
+            return Err(FsError::NotSupported);
+        }
+        client.msize = msize.min(MSIZE);
+        client.rpc(Msg::new(TATTACH).u32(ROOT_FID).u32(NOFID).str(uname).str(aname).u32(NOFID))?;
+        // check the root here instead of in `root_inode`, which can not fail
+        match client.getattr(ROOT_FID) {
+            Ok((mode, ..)) if mode & S_IFMT == S_IFDIR => {}
+            result => {
+                client.clunk(ROOT_FID);
+                return Err(result.err().unwrap_or(FsError::NotDir));
+            }
+        }
+        let fs = Arc::new(P9FileSystem {
+            client: Mutex::new(client),
+            self_ref: Mutex::new(Weak::new()),
+        });
+        *fs.self_ref.lock() = Arc::downgrade(&fs);
+        Ok(fs)
+    }
+
+    fn arc(&self) -> Arc<P9FileSystem> {
+        self.self_ref.lock().upgrade().unwrap()
+    }
+
+    fn inode(&self, fid: u32) -> Result<Arc<INode>> {
+        let (mode, ..) = self.client.lock().getattr(fid)?;
+        Ok(Arc::new(P9INode {
+            fs: self.arc(),
+            fid,
+            is_dir: mode & S_IFMT == S_IFDIR,
+            io_fid: Mutex::new(None),
+            entries: Mutex::new(None),
+        }))
+    }
+}
+
+impl FileSystem for P9FileSystem {
+    fn sync(&self) -> Result<()> {
+        // writes are sent to the server immediately
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        // the attached fid is shared by all root inodes, and never clunked
+        Arc::new(P9INode {
+            fs: self.arc(),
+            fid: ROOT_FID,
+            is_dir: true,
+            io_fid: Mutex::new(None),
+            entries: Mutex::new(None),
+        })
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: 255 };
+        &INFO
+    }
+}
+
+/// A file or directory on a 9P server, identified by a fid walked to it
+pub struct P9INode {
+    fs: Arc<P9FileSystem>,
+    fid: u32,
+    is_dir: bool,
+    /// An opened fid for reading and writing, opened on first use
+    io_fid: Mutex<Option<u32>>,
+    /// Names in the directory, read when listing starts from the first entry
+    /// and dropped when it is changed through this inode
+    entries: Mutex<Option<Vec<String>>>,
+}
+
+impl Drop for P9INode {
+    fn drop(&mut self) {
+        let mut client = self.fs.client.lock();
+        if let Some(fid) = *self.io_fid.lock() {
+            client.clunk(fid);
+        }
+        if self.fid != ROOT_FID {
+            client.clunk(self.fid);
+        }
+    }
+}
+
+impl P9INode {
+    fn check_dir(&self) -> Result<()> {
+        if !self.is_dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(())
+    }
+
+    /// Run `f` with the I/O fid, opening it first if needed.
+    /// The file is opened for reading and writing, or read only if not permitted.
+    fn with_io_fid<T>(&self, f: impl FnOnce(&mut Client, u32) -> Result<T>) -> Result<T> {
+        if self.is_dir {
+            return Err(FsError::IsDir);
+        }
+        let mut client = self.fs.client.lock();
+        let mut io_fid = self.io_fid.lock();
+        let fid = match *io_fid {
+            Some(fid) => fid,
+            None => {
+                let fid = client.open(self.fid, O_RDWR).or_else(|_| client.open(self.fid, O_RDONLY))?;
+                *io_fid = Some(fid);
+                fid
+            }
+        };
+        f(&mut client, fid)
+    }
+
+    /// Drop the cached names after the directory is changed
+    fn changed(&self) {
+        *self.entries.lock() = None;
+    }
+
+    fn find_fid(&self, name: &str) -> Result<u32> {
+        self.check_dir()?;
+        let names = if name == "." { &[][..] } else { &[name][..] };
+        self.fs.client.lock().walk(self.fid, names)
+    }
+}
+
+impl INode for P9INode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.with_io_fid(|client, fid| client.read(fid, offset, buf))
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.with_io_fid(|client, fid| client.write(fid, offset, buf))
+    }
+    fn info(&self) -> Result<FileInfo> {
+        let (mode, nlink, size, blocks) = self.fs.client.lock().getattr(self.fid)?;
+        Ok(FileInfo {
+            size: size as usize,
+            mode: mode & !S_IFMT,
+            type_: if self.is_dir { FileType::Dir } else { FileType::File },
+            blocks: blocks as usize,
+            nlinks: nlink as usize,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        let mut client = self.fs.client.lock();
+        match *self.io_fid.lock() {
+            Some(fid) => client.rpc(Msg::new(TFSYNC).u32(fid)).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+    fn resize(&self, len: usize) -> Result<()> {
+        if self.is_dir {
+            return Err(FsError::IsDir);
+        }
+        let mut msg = Msg::new(TSETATTR);
+        msg.u32(self.fid).u32(SETATTR_SIZE).u32(0).u32(0).u32(0).u64(len as u64)
+            .u64(0).u64(0).u64(0).u64(0);
+        self.fs.client.lock().rpc(&mut msg)?;
+        Ok(())
+    }
+    fn create(&self, name: &str, type_: FileType) -> Result<Arc<INode>> {
+        self.check_dir()?;
+        {
+            let mut client = self.fs.client.lock();
+            match type_ {
+                FileType::File => {
+                    // Tlcreate turns the fid into the opened new file
+                    let fid = client.walk(self.fid, &[])?;
+                    let result = client.rpc(Msg::new(TLCREATE).u32(fid).str(name).u32(O_RDWR).u32(0o644).u32(0));
+                    client.clunk(fid);
+                    result?;
+                }
+                FileType::Dir => {
+                    client.rpc(Msg::new(TMKDIR).u32(self.fid).str(name).u32(0o755).u32(0))?;
+                }
+            }
+        }
+        self.changed();
+        self.find(name)
+    }
+    fn unlink(&self, name: &str) -> Result<()> {
+        let is_dir = self.find(name)?.info()?.type_ == FileType::Dir;
+        let flags = if is_dir { AT_REMOVEDIR } else { 0 };
+        self.fs.client.lock().rpc(Msg::new(TUNLINKAT).u32(self.fid).str(name).u32(flags))?;
+        self.changed();
+        Ok(())
+    }
+    fn link(&self, name: &str, other: &Arc<INode>) -> Result<()> {
+        self.check_dir()?;
+        let other = other.as_any_ref().downcast_ref::<P9INode>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &other.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        self.fs.client.lock().rpc(Msg::new(TLINK).u32(self.fid).u32(other.fid).str(name))?;
+        self.changed();
+        Ok(())
+    }
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        self.check_dir()?;
+        self.fs.client.lock().rpc(Msg::new(TRENAMEAT).u32(self.fid).str(old_name).u32(self.fid).str(new_name))?;
+        self.changed();
+        Ok(())
+    }
+    fn move_(&self, old_name: &str, target: &Arc<INode>, new_name: &str) -> Result<()> {
+        self.check_dir()?;
+        let target = target.as_any_ref().downcast_ref::<P9INode>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        self.fs.client.lock().rpc(Msg::new(TRENAMEAT).u32(self.fid).str(old_name).u32(target.fid).str(new_name))?;
+        self.changed();
+        target.changed();
+        Ok(())
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        let fid = self.find_fid(name)?;
+        self.fs.inode(fid).map_err(|e| {
+            self.fs.client.lock().clunk(fid);
+            e
+        })
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.check_dir()?;
+        let mut entries = self.entries.lock();
+        if id == 0 || entries.is_none() {
+            *entries = Some(self.fs.client.lock().readdir(self.fid)?);
+        }
+        entries.as_ref().unwrap().get(id).cloned().ok_or(FsError::EntryNotFound)
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
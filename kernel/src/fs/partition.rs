@@ -0,0 +1,302 @@
+//! Partition table parsing, supporting MBR and GPT
+
+use simple_filesystem::Device;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::fmt;
+use log::*;
+use crate::sync::ThreadLock as Mutex;
+
+pub const SECTOR_SIZE: usize = 512;
+/// MBR partition type of the protective partition covering a GPT disk
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+/// Maximum number of GPT entries accepted
+const GPT_MAX_ENTRIES: usize = 1024;
+/// Maximum size of a GPT entry accepted, real tables use 128 bytes
+const GPT_MAX_ENTRY_SIZE: usize = 512;
+
+/// A disk which may contain partitions
+pub struct Disk {
+    device: Arc<Mutex<Box<Device>>>,
+}
+
+/// A region of a disk, which is also a device
+#[derive(Clone)]
+pub struct Partition {
+    device: Arc<Mutex<Box<Device>>>,
+    /// Offset from the start of disk in bytes
+    offset: usize,
+    /// Size in bytes
+    size: usize,
+    pub info: PartitionInfo,
+}
+
+/// An entry in the partition table
+#[derive(Clone, Copy)]
+pub struct PartitionInfo {
+    /// Index in the partition table, starts from 1
+    pub index: usize,
+    pub kind: PartitionKind,
+    pub start_lba: usize,
+    pub sectors: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PartitionKind {
+    /// The whole disk
+    Whole,
+    Mbr { type_: u8, bootable: bool },
+    Gpt { type_guid: Guid, guid: Guid },
+}
+
+/// GUID as stored on disk (mixed endian)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Parse the text form, e.g. "c12a7328-f81f-11d2-ba4b-00a0c93ec93b", case insensitive
+    pub fn parse(s: &str) -> Option<Guid> {
+        /// Position of each byte in the text form, in the order stored on disk
+        const ORDER: [usize; 16] = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+        let s = s.as_bytes();
+        if s.len() != 36 || [8, 13, 18, 23].iter().any(|&i| s[i] != b'-') {
+            return None;
+        }
+        let mut text = [0u8; 16];
+        let digits = s.iter().filter(|&&c| c != b'-');
+        let mut digits = digits.map(|&c| (c as char).to_digit(16));
+        for byte in text.iter_mut() {
+            *byte = (digits.next()?? << 4 | digits.next()??) as u8;
+        }
+        let mut guid = [0u8; 16];
+        for (i, &pos) in ORDER.iter().enumerate() {
+            guid[i] = text[pos];
+        }
+        Some(Guid(guid))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-",
+               b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9])?;
+        for byte in &b[10..] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PartitionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "partition {}: {:?}, {} sectors at LBA {}",
+               self.index, self.kind, self.sectors, self.start_lba)
+    }
+}
+
+impl Disk {
+    pub fn new(device: Box<Device>) -> Self {
+        Disk { device: Arc::new(Mutex::new(device)) }
+    }
+
+    /// The whole disk as a partition
+    pub fn whole(&self) -> Partition {
+        Partition {
+            device: self.device.clone(),
+            offset: 0,
+            size: usize::max_value(),
+            info: PartitionInfo { index: 0, kind: PartitionKind::Whole, start_lba: 0, sectors: 0 },
+        }
+    }
+
+    /// Partitions of the disk, empty if there is no partition table.
+    ///
+    /// GPT is used if the MBR contains a protective partition and the GPT is valid.
+    /// Otherwise partitions in the MBR are returned, ignoring the protective one.
+    pub fn partitions(&self) -> Vec<Partition> {
+        let mbr = self.read_mbr();
+        let mut infos = Vec::new();
+        if mbr.iter().any(|p| match p.kind {
+            PartitionKind::Mbr { type_, .. } => type_ == MBR_TYPE_GPT_PROTECTIVE,
+            _ => false,
+        }) {
+            infos = self.read_gpt();
+        }
+        if infos.is_empty() {
+            infos = mbr.into_iter().filter(|p| match p.kind {
+                PartitionKind::Mbr { type_, .. } => type_ != MBR_TYPE_GPT_PROTECTIVE,
+                _ => false,
+            }).collect();
+        }
+        infos.into_iter()
+            .filter_map(|info| {
+                let offset = info.start_lba.checked_mul(SECTOR_SIZE);
+                let size = info.sectors.checked_mul(SECTOR_SIZE);
+                match (offset, size) {
+                    (Some(offset), Some(size)) if offset.checked_add(size).is_some() =>
+                        Some(Partition { device: self.device.clone(), offset, size, info }),
+                    _ => {
+                        warn!("{:?} is beyond the addressable range, ignored", info);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Find a GPT partition by its unique GUID
+    pub fn partition_by_guid(&self, guid: Guid) -> Option<Partition> {
+        self.partitions().into_iter().find(|p| match p.info.kind {
+            PartitionKind::Gpt { guid: g, .. } => g == guid,
+            _ => false,
+        })
+    }
+
+    fn read_sectors(&self, lba: usize, buf: &mut [u8]) -> bool {
+        match lba.checked_mul(SECTOR_SIZE) {
+            Some(offset) => self.device.lock().read_at(offset, buf) == Some(buf.len()),
+            None => false,
+        }
+    }
+
+    fn read_mbr(&self) -> Vec<PartitionInfo> {
+        let mut mbr = [0u8; SECTOR_SIZE];
+        if !self.read_sectors(0, &mut mbr) || mbr[510] != 0x55 || mbr[511] != 0xaa {
+            return Vec::new();
+        }
+        let mut parts = Vec::new();
+        for i in 0..4 {
+            let entry = &mbr[446 + i * 16..446 + (i + 1) * 16];
+            // a valid boot sector without partition table has other status bytes
+            if entry[0] != 0 && entry[0] != 0x80 {
+                return Vec::new();
+            }
+            let info = PartitionInfo {
+                index: i + 1,
+                kind: PartitionKind::Mbr { type_: entry[4], bootable: entry[0] == 0x80 },
+                start_lba: read_u32(&entry[8..12]) as usize,
+                sectors: read_u32(&entry[12..16]) as usize,
+            };
+            if entry[4] != 0 && info.sectors != 0 {
+                parts.push(info);
+            }
+        }
+        parts
+    }
+
+    /// Read GPT at LBA 1, empty if it is invalid
+    fn read_gpt(&self) -> Vec<PartitionInfo> {
+        let mut header = [0u8; SECTOR_SIZE];
+        if !self.read_sectors(1, &mut header) || &header[0..8] != b"EFI PART" {
+            return Vec::new();
+        }
+        let header_size = read_u32(&header[12..16]) as usize;
+        if header_size < 92 || header_size > SECTOR_SIZE {
+            return Vec::new();
+        }
+        let header_crc = read_u32(&header[16..20]);
+        header[16..20].copy_from_slice(&[0; 4]);
+        if crc32(&header[..header_size]) != header_crc {
+            warn!("GPT header checksum mismatch");
+            return Vec::new();
+        }
+        let entries_lba = match to_usize(read_u64(&header[72..80])) {
+            Some(lba) => lba,
+            None => return Vec::new(),
+        };
+        let num_entries = read_u32(&header[80..84]) as usize;
+        let entry_size = read_u32(&header[84..88]) as usize;
+        let entries_crc = read_u32(&header[88..92]);
+        if entry_size < 128 || entry_size > GPT_MAX_ENTRY_SIZE || num_entries > GPT_MAX_ENTRIES {
+            return Vec::new();
+        }
+        let len = match num_entries.checked_mul(entry_size) {
+            Some(len) => len,
+            None => return Vec::new(),
+        };
+        let mut entries = vec![0u8; (len + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE];
+        if !self.read_sectors(entries_lba, &mut entries) || crc32(&entries[..len]) != entries_crc {
+            warn!("failed to read GPT entries or checksum mismatch");
+            return Vec::new();
+        }
+        let mut parts = Vec::new();
+        for (i, entry) in entries[..len].chunks(entry_size).enumerate() {
+            let mut type_guid = [0u8; 16];
+            type_guid.copy_from_slice(&entry[0..16]);
+            if type_guid == [0; 16] {
+                continue;
+            }
+            let mut guid = [0u8; 16];
+            guid.copy_from_slice(&entry[16..32]);
+            let first_lba = read_u64(&entry[32..40]);
+            let last_lba = read_u64(&entry[40..48]);
+            if last_lba < first_lba {
+                continue;
+            }
+            // LBAs are 64-bit, they may not fit in usize on 32-bit targets
+            let sectors = to_usize(last_lba - first_lba).and_then(|n| n.checked_add(1));
+            let (start_lba, sectors) = match (to_usize(first_lba), sectors) {
+                (Some(start_lba), Some(sectors)) => (start_lba, sectors),
+                _ => {
+                    warn!("GPT entry {} is beyond the addressable range, ignored", i + 1);
+                    continue;
+                }
+            };
+            parts.push(PartitionInfo {
+                index: i + 1,
+                kind: PartitionKind::Gpt { type_guid: Guid(type_guid), guid: Guid(guid) },
+                start_lba,
+                sectors,
+            });
+        }
+        parts
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    read_u32(&bytes[0..4]) as u64 | (read_u32(&bytes[4..8]) as u64) << 32
+}
+
+/// `x` as usize, None if it is truncated
+fn to_usize(x: u64) -> Option<usize> {
+    if x > usize::max_value() as u64 {
+        None
+    } else {
+        Some(x as usize)
+    }
+}
+
+/// CRC-32 (IEEE 802.3) used by GPT
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+impl Device for Partition {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        if offset >= self.size {
+            return Some(0);
+        }
+        let len = buf.len().min(self.size - offset);
+        let offset = self.offset.checked_add(offset)?;
+        self.device.lock().read_at(offset, &mut buf[..len])
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        if offset >= self.size {
+            return Some(0);
+        }
+        let len = buf.len().min(self.size - offset);
+        let offset = self.offset.checked_add(offset)?;
+        self.device.lock().write_at(offset, &buf[..len])
+    }
+}
@@ -0,0 +1,215 @@
+//! Process file system, presenting kernel state as files generated on read
+
+use simple_filesystem::*;
+use alloc::{sync::Arc, string::String};
+use core::any::Any;
+use core::fmt::Write;
+use crate::memory;
+use super::{cache, stats, ROOT_INODE};
+
+/// Files in the root directory, with functions generating their contents
+const FILES: [(&str, fn() -> String); 5] = [
+    ("mounts", mounts),
+    ("statfs", statfs),
+    ("bcache", bcache),
+    ("iostats", iostats),
+    ("meminfo", meminfo),
+];
+
+fn mounts() -> String {
+    let root_fs = ROOT_INODE.fs();
+    let mut s = String::new();
+    for (fs_type, fs) in super::mounts() {
+        let path = if Arc::ptr_eq(&fs, &root_fs) { "/" } else { "none" };
+        writeln!(s, "{} {}", path, fs_type).unwrap();
+    }
+    s.push_str("/dev devfs\n/proc procfs\n");
+    s
+}
+
+fn statfs() -> String {
+    let root_fs = ROOT_INODE.fs();
+    let mut s = String::new();
+    for (fs_type, fs, usage) in super::mount_usages() {
+        let path = if Arc::ptr_eq(&fs, &root_fs) { "/" } else { "none" };
+        let name_max = fs.info().max_file_name_length;
+        write!(s, "{} {} name_max={}", path, fs_type, name_max).unwrap();
+        match usage {
+            Some(Ok(usage)) => writeln!(s, " bsize={} blocks={} bfree={}",
+                                        usage.block_size, usage.blocks, usage.free_blocks).unwrap(),
+            Some(Err(e)) => writeln!(s, " error={:?}", e).unwrap(),
+            None => writeln!(s).unwrap(),
+        }
+    }
+    s
+}
+
+fn bcache() -> String {
+    let mut s = String::new();
+    for (i, stats) in cache::all_stats().iter().enumerate() {
+        writeln!(s, "cache{}: hits={} misses={} evictions={} writebacks={} readaheads={}", i,
+                 stats.hits, stats.misses, stats.evictions, stats.writebacks, stats.readaheads).unwrap();
+    }
+    s
+}
+
+fn iostats() -> String {
+    let mut s = String::new();
+    for stats in stats::all_stats() {
+        write!(s, "{}", stats).unwrap();
+    }
+    s
+}
+
+fn meminfo() -> String {
+    let stats = memory::heap_stats();
+    format!("heap_total={}\nheap_used={}\nheap_free={}\nheap_peak={}\nallocs={}\nfrees={}\n",
+            stats.total, stats.used, stats.total.saturating_sub(stats.used), stats.peak,
+            stats.alloc_count, stats.dealloc_count)
+}
+
+/// The process file system. All instances show the same files.
+pub struct ProcFileSystem;
+
+impl ProcFileSystem {
+    pub fn new() -> Arc<Self> {
+        Arc::new(ProcFileSystem)
+    }
+}
+
+impl FileSystem for ProcFileSystem {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        Arc::new(ProcRoot)
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: 255 };
+        &INFO
+    }
+}
+
+/// The root directory of procfs
+struct ProcRoot;
+
+impl INode for ProcRoot {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+    fn info(&self) -> Result<FileInfo> {
+        Ok(FileInfo {
+            size: FILES.len() + 2,
+            mode: 0o555,
+            type_: FileType::Dir,
+            blocks: 0,
+            nlinks: 2,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, _len: usize) -> Result<()> {
+        Err(FsError::IsDir)
+    }
+    fn create(&self, _name: &str, _type_: FileType) -> Result<Arc<INode>> {
+        Err(FsError::NotSupported)
+    }
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn link(&self, _name: &str, _other: &Arc<INode>) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn rename(&self, _old_name: &str, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn move_(&self, _old_name: &str, _target: &Arc<INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        if name == "." || name == ".." {
+            return Ok(Arc::new(ProcRoot));
+        }
+        let &(_, generate) = FILES.iter().find(|&&(n, _)| n == name).ok_or(FsError::EntryNotFound)?;
+        Ok(Arc::new(ProcFile(generate)))
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            _ => FILES.get(id - 2).map(|&(name, _)| String::from(name)).ok_or(FsError::EntryNotFound),
+        }
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        ProcFileSystem::new()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
+
+/// A read only file whose content is generated on each read
+struct ProcFile(fn() -> String);
+
+impl INode for ProcFile {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let content = (self.0)();
+        let content = content.as_bytes();
+        if offset >= content.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Ok(len)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+    fn info(&self) -> Result<FileInfo> {
+        // the size is unknown until generated, like Linux procfs
+        Ok(FileInfo {
+            size: 0,
+            mode: 0o444,
+            type_: FileType::File,
+            blocks: 0,
+            nlinks: 1,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, _len: usize) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn create(&self, _name: &str, _type_: FileType) -> Result<Arc<INode>> {
+        Err(FsError::NotDir)
+    }
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotDir)
+    }
+    fn link(&self, _name: &str, _other: &Arc<INode>) -> Result<()> {
+        Err(FsError::NotDir)
+    }
+    fn rename(&self, _old_name: &str, _new_name: &str) -> Result<()> {
+        Err(FsError::NotDir)
+    }
+    fn move_(&self, _old_name: &str, _target: &Arc<INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::NotDir)
+    }
+    fn find(&self, _name: &str) -> Result<Arc<INode>> {
+        Err(FsError::NotDir)
+    }
+    fn get_entry(&self, _id: usize) -> Result<String> {
+        Err(FsError::NotDir)
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        ProcFileSystem::new()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
@@ -0,0 +1,191 @@
+//! Devices composed of multiple devices (RAID-0 and RAID-1)
+
+use simple_filesystem::Device;
+use alloc::{boxed::Box, vec::Vec};
+use log::*;
+
+/// RAID-0: data is split into stripes, distributed to members in turn
+pub struct StripedDevice {
+    members: Vec<Box<Device>>,
+    stripe_size: usize,
+}
+
+impl StripedDevice {
+    pub fn new(members: Vec<Box<Device>>, stripe_size: usize) -> Self {
+        assert!(!members.is_empty());
+        assert!(stripe_size > 0);
+        StripedDevice { members, stripe_size }
+    }
+
+    /// Map `offset` to (member index, offset in member, bytes left in the stripe)
+    fn locate(&self, offset: usize) -> (usize, usize, usize) {
+        let n = self.members.len();
+        let stripe = offset / self.stripe_size;
+        let in_stripe = offset % self.stripe_size;
+        (stripe % n, stripe / n * self.stripe_size + in_stripe, self.stripe_size - in_stripe)
+    }
+}
+
+impl Device for StripedDevice {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (i, member_offset, left) = self.locate(offset + done);
+            let len = left.min(buf.len() - done);
+            let read = self.members[i].read_at(member_offset, &mut buf[done..done + len])?;
+            done += read;
+            if read < len {
+                break;
+            }
+        }
+        Some(done)
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let (i, member_offset, left) = self.locate(offset + done);
+            let len = left.min(buf.len() - done);
+            let written = self.members[i].write_at(member_offset, &buf[done..done + len])?;
+            done += written;
+            if written < len {
+                break;
+            }
+        }
+        Some(done)
+    }
+}
+
+/// RAID-1: every member holds a full copy of data.
+///
+/// Reads are balanced among members in turn. A member is marked failed on error
+/// and not used any more, the device keeps working until all members fail.
+pub struct MirroredDevice {
+    members: Vec<Box<Device>>,
+    failed: Vec<bool>,
+    /// Member to read from next
+    next: usize,
+}
+
+impl MirroredDevice {
+    pub fn new(members: Vec<Box<Device>>) -> Self {
+        assert!(!members.is_empty());
+        let n = members.len();
+        MirroredDevice { members, failed: vec![false; n], next: 0 }
+    }
+
+    /// Whether some member has failed
+    pub fn degraded(&self) -> bool {
+        self.failed.iter().any(|&f| f)
+    }
+
+    fn fail(&mut self, i: usize) {
+        warn!("mirror member {} failed", i);
+        self.failed[i] = true;
+    }
+}
+
+impl Device for MirroredDevice {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let n = self.members.len();
+        for k in 0..n {
+            let i = (self.next + k) % n;
+            if self.failed[i] {
+                continue;
+            }
+            match self.members[i].read_at(offset, buf) {
+                Some(len) => {
+                    self.next = (i + 1) % n;
+                    return Some(len);
+                }
+                None => self.fail(i),
+            }
+        }
+        None
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        let mut result: Option<usize> = None;
+        for i in 0..self.members.len() {
+            if self.failed[i] {
+                continue;
+            }
+            match self.members[i].write_at(offset, buf) {
+                Some(len) => result = Some(result.map_or(len, |r| r.min(len))),
+                None => self.fail(i),
+            }
+        }
+        result
+    }
+}
+
+pub mod test {
+    use super::*;
+    use crate::fs::MemDevice;
+
+    /// Fails every request
+    struct Broken;
+
+    impl Device for Broken {
+        fn read_at(&mut self, _offset: usize, _buf: &mut [u8]) -> Option<usize> {
+            None
+        }
+        fn write_at(&mut self, _offset: usize, _buf: &[u8]) -> Option<usize> {
+            None
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn striped() {
+        let members: Vec<Box<Device>> = vec![Box::new(MemDevice::new(4096)), Box::new(MemDevice::new(4096))];
+        let mut raid = StripedDevice::new(members, 512);
+        let data = pattern(8192);
+        assert_eq!(raid.write_at(0, &data), Some(8192));
+        let mut buf = vec![0; 1000];
+        assert_eq!(raid.read_at(300, &mut buf), Some(1000));
+        assert_eq!(buf[..], data[300..1300]);
+        // stripe 1 is the first stripe of member 1
+        let mut member = vec![0; 512];
+        assert_eq!(raid.members[1].read_at(0, &mut member), Some(512));
+        assert_eq!(member[..], data[512..1024]);
+        // short at the end of members
+        assert_eq!(raid.read_at(8000, &mut buf), Some(192));
+    }
+
+    fn mirrored() {
+        let members: Vec<Box<Device>> = vec![Box::new(MemDevice::new(4096)), Box::new(MemDevice::new(4096))];
+        let mut raid = MirroredDevice::new(members);
+        let data = pattern(4096);
+        assert_eq!(raid.write_at(0, &data), Some(4096));
+        for member in raid.members.iter_mut() {
+            let mut buf = vec![0; 4096];
+            assert_eq!(member.read_at(0, &mut buf), Some(4096));
+            assert_eq!(buf, data);
+        }
+        assert!(!raid.degraded());
+    }
+
+    fn mirrored_degraded() {
+        let members: Vec<Box<Device>> = vec![Box::new(Broken), Box::new(MemDevice::new(4096))];
+        let mut raid = MirroredDevice::new(members);
+        let data = pattern(100);
+        assert_eq!(raid.write_at(10, &data), Some(100));
+        assert!(raid.degraded());
+        for _ in 0..2 {
+            let mut buf = vec![0; 100];
+            assert_eq!(raid.read_at(10, &mut buf), Some(100));
+            assert_eq!(buf, data);
+        }
+        let members: Vec<Box<Device>> = vec![Box::new(Broken), Box::new(Broken)];
+        let mut raid = MirroredDevice::new(members);
+        assert_eq!(raid.write_at(0, &data), None);
+        assert_eq!(raid.read_at(0, &mut vec![0; 100]), None);
+    }
+
+    pub fn test_all() {
+        striped();
+        mirrored();
+        mirrored_degraded();
+    }
+}
@@ -0,0 +1,339 @@
+//! In-memory file system
+
+use simple_filesystem::*;
+use alloc::{collections::BTreeMap, sync::{Arc, Weak}, string::String, vec::Vec};
+use core::any::Any;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// Max size of a file, which is kept in the kernel heap
+const MAX_FILE_SIZE: usize = 64 * 1024 * 1024;
+
+/// A file system keeping everything in memory, empty when created.
+///
+/// Inodes keep the file system alive and it keeps the root directory,
+/// so like a mounted tmpfs it is never freed.
+pub struct RamFileSystem {
+    root: Mutex<Option<Arc<RamINode>>>,
+}
+
+/// A file or directory in a `RamFileSystem`
+pub struct RamINode(Mutex<RamINodeInner>);
+
+struct RamINodeInner {
+    content: Content,
+    /// Number of hard links, 0 if it has been removed
+    nlinks: usize,
+    /// The parent of a directory, itself for the root
+    parent: Weak<RamINode>,
+    this: Weak<RamINode>,
+    fs: Arc<RamFileSystem>,
+}
+
+enum Content {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Arc<RamINode>>),
+}
+
+impl RamFileSystem {
+    pub fn new() -> Arc<Self> {
+        let fs = Arc::new(RamFileSystem { root: Mutex::new(None) });
+        let root = RamINode::new(Content::Dir(BTreeMap::new()), fs.clone());
+        {
+            let mut inner = root.0.lock();
+            inner.parent = Arc::downgrade(&root);
+            inner.nlinks = 2;
+        }
+        *fs.root.lock() = Some(root);
+        fs
+    }
+}
+
+impl FileSystem for RamFileSystem {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        self.root.lock().clone().unwrap()
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: 255 };
+        &INFO
+    }
+}
+
+impl RamINode {
+    fn new(content: Content, fs: Arc<RamFileSystem>) -> Arc<Self> {
+        let inode = Arc::new(RamINode(Mutex::new(RamINodeInner {
+            content,
+            nlinks: 0,
+            parent: Weak::new(),
+            this: Weak::new(),
+            fs,
+        })));
+        inode.0.lock().this = Arc::downgrade(&inode);
+        inode
+    }
+
+    fn is_dir(&self) -> bool {
+        match self.0.lock().content {
+            Content::Dir(_) => true,
+            Content::File(_) => false,
+        }
+    }
+}
+
+/// Whether `a` is `b` or one of its ancestors
+fn is_ancestor(a: &Arc<RamINode>, b: &Arc<RamINode>) -> bool {
+    let mut node = b.clone();
+    loop {
+        if Arc::ptr_eq(a, &node) {
+            return true;
+        }
+        let parent = match node.0.lock().parent.upgrade() {
+            Some(parent) => parent,
+            None => return false,
+        };
+        if Arc::ptr_eq(&parent, &node) {
+            // reached the root
+            return false;
+        }
+        node = parent;
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 255 || name.contains('/') || name == "." || name == ".." {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(())
+}
+
+impl RamINodeInner {
+    fn file(&mut self) -> Result<&mut Vec<u8>> {
+        match self.content {
+            Content::File(ref mut data) => Ok(data),
+            Content::Dir(_) => Err(FsError::IsDir),
+        }
+    }
+    /// Children of a directory which has not been removed
+    fn dir(&mut self) -> Result<&mut BTreeMap<String, Arc<RamINode>>> {
+        match self.content {
+            Content::Dir(_) if self.nlinks == 0 => Err(FsError::DirRemoved),
+            Content::Dir(ref mut children) => Ok(children),
+            Content::File(_) => Err(FsError::NotDir),
+        }
+    }
+}
+
+impl INode for RamINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut inner = self.0.lock();
+        let data = inner.file()?;
+        if offset >= data.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        Ok(len)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut inner = self.0.lock();
+        let data = inner.file()?;
+        let end = match offset.checked_add(buf.len()) {
+            Some(end) if end <= MAX_FILE_SIZE => end,
+            _ => return Err(FsError::NoDeviceSpace),
+        };
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn info(&self) -> Result<FileInfo> {
+        let inner = self.0.lock();
+        let (size, type_) = match inner.content {
+            Content::File(ref data) => (data.len(), FileType::File),
+            Content::Dir(ref children) => (children.len() + 2, FileType::Dir),
+        };
+        Ok(FileInfo {
+            size,
+            // use default permissions
+            mode: 0,
+            type_,
+            blocks: (size + 511) / 512,
+            nlinks: inner.nlinks,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, len: usize) -> Result<()> {
+        if len > MAX_FILE_SIZE {
+            return Err(FsError::NoDeviceSpace);
+        }
+        self.0.lock().file()?.resize(len, 0);
+        Ok(())
+    }
+    fn create(&self, name: &str, type_: FileType) -> Result<Arc<INode>> {
+        check_name(name)?;
+        let mut inner = self.0.lock();
+        let this = inner.this.clone();
+        let fs = inner.fs.clone();
+        let children = inner.dir()?;
+        if children.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        let inode = match type_ {
+            FileType::File => {
+                let inode = RamINode::new(Content::File(Vec::new()), fs);
+                inode.0.lock().nlinks = 1;
+                inode
+            }
+            FileType::Dir => {
+                let inode = RamINode::new(Content::Dir(BTreeMap::new()), fs);
+                {
+                    let mut child = inode.0.lock();
+                    child.nlinks = 2;
+                    child.parent = this;
+                }
+                inode
+            }
+        };
+        children.insert(String::from(name), inode.clone());
+        if type_ == FileType::Dir {
+            // `..` of the new directory
+            inner.nlinks += 1;
+        }
+        Ok(inode)
+    }
+    fn unlink(&self, name: &str) -> Result<()> {
+        check_name(name)?;
+        let mut inner = self.0.lock();
+        let inode = inner.dir()?.get(name).ok_or(FsError::EntryNotFound)?.clone();
+        {
+            let mut child = inode.0.lock();
+            let is_dir = match child.content {
+                Content::Dir(ref children) => {
+                    if !children.is_empty() {
+                        return Err(FsError::DirNotEmpty);
+                    }
+                    true
+                }
+                Content::File(_) => false,
+            };
+            child.nlinks = if is_dir { 0 } else { child.nlinks - 1 };
+            if is_dir {
+                inner.nlinks -= 1;
+            }
+        }
+        inner.dir()?.remove(name);
+        Ok(())
+    }
+    fn link(&self, name: &str, other: &Arc<INode>) -> Result<()> {
+        check_name(name)?;
+        let other = other.as_any_ref().downcast_ref::<RamINode>().ok_or(FsError::NotSameFs)?;
+        if other as *const RamINode == self as *const RamINode {
+            return Err(FsError::IsDir);
+        }
+        let mut inner = self.0.lock();
+        let other = {
+            let other = other.0.lock();
+            if !Arc::ptr_eq(&other.fs, &inner.fs) {
+                return Err(FsError::NotSameFs);
+            }
+            if let Content::Dir(_) = other.content {
+                return Err(FsError::IsDir);
+            }
+            other.this.upgrade().unwrap()
+        };
+        let children = inner.dir()?;
+        if children.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        other.0.lock().nlinks += 1;
+        children.insert(String::from(name), other);
+        Ok(())
+    }
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        check_name(old_name)?;
+        check_name(new_name)?;
+        let mut inner = self.0.lock();
+        let children = inner.dir()?;
+        if !children.contains_key(old_name) {
+            return Err(FsError::EntryNotFound);
+        }
+        if children.contains_key(new_name) {
+            return Err(FsError::EntryExist);
+        }
+        let inode = children.remove(old_name).unwrap();
+        children.insert(String::from(new_name), inode);
+        Ok(())
+    }
+    fn move_(&self, old_name: &str, target: &Arc<INode>, new_name: &str) -> Result<()> {
+        check_name(old_name)?;
+        check_name(new_name)?;
+        let target = target.as_any_ref().downcast_ref::<RamINode>().ok_or(FsError::NotSameFs)?;
+        if target as *const RamINode == self as *const RamINode {
+            return self.rename(old_name, new_name);
+        }
+        let (this, target) = {
+            let inner = self.0.lock();
+            let target = target.0.lock();
+            if !Arc::ptr_eq(&target.fs, &inner.fs) {
+                return Err(FsError::NotSameFs);
+            }
+            (inner.this.upgrade().unwrap(), target.this.upgrade().unwrap())
+        };
+        let inode = self.0.lock().dir()?.get(old_name).ok_or(FsError::EntryNotFound)?.clone();
+        let is_dir = inode.is_dir();
+        if is_dir && is_ancestor(&inode, &target) {
+            // can not move a directory into itself
+            return Err(FsError::InvalidParam);
+        }
+        // lock the two directories in a fixed order
+        let (mut src, mut dst) = if (&*this as *const RamINode) < (&*target as *const RamINode) {
+            let src = this.0.lock();
+            (src, target.0.lock())
+        } else {
+            let dst = target.0.lock();
+            (this.0.lock(), dst)
+        };
+        if dst.dir()?.contains_key(new_name) {
+            return Err(FsError::EntryExist);
+        }
+        src.dir()?.remove(old_name);
+        dst.dir()?.insert(String::from(new_name), inode.clone());
+        if is_dir {
+            src.nlinks -= 1;
+            dst.nlinks += 1;
+            inode.0.lock().parent = Arc::downgrade(&target);
+        }
+        Ok(())
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        let mut inner = self.0.lock();
+        inner.dir()?;
+        let inode = match name {
+            "." => inner.this.upgrade(),
+            ".." => inner.parent.upgrade(),
+            _ => inner.dir()?.get(name).cloned(),
+        };
+        let inode: Arc<INode> = inode.ok_or(FsError::EntryNotFound)?;
+        Ok(inode)
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        let mut inner = self.0.lock();
+        let children = inner.dir()?;
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            _ => children.keys().nth(id - 2).cloned().ok_or(FsError::EntryNotFound),
+        }
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        self.0.lock().fs.clone()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
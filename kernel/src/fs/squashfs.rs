@@ -0,0 +1,500 @@
+//! SquashFS 4.0 file system, read only, compressed with gzip
+//!
+//! Images are made on the host by `mksquashfs <dir> <image> -comp gzip`.
+
+use simple_filesystem::*;
+use alloc::{boxed::Box, collections::BTreeMap, sync::{Arc, Weak}, string::String, vec::Vec};
+use core::any::Any;
+use log::*;
+use crate::sync::ThreadLock as Mutex;
+use super::{inflate, device_error, FsUsage};
+
+const MAGIC: u32 = 0x7371_7368;
+const COMPRESSION_GZIP: u16 = 1;
+/// Max size of a metadata block after decompression
+const METADATA_SIZE: usize = 8192;
+/// Set in the header of a metadata block stored uncompressed
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+/// Set in the size of a data block stored uncompressed
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+/// Size of an entry in the fragment table
+const FRAGMENT_ENTRY_SIZE: usize = 16;
+/// Number of decompressed metadata blocks kept
+const METADATA_CACHE_SIZE: usize = 32;
+/// Max number of blocks of a file, which bounds the block list read at once
+const MAX_FILE_BLOCKS: u64 = 1 << 20;
+
+const BASIC_DIR: u16 = 1;
+const BASIC_FILE: u16 = 2;
+const BASIC_SYMLINK: u16 = 3;
+const EXT_DIR: u16 = 8;
+const EXT_FILE: u16 = 9;
+const EXT_SYMLINK: u16 = 10;
+
+/// A SquashFS file system
+pub struct SquashFileSystem {
+    inner: Mutex<SquashInner>,
+    root: Inode,
+    /// Read only, so there are no free blocks
+    usage: FsUsage,
+    self_ref: Mutex<Weak<SquashFileSystem>>,
+}
+
+struct SquashInner {
+    device: Box<Device>,
+    block_size: usize,
+    inode_table: u64,
+    dir_table: u64,
+    fragment_table: u64,
+    fragments: u32,
+    /// Decompressed metadata blocks with the position of the next block, by position
+    metadata: BTreeMap<u64, (Vec<u8>, u64)>,
+    /// The last decompressed data block and its position
+    data: Option<(u64, Vec<u8>)>,
+}
+
+/// An inode read from the inode table
+#[derive(Clone)]
+struct Inode {
+    mode: u16,
+    nlink: u32,
+    kind: Kind,
+}
+
+#[derive(Clone)]
+enum Kind {
+    Dir(DirData),
+    File(FileData),
+    Symlink(Vec<u8>),
+    /// Devices, FIFOs and sockets
+    Other,
+}
+
+/// Where the listing of a directory is in the directory table
+#[derive(Clone)]
+struct DirData {
+    start: u64,
+    offset: usize,
+    size: usize,
+}
+
+#[derive(Clone)]
+struct FileData {
+    size: u64,
+    /// (position, size field) of each full block, a size of 0 is a hole
+    blocks: Vec<(u64, u32)>,
+    /// (fragment index, offset in the fragment) of the tail
+    fragment: Option<(u32, usize)>,
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    read_u16(&b[0..2]) as u32 | (read_u16(&b[2..4]) as u32) << 16
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    read_u32(&b[0..4]) as u64 | (read_u32(&b[4..8]) as u64) << 32
+}
+
+impl SquashFileSystem {
+    /// Open a SquashFS file system on `device`
+    pub fn open(mut device: Box<Device>) -> Result<Arc<Self>> {
+        let mut sb = [0u8; 96];
+        if device.read_at(0, &mut sb) != Some(96) || read_u32(&sb[0..4]) != MAGIC {
+            return Err(FsError::WrongFs);
+        }
+        let block_size = read_u32(&sb[12..16]) as usize;
+        let block_log = read_u16(&sb[22..24]);
+        if read_u16(&sb[28..30]) != 4 || block_size < 4096 || block_size > 1 << 20
+            || block_size != 1 << block_log {
+            return Err(FsError::WrongFs);
+        }
+        let compression = read_u16(&sb[20..22]);
+        if compression != COMPRESSION_GZIP {
+            warn!("squashfs: unsupported compression {}", compression);
+            return Err(FsError::NotSupported);
+        }
+        let mut inner = SquashInner {
+            device,
+            block_size,
+            inode_table: read_u64(&sb[64..72]),
+            dir_table: read_u64(&sb[72..80]),
+            fragment_table: read_u64(&sb[80..88]),
+            fragments: read_u32(&sb[16..20]),
+            metadata: BTreeMap::new(),
+            data: None,
+        };
+        let root = inner.inode(read_u64(&sb[32..40]))?;
+        if let Kind::Dir(_) = root.kind {} else {
+            return Err(FsError::WrongFs);
+        }
+        // bytes_used, the size of the image
+        let blocks = ((read_u64(&sb[40..48]) + block_size as u64 - 1) / block_size as u64) as usize;
+        let fs = Arc::new(SquashFileSystem {
+            inner: Mutex::new(inner),
+            root,
+            usage: FsUsage { block_size, blocks, free_blocks: 0 },
+            self_ref: Mutex::new(Weak::new()),
+        });
+        *fs.self_ref.lock() = Arc::downgrade(&fs);
+        Ok(fs)
+    }
+
+    pub fn usage(&self) -> Result<FsUsage> {
+        Ok(self.usage)
+    }
+
+    fn arc(&self) -> Arc<SquashFileSystem> {
+        self.self_ref.lock().upgrade().unwrap()
+    }
+}
+
+impl SquashInner {
+    fn read(&mut self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        match self.device.read_at(pos as usize, buf) {
+            Some(len) if len == buf.len() => Ok(()),
+            _ => Err(device_error()),
+        }
+    }
+
+    fn decompress(&self, data: Vec<u8>, compressed: bool, limit: usize) -> Result<Vec<u8>> {
+        if !compressed {
+            return Ok(data);
+        }
+        inflate::zlib_decompress(&data, limit).map_err(|e| {
+            warn!("squashfs: {}", e);
+            FsError::InvalidParam
+        })
+    }
+
+    /// Decompress the metadata block at `pos` into the cache
+    fn load_metadata(&mut self, pos: u64) -> Result<()> {
+        if self.metadata.contains_key(&pos) {
+            return Ok(());
+        }
+        let mut header = [0u8; 2];
+        self.read(pos, &mut header)?;
+        let header = read_u16(&header);
+        let mut raw = vec![0u8; (header & !METADATA_UNCOMPRESSED) as usize];
+        self.read(pos + 2, &mut raw)?;
+        let size = raw.len() as u64;
+        let data = self.decompress(raw, header & METADATA_UNCOMPRESSED == 0, METADATA_SIZE)?;
+        if self.metadata.len() >= METADATA_CACHE_SIZE {
+            self.metadata.clear();
+        }
+        self.metadata.insert(pos, (data, pos + 2 + size));
+        Ok(())
+    }
+
+    /// Read metadata at (block position, offset), which may continue in the next blocks.
+    /// The cursor is moved past the data read.
+    fn read_metadata(&mut self, cursor: &mut (u64, usize), buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            self.load_metadata(cursor.0)?;
+            let (ref data, next) = self.metadata[&cursor.0];
+            if data.is_empty() {
+                return Err(FsError::InvalidParam);
+            }
+            if cursor.1 >= data.len() {
+                *cursor = (next, cursor.1 - data.len());
+                continue;
+            }
+            let n = (data.len() - cursor.1).min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&data[cursor.1..cursor.1 + n]);
+            cursor.1 += n;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn read_metadata_vec(&mut self, cursor: &mut (u64, usize), len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.read_metadata(cursor, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read the inode referenced by `inode_ref`:
+    /// the position of its metadata block in the inode table, and the offset in it
+    fn inode(&mut self, inode_ref: u64) -> Result<Inode> {
+        let mut cursor = (self.inode_table + (inode_ref >> 16), (inode_ref & 0xffff) as usize);
+        let header = self.read_metadata_vec(&mut cursor, 16)?;
+        let type_ = read_u16(&header[0..2]);
+        let mode = read_u16(&header[2..4]);
+        let (nlink, kind) = match type_ {
+            BASIC_DIR => {
+                let b = self.read_metadata_vec(&mut cursor, 16)?;
+                let dir = DirData {
+                    start: read_u32(&b[0..4]) as u64,
+                    offset: read_u16(&b[10..12]) as usize,
+                    size: read_u16(&b[8..10]) as usize,
+                };
+                (read_u32(&b[4..8]), Kind::Dir(dir))
+            }
+            EXT_DIR => {
+                let b = self.read_metadata_vec(&mut cursor, 24)?;
+                let dir = DirData {
+                    start: read_u32(&b[8..12]) as u64,
+                    offset: read_u16(&b[18..20]) as usize,
+                    size: read_u32(&b[4..8]) as usize,
+                };
+                (read_u32(&b[0..4]), Kind::Dir(dir))
+            }
+            BASIC_FILE | EXT_FILE => {
+                let (start, fragment, frag_offset, size, nlink) = if type_ == BASIC_FILE {
+                    let b = self.read_metadata_vec(&mut cursor, 16)?;
+                    (read_u32(&b[0..4]) as u64, read_u32(&b[4..8]), read_u32(&b[8..12]), read_u32(&b[12..16]) as u64, 1)
+                } else {
+                    let b = self.read_metadata_vec(&mut cursor, 40)?;
+                    (read_u64(&b[0..8]), read_u32(&b[28..32]), read_u32(&b[32..36]), read_u64(&b[8..16]), read_u32(&b[24..28]))
+                };
+                let bs = self.block_size as u64;
+                let count = if fragment == NO_FRAGMENT && size % bs != 0 { size / bs + 1 } else { size / bs };
+                if count > MAX_FILE_BLOCKS {
+                    warn!("squashfs: file of {} blocks is too large", count);
+                    return Err(FsError::InvalidParam);
+                }
+                let sizes = self.read_metadata_vec(&mut cursor, count as usize * 4)?;
+                let mut pos = start;
+                let mut blocks = Vec::new();
+                for s in sizes.chunks(4).map(read_u32) {
+                    blocks.push((pos, s));
+                    pos += (s & !DATA_UNCOMPRESSED) as u64;
+                }
+                let fragment = match fragment {
+                    NO_FRAGMENT => None,
+                    index => Some((index, frag_offset as usize)),
+                };
+                (nlink, Kind::File(FileData { size, blocks, fragment }))
+            }
+            BASIC_SYMLINK | EXT_SYMLINK => {
+                let b = self.read_metadata_vec(&mut cursor, 8)?;
+                let target = self.read_metadata_vec(&mut cursor, read_u32(&b[4..8]) as usize)?;
+                (read_u32(&b[0..4]), Kind::Symlink(target))
+            }
+            4..=7 | 11..=14 => {
+                let b = self.read_metadata_vec(&mut cursor, 4)?;
+                (read_u32(&b[0..4]), Kind::Other)
+            }
+            _ => {
+                warn!("squashfs: unknown inode type {}", type_);
+                return Err(FsError::InvalidParam);
+            }
+        };
+        Ok(Inode { mode, nlink, kind })
+    }
+
+    /// (name, inode reference) of all entries in a directory
+    fn dir_entries(&mut self, dir: &DirData) -> Result<Vec<(String, u64)>> {
+        let mut cursor = (self.dir_table + dir.start, dir.offset);
+        // the size includes `.` and `..` which are not stored
+        let mut remaining = dir.size.saturating_sub(3);
+        let mut entries = Vec::new();
+        while remaining >= 12 {
+            let header = self.read_metadata_vec(&mut cursor, 12)?;
+            let count = read_u32(&header[0..4]) as usize + 1;
+            let start = read_u32(&header[4..8]) as u64;
+            remaining -= 12;
+            for _ in 0..count {
+                let b = self.read_metadata_vec(&mut cursor, 8)?;
+                let name_len = read_u16(&b[6..8]) as usize + 1;
+                let name = self.read_metadata_vec(&mut cursor, name_len)?;
+                entries.push((String::from_utf8_lossy(&name).into_owned(), start << 16 | read_u16(&b[0..2]) as u64));
+                remaining = remaining.saturating_sub(8 + name_len);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// (position, size field) of a fragment block
+    fn fragment(&mut self, index: u32) -> Result<(u64, u32)> {
+        if index >= self.fragments {
+            return Err(FsError::InvalidParam);
+        }
+        // the table is in metadata blocks, listed at `fragment_table`
+        let per_block = (METADATA_SIZE / FRAGMENT_ENTRY_SIZE) as u32;
+        let mut ptr = [0u8; 8];
+        let pos = self.fragment_table + (index / per_block) as u64 * 8;
+        self.read(pos, &mut ptr)?;
+        let mut cursor = (read_u64(&ptr), (index % per_block) as usize * FRAGMENT_ENTRY_SIZE);
+        let entry = self.read_metadata_vec(&mut cursor, FRAGMENT_ENTRY_SIZE)?;
+        Ok((read_u64(&entry[0..8]), read_u32(&entry[8..12])))
+    }
+
+    /// Decompress the data block at `pos`
+    fn data_block(&mut self, pos: u64, size: u32) -> Result<&[u8]> {
+        let cached = match self.data {
+            Some((p, _)) => p == pos,
+            None => false,
+        };
+        if !cached {
+            let mut raw = vec![0u8; (size & !DATA_UNCOMPRESSED) as usize];
+            self.read(pos, &mut raw)?;
+            let data = self.decompress(raw, size & DATA_UNCOMPRESSED == 0, self.block_size)?;
+            self.data = Some((pos, data));
+        }
+        Ok(&self.data.as_ref().unwrap().1)
+    }
+
+    fn read_file(&mut self, file: &FileData, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let size = file.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        let bs = self.block_size;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let n = (bs - pos % bs).min(len - done);
+            let target = &mut buf[done..done + n];
+            let (block, start) = match file.blocks.get(pos / bs) {
+                Some(&(_, 0)) => {
+                    // a hole
+                    for b in target.iter_mut() {
+                        *b = 0;
+                    }
+                    done += n;
+                    continue;
+                }
+                Some(&(block_pos, block_size)) => (self.data_block(block_pos, block_size)?, pos % bs),
+                None => {
+                    let (index, frag_offset) = file.fragment.ok_or(FsError::InvalidParam)?;
+                    let (frag_pos, frag_size) = self.fragment(index)?;
+                    (self.data_block(frag_pos, frag_size)?, frag_offset + pos % bs)
+                }
+            };
+            if start + n > block.len() {
+                return Err(FsError::InvalidParam);
+            }
+            target.copy_from_slice(&block[start..start + n]);
+            done += n;
+        }
+        Ok(len)
+    }
+}
+
+impl FileSystem for SquashFileSystem {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<INode> {
+        Arc::new(SquashINode { fs: self.arc(), inode: self.root.clone(), parent: None })
+    }
+    fn info(&self) -> &'static FsInfo {
+        static INFO: FsInfo = FsInfo { max_file_name_length: 256 };
+        &INFO
+    }
+}
+
+/// An inode in a SquashFS file system.
+///
+/// Symbolic links are presented as files containing the target path.
+pub struct SquashINode {
+    pub(super) fs: Arc<SquashFileSystem>,
+    inode: Inode,
+    /// The directory containing it, None for the root directory.
+    /// Directories only record the inode number of their parent, which can not be looked up.
+    parent: Option<Arc<SquashINode>>,
+}
+
+impl SquashINode {
+    fn this(&self) -> Arc<SquashINode> {
+        Arc::new(SquashINode { fs: self.fs.clone(), inode: self.inode.clone(), parent: self.parent.clone() })
+    }
+    fn entries(&self) -> Result<Vec<(String, u64)>> {
+        match self.inode.kind {
+            Kind::Dir(ref dir) => self.fs.inner.lock().dir_entries(dir),
+            _ => Err(FsError::NotDir),
+        }
+    }
+}
+
+impl INode for SquashINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        match self.inode.kind {
+            Kind::Dir(_) => Err(FsError::IsDir),
+            Kind::File(ref file) => self.fs.inner.lock().read_file(file, offset, buf),
+            Kind::Symlink(ref target) => {
+                if offset >= target.len() {
+                    return Ok(0);
+                }
+                let len = buf.len().min(target.len() - offset);
+                buf[..len].copy_from_slice(&target[offset..offset + len]);
+                Ok(len)
+            }
+            Kind::Other => Ok(0),
+        }
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+    fn info(&self) -> Result<FileInfo> {
+        let (size, blocks, type_) = match self.inode.kind {
+            Kind::Dir(ref dir) => (dir.size, 0, FileType::Dir),
+            Kind::File(ref file) => {
+                let stored: u64 = file.blocks.iter().map(|&(_, s)| (s & !DATA_UNCOMPRESSED) as u64).sum();
+                (file.size as usize, ((stored + 511) / 512) as usize, FileType::File)
+            }
+            Kind::Symlink(ref target) => (target.len(), 0, FileType::File),
+            Kind::Other => (0, 0, FileType::File),
+        };
+        Ok(FileInfo {
+            size,
+            mode: (self.inode.mode & 0o7777) as u32,
+            type_,
+            blocks,
+            nlinks: self.inode.nlink as usize,
+        })
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn resize(&self, _len: usize) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn create(&self, _name: &str, _type_: FileType) -> Result<Arc<INode>> {
+        Err(FsError::NotSupported)
+    }
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn link(&self, _name: &str, _other: &Arc<INode>) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn rename(&self, _old_name: &str, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn move_(&self, _old_name: &str, _target: &Arc<INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn find(&self, name: &str) -> Result<Arc<INode>> {
+        let entries = self.entries()?;
+        match name {
+            "." => return Ok(self.this()),
+            ".." => return Ok(self.parent.clone().unwrap_or_else(|| self.this())),
+            _ => {}
+        }
+        let &(_, inode_ref) = entries.iter().find(|(n, _)| n == name).ok_or(FsError::EntryNotFound)?;
+        let inode = self.fs.inner.lock().inode(inode_ref)?;
+        Ok(Arc::new(SquashINode { fs: self.fs.clone(), inode, parent: Some(self.this()) }))
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        let entries = self.entries()?;
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            _ => entries.into_iter().nth(id - 2).map(|(name, _)| name).ok_or(FsError::EntryNotFound),
+        }
+    }
+    fn fs(&self) -> Arc<FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &Any {
+        self
+    }
+}
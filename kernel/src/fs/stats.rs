@@ -0,0 +1,112 @@
+//! Per-device I/O statistics
+
+use simple_filesystem::Device;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::fmt;
+use lazy_static::lazy_static;
+use crate::arch::cpu;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// Number of buckets in latency histograms.
+/// Bucket `i` counts requests taking `[2^(i-1), 2^i)` cycles.
+const LATENCY_BUCKETS: usize = 32;
+
+/// A device wrapper recording statistics of requests passing through it
+pub struct StatsDevice {
+    device: Box<Device>,
+    stats: Arc<Mutex<IoStats>>,
+}
+
+#[derive(Clone)]
+pub struct IoStats {
+    pub name: &'static str,
+    pub reads: usize,
+    pub writes: usize,
+    pub read_bytes: usize,
+    pub write_bytes: usize,
+    pub errors: usize,
+    pub read_latency: [usize; LATENCY_BUCKETS],
+    pub write_latency: [usize; LATENCY_BUCKETS],
+}
+
+lazy_static! {
+    static ref DEVICE_STATS: Mutex<Vec<Arc<Mutex<IoStats>>>> = Mutex::new(Vec::new());
+}
+
+/// Get statistics of all devices wrapped by `StatsDevice`
+pub fn all_stats() -> Vec<IoStats> {
+    DEVICE_STATS.lock().iter().map(|s| s.lock().clone()).collect()
+}
+
+impl StatsDevice {
+    pub fn new(name: &'static str, device: Box<Device>) -> Self {
+        let stats = Arc::new(Mutex::new(IoStats {
+            name,
+            reads: 0,
+            writes: 0,
+            read_bytes: 0,
+            write_bytes: 0,
+            errors: 0,
+            read_latency: [0; LATENCY_BUCKETS],
+            write_latency: [0; LATENCY_BUCKETS],
+        }));
+        DEVICE_STATS.lock().push(stats.clone());
+        StatsDevice { device, stats }
+    }
+}
+
+fn latency_bucket(cycles: u64) -> usize {
+    let bits = 64 - cycles.leading_zeros() as usize;
+    bits.min(LATENCY_BUCKETS - 1)
+}
+
+impl Device for StatsDevice {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let begin = cpu::cycle();
+        let ret = self.device.read_at(offset, buf);
+        let bucket = latency_bucket(cpu::cycle().wrapping_sub(begin));
+        let mut stats = self.stats.lock();
+        stats.reads += 1;
+        stats.read_latency[bucket] += 1;
+        match ret {
+            Some(len) => stats.read_bytes += len,
+            None => stats.errors += 1,
+        }
+        ret
+    }
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Option<usize> {
+        let begin = cpu::cycle();
+        let ret = self.device.write_at(offset, buf);
+        let bucket = latency_bucket(cpu::cycle().wrapping_sub(begin));
+        let mut stats = self.stats.lock();
+        stats.writes += 1;
+        stats.write_latency[bucket] += 1;
+        match ret {
+            Some(len) => stats.write_bytes += len,
+            None => stats.errors += 1,
+        }
+        ret
+    }
+}
+
+impl fmt::Display for IoStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {} reads ({} bytes), {} writes ({} bytes), {} errors", self.name,
+                 self.reads, self.read_bytes, self.writes, self.write_bytes, self.errors)?;
+        for (kind, hist) in [("read", &self.read_latency), ("write", &self.write_latency)].iter() {
+            write!(f, "  {} latency (log2 cycles):", kind)?;
+            for (i, &count) in hist.iter().enumerate().filter(|&(_, &c)| c != 0) {
+                write!(f, " {}:{}", i, count)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print statistics of all devices
+pub fn dump() {
+    for stats in all_stats() {
+        print!("{}", stats);
+    }
+}
@@ -2,7 +2,9 @@
 
 use core::panic::PanicInfo;
 use core::alloc::Layout;
+use core::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
 use log::*;
+use crate::arch::cpu;
 use crate::backtrace;
 
 #[lang = "eh_personality"] 
@@ -11,14 +13,22 @@ extern fn eh_personality() {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // Set when the backtrace is being printed,
+    // so that a fault while walking the stack won't panic recursively.
+    static IN_BACKTRACE: AtomicBool = ATOMIC_BOOL_INIT;
+
     let location = info.location().unwrap();
     let message = info.message().unwrap();
-    error!("\n\nPANIC in {} at line {}\n    {}", location.file(), location.line(), message);
-    backtrace::backtrace();
-    loop { crate::arch::cpu::halt() }
+    error!("\n\nPANIC on CPU{} at {}:{}:{}\n    {}",
+           cpu::id(), location.file(), location.line(), location.column(), message);
+    if !IN_BACKTRACE.swap(true, Ordering::SeqCst) {
+        backtrace::backtrace();
+        IN_BACKTRACE.store(false, Ordering::SeqCst);
+    }
+    loop { cpu::halt() }
 }
 
 #[lang = "oom"]
-fn oom(_: Layout) -> ! {
-    panic!("out of memory");
+fn oom(layout: Layout) -> ! {
+    panic!("out of memory: {:?}, heap: {:?}", layout, crate::memory::heap_stats());
 }
@@ -6,6 +6,7 @@
 #![feature(optin_builtin_traits)]
 #![feature(panic_info_message)]
 #![feature(global_asm)]
+#![feature(try_reserve)]
 #![no_std]
 
 // just keep it ...
@@ -14,19 +15,24 @@ extern crate alloc;
 
 pub use crate::process::{processor, new_kernel_context};
 use rcore_process::thread;
-use linked_list_allocator::LockedHeap;
+use crate::memory::LockedHeapNoIrq;
 
 #[macro_use]    // print!
 mod logging;
+mod cmdline;
 mod memory;
 mod lang;
 mod util;
 mod consts;
 mod process;
 mod syscall;
+#[macro_use]    // impl_inode!
 mod fs;
 mod sync;
 mod trap;
+mod time;
+mod rand;
+mod watchdog;
 mod shell;
 mod drivers;
 mod net;
@@ -55,4 +61,4 @@ pub fn kmain() -> ! {
 ///
 /// It should be defined in memory mod, but in Rust `global_allocator` must be in root mod.
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: LockedHeapNoIrq = LockedHeapNoIrq::empty();
@@ -5,20 +5,95 @@ use lazy_static::lazy_static;
 
 lazy_static! {
     static ref log_mutex: Mutex<()> = Mutex::new(());
+    /// Log level of each target (module path prefix), overriding the default level
+    ///
+    /// The longest target containing the module wins, matched on `::` boundaries,
+    /// so `fs` covers `fs::cache` but not `fsck`.
+    /// It is a fixed array since the logger is initialized before the heap.
+    static ref TARGET_LEVELS: Mutex<[Option<(&'static str, LevelFilter)>; MAX_TARGETS]> =
+        Mutex::new([None; MAX_TARGETS]);
+    /// Log level of targets not listed in `TARGET_LEVELS`
+    static ref DEFAULT_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Warn);
 }
 
+const MAX_TARGETS: usize = 8;
+
+/// Init the logger
+///
+/// The initial levels are read from environment variable `LOG` at compile time,
+/// e.g. `LOG=warn,simple_filesystem=trace`.
+/// They can be changed at runtime by `set_level` and `set_target_level`.
 pub fn init() {
     static LOGGER: SimpleLogger = SimpleLogger;
     log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(match option_env!("LOG") {
-        Some("off") => LevelFilter::Off,
-        Some("error") => LevelFilter::Error,
-        Some("warn") => LevelFilter::Warn,
-        Some("info") => LevelFilter::Info,
-        Some("debug") => LevelFilter::Debug,
-        Some("trace") => LevelFilter::Trace,
+    for directive in option_env!("LOG").unwrap_or("warn").split(',') {
+        let mut iter = directive.splitn(2, '=');
+        match (iter.next(), iter.next()) {
+            (Some(target), Some(level)) => { set_target_level(target, level_from_str(level)); }
+            (Some(level), None) => set_level(level_from_str(level)),
+            _ => {}
+        }
+    }
+}
+
+fn level_from_str(s: &str) -> LevelFilter {
+    match s {
+        "off" => LevelFilter::Off,
+        "error" => LevelFilter::Error,
+        "warn" => LevelFilter::Warn,
+        "info" => LevelFilter::Info,
+        "debug" => LevelFilter::Debug,
+        "trace" => LevelFilter::Trace,
         _ => LevelFilter::Warn,
-    });
+    }
+}
+
+/// Set the log level of all targets without a specific level
+pub fn set_level(level: LevelFilter) {
+    *DEFAULT_LEVEL.lock() = level;
+    update_max_level();
+}
+
+/// Set the log level of `target` and all its sub-modules
+///
+/// Return false if there are too many targets.
+pub fn set_target_level(target: &'static str, level: LevelFilter) -> bool {
+    let mut levels = TARGET_LEVELS.lock();
+    let slot = match levels.iter().position(|entry| entry.map(|(t, _)| t) == Some(target)) {
+        Some(i) => i,
+        None => match levels.iter().position(|entry| entry.is_none()) {
+            Some(i) => i,
+            None => return false,
+        },
+    };
+    levels[slot] = Some((target, level));
+    drop(levels);
+    update_max_level();
+    true
+}
+
+/// The `log` crate drops records above the max level before calling the logger,
+/// so it must be the loosest of all levels.
+fn update_max_level() {
+    let max = TARGET_LEVELS.lock().iter()
+        .filter_map(|&entry| entry.map(|(_, level)| level))
+        .fold(*DEFAULT_LEVEL.lock(), |a, b| a.max(b));
+    log::set_max_level(max);
+}
+
+/// Whether `target` is `module` or one of its sub-modules
+fn is_in_module(target: &str, module: &str) -> bool {
+    target.starts_with(module) && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+}
+
+/// Level of the most specific module containing `target`
+fn target_level(target: &str) -> LevelFilter {
+    TARGET_LEVELS.lock().iter()
+        .filter_map(|&entry| entry)
+        .filter(|&(t, _)| is_in_module(target, t))
+        .max_by_key(|&(t, _)| t.len())
+        .map(|(_, level)| level)
+        .unwrap_or(*DEFAULT_LEVEL.lock())
 }
 
 #[macro_export]
@@ -57,8 +132,8 @@ pub fn print(args: fmt::Arguments) {
 struct SimpleLogger;
 
 impl Log for SimpleLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-       true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= target_level(metadata.target())
     }
     fn log(&self, record: &Record) {
         static DISABLED_TARGET: &[&str] = &[
@@ -6,10 +6,16 @@ use rcore_memory::*;
 use rcore_memory::cow::CowExt;
 pub use rcore_memory::memory_set::{MemoryArea, MemoryAttr, handler::*};
 use crate::process::{process};
-use crate::sync::{SpinNoIrqLock, SpinNoIrq, MutexGuard};
+use crate::sync::{SpinNoIrqLock, SpinNoIrq, MutexGuard, FlagsGuard};
 use lazy_static::*;
 use log::*;
 use linked_list_allocator::LockedHeap;
+#[cfg(not(feature = "buddy_alloc"))]
+use linked_list_allocator::LockedHeap as KernelHeap;
+#[cfg(feature = "buddy_alloc")]
+use buddy_allocator::LockedHeap as KernelHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[cfg(not(feature = "no_mmu"))]
 pub type MemorySet = rcore_memory::memory_set::MemorySet<InactivePageTable0>;
@@ -102,10 +108,294 @@ pub fn page_fault_handler(addr: usize) -> bool {
 pub fn init_heap() {
     use crate::consts::KERNEL_HEAP_SIZE;
     static mut HEAP: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
-    unsafe { HEAP_ALLOCATOR.lock().init(HEAP.as_ptr() as usize, KERNEL_HEAP_SIZE); }
+    unsafe { HEAP_ALLOCATOR.init(HEAP.as_ptr() as usize, KERNEL_HEAP_SIZE); }
     info!("heap init end");
 }
 
+/// Heap allocator which can be used in interrupt context.
+///
+/// Interrupt is disabled while holding the inner lock,
+/// so an interrupt handler allocating memory never deadlocks with the code it interrupted.
+///
+/// Small objects are allocated from slabs, see `slab_class()`.
+///
+/// It also keeps statistics of heap usage, see `heap_stats()`.
+pub struct LockedHeapNoIrq {
+    heap: KernelHeap,
+    /// Head of the free object list of each slab class, 0 if empty
+    slabs: spin::Mutex<[usize; SLAB_CLASSES]>,
+    alloc_count: AtomicUsize,
+    dealloc_count: AtomicUsize,
+    total: AtomicUsize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// Statistics of the kernel heap
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Number of successful allocations
+    pub alloc_count: usize,
+    /// Number of deallocations
+    pub dealloc_count: usize,
+    /// Size of the heap in bytes
+    pub total: usize,
+    /// Bytes currently allocated
+    pub used: usize,
+    /// Maximum of `used` since boot
+    pub peak: usize,
+}
+
+impl LockedHeapNoIrq {
+    pub const fn empty() -> Self {
+        LockedHeapNoIrq {
+            heap: KernelHeap::empty(),
+            slabs: spin::Mutex::new([0; SLAB_CLASSES]),
+            alloc_count: AtomicUsize::new(0),
+            dealloc_count: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        let _flags = FlagsGuard::no_irq_region();
+        self.heap.lock().init(start, size);
+        self.total.store(size, Ordering::Relaxed);
+    }
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            alloc_count: self.alloc_count.load(Ordering::Relaxed),
+            dealloc_count: self.dealloc_count.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            used: self.used.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+        }
+    }
+    fn account_alloc(&self, size: usize) {
+        self.alloc_count.fetch_add(1, Ordering::Relaxed);
+        let used = self.used.fetch_add(size, Ordering::Relaxed) + size;
+        let mut peak = self.peak.load(Ordering::Relaxed);
+        while used > peak {
+            match self.peak.compare_exchange_weak(peak, used, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(p) => peak = p,
+            }
+        }
+    }
+    fn account_dealloc(&self, size: usize) {
+        self.dealloc_count.fetch_add(1, Ordering::Relaxed);
+        self.used.fetch_sub(size, Ordering::Relaxed);
+    }
+    /// Allocate a small object from its slab, or a large block from the heap
+    unsafe fn alloc_block(&self, layout: Layout) -> *mut u8 {
+        let class = match slab_class(&layout) {
+            Some(class) => class,
+            None => return self.heap.alloc(layout),
+        };
+        let mut slabs = self.slabs.lock();
+        if slabs[class] == 0 {
+            // refill the free list with a new slab
+            let obj_size = SLAB_MIN_OBJECT << class;
+            let slab = self.heap.alloc(Layout::from_size_align_unchecked(SLAB_SIZE, obj_size));
+            if slab.is_null() {
+                return slab;
+            }
+            for obj in (slab as usize..slab as usize + SLAB_SIZE).step_by(obj_size) {
+                *(obj as *mut usize) = slabs[class];
+                slabs[class] = obj;
+            }
+        }
+        let obj = slabs[class];
+        slabs[class] = *(obj as *const usize);
+        obj as *mut u8
+    }
+    /// Return a small object to its slab, or a large block to the heap
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout) {
+        match slab_class(&layout) {
+            Some(class) => {
+                let mut slabs = self.slabs.lock();
+                *(ptr as *mut usize) = slabs[class];
+                slabs[class] = ptr as usize;
+            }
+            None => self.heap.dealloc(ptr, layout),
+        }
+    }
+}
+
+/// Number of slab classes. Objects of class `i` have size `SLAB_MIN_OBJECT << i`.
+const SLAB_CLASSES: usize = 5;
+const SLAB_MIN_OBJECT: usize = 16;
+/// Size of memory taken from the heap each time a slab is empty
+const SLAB_SIZE: usize = 4096;
+
+/// Slab class for `layout`, or None if it's too large for slabs.
+///
+/// Kernel objects like inodes, cache entries and directory entries are small and
+/// allocated frequently. Serving them from slabs of fixed-size objects avoids
+/// fragmenting the heap. Slab memory is not returned to the heap.
+fn slab_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    (0..SLAB_CLASSES).find(|&i| size <= SLAB_MIN_OBJECT << i)
+}
+
+unsafe impl GlobalAlloc for LockedHeapNoIrq {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _flags = FlagsGuard::no_irq_region();
+        #[cfg(feature = "debug_alloc")]
+        let ptr = debug_alloc::on_alloc(self.alloc_block(debug_alloc::outer_layout(layout)), layout);
+        #[cfg(not(feature = "debug_alloc"))]
+        let ptr = self.alloc_block(layout);
+        if !ptr.is_null() {
+            self.account_alloc(layout.size());
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _flags = FlagsGuard::no_irq_region();
+        #[cfg(feature = "debug_alloc")]
+        self.dealloc_block(debug_alloc::on_dealloc(ptr, layout), debug_alloc::outer_layout(layout));
+        #[cfg(not(feature = "debug_alloc"))]
+        self.dealloc_block(ptr, layout);
+        self.account_dealloc(layout.size());
+    }
+}
+
+/// Red zones around heap blocks, poison on free and double free detection
+///
+/// Each block is surrounded by red zones filled with `RED_ZONE_BYTE`.
+/// Live blocks are recorded in a fixed table, so freeing a block twice, or freeing
+/// a pointer which was never allocated, is caught without trusting heap memory.
+/// Everything is checked when the block is freed.
+#[cfg(feature = "debug_alloc")]
+mod debug_alloc {
+    use core::alloc::Layout;
+    use core::{ptr, slice};
+    use log::*;
+
+    const RED_ZONE_SIZE: usize = 32;
+    const RED_ZONE_BYTE: u8 = 0xfd;
+    const POISON_BYTE: u8 = 0xdd;
+    /// Number of live blocks which can be tracked, must be a power of 2
+    const LIVE_SLOTS: usize = 1 << 14;
+
+    /// Open addressing hash table from user pointers to sizes, 0 is an empty slot
+    struct LiveTable {
+        slots: [(usize, usize); LIVE_SLOTS],
+        /// Set when the table was full, after that unknown pointers can't be reported
+        overflowed: bool,
+    }
+
+    static LIVE: spin::Mutex<LiveTable> = spin::Mutex::new(LiveTable {
+        slots: [(0, 0); LIVE_SLOTS],
+        overflowed: false,
+    });
+
+    impl LiveTable {
+        fn hash(addr: usize) -> usize {
+            (addr >> 4).wrapping_mul(0x9e37_79b9) % LIVE_SLOTS
+        }
+
+        fn insert(&mut self, addr: usize, size: usize) {
+            let mut i = Self::hash(addr);
+            for _ in 0..LIVE_SLOTS {
+                if self.slots[i].0 == 0 {
+                    self.slots[i] = (addr, size);
+                    return;
+                }
+                i = (i + 1) % LIVE_SLOTS;
+            }
+            if !self.overflowed {
+                warn!("debug_alloc: more than {} live blocks, stop checking frees", LIVE_SLOTS);
+                self.overflowed = true;
+            }
+        }
+
+        /// Remove `addr`, return its size
+        fn remove(&mut self, addr: usize) -> Option<usize> {
+            let mut i = Self::hash(addr);
+            loop {
+                match self.slots[i] {
+                    (0, _) => return None,
+                    (a, _) if a == addr => break,
+                    _ => i = (i + 1) % LIVE_SLOTS,
+                }
+            }
+            let size = self.slots[i].1;
+            // shift later entries of the probe sequence back into the hole
+            let mut hole = i;
+            let mut j = i;
+            loop {
+                j = (j + 1) % LIVE_SLOTS;
+                let (a, _) = self.slots[j];
+                if a == 0 {
+                    break;
+                }
+                let home = Self::hash(a);
+                let dist_hole = (hole + LIVE_SLOTS - home) % LIVE_SLOTS;
+                let dist_j = (j + LIVE_SLOTS - home) % LIVE_SLOTS;
+                if dist_hole < dist_j {
+                    self.slots[hole] = self.slots[j];
+                    hole = j;
+                }
+            }
+            self.slots[hole] = (0, 0);
+            Some(size)
+        }
+    }
+
+    fn red_zone_size(layout: &Layout) -> usize {
+        layout.align().max(RED_ZONE_SIZE)
+    }
+
+    /// The layout actually allocated from the heap
+    pub fn outer_layout(layout: Layout) -> Layout {
+        let rz = red_zone_size(&layout);
+        Layout::from_size_align(layout.size() + rz * 2, layout.align()).unwrap()
+    }
+
+    /// Fill the red zones of a newly allocated block, return the pointer given to the user
+    pub unsafe fn on_alloc(outer: *mut u8, layout: Layout) -> *mut u8 {
+        if outer.is_null() {
+            return outer;
+        }
+        let rz = red_zone_size(&layout);
+        let user = outer.add(rz);
+        ptr::write_bytes(outer, RED_ZONE_BYTE, rz);
+        ptr::write_bytes(user.add(layout.size()), RED_ZONE_BYTE, rz);
+        LIVE.lock().insert(user as usize, layout.size());
+        user
+    }
+
+    /// Check and poison a block being freed, return the pointer to give back to the heap
+    pub unsafe fn on_dealloc(user: *mut u8, layout: Layout) -> *mut u8 {
+        let rz = red_zone_size(&layout);
+        let (size, overflowed) = {
+            let mut live = LIVE.lock();
+            (live.remove(user as usize), live.overflowed)
+        };
+        match size {
+            Some(size) if size == layout.size() => {}
+            Some(size) => panic!("free of {:?} with size {}, allocated with size {}", user, layout.size(), size),
+            None if overflowed => {}
+            None => panic!("double free or free of unallocated pointer {:?}", user),
+        }
+        let outer = user.sub(rz);
+        let front = slice::from_raw_parts(outer, rz);
+        let back = slice::from_raw_parts(user.add(layout.size()), rz);
+        if front.iter().chain(back).any(|&b| b != RED_ZONE_BYTE) {
+            panic!("heap block {:?} {:?} overflowed its red zone", user, layout);
+        }
+        ptr::write_bytes(user, POISON_BYTE, layout.size());
+        outer
+    }
+}
+
+/// Get statistics of the kernel heap
+pub fn heap_stats() -> HeapStats {
+    HEAP_ALLOCATOR.stats()
+}
+
 /// Allocator for the rest memory space on NO-MMU case.
 pub static MEMORY_ALLOCATOR: LockedHeap = LockedHeap::empty();
 
@@ -1,2 +1,4 @@
 mod test;
-pub use self::test::server;
\ No newline at end of file
+mod stack;
+pub use self::test::server;
+pub use self::stack::{TcpStream, parse_ipv4};
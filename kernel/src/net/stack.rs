@@ -0,0 +1,346 @@
+//! TCP/IP stack of clients in the kernel, e.g. of an NFS or NBD root
+//!
+//! It runs on the first network device, with the address given by option
+//! `ip=<addr>/<prefix length>` of the command line, 10.0.0.2/24 by default.
+//! There is no gateway, so servers must be on the same subnet.
+//! There is no network thread either: the interface is polled by the sockets waiting on it.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use alloc::vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use log::*;
+use smoltcp::iface::*;
+use smoltcp::phy::{self, DeviceCapabilities};
+use smoltcp::socket::*;
+use smoltcp::time::Instant;
+use smoltcp::wire::*;
+use smoltcp::{Error, Result};
+
+use crate::drivers::NET_DRIVERS;
+use crate::fs::{NbdStream, NfsTransport};
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::thread;
+
+/// Max length of a frame
+const MTU: usize = 1536;
+/// Milliseconds of a timer tick, the timer runs at 100Hz
+const TICK_MS: i64 = 10;
+/// Ticks to wait for a connection, or for a peer to take or give data
+const TIMEOUT: usize = 10 * 100;
+/// Ticks to wait for a peer to acknowledge the end of a connection
+const CLOSE_TIMEOUT: usize = 100;
+/// Size of the send and receive buffers of a TCP socket
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+/// Local ports are taken in turn from 600 to 1023,
+/// NFS servers only accept requests from ports below 1024 by default
+const FIRST_LOCAL_PORT: usize = 600;
+const LOCAL_PORT_COUNT: usize = 1024 - FIRST_LOCAL_PORT;
+/// Max length of an RPC record, which is much more than the largest NFS reply
+const MAX_RECORD: usize = 1024 * 1024;
+
+/// A driver in `NET_DRIVERS`, as a device of smoltcp
+struct NetDevice(usize);
+
+struct NetRxToken(Vec<u8>);
+struct NetTxToken(usize);
+
+impl<'a> phy::Device<'a> for NetDevice {
+    type RxToken = NetRxToken;
+    type TxToken = NetTxToken;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut frame = vec![0u8; MTU];
+        let len = NET_DRIVERS.lock()[self.0].receive(&mut frame)?;
+        frame.truncate(len);
+        Some((NetRxToken(frame), NetTxToken(self.0)))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(NetTxToken(self.0))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = MTU;
+        caps.max_burst_size = Some(1);
+        caps
+    }
+}
+
+impl phy::RxToken for NetRxToken {
+    fn consume<R, F>(self, _timestamp: Instant, f: F) -> Result<R>
+        where F: FnOnce(&[u8]) -> Result<R>
+    {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for NetTxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+        where F: FnOnce(&mut [u8]) -> Result<R>
+    {
+        let mut frame = vec![0u8; len];
+        let result = f(&mut frame)?;
+        if NET_DRIVERS.lock()[self.0].send(&frame) {
+            Ok(result)
+        } else {
+            Err(Error::Exhausted)
+        }
+    }
+}
+
+struct Stack {
+    iface: EthernetInterface<'static, 'static, 'static, NetDevice>,
+    sockets: SocketSet<'static, 'static, 'static>,
+}
+
+lazy_static! {
+    /// None if there is no network device
+    static ref STACK: Mutex<Option<Stack>> = Mutex::new(Stack::new());
+}
+
+impl Stack {
+    fn new() -> Option<Stack> {
+        let drivers = NET_DRIVERS.lock();
+        let driver = match drivers.first() {
+            Some(driver) => driver,
+            None => {
+                warn!("net: no network device");
+                return None;
+            }
+        };
+        let mut cidr = IpCidr::new(IpAddress::v4(10, 0, 0, 2), 24);
+        if let Some(ip) = crate::cmdline::get("ip") {
+            match parse_cidr(ip) {
+                Some(ip) => cidr = ip,
+                None => warn!("invalid option ip={}, using {}", ip, cidr),
+            }
+        }
+        let iface = EthernetInterfaceBuilder::new(NetDevice(0))
+            .ethernet_addr(driver.get_mac())
+            .ip_addrs(vec![cidr])
+            .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+            .finalize();
+        info!("net: {} at {}", driver.get_ifname(), cidr);
+        Some(Stack { iface, sockets: SocketSet::new(vec![]) })
+    }
+
+    fn poll(&mut self) {
+        let timestamp = Instant::from_millis(unsafe { crate::trap::TICK } as i64 * TICK_MS);
+        if let Err(e) = self.iface.poll(&mut self.sockets, timestamp) {
+            // e.g. a packet of an unsupported protocol
+            debug!("net: {}", e);
+        }
+    }
+}
+
+/// Poll the interface and call `f` with the sockets until it returns Some,
+/// None if `timeout` ticks pass first
+fn poll_until<T>(timeout: usize, mut f: impl FnMut(&mut SocketSet<'static, 'static, 'static>) -> Option<T>) -> Option<T> {
+    let start = unsafe { crate::trap::TICK };
+    loop {
+        {
+            let mut stack = STACK.lock();
+            let stack = stack.as_mut()?;
+            stack.poll();
+            if let Some(result) = f(&mut stack.sockets) {
+                return Some(result);
+            }
+        }
+        if unsafe { crate::trap::TICK }.wrapping_sub(start) > timeout {
+            return None;
+        }
+        thread::yield_now();
+    }
+}
+
+/// Parse an IPv4 address like "10.0.0.1"
+pub fn parse_ipv4(s: &str) -> Option<Ipv4Address> {
+    let mut bytes = [0u8; 4];
+    let mut parts = s.split('.');
+    for byte in bytes.iter_mut() {
+        *byte = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Ipv4Address::from_bytes(&bytes))
+}
+
+/// Parse an IPv4 address with the length of the network prefix, like "10.0.0.2/24"
+fn parse_cidr(s: &str) -> Option<IpCidr> {
+    let mut iter = s.splitn(2, '/');
+    let address = parse_ipv4(iter.next()?)?;
+    let prefix_len = iter.next()?.parse().ok()?;
+    if prefix_len > 32 {
+        return None;
+    }
+    Some(IpCidr::new(IpAddress::Ipv4(address), prefix_len))
+}
+
+static NEXT_LOCAL_PORT: AtomicUsize = AtomicUsize::new(0);
+
+fn local_port() -> u16 {
+    (FIRST_LOCAL_PORT + NEXT_LOCAL_PORT.fetch_add(1, Ordering::Relaxed) % LOCAL_PORT_COUNT) as u16
+}
+
+/// A TCP connection to a server
+pub struct TcpStream {
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    /// Connect to `port` of `server`, None if it fails or times out
+    pub fn connect(server: Ipv4Address, port: u16) -> Option<Self> {
+        let socket = TcpSocket::new(TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                                    TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]));
+        let handle = {
+            let mut stack = STACK.lock();
+            let stack = stack.as_mut()?;
+            let handle = stack.sockets.add(socket);
+            let remote = IpEndpoint::new(IpAddress::Ipv4(server), port);
+            if let Err(e) = stack.sockets.get::<TcpSocket>(handle).connect(remote, local_port()) {
+                warn!("net: failed to connect to {}:{}: {}", server, port, e);
+                stack.sockets.remove(handle);
+                return None;
+            }
+            handle
+        };
+        // removes the socket if the connection fails
+        let stream = TcpStream { handle };
+        let established = poll_until(TIMEOUT, |sockets| {
+            match sockets.get::<TcpSocket>(handle).state() {
+                TcpState::SynSent | TcpState::SynReceived => None,
+                state => Some(state == TcpState::Established),
+            }
+        });
+        if established != Some(true) {
+            warn!("net: failed to connect to {}:{}", server, port);
+            return None;
+        }
+        Some(stream)
+    }
+
+    /// Send all bytes of `buf`, None if the connection fails or times out
+    pub fn send(&mut self, mut buf: &[u8]) -> Option<()> {
+        let handle = self.handle;
+        while !buf.is_empty() {
+            // 0 if the connection is closed
+            let sent = poll_until(TIMEOUT, |sockets| {
+                let mut socket = sockets.get::<TcpSocket>(handle);
+                if !socket.may_send() {
+                    return Some(0);
+                }
+                match socket.send_slice(buf) {
+                    Ok(0) => None,
+                    Ok(sent) => Some(sent),
+                    Err(_) => Some(0),
+                }
+            })?;
+            if sent == 0 {
+                return None;
+            }
+            buf = &buf[sent..];
+        }
+        Some(())
+    }
+
+    /// Receive exactly `buf.len()` bytes, None if the connection fails, is closed or times out
+    pub fn recv(&mut self, buf: &mut [u8]) -> Option<()> {
+        let handle = self.handle;
+        let mut done = 0;
+        while done < buf.len() {
+            // 0 if the connection is closed
+            let received = poll_until(TIMEOUT, |sockets| {
+                let mut socket = sockets.get::<TcpSocket>(handle);
+                if socket.can_recv() {
+                    return Some(socket.recv_slice(&mut buf[done..]).unwrap_or(0));
+                }
+                if !socket.may_recv() {
+                    return Some(0);
+                }
+                None
+            })?;
+            if received == 0 {
+                return None;
+            }
+            done += received;
+        }
+        Some(())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let handle = self.handle;
+        if let Some(stack) = STACK.lock().as_mut() {
+            stack.sockets.get::<TcpSocket>(handle).close();
+        }
+        // send the data left and the FIN, the server may keep its side open
+        poll_until(CLOSE_TIMEOUT, |sockets| {
+            match sockets.get::<TcpSocket>(handle).state() {
+                TcpState::FinWait2 | TcpState::TimeWait | TcpState::Closed => Some(()),
+                _ => None,
+            }
+        });
+        if let Some(stack) = STACK.lock().as_mut() {
+            stack.sockets.remove(handle);
+        }
+    }
+}
+
+impl NbdStream for TcpStream {
+    fn send(&mut self, buf: &[u8]) -> Option<()> {
+        TcpStream::send(self, buf)
+    }
+    fn recv(&mut self, buf: &mut [u8]) -> Option<()> {
+        TcpStream::recv(self, buf)
+    }
+}
+
+/// ONC RPC over TCP, a message is sent as a record of one fragment
+impl NfsTransport for TcpStream {
+    fn call(&mut self, msg: &[u8]) -> Option<Vec<u8>> {
+        const LAST_FRAGMENT: u32 = 1 << 31;
+        self.send(&(msg.len() as u32 | LAST_FRAGMENT).to_be_bytes())?;
+        self.send(msg)?;
+        let mut reply = Vec::new();
+        loop {
+            let mut mark = [0u8; 4];
+            self.recv(&mut mark)?;
+            let mark = u32::from_be_bytes(mark);
+            let start = reply.len();
+            let len = (mark & !LAST_FRAGMENT) as usize;
+            if start + len > MAX_RECORD {
+                warn!("net: RPC record of more than {} bytes", MAX_RECORD);
+                return None;
+            }
+            reply.resize(start + len, 0);
+            self.recv(&mut reply[start..])?;
+            if mark & LAST_FRAGMENT != 0 {
+                return Some(reply);
+            }
+        }
+    }
+}
+
+pub mod test {
+    use super::*;
+
+    fn parse() {
+        assert_eq!(parse_ipv4("10.0.0.1"), Some(Ipv4Address([10, 0, 0, 1])));
+        assert_eq!(parse_ipv4("10.0.0"), None);
+        assert_eq!(parse_ipv4("10.0.0.1.2"), None);
+        assert_eq!(parse_ipv4("10.0.0.256"), None);
+        assert_eq!(parse_cidr("192.168.1.2/16"),
+                   Some(IpCidr::new(IpAddress::v4(192, 168, 1, 2), 16)));
+        assert_eq!(parse_cidr("10.0.0.2"), None);
+        assert_eq!(parse_cidr("10.0.0.2/33"), None);
+    }
+
+    pub fn test_all() {
+        parse();
+    }
+}
@@ -130,8 +130,9 @@ impl Process {
             arch: unsafe { ArchContext::new_fork(tf, kstack.top(), memory_set.token()) },
             memory_set,
             kstack,
-            files: BTreeMap::default(),
-            cwd: String::new(),
+            // the child shares the open files, and their offsets, with the parent
+            files: self.files.clone(),
+            cwd: self.cwd.clone(),
         })
     }
 }
@@ -9,7 +9,18 @@ pub mod context;
 
 pub fn init() {
     // NOTE: max_time_slice <= 5 to ensure 'priority' test pass
-    let scheduler = Box::new(scheduler::RRScheduler::new(5));
+    // chosen by option `sched=` of the command line, round robin by default
+    let sched = crate::cmdline::get("sched").unwrap_or("rr");
+    let scheduler: Box<scheduler::Scheduler> = match sched {
+        "stride" => Box::new(scheduler::StrideScheduler::new(5)),
+        "cfs" => Box::new(scheduler::CFSScheduler::new(5)),
+        "rr" => Box::new(scheduler::RRScheduler::new(5)),
+        _ => {
+            warn!("unknown scheduler {}, using rr", sched);
+            Box::new(scheduler::RRScheduler::new(5))
+        }
+    };
+    info!("scheduler: {}", sched);
     let manager = Arc::new(ProcessManager::new(scheduler, MAX_PROCESS_NUM));
 
     unsafe {
@@ -19,15 +30,22 @@ pub fn init() {
     }
 
     // Add idle threads
+    // They give the CPU back after every interrupt, instead of keeping it for
+    // a whole time slice, so that a process woken up by the interrupt runs at once
     extern fn idle(_arg: usize) -> ! {
-        loop { cpu::halt(); }
+        loop {
+            cpu::halt();
+            thread::yield_now();
+        }
     }
     use core::str::FromStr;
     let cores = usize::from_str(env!("SMP")).unwrap();
     for i in 0..cores {
         manager.add(Process::new_kernel(idle, i), 0);
     }
+    crate::fs::init();
     crate::shell::run_user_shell();
+    crate::fs::start_flusher();
 
     info!("process init end");
 }
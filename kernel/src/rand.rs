@@ -0,0 +1,176 @@
+//! Random numbers for the kernel and for `/dev/random` and `/dev/urandom`
+//!
+//! Entropy from the hardware random number generator, timestamps of interrupts
+//! and the timer is mixed into a pool. The pool reseeds a ChaCha20 generator,
+//! whose key is replaced after every request, so earlier output can't be recovered.
+
+use lazy_static::lazy_static;
+use log::*;
+use rcore_memory::PAGE_SIZE;
+use crate::arch::cpu;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// Reseed after this many entropy events have been added
+const RESEED_EVENTS: usize = 64;
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function, with a 64-bit counter and nonce
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: [u32; 2]) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce[0];
+    input[15] = nonce[1];
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (s, i) in state.iter_mut().zip(input.iter()) {
+        *s = s.wrapping_add(*i);
+    }
+    state
+}
+
+struct Pool {
+    /// Key of the generator
+    key: [u32; 8],
+    counter: u64,
+    /// Entropy added since the last reseed
+    pending: [u32; 16],
+    /// Where the next entropy is mixed in
+    pos: usize,
+    events: usize,
+}
+
+impl Pool {
+    /// Mix `value` in and count it towards the next reseed
+    fn mix(&mut self, value: u64) {
+        self.stir(value);
+        self.events += 1;
+    }
+
+    /// Mix `value` in without counting it, used for data which may be known to others
+    fn stir(&mut self, value: u64) {
+        for &word in [value as u32, (value >> 32) as u32].iter() {
+            let next = self.pending[(self.pos + 1) % 16];
+            self.pending[self.pos] = self.pending[self.pos].rotate_left(7) ^ word ^ next;
+            self.pos = (self.pos + 1) % 16;
+        }
+    }
+
+    /// Derive a new key from the old key and the pending entropy
+    fn reseed(&mut self) {
+        let mut key = self.key;
+        for (k, p) in key.iter_mut().zip(self.pending[..8].iter()) {
+            *k ^= *p;
+        }
+        let block = chacha20_block(&key, self.pending[8] as u64 | (self.pending[9] as u64) << 32,
+                                   [self.pending[10] ^ self.pending[12] ^ self.pending[14],
+                                    self.pending[11] ^ self.pending[13] ^ self.pending[15]]);
+        self.key.copy_from_slice(&block[..8]);
+        self.pending = [0; 16];
+        self.events = 0;
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        if self.events >= RESEED_EVENTS {
+            self.reseed();
+        }
+        for chunk in buf.chunks_mut(64) {
+            let block = chacha20_block(&self.key, self.counter, [0; 2]);
+            self.counter += 1;
+            for (i, b) in chunk.iter_mut().enumerate() {
+                *b = (block[i / 4] >> (i % 4 * 8)) as u8;
+            }
+        }
+        // replace the key, so the output can't be recovered if the state leaks later
+        let block = chacha20_block(&self.key, self.counter, [!0; 2]);
+        self.counter += 1;
+        self.key.copy_from_slice(&block[..8]);
+    }
+}
+
+lazy_static! {
+    static ref POOL: Mutex<Pool> = {
+        let mut pool = Pool { key: [0; 8], counter: 0, pending: [0; 16], pos: 0, events: 0 };
+        let mut hardware = 0;
+        for _ in 0..8 {
+            if let Some(value) = cpu::random() {
+                pool.mix(value);
+                hardware += 1;
+            }
+            pool.mix(cpu::cycle());
+        }
+        pool.mix(crate::time::unix_time());
+        if hardware == 0 {
+            warn!("rand: no hardware random number generator, seeded from timestamps");
+        }
+        pool.reseed();
+        Mutex::new(pool)
+    };
+}
+
+/// Mix `value` into the pool, only its unpredictable bits count
+pub fn add_entropy(value: u64) {
+    POOL.lock().mix(value);
+}
+
+/// Mix data written by users into the pool without crediting any entropy,
+/// so that a caller can't force a reseed from values it knows
+pub fn add_data(buf: &[u8]) {
+    for page in buf.chunks(PAGE_SIZE) {
+        let mut pool = POOL.lock();
+        for chunk in page.chunks(8) {
+            pool.stir(chunk.iter().fold(0, |value, &b| value << 8 | b as u64));
+        }
+    }
+}
+
+/// Mix the time of an interrupt into the pool, should be called on every interrupt
+pub fn add_interrupt_entropy(irq: usize) {
+    add_entropy(cpu::cycle() ^ (irq as u64).rotate_right(8));
+}
+
+/// Fill `buf` with random bytes.
+///
+/// Large requests are served a page at a time, releasing the pool in between,
+/// so that interrupts are not disabled for long.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(PAGE_SIZE) {
+        let value = cpu::random();
+        let mut pool = POOL.lock();
+        if let Some(value) = value {
+            pool.mix(value);
+        }
+        pool.mix(cpu::cycle());
+        pool.fill(chunk);
+    }
+}
+
+/// A random 64-bit number
+pub fn rand() -> u64 {
+    let mut buf = [0u8; 8];
+    fill(&mut buf);
+    buf.iter().rev().fold(0, |value, &b| value << 8 | b as u64)
+}
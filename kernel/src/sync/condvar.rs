@@ -1,4 +1,5 @@
 use alloc::collections::VecDeque;
+use core::time::Duration;
 use super::*;
 use crate::thread;
 
@@ -19,10 +20,59 @@ impl Condvar {
         where S: MutexSupport
     {
         let mutex = guard.mutex;
+        // join the queue before unlocking, or a notify in between would be missed
+        self.wait_queue.lock().push_back(thread::current());
         drop(guard);
-        self._wait();
+        thread::park();
         mutex.lock()
     }
+    /// Wait until `condition` is true, it is checked with the lock held
+    pub fn wait_until<'a, T, S, F>(&self, mut guard: MutexGuard<'a, T, S>, mut condition: F) -> MutexGuard<'a, T, S>
+        where S: MutexSupport, F: FnMut(&mut T) -> bool
+    {
+        while !condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+    /// Wait for a notify, or until `timeout` has passed.
+    /// Return true as the second value if it timed out.
+    pub fn wait_timeout<'a, T, S>(&self, guard: MutexGuard<'a, T, S>, timeout: Duration) -> (MutexGuard<'a, T, S>, bool)
+        where S: MutexSupport
+    {
+        let mutex = guard.mutex;
+        let current = thread::current();
+        let id = current.id();
+        self.wait_queue.lock().push_back(current);
+        drop(guard);
+        thread::park_timeout(timeout);
+        // a notify takes us out of the queue, so we are still there after a timeout.
+        // a notify right after the timeout leaves a wakeup token, and the next park
+        // returns at once, so waits may wake up spuriously, like std's
+        let timed_out = {
+            let mut queue = self.wait_queue.lock();
+            let len = queue.len();
+            queue.retain(|t| t.id() != id);
+            queue.len() != len
+        };
+        (mutex.lock(), timed_out)
+    }
+    /// Wait until `condition` is true, or until `timeout` has passed.
+    /// Return true as the second value if it timed out with `condition` still false.
+    pub fn wait_timeout_until<'a, T, S, F>(&self, mut guard: MutexGuard<'a, T, S>, timeout: Duration, mut condition: F) -> (MutexGuard<'a, T, S>, bool)
+        where S: MutexSupport, F: FnMut(&mut T) -> bool
+    {
+        // the deadline is counted in timer ticks, of 10 ms each
+        let deadline = unsafe { crate::trap::TICK } + (timeout.as_millis() as usize + 9) / 10;
+        while !condition(&mut *guard) {
+            let now = unsafe { crate::trap::TICK };
+            if now >= deadline {
+                return (guard, true);
+            }
+            guard = self.wait_timeout(guard, Duration::from_millis((deadline - now) as u64 * 10)).0;
+        }
+        (guard, false)
+    }
     pub fn notify_one(&self) {
         if let Some(t) = self.wait_queue.lock().pop_front() {
             t.unpark();
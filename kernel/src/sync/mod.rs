@@ -12,6 +12,10 @@
 //!
 //! * `condvar`: 条件变量。
 //!     依赖`thread`，为其它工具提供线程调度支持。
+//!     即等待队列：支持按条件等待(`wait_until`)、超时等待，唤醒一个或全部线程。
+//!
+//! * `rwlock`: 读写锁。
+//!     依赖`Condvar`，等待时让出CPU。写者优先，避免写者饥饿。
 //!
 //! * `semaphore`: 信号量。
 //!     完全照搬`std::sync::Semaphore`，std中已经废弃。
@@ -37,6 +41,7 @@
 //!	    Condvar --> SpinLock
 //!     Condvar --> thread
 //!     Mutex --> Condvar
+//!     RwLock --> Condvar
 //!	    Monitor --> Condvar
 //!	    Semaphore --> Condvar
 //!	    Semaphore --> SpinLock
@@ -53,9 +58,11 @@
 pub use self::condvar::*;
 pub use self::mutex::*;
 pub use self::semaphore::*;
+pub use self::rwlock::*;
 
 mod mutex;
 mod condvar;
 mod semaphore;
+mod rwlock;
 pub mod mpsc;
 pub mod test;
@@ -232,6 +232,13 @@ pub struct SpinNoIrq;
 /// Contains RFLAGS before disable interrupt, will auto restore it when dropping
 pub struct FlagsGuard(usize);
 
+impl FlagsGuard {
+    /// Disable interrupt until the guard is dropped
+    pub fn no_irq_region() -> Self {
+        FlagsGuard(unsafe { interrupt::disable_and_store() })
+    }
+}
+
 impl Drop for FlagsGuard {
     fn drop(&mut self) {
         unsafe { interrupt::restore(self.0) };
@@ -254,7 +261,7 @@ impl MutexSupport for SpinNoIrq {
         }
     }
     fn before_lock() -> Self::GuardData {
-        FlagsGuard(unsafe { interrupt::disable_and_store() })
+        FlagsGuard::no_irq_region()
     }
     fn after_unlock(&self) {}
 }
@@ -36,10 +36,7 @@ impl Semaphore {
     /// This method will block until the internal count of the semaphore is at
     /// least 1.
     pub fn acquire(&self) {
-        let mut count = self.lock.lock();
-        while *count <= 0 {
-            count = self.cvar.wait(count);
-        }
+        let mut count = self.cvar.wait_until(self.lock.lock(), |count| *count > 0);
         *count -= 1;
     }
 
@@ -9,7 +9,6 @@ use bitflags::bitflags;
 use crate::arch::interrupt::TrapFrame;
 use crate::process::*;
 use crate::thread;
-use crate::util;
 
 /// System call dispatcher
 pub fn syscall(id: usize, args: [usize; 6], tf: &mut TrapFrame) -> isize {
@@ -22,7 +21,7 @@ pub fn syscall(id: usize, args: [usize; 6], tf: &mut TrapFrame) -> isize {
         030 => sys_putc(args[0] as u8 as char),
 //        104 => sys_seek(),
         110 => sys_fstat(args[0], args[1] as *mut Stat),
-//        111 => sys_fsync(),
+        111 => sys_fsync(args[0]),
 //        121 => sys_getcwd(),
         128 => sys_getdirentry(args[0], args[1] as *mut DirEntry),
         130 => sys_dup(args[0], args[1]),
@@ -58,32 +57,31 @@ pub fn syscall(id: usize, args: [usize; 6], tf: &mut TrapFrame) -> isize {
 }
 
 fn sys_read(fd: usize, base: *mut u8, len: usize) -> SysResult {
-    // TODO: check ptr
     info!("read: fd: {}, base: {:?}, len: {:#x}", fd, base, len);
+    check_mut_array(base, len)?;
     let slice = unsafe { slice::from_raw_parts_mut(base, len) };
     let len = get_file(fd)?.lock().read(slice)?;
     Ok(len as isize)
 }
 
 fn sys_write(fd: usize, base: *const u8, len: usize) -> SysResult {
-    // TODO: check ptr
     info!("write: fd: {}, base: {:?}, len: {:#x}", fd, base, len);
+    check_array(base, len)?;
     let slice = unsafe { slice::from_raw_parts(base, len) };
     let len = get_file(fd)?.lock().write(slice)?;
     Ok(len as isize)
 }
 
 fn sys_open(path: *const u8, flags: usize) -> SysResult {
-    // TODO: check ptr
-    let path = unsafe { util::from_cstr(path) };
+    let path = check_and_clone_cstr(path)?;
     let flags = VfsFlags::from_ucore_flags(flags);
     info!("open: path: {:?}, flags: {:?}", path, flags);
-    let (fd, inode) = match path {
+    let (fd, inode) = match path.as_str() {
         "stdin:" => (0, crate::fs::STDIN.clone() as Arc<INode>),
         "stdout:" => (1, crate::fs::STDOUT.clone() as Arc<INode>),
         _ => {
             let fd = (3..).find(|i| !process().files.contains_key(i)).unwrap();
-            let inode = crate::fs::ROOT_INODE.lookup(path)?;
+            let inode = crate::fs::lookup(path.as_str())?;
             (fd, inode)
         }
     };
@@ -100,11 +98,18 @@ fn sys_close(fd: usize) -> SysResult {
     }
 }
 
+fn sys_fsync(fd: usize) -> SysResult {
+    info!("fsync: fd: {}", fd);
+    get_file(fd)?;
+    crate::fs::sync()?;
+    Ok(0)
+}
+
 fn sys_fstat(fd: usize, stat_ptr: *mut Stat) -> SysResult {
-    // TODO: check ptr
     info!("fstat: {}", fd);
+    check_mut_ptr(stat_ptr)?;
     let file = get_file(fd)?;
-    let stat = Stat::from(file.lock().info()?);
+    let stat = Stat::new(file.lock().info()?)?;
     unsafe { stat_ptr.write(stat); }
     Ok(0)
 }
@@ -113,18 +118,20 @@ fn sys_fstat(fd: usize, stat_ptr: *mut Stat) -> SysResult {
 /// dentry.name = entry_name
 /// dentry.offset += 256
 fn sys_getdirentry(fd: usize, dentry_ptr: *mut DirEntry) -> SysResult {
-    // TODO: check ptr
     info!("getdirentry: {}", fd);
+    check_mut_ptr(dentry_ptr)?;
     let file = get_file(fd)?;
     let dentry = unsafe { &mut *dentry_ptr };
     if !dentry.check() {
         return Err(SysError::Inval);
     }
-    let info = file.lock().info()?;
+    // hold the lock across both calls, so the directory can not change in between
+    let file = file.lock();
+    let info = file.info()?;
     if info.type_ != FileType::Dir || info.size <= dentry.entry_id() {
         return Err(SysError::Inval);
     }
-    let name = file.lock().get_entry(dentry.entry_id())?;
+    let name = file.get_entry(dentry.entry_id())?;
     dentry.set_name(name.as_str());
     Ok(0)
 }
@@ -150,7 +157,9 @@ fn sys_fork(tf: &TrapFrame) -> SysResult {
 /// Wait the process exit.
 /// Return the PID. Store exit code to `code` if it's not null.
 fn sys_wait(pid: usize, code: *mut i32) -> SysResult {
-    // TODO: check ptr
+    if !code.is_null() {
+        check_mut_ptr(code)?;
+    }
     loop {
         use alloc::vec;
         let wait_procs = match pid {
@@ -186,24 +195,24 @@ fn sys_wait(pid: usize, code: *mut i32) -> SysResult {
 }
 
 fn sys_exec(name: *const u8, argc: usize, argv: *const *const u8, tf: &mut TrapFrame) -> SysResult {
-    // TODO: check ptr
-    let name = if name.is_null() { "" } else { unsafe { util::from_cstr(name) } };
+    let name = if name.is_null() { String::new() } else { check_and_clone_cstr(name)? };
     info!("exec: {:?}, argc: {}, argv: {:?}", name, argc, argv);
     // Copy args to kernel
-    let args: Vec<String> = unsafe {
-        slice::from_raw_parts(argv, argc).iter()
-            .map(|&arg| String::from(util::from_cstr(arg)))
-            .collect()
-    };
+    check_array(argv, argc)?;
+    let args: Vec<String> = unsafe { slice::from_raw_parts(argv, argc) }.iter()
+        .map(|&arg| check_and_clone_cstr(arg))
+        .collect::<Result<_, _>>()?;
 
     if args.len() <= 0 {
         return Err(SysError::Inval);
     }
     // Read program file
     let path = args[0].as_str();
-    let inode = crate::fs::ROOT_INODE.lookup(path)?;
+    let inode = crate::fs::lookup(path)?;
     let size = inode.info()?.size;
-    let mut buf = Vec::with_capacity(size);
+    // The file size is controlled by user, so don't abort the kernel if it's too large
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(size).map_err(|_| SysError::Nomem)?;
     unsafe { buf.set_len(size); }
     inode.read_at(0, buf.as_mut_slice())?;
 
@@ -217,8 +226,10 @@ fn sys_exec(name: *const u8, argc: usize, argv: *const *const u8, tf: &mut TrapF
     // Modify the TrapFrame
     *tf = unsafe { context.arch.get_init_tf() };
 
-    // Swap Context but keep KStack
+    // Swap Context but keep KStack, open files and working directory
     ::core::mem::swap(&mut process().kstack, &mut context.kstack);
+    ::core::mem::swap(&mut process().files, &mut context.files);
+    ::core::mem::swap(&mut process().cwd, &mut context.cwd);
     ::core::mem::swap(process(), &mut *context);
 
     Ok(0)
@@ -248,6 +259,8 @@ fn sys_getpid() -> SysResult {
 fn sys_exit(exit_code: isize) -> SysResult {
     let pid = thread::current().id();
     info!("exit: {}, code: {}", pid, exit_code);
+    // close the files now, the rest is freed when the parent waits for us
+    process().files.clear();
     processor().manager().exit(pid, exit_code as usize);
     processor().yield_now();
     unreachable!();
@@ -282,6 +295,39 @@ fn get_file(fd: usize) -> Result<&'static Arc<Mutex<File>>, SysError> {
     process().files.get(&fd).ok_or(SysError::Inval)
 }
 
+/// Check `ptr` points to `count` valid `T` in the current process
+fn check_array<T>(ptr: *const T, count: usize) -> Result<(), SysError> {
+    if count == 0 || process().memory_set.check_array(ptr, count) {
+        Ok(())
+    } else {
+        Err(SysError::Fault)
+    }
+}
+
+/// Check `ptr` points to a valid and writable `T` in the current process
+fn check_mut_ptr<T>(ptr: *mut T) -> Result<(), SysError> {
+    if process().memory_set.check_mut_ptr(ptr) {
+        Ok(())
+    } else {
+        Err(SysError::Fault)
+    }
+}
+
+/// Check `ptr` points to `count` valid and writable `T` in the current process
+fn check_mut_array<T>(ptr: *mut T, count: usize) -> Result<(), SysError> {
+    if count == 0 || process().memory_set.check_mut_array(ptr, count) {
+        Ok(())
+    } else {
+        Err(SysError::Fault)
+    }
+}
+
+/// Copy the null-terminated string at `ptr` in the current process to kernel
+fn check_and_clone_cstr(ptr: *const u8) -> Result<String, SysError> {
+    process().memory_set.check_and_clone_cstr(ptr)
+        .ok_or(SysError::Fault)
+}
+
 pub type SysResult = Result<isize, SysError>;
 
 #[repr(isize)]
@@ -294,6 +340,9 @@ pub enum SysError {
     // we only add current used errors here
     Inval = 3,// Invalid argument, also Invaild fd number.
     Nomem = 4,// Out of memory, also used as no device space in ucore
+    Fault = 6,// Memory fault, e.g. invalid user pointer
+    Toobig = 12,// Value too large, e.g. a file size which does not fit in struct stat
+    Io = 14,// I/O error of a device, ucore has no EIO so E_NA_DEV is used
     Noent = 16,// No such file or directory
     Isdir = 17,// Fd is a directory
     Notdir = 18,// Fd is not a directory
@@ -308,7 +357,10 @@ pub enum SysError {
 
 impl From<FsError> for SysError {
     fn from(error: FsError) -> Self {
+        // always taken, so that it is not left for a later error
+        let device_error = crate::fs::take_device_error();
         match error {
+            FsError::NotSupported if device_error => SysError::Io,
             FsError::NotSupported => SysError::Unimp,
             FsError::NotFile => SysError::Isdir,
             FsError::IsDir => SysError::Isdir,
@@ -368,6 +420,17 @@ impl DirEntry {
     }
 }
 
+/// Type of `Stat::blocks` and `Stat::size`
+///
+/// The default layout matches 32-bit ucore user programs.
+/// Enable feature `stat64` for user programs built with 64-bit file sizes.
+/// Only the stat layout changes: offsets and lengths of read and write are `usize`
+/// in simple-filesystem and the syscall ABI, so they are 64-bit on 64-bit targets only.
+#[cfg(not(feature = "stat64"))]
+type StatSize = u32;
+#[cfg(feature = "stat64")]
+type StatSize = u64;
+
 #[repr(C)]
 struct Stat {
     /// protection mode and file type
@@ -375,14 +438,32 @@ struct Stat {
     /// number of hard links
     nlinks: u32,
     /// number of blocks file is using
-    blocks: u32,
+    blocks: StatSize,
     /// file size (bytes)
-    size: u32,
+    size: StatSize,
 }
 
+/// Fail the build if a struct shared with user programs changes its size.
+///
+/// The sizes must match `struct dirent` in ucore's `user/libs/dir.h` and `struct stat` in `libs/stat.h`.
+macro_rules! assert_abi_size {
+    ($name:ident, $type_:ty, $size:expr) => {
+        #[allow(dead_code)]
+        const $name: [(); $size] = [(); core::mem::size_of::<$type_>()];
+    };
+}
+
+assert_abi_size!(DIR_ENTRY_SIZE, DirEntry, 260);
+#[cfg(not(feature = "stat64"))]
+assert_abi_size!(STAT_SIZE, Stat, 16);
+#[cfg(feature = "stat64")]
+assert_abi_size!(STAT_SIZE, Stat, 24);
+
 bitflags! {
     struct StatMode: u32 {
         const NULL  = 0;
+        /// Type
+        const TYPE_MASK = 0o170000;
         /// ordinary regular file
         const FILE  = 0o10000;
         /// directory
@@ -393,13 +474,46 @@ bitflags! {
         const CHAR  = 0o40000;
         /// block device
         const BLOCK = 0o50000;
+
+        /// Set user ID on execution
+        const SET_UID = 0o4000;
+        /// Set group ID on execution
+        const SET_GID = 0o2000;
+        /// Sticky bit
+        const STICKY = 0o1000;
+        /// Read, write, execute/search by owner
+        const OWNER_MASK = 0o700;
+        /// Read permission, owner
+        const OWNER_READ = 0o400;
+        /// Write permission, owner
+        const OWNER_WRITE = 0o200;
+        /// Execute/search permission, owner
+        const OWNER_EXEC = 0o100;
+        /// Read, write, execute/search by group
+        const GROUP_MASK = 0o70;
+        /// Read permission, group
+        const GROUP_READ = 0o40;
+        /// Write permission, group
+        const GROUP_WRITE = 0o20;
+        /// Execute/search permission, group
+        const GROUP_EXEC = 0o10;
+        /// Read, write, execute/search by others
+        const OTHER_MASK = 0o7;
+        /// Read permission, others
+        const OTHER_READ = 0o4;
+        /// Write permission, others
+        const OTHER_WRITE = 0o2;
+        /// Execute/search permission, others
+        const OTHER_EXEC = 0o1;
     }
 }
 
-impl From<FileInfo> for Stat {
-    fn from(info: FileInfo) -> Self {
-        Stat {
-            mode: match info.type_ {
+impl StatMode {
+    fn from_type_mode(type_: FileType, mode: u32) -> Self {
+        let type_ = match StatMode::from_bits_truncate(mode) & StatMode::TYPE_MASK {
+            // devices keep their type in the mode, see `fs::MODE_CHAR`
+            device if !device.is_empty() => device,
+            _ => match type_ {
                 FileType::File => StatMode::FILE,
                 FileType::Dir => StatMode::DIR,
                 // _ => StatMode::NULL,
@@ -407,9 +521,30 @@ impl From<FileInfo> for Stat {
                 //      but it is currently not implemented for enum
                 //      see rust-lang/rust#44109
             },
-            nlinks: info.nlinks as u32,
-            blocks: info.blocks as u32,
-            size: info.size as u32,
+        };
+        // SFS does not store permission bits on disk (mode is always 0),
+        // so report the conventional defaults instead of `----------`.
+        let mode = match StatMode::from_bits_truncate(mode) & !StatMode::TYPE_MASK {
+            perm if !perm.is_empty() => perm,
+            _ if type_ == StatMode::DIR => StatMode::from_bits_truncate(0o755),
+            _ => StatMode::from_bits_truncate(0o644),
+        };
+        type_ | mode
+    }
+}
+
+impl Stat {
+    /// Fail instead of truncating sizes which do not fit in `StatSize`
+    fn new(info: FileInfo) -> Result<Self, SysError> {
+        let max = StatSize::max_value() as u64;
+        if info.size as u64 > max || info.blocks as u64 > max {
+            return Err(SysError::Toobig);
         }
+        Ok(Stat {
+            mode: StatMode::from_type_mode(info.type_, info.mode),
+            nlinks: info.nlinks as u32,
+            blocks: info.blocks as StatSize,
+            size: info.size as StatSize,
+        })
     }
 }
@@ -0,0 +1,84 @@
+//! Wall clock time, read from a real-time clock
+
+use lazy_static::lazy_static;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+lazy_static! {
+    /// Read the real-time clock, in seconds since the Unix epoch
+    static ref RTC: Mutex<Option<fn() -> u64>> = Mutex::new(None);
+}
+
+/// Use `read` as the source of wall clock time
+pub fn register_rtc(read: fn() -> u64) {
+    *RTC.lock() = Some(read);
+}
+
+/// Seconds since the Unix epoch, or 0 if there is no RTC
+pub fn unix_time() -> u64 {
+    match *RTC.lock() {
+        Some(read) => read(),
+        None => 0,
+    }
+}
+
+/// The current date and time, in UTC
+pub fn now() -> DateTime {
+    DateTime::from_unix(unix_time())
+}
+
+/// A date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    /// 1 to 12
+    pub month: u32,
+    /// 1 to 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    pub fn from_unix(time: u64) -> Self {
+        let days = (time / 86400) as i64;
+        let secs = (time % 86400) as u32;
+        // days since 0000-03-01, so leap days are at the end of years
+        let days = days + 719468;
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let m = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * m + 2) / 5 + 1) as u32;
+        let month = if m < 10 { m + 3 } else { m - 9 } as u32;
+        let year = (era * 400 + year_of_era) as u32 + if month <= 2 { 1 } else { 0 };
+        DateTime { year, month, day, hour: secs / 3600, minute: secs / 60 % 60, second: secs % 60 }
+    }
+
+    /// Seconds since the Unix epoch, 0 for a date before it
+    pub fn to_unix(&self) -> u64 {
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let m = self.month as i64;
+        let day_of_year = (153 * if m > 2 { m - 3 } else { m + 9 } + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        if days < 0 {
+            return 0;
+        }
+        days as u64 * 86400 + (self.hour * 3600 + self.minute * 60 + self.second) as u64
+    }
+
+    /// Date in the high 16 bits and time in the low 16 bits, as in FAT and exFAT.
+    /// Dates before 1980 are clamped to 1980-01-01 00:00.
+    pub fn to_dos(&self) -> u32 {
+        if self.year < 1980 {
+            return 0x0021_0000;
+        }
+        let date = (self.year - 1980).min(127) << 9 | self.month << 5 | self.day;
+        let time = self.hour << 11 | self.minute << 5 | self.second / 2;
+        date << 16 | time
+    }
+}
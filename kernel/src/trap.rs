@@ -9,6 +9,7 @@ pub fn timer() {
     if cpu::id() == 0 {
         unsafe { TICK += 1; }
     }
+    crate::watchdog::tick(cpu::id(), processor().pid());
     processor().tick();
 }
 
@@ -0,0 +1,100 @@
+//! Hardware watchdog, and detection of CPUs which stop taking timer interrupts
+//!
+//! Every CPU counts its timer interrupts. On each tick CPU 0 checks the counts of
+//! the others: a CPU whose count has not moved for `LOCKUP_TICKS` is stuck, e.g.
+//! spinning on a lock with interrupts disabled, and the kernel panics with what
+//! it was running. If CPU 0 itself gets stuck, it stops petting the hardware
+//! watchdog, which resets the machine.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use log::*;
+use crate::consts::MAX_CPU_NUM;
+use crate::sync::SpinNoIrqLock as Mutex;
+
+/// Ticks of CPU 0 without a tick on another CPU before it is considered stuck
+const LOCKUP_TICKS: usize = 2000;
+/// Seconds without petting before the hardware watchdog resets the machine
+const TIMEOUT: u32 = 30;
+/// Pet the hardware watchdog every this many ticks
+const PET_INTERVAL: usize = 16;
+
+/// A watchdog which resets the machine unless it is petted in time
+pub trait Watchdog: Send {
+    /// Start counting down from `timeout` seconds
+    fn start(&mut self, timeout: u32);
+    /// Restart the countdown
+    fn pet(&mut self);
+    fn stop(&mut self);
+}
+
+lazy_static! {
+    static ref WATCHDOG: Mutex<Option<Box<Watchdog>>> = Mutex::new(None);
+}
+
+/// Timer interrupts taken by each CPU
+static TICKS: [AtomicUsize; MAX_CPU_NUM] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+/// Process running on each CPU at its last tick
+static RUNNING: [AtomicUsize; MAX_CPU_NUM] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+/// What CPU 0 saw of each CPU: its count of ticks, and the tick of CPU 0 when it changed
+struct Progress {
+    seen: [usize; MAX_CPU_NUM],
+    since: [usize; MAX_CPU_NUM],
+}
+
+/// Only touched by CPU 0
+static mut PROGRESS: Progress = Progress { seen: [0; MAX_CPU_NUM], since: [0; MAX_CPU_NUM] };
+
+/// Use `watchdog` and start it. It is petted from the timer interrupt from now on.
+pub fn register_watchdog(mut watchdog: Box<Watchdog>) {
+    watchdog.start(TIMEOUT);
+    info!("watchdog: started with a timeout of {} seconds", TIMEOUT);
+    *WATCHDOG.lock() = Some(watchdog);
+}
+
+/// Stop the hardware watchdog, e.g. before shutting down
+pub fn stop() {
+    if let Some(watchdog) = WATCHDOG.lock().as_mut() {
+        watchdog.stop();
+    }
+}
+
+/// Called on every timer interrupt of CPU `cpu`, which is running process `pid`
+pub fn tick(cpu: usize, pid: usize) {
+    let ticks = TICKS[cpu].fetch_add(1, Ordering::Relaxed) + 1;
+    RUNNING[cpu].store(pid, Ordering::Relaxed);
+    if cpu != 0 {
+        return;
+    }
+    let progress = unsafe { &mut PROGRESS };
+    for other in 1..MAX_CPU_NUM {
+        let count = TICKS[other].load(Ordering::Relaxed);
+        if count == 0 {
+            // not started
+            continue;
+        }
+        if count != progress.seen[other] {
+            progress.seen[other] = count;
+            progress.since[other] = ticks;
+        } else if ticks - progress.since[other] > LOCKUP_TICKS {
+            panic!("soft lockup: CPU{} has taken no timer interrupt for {} ticks, running process {}",
+                   other, ticks - progress.since[other], RUNNING[other].load(Ordering::Relaxed));
+        }
+    }
+    if ticks % PET_INTERVAL == 0 {
+        // a lock held by a stuck CPU must not stop us
+        if let Some(mut watchdog) = WATCHDOG.try_lock() {
+            if let Some(watchdog) = watchdog.as_mut() {
+                watchdog.pet();
+            }
+        }
+    }
+}